@@ -0,0 +1,70 @@
+//! Measure how fast a heap allocates and frees data.
+//!
+//! Every round builds lists of small data, keeps every tenth list alive, and
+//! collects the rest, so that later rounds allocate into the storage freed by
+//! earlier ones. Run with `cargo run --release --example alloc_bench`.
+
+extern crate mana;
+
+use std::time::Duration;
+use std::time::Instant;
+
+use mana::datum::Datum;
+use mana::datum::Heap;
+use mana::sigil::Sigil;
+
+const NIL:  Sigil = Sigil(0);
+const CONS: Sigil = Sigil(1);
+
+/// The number of rounds to run, and the number of lists and their length in
+/// each round.
+const ROUNDS:      usize = 20;
+const LISTS:       usize = 100;
+const LIST_LENGTH: usize = 1_000;
+
+/// The number of times the whole benchmark is run; the fastest run counts.
+const RUNS: usize = 5;
+
+fn main() {
+    let mut best = Duration::from_secs(u64::MAX);
+    for _ in 0 .. RUNS {
+        let elapsed = run();
+        println!("{:?}", elapsed);
+        best = best.min(elapsed);
+    }
+    let data = ROUNDS * LISTS * LIST_LENGTH;
+    println!("best of {}: {:?} for {} data, {:.1}ns per datum", RUNS, best,
+             data, best.as_nanos() as f64 / data as f64);
+}
+
+fn run() -> Duration {
+    let heap = Heap::new();
+    let start = Instant::now();
+    let mut survivors = Vec::new();
+    for _ in 0 .. ROUNDS {
+        for index in 0 .. LISTS {
+            let list = list(&heap, LIST_LENGTH);
+            if index % 10 == 0 {
+                survivors.push(list);
+            }
+        }
+        heap.collect_garbage();
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(heap.len(), survivors.len() * (LIST_LENGTH + 1));
+    elapsed
+}
+
+/// Allocate a list of the given length, whose elements are immediates.
+fn list(heap: &Heap, length: usize) -> Datum<'_> {
+    // This is safe because every pointer is an immediate or belongs to the
+    // heap.
+    unsafe {
+        let mut list = heap.allocate(NIL, &[], &[]);
+        for element in 0 .. length {
+            let element = Datum::from_i64(element as i64).unwrap();
+            list = heap.allocate(CONS, &[element, list], &[]);
+        }
+        list
+    }
+}
@@ -0,0 +1,107 @@
+//! Measure how much linking speeds up static invocations.
+//!
+//! The naive recursive Fibonacci spell is run on a database as built, whose
+//! invocations look up the spell by id, and on the same database once linked,
+//! whose invocations index the spell directly. Run with
+//! `cargo run --release --example link_bench`.
+
+extern crate mana;
+
+use std::time::Duration;
+use std::time::Instant;
+
+use mana::datum::Datum;
+use mana::datum::Heap;
+use mana::interpret::Runtime;
+use mana::interpret::TypeSigils;
+use mana::interpret::run;
+use mana::sigil::Sigil;
+use mana::spell::ConstantValue;
+use mana::spell::SpellBuilder;
+use mana::spell::SpellId;
+use mana::spell::Spells;
+
+const TYPES: TypeSigils = TypeSigils{
+    falsy:   Sigil(0),
+    truthy:  Sigil(1),
+    integer: Sigil(2),
+    string:  Sigil(3),
+};
+
+const FIB: SpellId = SpellId{spellbook: Sigil(4), spell: Sigil(5), arity: 1};
+
+/// The argument the Fibonacci spell is invoked with.
+const N: i64 = 27;
+
+/// The number of times each database is run; the fastest run counts.
+const RUNS: usize = 3;
+
+fn main() {
+    let unlinked = fib();
+    let mut linked = fib();
+    let replaced = linked.link();
+    assert_eq!(replaced, 2);
+
+    let unlinked = best(&unlinked);
+    let linked   = best(&linked);
+    println!("fib({}), best of {}: {:?} unlinked, {:?} linked",
+             N, RUNS, unlinked, linked);
+}
+
+/// Run the Fibonacci spell in the given database a few times, and return the
+/// time the fastest run took.
+fn best(spells: &Spells) -> Duration {
+    let heap = Heap::new();
+    let runtime = Runtime{spells, heap: &heap, types: TYPES};
+    let mut best = Duration::from_secs(u64::MAX);
+    for _ in 0 .. RUNS {
+        let start = Instant::now();
+        let result = run(&runtime, FIB, &[Datum::from_i64(N).unwrap()]);
+        let elapsed = start.elapsed();
+        assert_eq!(result.as_i64(), Some(196_418));
+        heap.collect_garbage();
+        best = best.min(elapsed);
+    }
+    best
+}
+
+/// Build a database with the naive recursive Fibonacci spell, whose
+/// invocations are not linked.
+fn fib() -> Spells {
+    let mut builder = SpellBuilder::new();
+    let n    = builder.local();
+    let two  = builder.local();
+    let one  = builder.local();
+    let less = builder.local();
+    let a    = builder.local();
+    let b    = builder.local();
+    let base = builder.label();
+
+    let two_index = builder.constant(integer(2));
+    let one_index = builder.constant(integer(1));
+
+    builder
+        .load_constant(two, two_index)
+        .load_constant(one, one_index)
+        .less_than(less, n, two)
+        .branch_if_truthy(less, base)
+        .sub(a, n, one)
+        .invoke_static(a, FIB.spellbook, FIB.spell, &[a])
+        .sub(b, n, two)
+        .invoke_static(b, FIB.spellbook, FIB.spell, &[b])
+        .add(a, a, b)
+        .ret(a)
+        .bind(base)
+        .ret(n);
+
+    let mut spells = Spells::new();
+    spells.insert(FIB, builder.build()).unwrap();
+    spells
+}
+
+fn integer(value: i64) -> ConstantValue {
+    ConstantValue{
+        enchantment: TYPES.integer,
+        auxiliary:   Box::new(value.to_le_bytes()),
+    }
+}
@@ -0,0 +1,193 @@
+use super::*;
+
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::ops::DerefMut;
+use std::ptr;
+
+/// The number of data that fit in a chunk of an arena.
+const CHUNK_LEN: usize = 256;
+
+/// Storage for the data of a heap.
+///
+/// Data are stored in chunks of fixed size, which are never moved, so data
+/// have a stable address. Chunks are only freed by [Arena::shrink_to_fit],
+/// once none of their slots hold data. Allocating a datum takes a slot from
+/// the free list, or else the next slot of the last chunk; only when the last
+/// chunk is full is memory allocated, for a new chunk. Freeing a datum puts
+/// its slot on the free list.
+///
+/// The arena does not know which slots hold data, so it does not drop the
+/// data it holds when it is dropped. The heap does that; see [Arena::free].
+pub(super) struct Arena {
+    #[allow(clippy::vec_box)]
+    chunks: Vec<Box<[MaybeUninit<DatumInner>]>>,
+
+    /// The number of slots of the last chunk that were ever used.
+    used:   usize,
+
+    /// Slots that were freed and can be reused.
+    free:   Vec<NonNull<DatumInner>>,
+}
+
+/// An owning pointer to a datum in an arena. Dropping it does not free the
+/// datum; that has to be done explicitly with [Arena::free].
+pub(super) struct ArenaBox(NonNull<DatumInner>);
+
+impl Arena {
+    pub(super) fn new() -> Self {
+        Arena{chunks: Vec::new(), used: CHUNK_LEN, free: Vec::new()}
+    }
+
+    /// Move a datum into the arena.
+    pub(super) fn allocate(&mut self, datum: DatumInner) -> ArenaBox {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                if self.used == CHUNK_LEN {
+                    let chunk = (0 .. CHUNK_LEN)
+                        .map(|_| MaybeUninit::uninit())
+                        .collect();
+                    self.chunks.push(chunk);
+                    self.used = 0;
+                }
+                let chunk = self.chunks.last_mut().unwrap();
+                let slot = NonNull::from(&mut chunk[self.used]).cast();
+                self.used += 1;
+                slot
+            },
+        };
+
+        // This is safe because the slot is not in use, and is valid for
+        // writes as it lies within a chunk.
+        unsafe { ptr::write(slot.as_ptr(), datum) };
+        ArenaBox(slot)
+    }
+
+    /// Drop a datum and make its slot available for reuse.
+    ///
+    /// This function is unsafe because the datum must have been allocated in
+    /// this arena, and no references to it may be used afterwards.
+    pub(super) unsafe fn free(&mut self, datum: ArenaBox) {
+        ptr::drop_in_place(datum.0.as_ptr());
+        self.free.push(datum.0);
+    }
+
+    /// Free the trailing chunks none of whose slots hold data, and shrink the
+    /// free list to fit. Chunks before a chunk that holds data are kept, even
+    /// if they hold no data themselves, as the slots of a chunk must stay
+    /// where they are.
+    pub(super) fn shrink_to_fit(&mut self) {
+        while let Some(chunk) = self.chunks.last() {
+            let start = chunk.as_ptr() as *const DatumInner;
+            // This is safe because the used slots lie within the chunk.
+            let end = unsafe { start.add(self.used) };
+            let in_chunk = |slot: &NonNull<DatumInner>| {
+                let slot = slot.as_ptr() as *const DatumInner;
+                start <= slot && slot < end
+            };
+            if self.free.iter().filter(|slot| in_chunk(slot)).count()
+                != self.used {
+                break;
+            }
+            self.free.retain(|slot| !in_chunk(slot));
+            self.chunks.pop();
+
+            // Every chunk but the last is full.
+            self.used = CHUNK_LEN;
+        }
+        self.chunks.shrink_to_fit();
+        self.free.shrink_to_fit();
+    }
+}
+
+impl Deref for ArenaBox {
+    type Target = DatumInner;
+
+    fn deref(&self) -> &DatumInner {
+        // This is safe because the datum is not freed while the arena box
+        // exists, as freeing consumes the arena box.
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl DerefMut for ArenaBox {
+    fn deref_mut(&mut self) -> &mut DatumInner {
+        // This is safe for the same reason as in deref, and because the arena
+        // box is the only owner of the datum.
+        unsafe { self.0.as_mut() }
+    }
+}
+
+impl AsRef<DatumInner> for ArenaBox {
+    fn as_ref(&self) -> &DatumInner {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse() {
+        let heap = Heap::new();
+        let datum = |auxiliary: &[u8]| unsafe {
+            heap.construct(Sigil(0), &[], auxiliary, None)
+        };
+
+        let mut arena = Arena::new();
+        let boxes: Vec<ArenaBox> =
+            (0 .. CHUNK_LEN + 1).map(|_| arena.allocate(datum(b"a")))
+                .collect();
+        assert_eq!(arena.chunks.len(), 2);
+
+        // The slot that was freed last is reused first.
+        let last = NonNull::from(boxes[CHUNK_LEN].as_ref());
+        for datum in boxes {
+            unsafe { arena.free(datum) };
+        }
+        let reused = arena.allocate(datum(b"b"));
+        assert_eq!(arena.chunks.len(), 2);
+        assert_eq!(arena.free.len(), CHUNK_LEN);
+        assert_eq!(&*reused.auxiliary, b"b");
+        assert_eq!(NonNull::from(reused.as_ref()), last);
+        unsafe { arena.free(reused) };
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let heap = Heap::new();
+        let datum = || unsafe { heap.construct(Sigil(0), &[], &[], None) };
+
+        let mut arena = Arena::new();
+        let mut boxes: Vec<ArenaBox> =
+            (0 .. 2 * CHUNK_LEN + 1).map(|_| arena.allocate(datum()))
+                .collect();
+        assert_eq!(arena.chunks.len(), 3);
+
+        // Only the last two chunks are empty, once all but the first datum
+        // are freed.
+        let first = boxes.remove(0);
+        for datum in boxes {
+            unsafe { arena.free(datum) };
+        }
+        arena.shrink_to_fit();
+        assert_eq!(arena.chunks.len(), 1);
+        assert_eq!(arena.free.len(), CHUNK_LEN - 1);
+
+        // The remaining free slots are reused before a new chunk is added.
+        let boxes: Vec<ArenaBox> =
+            (0 .. CHUNK_LEN).map(|_| arena.allocate(datum())).collect();
+        assert_eq!(arena.chunks.len(), 2);
+        assert!(arena.free.is_empty());
+
+        unsafe { arena.free(first) };
+        for datum in boxes {
+            unsafe { arena.free(datum) };
+        }
+        arena.shrink_to_fit();
+        assert!(arena.chunks.is_empty());
+        assert!(arena.free.is_empty());
+    }
+}
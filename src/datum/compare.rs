@@ -0,0 +1,282 @@
+use super::*;
+
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::hash::Hasher;
+
+/// How many data [Datum::structural_hash] takes into account.
+///
+/// [Datum::structural_hash]: struct.Datum.html#method.structural_hash
+const STRUCTURAL_HASH_LIMIT: usize = 64;
+
+/// The identity of a datum, for use as a key in maps of data by identity
+/// rather than by structure; see [Datum::id].
+///
+/// Ids are only meaningful among data that exist at the same time. Once a
+/// datum is garbage collected, a datum allocated later may get the same
+/// address and so the same id. To keep an id from being reused, keep a root
+/// to its datum around for as long as the id is.
+///
+/// [Datum::id]: struct.Datum.html#method.id
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct DatumId(NonNull<DatumInner>);
+
+// The pointer is only compared and hashed, never dereferenced.
+unsafe impl Send for DatumId {}
+unsafe impl Sync for DatumId {}
+
+impl<'a> Datum<'a> {
+    /// The identity of the datum. Two references have the same id if and only
+    /// if they refer to the same datum, as by [ptr_eq].
+    ///
+    /// Immediates have the same id if they are the same integer.
+    ///
+    /// [ptr_eq]: #method.ptr_eq
+    pub fn id(&self) -> DatumId {
+        DatumId(self.ptr)
+    }
+
+    /// Whether two references refer to the same datum.
+    ///
+    /// Immediates are the same if they are the same integer.
+    pub fn ptr_eq(&self, other: &Datum) -> bool {
+        self.ptr == other.ptr
+    }
+
+    /// The address of the datum, which identifies it among the data that
+    /// exist at the same time; see [Heap::find_cycles]. The address of an
+    /// immediate encodes its integer, and is not that of any datum.
+    ///
+    /// [Heap::find_cycles]: struct.Heap.html#method.find_cycles
+    pub fn as_ptr(&self) -> *const () {
+        self.ptr.as_ptr() as *const ()
+    }
+
+    /// Whether two data have the same structure.
+    ///
+    /// Two data have the same structure if they have the same enchantment, the
+    /// same auxiliary part, and pointers to data that in turn have the same
+    /// structure. Cyclic data are handled: two data have the same structure if
+    /// no difference can be found by following pointers, no matter how far.
+    /// Immediates have the same structure only if they are the same integer.
+    pub fn structural_eq(&self, other: &Datum) -> bool {
+        self.structural_eq_to_depth(other, usize::MAX)
+    }
+
+    /// Like [structural_eq], but only compare the data that can be reached
+    /// by following at most `max_depth` pointers, and assume that the data
+    /// beyond that have the same structure.
+    ///
+    /// The enchantments, auxiliary parts, and numbers of pointers of the data
+    /// up to that depth are compared, as are immediates up to that depth. A
+    /// `max_depth` of zero compares only the two data themselves. Data that
+    /// are structurally equal are therefore equal to any depth, and data that
+    /// are equal to a depth may still differ further down.
+    ///
+    /// [structural_eq]: #method.structural_eq
+    pub fn structural_eq_to_depth(&self, other: &Datum, max_depth: usize)
+        -> bool {
+        // Pairs of data that are assumed to have the same structure. Assuming
+        // so while their pointees are compared is what makes cyclic data
+        // terminate. The pairs are visited in breadth-first order, so that
+        // each pair is first visited at the smallest depth it is reachable
+        // at, and is not skipped because it was visited beyond max_depth.
+        let mut assumed = HashSet::new();
+        let mut pending = VecDeque::new();
+        pending.push_back((self.ptr, other.ptr, 0));
+
+        while let Some((a, b, depth)) = pending.pop_front() {
+            if a == b || !assumed.insert((a, b)) {
+                continue;
+            }
+            if is_immediate(a) || is_immediate(b) {
+                return false;
+            }
+
+            // This is safe because both data are reachable from the roots
+            // self and other.
+            let (a, b) = unsafe { (a.as_ref(), b.as_ref()) };
+
+            if a.enchantment    != b.enchantment    ||
+               a.auxiliary      != b.auxiliary      ||
+               a.pointers.len() != b.pointers.len() {
+                return false;
+            }
+            if depth < max_depth {
+                pending.extend(a.pointers.iter().map(Cell::get)
+                                   .zip(b.pointers.iter().map(Cell::get))
+                                   .map(|(a, b)| (a, b, depth + 1)));
+            }
+        }
+
+        true
+    }
+
+    /// Feed the structure of the datum to a hasher.
+    ///
+    /// Data that have the same structure, as determined by [structural_eq],
+    /// produce the same hash. Only the datum and the first few data reachable
+    /// from it, in breadth-first order, are taken into account, so hashing
+    /// large or cyclic data takes bounded time.
+    ///
+    /// [structural_eq]: #method.structural_eq
+    pub fn structural_hash<H: Hasher>(&self, state: &mut H) {
+        let mut pending = VecDeque::new();
+        pending.push_back(self);
+
+        for _ in 0 .. STRUCTURAL_HASH_LIMIT {
+            let datum = match pending.pop_front() {
+                Some(datum) => datum,
+                None        => break,
+            };
+            match datum.immediate() {
+                Some(value) => {
+                    true.hash(state);
+                    value.hash(state);
+                },
+                None => {
+                    false.hash(state);
+                    datum.enchantment().hash(state);
+                    datum.auxiliary().hash(state);
+                    datum.pointers().len().hash(state);
+                    pending.extend(datum.pointers());
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::collections::hash_map::DefaultHasher;
+    use std::slice;
+
+    fn hash(datum: &Datum) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        datum.structural_hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_ptr_eq() {
+        let heap = Heap::new();
+        let datum_a = unsafe { heap.allocate(Sigil(0), &[], b"a") };
+        let datum_b = unsafe { heap.allocate(Sigil(0), &[], b"a") };
+        assert!(datum_a.ptr_eq(&datum_a.clone()));
+        assert!(!datum_a.ptr_eq(&datum_b));
+
+        let one = Datum::from_i64(1).unwrap();
+        assert!(one.ptr_eq(&Datum::from_i64(1).unwrap()));
+        assert!(!one.ptr_eq(&Datum::from_i64(2).unwrap()));
+    }
+
+    #[test]
+    fn test_id() {
+        let heap = Heap::new();
+        let datum_a = unsafe { heap.allocate(Sigil(0), &[], b"a") };
+        let datum_b = unsafe { heap.allocate(Sigil(0), &[], b"a") };
+        assert_eq!(datum_a.id(), datum_a.clone().id());
+        assert_ne!(datum_a.id(), datum_b.id());
+
+        let one = Datum::from_i64(1).unwrap();
+        assert_eq!(one.id(), Datum::from_i64(1).unwrap().id());
+        assert_ne!(one.id(), Datum::from_i64(2).unwrap().id());
+
+        // Memoize the length of the auxiliary part of each datum.
+        let mut lengths = HashMap::new();
+        for datum in [&datum_a, &datum_b, &datum_a, &one] {
+            lengths.entry(datum.id()).or_insert(datum.auxiliary().len());
+        }
+        assert_eq!(lengths.len(), 3);
+        assert_eq!(lengths[&datum_b.id()], 1);
+        assert_eq!(lengths[&one.id()], 0);
+    }
+
+    #[test]
+    fn test_structural_eq() {
+        let heap = Heap::new();
+        let one = Datum::from_i64(1).unwrap();
+        let leaf_a = unsafe { heap.allocate(Sigil(0), &[], b"leaf") };
+        let leaf_b = unsafe { heap.allocate(Sigil(0), &[], b"leaf") };
+        let leaf_c = unsafe { heap.allocate(Sigil(1), &[], b"leaf") };
+        let node_a = unsafe { heap.allocate(Sigil(2), &[leaf_a.clone(),
+                                                         one.clone()], &[]) };
+        let node_b = unsafe { heap.allocate(Sigil(2), &[leaf_b.clone(),
+                                                         one.clone()], &[]) };
+        let node_c = unsafe { heap.allocate(Sigil(2), &[leaf_c.clone(),
+                                                         one.clone()], &[]) };
+        let node_d = unsafe { heap.allocate(Sigil(2), &[leaf_a.clone(),
+                                                         leaf_a.clone()],
+                                            &[]) };
+
+        assert!(leaf_a.structural_eq(&leaf_b));
+        assert!(!leaf_a.structural_eq(&leaf_c));
+        assert!(node_a.structural_eq(&node_b));
+        assert!(!node_a.structural_eq(&node_c));
+        assert!(!node_a.structural_eq(&node_d));
+        assert!(!one.structural_eq(&leaf_a));
+        assert_eq!(hash(&node_a), hash(&node_b));
+        assert_eq!(hash(&leaf_a), hash(&leaf_b));
+    }
+
+    #[test]
+    fn test_structural_eq_shared() {
+        // A pair whose elements are the same datum, and a pair whose elements
+        // are distinct data with the same structure.
+        let heap = Heap::new();
+        let leaf_a = unsafe { heap.allocate(Sigil(0), &[], b"leaf") };
+        let leaf_b = unsafe { heap.allocate(Sigil(0), &[], b"leaf") };
+        let shared = unsafe { heap.allocate(Sigil(1), &[leaf_a.clone(),
+                                                         leaf_a.clone()],
+                                            &[]) };
+        let unshared = unsafe { heap.allocate(Sigil(1), &[leaf_a.clone(),
+                                                           leaf_b.clone()],
+                                              &[]) };
+
+        assert!(shared.structural_eq(&unshared));
+        assert_eq!(hash(&shared), hash(&unshared));
+    }
+
+    #[test]
+    fn test_structural_eq_to_depth() {
+        // Two chains root -> middle -> leaf that differ only in their leaves,
+        // and whose middles point back to their roots.
+        let heap = Heap::new();
+        let chain = |leaf: &[u8]| unsafe {
+            let leaf = heap.allocate(Sigil(0), &[], leaf);
+            let middle = heap.allocate(Sigil(1), &[leaf.clone(), leaf],
+                                       b"middle");
+            let root = heap.allocate(Sigil(2), slice::from_ref(&middle),
+                                     b"root");
+            middle.set_pointer(1, &root);
+            root
+        };
+        let chain_a = chain(b"a");
+        let chain_b = chain(b"b");
+
+        assert!(chain_a.structural_eq_to_depth(&chain_b, 0));
+        assert!(chain_a.structural_eq_to_depth(&chain_b, 1));
+        assert!(!chain_a.structural_eq_to_depth(&chain_b, 2));
+        assert!(!chain_a.structural_eq_to_depth(&chain_b, usize::MAX));
+        assert!(!chain_a.structural_eq(&chain_b));
+        assert!(chain_a.structural_eq_to_depth(&chain(b"a"), usize::MAX));
+
+        // Immediates are compared up to the depth, and not beyond.
+        let one = Datum::from_i64(1).unwrap();
+        let two = Datum::from_i64(2).unwrap();
+        let node_one = unsafe {
+            heap.allocate(Sigil(3), slice::from_ref(&one), &[])
+        };
+        let node_two = unsafe {
+            heap.allocate(Sigil(3), slice::from_ref(&two), &[])
+        };
+        assert!(node_one.structural_eq_to_depth(&node_two, 0));
+        assert!(!node_one.structural_eq_to_depth(&node_two, 1));
+        assert!(!one.structural_eq_to_depth(&two, 0));
+        assert!(one.structural_eq_to_depth(&one, 0));
+    }
+}
@@ -0,0 +1,303 @@
+use super::*;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use sigil::Sigils;
+
+/// How many bytes or characters of auxiliary parts [Heap::to_dot] shows.
+///
+/// [Heap::to_dot]: struct.Heap.html#method.to_dot
+const DOT_AUXILIARY_LIMIT: usize = 16;
+
+/// Formats a datum for humans, resolving sigils using a sigil database. See
+/// [Datum::display].
+///
+/// [Datum::display]: struct.Datum.html#method.display
+#[derive(Clone, Copy)]
+pub struct DatumDisplay<'d, 'a> {
+    datum:   &'d Datum<'a>,
+    sigils:  &'d Sigils,
+    integer: Option<Sigil>,
+}
+
+impl<'a> Datum<'a> {
+    /// Format the datum for humans.
+    ///
+    /// Immediates are formatted as decimal integers. Other data are formatted
+    /// as the name of their enchantment, followed by their pointers in
+    /// parentheses if they have any, followed by their auxiliary part if it is
+    /// not empty. The auxiliary part is quoted if it is valid UTF-8 without
+    /// control characters, and written as hexadecimal bytes otherwise. For
+    /// example:
+    ///
+    /// ```text
+    /// pair(1, string "two")
+    /// ```
+    ///
+    /// Pointers that lead back to a datum that is already being formatted are
+    /// written as `<cycle>`. Integers on the heap are only formatted as decimal
+    /// integers if their enchantment is given to [integer].
+    ///
+    /// [integer]: struct.DatumDisplay.html#method.integer
+    pub fn display<'d>(&'d self, sigils: &'d Sigils) -> DatumDisplay<'d, 'a> {
+        DatumDisplay{datum: self, sigils, integer: None}
+    }
+}
+
+impl<'d, 'a> DatumDisplay<'d, 'a> {
+    /// Format data with the given enchantment as decimal integers. See
+    /// [Datum::as_i64] for how they are represented.
+    ///
+    /// [Datum::as_i64]: struct.Datum.html#method.as_i64
+    pub fn integer(self, integer: Sigil) -> Self {
+        DatumDisplay{integer: Some(integer), ..self}
+    }
+
+    /// Format a datum, given the data that are already being formatted.
+    fn write(&self, f: &mut fmt::Formatter, datum: &Datum,
+             ancestors: &mut Vec<NonNull<DatumInner>>) -> fmt::Result {
+        if let Some(value) = datum.immediate() {
+            return write!(f, "{}", value);
+        }
+        if ancestors.contains(&datum.ptr) {
+            return write!(f, "<cycle>");
+        }
+
+        let enchantment = datum.enchantment();
+        if Some(enchantment) == self.integer {
+            if let Some(value) = datum.as_i64() {
+                return write!(f, "{}", value);
+            }
+        }
+
+        write_enchantment(f, self.sigils, enchantment)?;
+
+        let pointers = datum.pointers();
+        if !pointers.is_empty() {
+            ancestors.push(datum.ptr);
+            write!(f, "(")?;
+            for (index, pointer) in pointers.iter().enumerate() {
+                if index != 0 {
+                    write!(f, ", ")?;
+                }
+                self.write(f, pointer, ancestors)?;
+            }
+            write!(f, ")")?;
+            ancestors.pop();
+        }
+
+        let auxiliary = datum.auxiliary();
+        if auxiliary.is_empty() {
+            return Ok(());
+        }
+        write!(f, " ")?;
+        write_auxiliary(f, auxiliary, usize::MAX)
+    }
+}
+
+impl fmt::Display for DatumDisplay<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, self.datum, &mut Vec::new())
+    }
+}
+
+impl Heap {
+    /// Render the live data of the heap, as found by [live_set], as a graph
+    /// in the DOT language of Graphviz.
+    ///
+    /// Every live datum is a node, labelled with the name of its enchantment
+    /// and the start of its auxiliary part, written like [Datum::display]
+    /// writes them. Rooted data are drawn with a double border. Every pointer
+    /// is an edge labelled with its index. Pointers to immediates and to data
+    /// on other heaps lead to nodes of their own, labelled with the integer
+    /// and `<foreign>` respectively. The heap is not changed, and neither are
+    /// roots into it.
+    ///
+    /// [live_set]: #method.live_set
+    /// [Datum::display]: struct.Datum.html#method.display
+    pub fn to_dot(&self, sigils: &Sigils) -> String {
+        self.with_live_data(|live| {
+            let numbers: HashMap<NonNull<DatumInner>, usize> = live.iter()
+                .enumerate()
+                .map(|(number, &datum)| (NonNull::from(datum), number))
+                .collect();
+
+            // Writing to a string does not fail.
+            let mut dot = String::from("digraph heap {\n");
+            for (number, datum) in live.iter().enumerate() {
+                let mut label = String::new();
+                write_enchantment(&mut label, sigils, datum.enchantment)
+                    .unwrap();
+                let mut label = escape_dot(&label);
+                if !datum.auxiliary.is_empty() {
+                    let mut auxiliary = String::new();
+                    write_auxiliary(&mut auxiliary, &datum.auxiliary,
+                                    DOT_AUXILIARY_LIMIT).unwrap();
+                    label.push_str("\\n");
+                    label.push_str(&escape_dot(&auxiliary));
+                }
+                let rooted =
+                    if datum.roots.get() > 0 { ", peripheries=2" } else { "" };
+                writeln!(dot, "    n{} [label=\"{}\"{}];",
+                         number, label, rooted).unwrap();
+
+                for (index, pointer) in datum.pointers.iter().enumerate() {
+                    let pointer = pointer.get();
+                    if let Some(target) = numbers.get(&pointer) {
+                        writeln!(dot, "    n{} -> n{} [label=\"{}\"];",
+                                 number, target, index).unwrap();
+                        continue;
+                    }
+
+                    let label = if is_immediate(pointer) {
+                        // This is safe because the pointer is an immediate.
+                        let immediate = unsafe { Datum::enroot(pointer) };
+                        immediate.immediate().unwrap().to_string()
+                    } else {
+                        "<foreign>".to_string()
+                    };
+                    writeln!(dot, "    n{}_{} [label=\"{}\", \
+                                   shape=plaintext];",
+                             number, index, label).unwrap();
+                    writeln!(dot, "    n{} -> n{}_{} [label=\"{}\"];",
+                             number, number, index, index).unwrap();
+                }
+            }
+            dot.push_str("}\n");
+            dot
+        })
+    }
+}
+
+/// Write the name of an enchantment, or the sigil if it has no name.
+fn write_enchantment(f: &mut impl Write, sigils: &Sigils, enchantment: Sigil)
+    -> fmt::Result {
+    match sigils.name(enchantment) {
+        Some(name) => write!(f, "{}", String::from_utf8_lossy(name)),
+        None       => write!(f, "{:?}", enchantment),
+    }
+}
+
+/// Write an auxiliary part, quoted if it is valid UTF-8 without control
+/// characters and as hexadecimal bytes otherwise. Only the first `limit`
+/// characters or bytes are written, followed by `...` if there are more.
+fn write_auxiliary(f: &mut impl Write, auxiliary: &[u8], limit: usize)
+    -> fmt::Result {
+    let text = str::from_utf8(auxiliary).ok()
+        .filter(|text| !text.chars().any(char::is_control));
+    match text {
+        Some(text) => {
+            let end = text.char_indices().nth(limit)
+                .map_or(text.len(), |(end, _)| end);
+            write!(f, "{:?}", &text[.. end])?;
+            if end < text.len() {
+                write!(f, "...")?;
+            }
+            Ok(())
+        },
+        None => {
+            write!(f, "[")?;
+            for (index, byte) in auxiliary.iter().take(limit).enumerate() {
+                let separator = if index == 0 { "" } else { " " };
+                write!(f, "{}{:02x}", separator, byte)?;
+            }
+            if auxiliary.len() > limit {
+                write!(f, " ...")?;
+            }
+            write!(f, "]")
+        },
+    }
+}
+
+/// Escape text for use in a quoted string in the DOT language.
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let mut sigils = Sigils::new();
+        let pair = sigils.intern_str("pair");
+        let string = sigils.intern_str("string");
+        let integer = sigils.intern_str("integer");
+
+        let heap = Heap::new();
+        let datum = unsafe {
+            let two = heap.allocate(string, &[], b"two");
+            let big = heap.allocate(integer, &[], &(1i64 << 62).to_le_bytes());
+            let bytes = heap.allocate(Sigil(9), &[], &[0x0a, 0xff]);
+            let one = Datum::from_i64(1).unwrap();
+            heap.allocate(pair, &[one, two, big, bytes], &[])
+        };
+
+        assert_eq!(
+            datum.display(&sigils).integer(integer).to_string(),
+            "pair(1, string \"two\", 4611686018427387904, Sigil(9) [0a ff])",
+        );
+        assert_eq!(
+            datum.display(&sigils).to_string(),
+            "pair(1, string \"two\", integer [00 00 00 00 00 00 00 40], \
+             Sigil(9) [0a ff])",
+        );
+    }
+
+    #[test]
+    fn test_display_cycle() {
+        let mut sigils = Sigils::new();
+        let cons = sigils.intern_str("cons");
+
+        let heap = Heap::new();
+        let nil = unsafe { heap.allocate(cons, &[], b"nil") };
+        let datum = unsafe {
+            heap.allocate(cons, &[nil.clone(), nil.clone()], &[])
+        };
+        unsafe { datum.set_pointer(1, &datum) };
+
+        assert_eq!(datum.display(&sigils).to_string(),
+                   "cons(cons \"nil\", <cycle>)");
+
+        // Data that are reachable twice without a cycle are written twice.
+        let shared = unsafe { heap.allocate(cons, &[nil.clone(), nil], &[]) };
+        assert_eq!(shared.display(&sigils).to_string(),
+                   "cons(cons \"nil\", cons \"nil\")");
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let mut sigils = Sigils::new();
+        let pair = sigils.intern_str("pair");
+        let string = sigils.intern_str("string");
+
+        let heap = Heap::new();
+        let datum = unsafe {
+            let leaf = heap.allocate(string, &[], b"leaf");
+            let bytes = heap.allocate(Sigil(9), &[], &[0xff; 18]);
+            drop(heap.allocate(string, &[], b"garbage"));
+            let one = Datum::from_i64(1).unwrap();
+            heap.allocate(pair, &[one, leaf, bytes], b"say \"hi\"")
+        };
+
+        assert_eq!(heap.to_dot(&sigils), concat!(
+            "digraph heap {\n",
+            r#"    n0 [label="string\n\"leaf\""];"#, "\n",
+            r#"    n1 [label="Sigil(9)\n[ff ff ff ff ff ff ff ff ff ff ff "#,
+            r#"ff ff ff ff ff ...]"];"#, "\n",
+            r#"    n2 [label="pair\n\"say \\\"hi\\\"\"", peripheries=2];"#,
+            "\n",
+            r#"    n2_0 [label="1", shape=plaintext];"#, "\n",
+            r#"    n2 -> n2_0 [label="0"];"#, "\n",
+            r#"    n2 -> n0 [label="1"];"#, "\n",
+            r#"    n2 -> n1 [label="2"];"#, "\n",
+            "}\n",
+        ));
+        assert_eq!(heap.total_roots(), 1);
+
+        drop(datum);
+        assert_eq!(heap.to_dot(&sigils), "digraph heap {\n}\n");
+    }
+}
@@ -2,153 +2,1098 @@ use super::*;
 
 use std::cell::Cell;
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::mem;
 use std::mem::transmute;
+use std::ptr;
 use std::ptr::NonNull;
+#[cfg(feature = "checked")]
+use std::sync::atomic::AtomicU64;
+#[cfg(feature = "checked")]
+use std::sync::atomic::Ordering;
 
 use sigil::Sigil;
 
 /// A collection of data.
 ///
 /// A heap provides for the creation and garbage collection of data.
+///
+/// Heaps and data can only be shared between threads with the `sync` feature
+/// enabled. Every heap then has a lock, which is held during allocation,
+/// during each call that collects garbage, and whenever a `Datum` is cloned or
+/// dropped; so garbage collection stops other threads from using the heap.
 pub struct Heap {
     /// All data in the heap.
     ///
     /// The heap needs to keep track of all data, so that it knows what data to
-    /// free when collecting garbage. The data are boxed so that they have a
-    /// stable address; a reallocation of the vector will not cause pointers to
-    /// the data to become invalid. There exist two invariants:
-    ///
-    ///  1. Data at a higher index in the vector were allocated later than data
-    ///     at a lower index in the vector.
-    ///  2. Data allocated later only point to other data allocated earlier.
-    data: RefCell<Vec<Box<DatumInner>>>,
+    /// free when collecting garbage. The data live in the arena so that they
+    /// have a stable address; a reallocation of the vector will not cause
+    /// pointers to the data to become invalid. Data at a higher index in the
+    /// vector were allocated later than data at a lower index in the vector.
+    /// Data may point to any data in the heap, regardless of allocation order.
+    data: RefCell<Vec<ArenaBox>>,
+
+    /// The storage of the data. Every datum in the arena is in `data`.
+    arena: RefCell<Arena>,
+
+    /// The state of the garbage collection in progress, if any.
+    collection: RefCell<Collection>,
+
+    /// Whether marking is in progress, in which case new data are allocated
+    /// marked. This duplicates part of `collection` so that allocation does
+    /// not need to borrow it.
+    marking: Cell<bool>,
+
+    /// The number of bytes of the data in `data`, counted as in
+    /// `CollectStatistics`, less those of data that a sweep in progress found
+    /// to be unreachable.
+    bytes: Cell<usize>,
+
+    /// The number of bytes beyond which `bytes` may not grow, if any.
+    limit: Cell<Option<usize>>,
+
+    /// An id that is unique among heaps, with which all data in the heap are
+    /// stamped.
+    #[cfg(feature = "checked")]
+    id: u64,
+
+    /// The observer that is told about allocations and collections, if any.
+    observer: Option<Box<Observer>>,
+
+    /// The lock that protects the fields above and the cells of the data. It
+    /// is boxed so that data can refer to it even if the heap moves.
+    lock: Box<Lock>,
 }
 
+// With the sync feature, the heap and its data are only accessed with the lock
+// held; see the lock module.
+#[cfg(feature = "sync")]
+unsafe impl Send for Heap {}
+#[cfg(feature = "sync")]
+unsafe impl Sync for Heap {}
+
+#[cfg(feature = "checked")]
+static NEXT_HEAP_ID: AtomicU64 = AtomicU64::new(0);
+
 impl Heap {
     /// Create a new heap with no data.
     pub fn new() -> Self {
-        Heap{data: RefCell::new(Vec::new())}
+        Self::with_capacity(0)
+    }
+
+    /// Create a new heap with no data, which can hold at least the given
+    /// number of data before it needs to grow its bookkeeping.
+    ///
+    /// The data themselves are stored separately either way; see [reserve].
+    ///
+    /// [reserve]: #method.reserve
+    pub fn with_capacity(capacity: usize) -> Self {
+        Heap{
+            data:       RefCell::new(Vec::with_capacity(capacity)),
+            arena:      RefCell::new(Arena::new()),
+            collection: RefCell::new(Collection::Idle),
+            marking:    Cell::new(false),
+            bytes:      Cell::new(0),
+            limit:      Cell::new(None),
+            #[cfg(feature = "checked")]
+            id:         NEXT_HEAP_ID.fetch_add(1, Ordering::Relaxed),
+            observer:   None,
+            lock:       Box::new(Lock::new()),
+        }
+    }
+
+    /// Create a new heap with no data, which tells the observer about every
+    /// datum it allocates and every garbage collection it completes; see
+    /// [HeapObserver].
+    ///
+    /// Heaps created otherwise have no observer, and do not pay for one.
+    ///
+    /// [HeapObserver]: trait.HeapObserver.html
+    pub fn new_with_observer(observer: Box<Observer>) -> Self {
+        let mut heap = Self::new();
+        heap.observer = Some(observer);
+        heap
+    }
+
+    /// Create a new heap with no data, whose data may retain at most the given
+    /// number of bytes; see [set_limit].
+    ///
+    /// [set_limit]: #method.set_limit
+    pub fn with_limit(max_bytes: usize) -> Self {
+        let heap = Self::new();
+        heap.limit.set(Some(max_bytes));
+        heap
+    }
+
+    /// The number of bytes the data in the heap may retain at most, if that is
+    /// limited.
+    pub fn limit(&self) -> Option<usize> {
+        let _guard = self.lock.lock();
+        self.limit.get()
+    }
+
+    /// Limit the number of bytes the data in the heap may retain, counted as
+    /// in [bytes], or remove the limit.
+    ///
+    /// An allocation that would make the heap exceed the limit first collects
+    /// garbage to make room, and fails if that does not make enough room. The
+    /// heap may already retain more bytes than a new limit; it then only
+    /// affects later allocations.
+    ///
+    /// [bytes]: #method.bytes
+    pub fn set_limit(&self, limit: Option<usize>) {
+        let _guard = self.lock.lock();
+        self.limit.set(limit);
+    }
+
+    /// Make room in the bookkeeping of the heap for at least the given number
+    /// of additional data.
+    ///
+    /// The heap keeps track of its data in a vector, which grows as data are
+    /// allocated. Reserving avoids repeatedly growing it when many data are
+    /// about to be allocated. The data themselves are stored elsewhere, so
+    /// this does not affect their addresses.
+    pub fn reserve(&self, additional: usize) {
+        let _guard = self.lock.lock();
+        self.data.borrow_mut().reserve(additional);
+    }
+
+    /// Shrink the bookkeeping and storage of the heap to fit the data it
+    /// holds.
+    ///
+    /// Garbage collection never shrinks the heap by itself, as that would
+    /// undo [reserve]. Calling this after a collection that freed most of the
+    /// heap returns the memory the heap no longer needs. Data freed by an
+    /// incremental collection in progress are only counted as gone once it
+    /// completes.
+    ///
+    /// Data never move, so storage is only returned in chunks that hold no
+    /// data and were allocated after every chunk that does. A heap whose
+    /// surviving data were allocated late keeps the storage of the garbage
+    /// allocated before them, which new data reuse.
+    ///
+    /// [reserve]: #method.reserve
+    pub fn shrink_to_fit(&self) {
+        let _guard = self.lock.lock();
+        self.data.borrow_mut().shrink_to_fit();
+        self.arena.borrow_mut().shrink_to_fit();
     }
 
     /// Create a datum.
     ///
     /// The datum is a root until the return value is dropped.
     ///
-    /// This function is unsafe because the pointers must belong to this heap
-    /// and this is currently not checked.
+    /// Panics if the datum does not fit within the limit of the heap, even
+    /// after collecting garbage; see [allocate_within_limit].
+    ///
+    /// # Safety
+    ///
+    /// The pointers must belong to this heap. This is only checked when the
+    /// `checked` feature is enabled, in which case a violation panics.
+    ///
+    /// [allocate_within_limit]: #method.allocate_within_limit
     pub unsafe fn allocate(&self,
                            enchantment: Sigil,
                            pointers:    &[Datum],
                            auxiliary:   &[u8],
-                           ) -> Datum {
-        let mut data = self.data.borrow_mut();
+                           ) -> Datum<'_> {
+        self.allocate_inner(enchantment, pointers, auxiliary, None)
+            .expect("Heap limit exceeded")
+    }
+
+    /// Create a datum, or return an error if it does not fit within the limit
+    /// of the heap; see [set_limit].
+    ///
+    /// If the datum does not fit, garbage is collected first to make room,
+    /// and the finalizers of the freed data run before this returns.
+    ///
+    /// # Safety
+    ///
+    /// See [allocate].
+    ///
+    /// [set_limit]: #method.set_limit
+    /// [allocate]: #method.allocate
+    pub unsafe fn allocate_within_limit(&self,
+                                        enchantment: Sigil,
+                                        pointers:    &[Datum],
+                                        auxiliary:   &[u8])
+        -> Result<Datum<'_>, HeapLimitExceeded>
+    {
+        self.allocate_inner(enchantment, pointers, auxiliary, None)
+    }
+
+    /// Create a datum with a finalizer.
+    ///
+    /// The finalizer is called with the auxiliary part of the datum when the
+    /// datum is garbage collected. Finalizers run at the end of
+    /// [collect_garbage] or the call to [collect_garbage_incremental] that
+    /// freed the datum, after the heap has been updated, in reverse
+    /// allocation order: data allocated later are finalized first. Data that
+    /// are still in the heap when it is dropped are not finalized.
+    ///
+    /// Panics like [allocate] if the datum does not fit within the limit of
+    /// the heap.
+    ///
+    /// # Safety
+    ///
+    /// See [allocate].
+    ///
+    /// [allocate]: #method.allocate
+    /// [collect_garbage]: #method.collect_garbage
+    /// [collect_garbage_incremental]: #method.collect_garbage_incremental
+    pub unsafe fn allocate_with_finalizer(&self,
+                                          enchantment: Sigil,
+                                          pointers:    &[Datum],
+                                          auxiliary:   &[u8],
+                                          finalizer:   Box<Finalizer>,
+                                          ) -> Datum<'_> {
+        self.allocate_inner(enchantment, pointers, auxiliary, Some(finalizer))
+            .expect("Heap limit exceeded")
+    }
+
+    unsafe fn allocate_inner(&self,
+                             enchantment: Sigil,
+                             pointers:    &[Datum],
+                             auxiliary:   &[u8],
+                             finalizer:   Option<Box<Finalizer>>,
+                             ) -> Result<Datum<'_>, HeapLimitExceeded> {
+        #[cfg(feature = "checked")]
+        for pointer in pointers {
+            if let Some(inner) = pointer.inner() {
+                assert_eq!(inner.heap, self.id,
+                           "Pointer belongs to a different heap");
+            }
+        }
+
+        let size = DatumInner::size_for(pointers.len(), auxiliary.len());
+        let fits = {
+            let _guard = self.lock.lock();
+            self.fits(size)
+        };
+        if !fits {
+            // The lock is not held, so that finalizers can use the heap.
+            self.collect_garbage();
+        }
+
+        let datum = {
+            let _guard = self.lock.lock();
+            if !self.fits(size) {
+                let limit = self.limit.get().unwrap_or(usize::MAX);
+                return Err(HeapLimitExceeded{size, limit});
+            }
+            let mut data = self.data.borrow_mut();
+
+            let inner =
+                self.construct(enchantment, pointers, auxiliary, finalizer);
+            self.bytes.set(self.bytes.get() + inner.size());
+            let inner = self.arena.borrow_mut().allocate(inner);
+            let ptr = NonNull::from(inner.as_ref());
+            data.push(inner);
+
+            // This is safe because self.data owns the datum, hence the pointer
+            // is still valid here.
+            Datum::enroot(ptr)
+        };
+
+        // The lock is not held, so that the observer can use the datum.
+        if let Some(observer) = &self.observer {
+            observer.on_allocate(&datum);
+        }
+        Ok(datum)
+    }
+
+    /// Whether a datum of the given size fits within the limit of the heap.
+    /// The lock must be held.
+    fn fits(&self, size: usize) -> bool {
+        self.limit.get().is_none_or(|limit| {
+            self.bytes.get().checked_add(size).is_some_and(|b| b <= limit)
+        })
+    }
+
+    /// Create a datum, or return an error if any of the pointers does not
+    /// belong to this heap, or if the datum does not fit within the limit of
+    /// the heap.
+    ///
+    /// This is a safe alternative to [allocate_within_limit]. Checking the
+    /// pointers takes time proportional to the size of the heap for each
+    /// pointer, so this is meant for debugging and for untrusted pointers.
+    ///
+    /// [allocate_within_limit]: #method.allocate_within_limit
+    pub fn try_allocate(&self,
+                        enchantment: Sigil,
+                        pointers:    &[Datum],
+                        auxiliary:   &[u8],
+                        ) -> Result<Datum<'_>, AllocateError> {
+        if let Some(index) = self.find_foreign_pointer(pointers) {
+            return Err(AllocateError::ForeignPointer(
+                ForeignPointerError{index}));
+        }
+
+        // This is safe because every pointer was just checked to belong to
+        // this heap.
+        unsafe { self.allocate_within_limit(enchantment, pointers, auxiliary) }
+            .map_err(AllocateError::LimitExceeded)
+    }
+
+    /// Copy a datum and all data reachable from it into this heap.
+    ///
+    /// The datum may belong to any heap, including this one, which is not
+    /// changed. Data that are reachable along several paths are copied once, so
+    /// the copy has the same sharing and cycles as the original. Immediates are
+    /// not copied, as they do not live on any heap. Finalizers and weak
+    /// references are not copied either.
+    ///
+    /// This is the way to move data from one heap to another, as data may not
+    /// point to data in other heaps.
+    ///
+    /// Panics if a copy does not fit within the limit of the heap, even after
+    /// collecting garbage; see [try_import].
+    ///
+    /// [try_import]: #method.try_import
+    pub fn import(&self, root: &Datum) -> Datum<'_> {
+        self.try_import(root).expect("Heap limit exceeded")
+    }
+
+    /// Copy a datum and all data reachable from it into this heap, as
+    /// [import] does, or return an error if a copy does not fit within the
+    /// limit of the heap. The data copied before the error are garbage.
+    ///
+    /// [import]: #method.import
+    pub fn try_import(&self, root: &Datum)
+        -> Result<Datum<'_>, HeapLimitExceeded>
+    {
+        // Find all data reachable from the root, numbering them in the order
+        // in which they are found.
+        let mut indices = HashMap::new();
+        let mut originals = Vec::new();
+        let mut pending = vec![root];
+        while let Some(datum) = pending.pop() {
+            if datum.is_immediate() || indices.contains_key(&datum.ptr) {
+                continue;
+            }
+            indices.insert(datum.ptr, originals.len());
+            originals.push(datum);
+            pending.extend(datum.pointers().iter().rev());
+        }
+
+        // Allocate the copies with immediates as placeholder pointers, since a
+        // copy may point to copies that are allocated later.
+        let placeholder = Datum::from_i64(0).unwrap();
+        let copies = originals.iter().map(|original| {
+            let pointers = vec![placeholder.clone(); original.pointers().len()];
+            // This is safe because the pointers are immediates.
+            unsafe {
+                self.allocate_within_limit(original.enchantment(), &pointers,
+                                           original.auxiliary())
+            }
+        }).collect::<Result<Vec<_>, _>>()?;
+
+        for (original, copy) in originals.iter().zip(&copies) {
+            for (index, pointer) in original.pointers().iter().enumerate() {
+                let value = match indices.get(&pointer.ptr) {
+                    Some(&copied) => &copies[copied],
+                    None          => pointer,
+                };
+                // This is safe because the value is an immediate or a copy,
+                // and no slice of pointers of the copy is in use.
+                unsafe { copy.set_pointer(index, value) };
+            }
+        }
+
+        match copies.into_iter().next() {
+            Some(copy) => Ok(copy),
+            // This is safe because the root is an immediate.
+            None => Ok(unsafe { Datum::enroot(root.ptr) }),
+        }
+    }
+
+    /// Find the index of the first pointer that does not belong to this heap.
+    fn find_foreign_pointer(&self, pointers: &[Datum]) -> Option<usize> {
+        let _guard = self.lock.lock();
+        let data = self.data.borrow();
+        pointers.iter().position(|pointer| {
+            !pointer.is_immediate() &&
+                !data.iter().any(|datum| ptr::eq(datum.as_ref(),
+                                                 pointer.ptr.as_ptr()))
+        })
+    }
+
+    /// The number of data in the heap.
+    ///
+    /// This includes data that are no longer reachable but have not yet been
+    /// garbage collected.
+    pub fn len(&self) -> usize {
+        let _guard = self.lock.lock();
+        let freed = match *self.collection.borrow() {
+            Collection::Sweeping{read, write, ..} => read - write,
+            _ => 0,
+        };
+        self.data.borrow().len() - freed
+    }
+
+    /// The number of bytes retained by the data in the heap, counted as in
+    /// [CollectStatistics].
+    ///
+    /// Like [len], this includes data that are no longer reachable but have
+    /// not yet been garbage collected.
+    ///
+    /// [CollectStatistics]: struct.CollectStatistics.html
+    /// [len]: #method.len
+    pub fn bytes(&self) -> usize {
+        let _guard = self.lock.lock();
+        self.bytes.get()
+    }
+
+    /// Whether the heap contains no data.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The sum of the root counts of all data in the heap.
+    ///
+    /// Every `Datum` that is not an immediate contributes exactly one root to
+    /// the heap it belongs to, so comparing this number before and after some
+    /// operation tells you whether that operation leaked or double-released
+    /// roots.
+    pub fn total_roots(&self) -> usize {
+        let _guard = self.lock.lock();
+        self.data.borrow().iter().map(|datum| datum.roots.get()).sum()
+    }
+
+    /// The data that a garbage collection would find to be live: those that
+    /// are rooted, and those that can be reached from them by following
+    /// pointers. They are returned in allocation order.
+    ///
+    /// This does not use the marks of the garbage collector, so it can be
+    /// called while an incremental collection is in progress without
+    /// affecting which data that collection retains. Data that were freed by
+    /// a sweep in progress are left out. The returned data are rooted, and
+    /// thus remain live until they are dropped.
+    pub fn live_set(&self) -> Vec<Datum<'_>> {
+        let _guard = self.lock.lock();
+        self.live_data().into_iter()
+            // This is safe because the datum is live, and the lock is held.
+            .map(|datum| unsafe { Datum::enroot(NonNull::from(datum)) })
+            .collect()
+    }
+
+    /// Capture the live data of the heap, as found by [live_set], so that
+    /// they can be copied into a heap later with [restore].
+    ///
+    /// The heap is not changed, and neither are roots into it.
+    ///
+    /// [live_set]: #method.live_set
+    /// [restore]: #method.restore
+    pub fn snapshot(&self) -> HeapSnapshot {
+        let _guard = self.lock.lock();
+        let live = self.live_data();
+        let indices: HashMap<NonNull<DatumInner>, usize> = live.iter()
+            .enumerate()
+            .map(|(index, &datum)| (NonNull::from(datum), index))
+            .collect();
+
+        let pointer = |pointer: &Cell<NonNull<DatumInner>>| {
+            match indices.get(&pointer.get()) {
+                Some(&index) => SnapshotPointer::Datum(index),
+                // This is safe because every pointer that is not to a live
+                // datum is an immediate.
+                None => SnapshotPointer::Immediate(unsafe {
+                    Datum::enroot(pointer.get())
+                }),
+            }
+        };
+        let data = live.iter().map(|datum| SnapshotDatum{
+            enchantment: datum.enchantment,
+            pointers:    datum.pointers.iter().map(pointer).collect(),
+            auxiliary:   datum.auxiliary.clone(),
+        }).collect();
+        let (roots, addresses) = live.iter().enumerate()
+            .filter(|(_, datum)| datum.roots.get() > 0)
+            .map(|(index, &datum)| (index, datum as *const _ as *const ()))
+            .unzip();
+        HeapSnapshot{data, roots, addresses}
+    }
+
+    /// Call a function with the data that are rooted or reachable from roots,
+    /// in allocation order, while holding the lock. The function must not
+    /// call methods of the heap that take the lock.
+    pub(super) fn with_live_data<R>(&self,
+                                    f: impl FnOnce(&[&DatumInner]) -> R)
+        -> R {
+        let _guard = self.lock.lock();
+        f(&self.live_data())
+    }
+
+    /// The data that are rooted or reachable from roots, in allocation order.
+    /// The lock must be held.
+    fn live_data(&self) -> Vec<&DatumInner> {
+        let data = self.data.borrow();
+
+        let freed = match *self.collection.borrow() {
+            Collection::Sweeping{read, write, ..} => write .. read,
+            _ => 0 .. 0,
+        };
+        let mut live = HashSet::new();
+        let mut worklist: Vec<NonNull<DatumInner>> = data.iter().enumerate()
+            .filter(|(index, datum)| !freed.contains(index) &&
+                                     datum.roots.get() > 0)
+            .map(|(_, datum)| NonNull::from(datum.as_ref()))
+            .collect();
+        while let Some(datum) = worklist.pop() {
+            if is_immediate(datum) || !live.insert(datum) {
+                continue;
+            }
+            // This is safe because the datum is reachable from a root, and
+            // hence has not been garbage collected.
+            let inner = unsafe { datum.as_ref() };
+            worklist.extend(inner.pointers.iter().map(Cell::get));
+        }
+
+        data.iter()
+            .filter(|datum| live.contains(&NonNull::from(datum.as_ref())))
+            // This is safe because the data live in the arena, which outlives
+            // the borrow of data, and live data are not freed while the lock
+            // is held.
+            .map(|datum| unsafe { &*(datum.as_ref() as *const DatumInner) })
+            .collect()
+    }
+
+    /// Find the groups of data that form cycles.
+    ///
+    /// Every group is a strongly connected component of the heap: from every
+    /// datum in the group, every other datum in the group can be reached by
+    /// following pointers. A datum that points to itself forms a group on its
+    /// own. Data are identified by their address; see [Datum::as_ptr]. Each
+    /// group lists its data in allocation order, and the groups are ordered
+    /// by their first datum.
+    ///
+    /// Data that are no longer reachable but have not yet been garbage
+    /// collected are included, so this can tell cycles that keep each other
+    /// alive apart from garbage. The heap is not changed.
+    ///
+    /// [Datum::as_ptr]: struct.Datum.html#method.as_ptr
+    pub fn find_cycles(&self) -> Vec<Vec<*const ()>> {
+        let _guard = self.lock.lock();
+        let data = self.data.borrow();
+
+        // Data that were freed by a sweep in progress are left out.
+        let freed = match *self.collection.borrow() {
+            Collection::Sweeping{read, write, ..} => write .. read,
+            _ => 0 .. 0,
+        };
+        let nodes: Vec<&DatumInner> = data.iter().enumerate()
+            .filter(|(index, _)| !freed.contains(index))
+            .map(|(_, datum)| datum.as_ref())
+            .collect();
+        let numbers: HashMap<NonNull<DatumInner>, usize> = nodes.iter()
+            .enumerate()
+            .map(|(number, &datum)| (NonNull::from(datum), number))
+            .collect();
+        let successor = |node: usize, edge: usize| {
+            nodes[node].pointers.get(edge).map(|pointer| {
+                numbers.get(&pointer.get()).cloned()
+            })
+        };
+
+        // Tarjan's algorithm, with an explicit stack of the nodes being
+        // visited along with the next pointer to follow.
+        let mut order = vec![usize::MAX; nodes.len()];
+        let mut lowlink = vec![0; nodes.len()];
+        let mut on_stack = vec![false; nodes.len()];
+        let mut stack = Vec::new();
+        let mut cycles = Vec::new();
+        let mut next_order = 0;
+        for start in 0 .. nodes.len() {
+            if order[start] != usize::MAX {
+                continue;
+            }
+
+            let mut visiting = vec![(start, 0)];
+            order[start] = next_order;
+            lowlink[start] = next_order;
+            next_order += 1;
+            stack.push(start);
+            on_stack[start] = true;
+
+            while let Some(&mut (node, ref mut edge)) = visiting.last_mut() {
+                match successor(node, *edge) {
+                    // Immediates and foreign data are not part of any cycle.
+                    Some(None) => *edge += 1,
+                    Some(Some(next)) => {
+                        *edge += 1;
+                        if order[next] == usize::MAX {
+                            order[next] = next_order;
+                            lowlink[next] = next_order;
+                            next_order += 1;
+                            stack.push(next);
+                            on_stack[next] = true;
+                            visiting.push((next, 0));
+                        } else if on_stack[next] {
+                            lowlink[node] = lowlink[node].min(order[next]);
+                        }
+                    },
+                    None => {
+                        visiting.pop();
+                        if let Some(&(parent, _)) = visiting.last() {
+                            lowlink[parent] =
+                                lowlink[parent].min(lowlink[node]);
+                        }
+                        if lowlink[node] != order[node] {
+                            continue;
+                        }
 
-        let inner = Box::new(Self::construct(enchantment, pointers, auxiliary));
-        let ptr = NonNull::from(inner.as_ref());
-        data.push(inner);
+                        let mut cycle = Vec::new();
+                        while let Some(member) = stack.pop() {
+                            on_stack[member] = false;
+                            cycle.push(member);
+                            if member == node {
+                                break;
+                            }
+                        }
+                        let points_to_itself = nodes[node].pointers.iter()
+                            .any(|pointer| pointer.get() == NonNull::from(
+                                nodes[node]));
+                        if cycle.len() > 1 || points_to_itself {
+                            cycle.sort();
+                            cycles.push(cycle);
+                        }
+                    },
+                }
+            }
+        }
 
-        // This is safe because self.data owns the box, hence the pointer is
-        // still valid here.
-        Datum::enroot(ptr)
+        cycles.sort();
+        cycles.into_iter()
+            .map(|cycle| cycle.into_iter()
+                .map(|member| nodes[member] as *const DatumInner as *const ())
+                .collect())
+            .collect()
     }
 
     /// Perform garbage collection.
     ///
-    /// This will free all data that are not accessible through any roots.
+    /// This will free all data that are not accessible through any roots, and
+    /// then run the finalizers of the freed data. Data that only point to each
+    /// other, in a cycle, are freed like any other unreachable data; cycles
+    /// never leak. If an incremental collection is in progress, it is
+    /// completed first; the returned statistics then cover both collections.
+    ///
+    /// Finalizers run once the collection no longer uses the heap, so they may
+    /// allocate data on it and inspect it. If a finalizer panics, the panic
+    /// propagates out of this method. The heap remains consistent, but the
+    /// finalizers that were yet to run are dropped without being called.
     pub fn collect_garbage(&self) -> CollectStatistics {
-        // Keep in mind the invariants discussed earlier. With those invariants
-        // guaranteed, garbage collection proceeds as follows:
+        let in_progress = {
+            let _guard = self.lock.lock();
+            !matches!(*self.collection.borrow(), Collection::Idle)
+        };
+
+        let mut stat = self.complete_collection();
+        if in_progress {
+            let current = self.complete_collection();
+            stat.data_freed  += current.data_freed;
+            stat.bytes_freed += current.bytes_freed;
+            stat.data_live    = current.data_live;
+            stat.bytes_live   = current.bytes_live;
+        }
+        stat
+    }
+
+    /// Perform part of a garbage collection.
+    ///
+    /// Each call marks or sweeps at most `budget` data, and then returns
+    /// whether the collection is complete. The first call after a completed
+    /// collection starts a new one. Finalizers of the data freed by a call run
+    /// at the end of that call.
+    ///
+    /// Two steps of a collection look at every datum once regardless of the
+    /// budget: finding the roots when the collection starts, and finding the
+    /// roots and mutated data once more when marking is done. Both are cheap
+    /// compared to marking and sweeping. Data allocated while marking is in
+    /// progress survive the collection.
+    ///
+    /// If a finalizer panics, see [collect_garbage].
+    ///
+    /// [collect_garbage]: #method.collect_garbage
+    pub fn collect_garbage_incremental(&self, budget: usize)
+        -> CollectProgress
+    {
+        let mut finalizers = Vec::new();
+        let progress = self.collect_step(budget, &mut finalizers);
+
+        for (finalizer, auxiliary) in finalizers.into_iter().rev() {
+            finalizer(&auxiliary);
+        }
+
+        if let (Some(observer), CollectProgress::Complete(stat)) =
+            (&self.observer, &progress) {
+            observer.on_collect(stat);
+        }
+        progress
+    }
+
+    /// Perform the remainder of the current collection, or a full collection
+    /// if none is in progress.
+    fn complete_collection(&self) -> CollectStatistics {
+        loop {
+            let progress = self.collect_garbage_incremental(usize::MAX);
+            if let CollectProgress::Complete(stat) = progress {
+                return stat;
+            }
+        }
+    }
+
+    fn collect_step(&self,
+                    mut budget: usize,
+                    finalizers: &mut Vec<(Box<Finalizer>, Box<[u8]>)>,
+                    ) -> CollectProgress {
+        // Garbage collection proceeds as follows:
+        //
+        //  1. Mark all roots, and push them onto a worklist.
+        //  2. Pop a datum from the worklist, if any.
+        //  3. Mark the direct pointees of the datum that are not yet marked,
+        //     and push them onto the worklist.
+        //  4. Start over at step 2 until the worklist is empty.
+        //  5. Push the roots that are not yet marked, and the marked data that
+        //     were mutated or allocated since the collection started, and
+        //     repeat steps 2 to 4 without a budget. All data that are
+        //     reachable from roots are now marked. Detach weak references to
+        //     data that are not.
+        //  6. Free all unmarked data, and unmark the remaining data. Survivors
+        //     are moved down over freed data to keep allocation order; the
+        //     freed data are removed from the heap once the sweep is done.
         //
-        //  1. Find the latest allocated datum, if any.
-        //  2. If the datum is a root, mark it.
-        //  3. If the datum is marked:
-        //     1. Mark the direct pointees of the datum. They will be processed
-        //        eventually, because of the invariants and the backwards
-        //        traversal.
-        //     2. Unmark the datum.
-        //  4. Else:
-        //     1. Free the datum.
-        //     2. Remove the datum from the heap.
-        //  5. Start over at last the datum allocated before the datum.
+        // Marking a datum when it is pushed ensures that every datum is
+        // pushed at most once per marking, even if it is part of a cycle.
+        //
+        // Between calls, the program can create roots, overwrite pointers,
+        // and allocate. Step 5 accounts for all of these, since any datum
+        // newly reachable from a root is reachable through a root that was
+        // not yet marked, or through a datum that was mutated or allocated.
+        let _guard = self.lock.lock();
         let mut data = self.data.borrow_mut();
-        let mut stat = CollectStatistics{data_freed: 0};
+        let mut collection = self.collection.borrow_mut();
+
+        loop {
+            match *collection {
+                Collection::Idle => {
 
         /**********************************************************************/
         /* Step 1                                                             */
-        for i in Iterator::rev(0 .. data.len()) {
-            let mark = {
-                let datum = data[i].as_ref();
+                    let mut worklist = Vec::new();
+                    for datum in data.iter() {
+                        datum.dirty.set(false);
+                        if datum.roots.get() > 0 {
+                            datum.mark.set(true);
+                            worklist.push(NonNull::from(datum.as_ref()));
+                        }
+                    }
+                    self.marking.set(true);
+                    *collection = Collection::Marking{worklist};
+                },
 
-        /**********************************************************************/
-        /* Step 2                                                             */
-                if datum.roots.get() > 0 {
-                    datum.mark.set(true);
-                }
+                Collection::Marking{ref mut worklist} => {
 
         /**********************************************************************/
-        /* Step 3                                                             */
-                if datum.mark.get() {
-                    for pointee in datum.pointers.iter() {
-                        // This is safe because the pointee definitely has not
-                        // yet been garbage collected, because of the
-                        // invariants and the backwards traversal.
-                        unsafe { pointee.as_ref() }.mark.set(true);
+        /* Steps 2, 3, and 4                                                  */
+                    // This is safe because data on the worklist are marked,
+                    // and hence have not been garbage collected.
+                    budget -= unsafe { mark(worklist, budget) };
+                    if !worklist.is_empty() {
+                        return CollectProgress::InProgress;
                     }
-                    datum.mark.set(false);
 
         /**********************************************************************/
-        /* Step 4                                                             */
-                    true
-                } else {
-                    false
-                }
-            };
+        /* Step 5                                                             */
+                    for datum in data.iter() {
+                        let unmarked_root =
+                            datum.roots.get() > 0 && !datum.mark.replace(true);
+                        let dirty = datum.mark.get() && datum.dirty.get();
+                        if unmarked_root || dirty {
+                            worklist.push(NonNull::from(datum.as_ref()));
+                        }
+                    }
+                    // This is safe for the same reason as above.
+                    unsafe { mark(worklist, usize::MAX) };
 
-            if !mark {
-                stat.data_freed += 1;
-                data.pop();
-            }
+                    for datum in data.iter() {
+                        if !datum.mark.get() {
+                            if let Some(ref target) = *datum.weak.borrow() {
+                                target.set(None);
+                            }
+                        }
+                    }
+
+                    self.marking.set(false);
+                    *collection = Collection::Sweeping{
+                        read:  0,
+                        write: 0,
+                        end:   data.len(),
+                        stat:  CollectStatistics{
+                            data_freed:  0,
+                            data_live:   0,
+                            bytes_freed: 0,
+                            bytes_live:  0,
+                        },
+                    };
+                },
+
+                Collection::Sweeping{ref mut read, ref mut write, end,
+                                     ref mut stat} => {
 
         /**********************************************************************/
-        /* Step 5                                                             */
-            continue;
-        }
+        /* Step 6                                                             */
+                    while *read < end && budget > 0 {
+                        let datum = &mut data[*read];
+                        if datum.mark.replace(false) {
+                            stat.data_live  += 1;
+                            stat.bytes_live += datum.size();
+                            data.swap(*write, *read);
+                            *write += 1;
+                        } else {
+                            stat.data_freed  += 1;
+                            stat.bytes_freed += datum.size();
+                            self.bytes.set(self.bytes.get() - datum.size());
+                            if let Some(finalizer) = datum.finalizer.take() {
+                                let auxiliary = mem::take(&mut datum.auxiliary);
+                                finalizers.push((finalizer, auxiliary));
+                            }
+                        }
+                        *read  += 1;
+                        budget -= 1;
+                    }
+                    if *read < end {
+                        return CollectProgress::InProgress;
+                    }
 
-        stat
+                    let mut arena = self.arena.borrow_mut();
+                    for datum in data.drain(*write .. end) {
+                        // This is safe because the datum is unreachable.
+                        unsafe { arena.free(datum) };
+                    }
+                    let stat = stat.clone();
+                    *collection = Collection::Idle;
+                    return CollectProgress::Complete(stat);
+                },
+            }
+        }
     }
 
     /// This function is unsafe because the pointers must belong to this heap
-    /// and this is currently not checked.
-    unsafe fn construct(enchantment: Sigil,
-                        pointers:    &[Datum],
-                        auxiliary:   &[u8],
-                        ) -> DatumInner {
+    /// and this is not checked here.
+    pub(super) unsafe fn construct(&self,
+                                   enchantment: Sigil,
+                                   pointers:    &[Datum],
+                                   auxiliary:   &[u8],
+                                   finalizer:   Option<Box<Finalizer>>,
+                                   ) -> DatumInner {
         // This is safe because the representation of Datum is equivalent to
         // that of DatumInner.
         let pointers_inner =
             transmute::<&[Datum], &[NonNull<DatumInner>]>(pointers);
 
         DatumInner{
-            mark:        Cell::new(false),
+            #[cfg(feature = "checked")]
+            heap:        self.id,
+            #[cfg(feature = "sync")]
+            lock:        NonNull::from(self.lock.as_ref()),
+            mark:        Cell::new(self.marking.get()),
+            dirty:       Cell::new(self.marking.get()),
             roots:       Cell::new(0),
-            enchantment: enchantment,
-            pointers:    Box::from(pointers_inner),
+            enchantment,
+            pointers:    pointers_inner.iter().cloned().map(Cell::new)
+                             .collect(),
             auxiliary:   Box::from(auxiliary),
+            weak:        RefCell::new(None),
+            finalizer,
+        }
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        let arena = self.arena.get_mut();
+        for datum in self.data.get_mut().drain(..) {
+            // This is safe because the heap is not borrowed, hence no roots
+            // exist.
+            unsafe { arena.free(datum) };
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The state of an incremental garbage collection between calls.
+enum Collection {
+    /// No collection is in progress.
+    Idle,
+
+    /// Marking is in progress. The worklist holds marked data whose pointees
+    /// have not yet been marked.
+    Marking{worklist: Vec<NonNull<DatumInner>>},
+
+    /// Sweeping is in progress. Data below `write` survived, data from `write`
+    /// up to `read` were freed, and data from `read` up to `end` are yet to be
+    /// swept. Data from `end` onwards were allocated during the sweep.
+    Sweeping{read: usize, write: usize, end: usize, stat: CollectStatistics},
+}
+
+/// Pop at most `budget` data from the worklist, marking and pushing their
+/// unmarked pointees. Returns the number of data popped.
+///
+/// This function is unsafe because the data on the worklist must not have
+/// been garbage collected.
+unsafe fn mark(worklist: &mut Vec<NonNull<DatumInner>>, budget: usize)
+    -> usize
+{
+    let mut popped = 0;
+    while popped < budget {
+        let datum = match worklist.pop() {
+            Some(datum) => datum.as_ref(),
+            None        => break,
+        };
+        popped += 1;
+
+        for pointee in datum.pointers.iter().map(Cell::get) {
+            // Immediates do not live on the heap.
+            if is_immediate(pointee) {
+                continue;
+            }
+
+            // The pointee is reachable from a datum that has not been garbage
+            // collected, and hence has not been garbage collected either.
+            if !pointee.as_ref().mark.replace(true) {
+                worklist.push(pointee);
+            }
         }
     }
+    popped
 }
 
+/// A function that is called when a datum is garbage collected. See
+/// [Heap::allocate_with_finalizer].
+///
+/// [Heap::allocate_with_finalizer]:
+///     struct.Heap.html#method.allocate_with_finalizer
+#[cfg(not(feature = "sync"))]
+pub type Finalizer = dyn FnOnce(&[u8]);
+
+/// A function that is called when a datum is garbage collected. See
+/// [Heap::allocate_with_finalizer]. With the `sync` feature, finalizers run on
+/// whichever thread collects garbage, so they must be `Send`.
+///
+/// [Heap::allocate_with_finalizer]:
+///     struct.Heap.html#method.allocate_with_finalizer
+#[cfg(feature = "sync")]
+pub type Finalizer = dyn FnOnce(&[u8]) + Send;
+
 /// Statistics on a single garbage collection.
+///
+/// Byte counts include the pointers and auxiliary parts of data, but not the
+/// fixed-size bookkeeping every datum carries.
 #[derive(Clone, Debug)]
 pub struct CollectStatistics {
     /// The number of data that were freed by this garbage collection.
     pub data_freed: usize,
+
+    /// The number of data that survived this garbage collection.
+    pub data_live: usize,
+
+    /// The number of bytes that were freed by this garbage collection.
+    pub bytes_freed: usize,
+
+    /// The number of bytes retained by data that survived this garbage
+    /// collection.
+    pub bytes_live: usize,
+}
+
+/// The result of a call to [Heap::collect_garbage_incremental].
+///
+/// [Heap::collect_garbage_incremental]:
+///     struct.Heap.html#method.collect_garbage_incremental
+#[derive(Clone, Debug)]
+pub enum CollectProgress {
+    /// The collection is not yet complete.
+    InProgress,
+
+    /// The collection is complete, with the given statistics.
+    Complete(CollectStatistics),
+}
+
+/// This error is returned when attempting to allocate a datum with a pointer
+/// to a datum that does not belong to the heap.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ForeignPointerError {
+    /// The index of the offending pointer.
+    pub index: usize,
+}
+
+impl fmt::Display for ForeignPointerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pointer {} belongs to a different heap", self.index)
+    }
+}
+
+impl Error for ForeignPointerError {}
+
+/// This error is returned when attempting to allocate a datum that does not
+/// fit within the limit of the heap, even after collecting garbage.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HeapLimitExceeded {
+    /// The number of bytes the datum would retain.
+    pub size:  usize,
+
+    /// The limit of the heap.
+    pub limit: usize,
 }
 
+impl fmt::Display for HeapLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "datum of {} bytes does not fit within heap limit of {} \
+                   bytes", self.size, self.limit)
+    }
+}
+
+impl Error for HeapLimitExceeded {}
+
+/// This error is returned by [Heap::try_allocate].
+///
+/// [Heap::try_allocate]: struct.Heap.html#method.try_allocate
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AllocateError {
+    ForeignPointer(ForeignPointerError),
+    LimitExceeded(HeapLimitExceeded),
+}
+
+impl fmt::Display for AllocateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocateError::ForeignPointer(error) => error.fmt(f),
+            AllocateError::LimitExceeded(error)  => error.fmt(f),
+        }
+    }
+}
+
+impl Error for AllocateError {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::mem::size_of;
+    use std::panic;
+    use std::panic::AssertUnwindSafe;
+    use std::slice;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
     #[test]
     fn test_empty_heap() {
         let heap = Heap::new();
@@ -176,30 +1121,818 @@ mod tests {
     }
 
     #[test]
-    fn test_pointers_heap() {
+    fn test_collect_statistics_bytes() {
         let sigil = Sigil(0);
+        let pointer = size_of::<NonNull<DatumInner>>();
 
         let heap = Heap::new();
-        let datum_a = unsafe { heap.allocate(sigil, &[], &[]) };
-        let datum_b = unsafe { heap.allocate(sigil, &[datum_a.clone()], &[]) };
-        let datum_c = unsafe { heap.allocate(sigil, &[datum_b.clone()], &[]) };
-        let datum_d = unsafe { heap.allocate(sigil, &[datum_b.clone(),
-                                                      datum_c.clone()], &[]) };
+        let datum_a = unsafe { heap.allocate(sigil, &[], b"abc") };
+        let datum_b = unsafe { heap.allocate(sigil, slice::from_ref(&datum_a),
+                                             b"de") };
+        let datum_c = unsafe { heap.allocate(sigil, &[datum_a.clone(),
+                                                      datum_b.clone()], &[]) };
 
         drop(datum_a);
         drop(datum_c);
+        assert_eq!(heap.bytes(), 3 + pointer + 2 + 2 * pointer);
 
-        { let stat = heap.collect_garbage()
-        ; assert_eq!(stat.data_freed, 0) }
+        let stat = heap.collect_garbage();
+        assert_eq!(stat.data_freed,  1);
+        assert_eq!(stat.data_live,   2);
+        assert_eq!(stat.bytes_freed, 2 * pointer);
+        assert_eq!(stat.bytes_live,  3 + pointer + 2);
+        assert_eq!(heap.bytes(), stat.bytes_live);
 
-        drop(datum_d);
+        drop(datum_b);
 
-        { let stat = heap.collect_garbage()
-        ; assert_eq!(stat.data_freed, 2) }
+        let stat = heap.collect_garbage();
+        assert_eq!(stat.data_freed,  2);
+        assert_eq!(stat.data_live,   0);
+        assert_eq!(stat.bytes_freed, 3 + pointer + 2);
+        assert_eq!(stat.bytes_live,  0);
+        assert_eq!(heap.bytes(), 0);
+    }
 
-        drop(datum_b);
+    #[test]
+    fn test_try_allocate() {
+        let sigil = Sigil(0);
+        let immediate = Datum::from_i64(1).unwrap();
 
-        { let stat = heap.collect_garbage()
-        ; assert_eq!(stat.data_freed, 2) }
+        let heap_a = Heap::new();
+        let heap_b = Heap::new();
+        let datum_a = heap_a.try_allocate(sigil, &[], &[]).unwrap();
+        let datum_b = heap_b.try_allocate(sigil, &[], &[]).unwrap();
+
+        let result = heap_a.try_allocate(sigil, &[datum_a.clone(),
+                                                  immediate.clone()], &[]);
+        assert!(result.is_ok());
+
+        let result = heap_a.try_allocate(sigil, &[datum_a, immediate,
+                                                  datum_b], &[]);
+        let error = ForeignPointerError{index: 2};
+        assert_eq!(result.unwrap_err(), AllocateError::ForeignPointer(error));
+        assert_eq!(heap_a.len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "checked")]
+    #[should_panic(expected = "Pointer belongs to a different heap")]
+    fn test_allocate_foreign_pointer() {
+        let sigil = Sigil(0);
+
+        let heap_a = Heap::new();
+        let heap_b = Heap::new();
+        let datum_b = unsafe { heap_b.allocate(sigil, &[], &[]) };
+        unsafe { heap_a.allocate(sigil, &[datum_b], &[]) };
+    }
+
+    #[test]
+    fn test_finalizers() {
+        let sigil = Sigil(0);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let finalizer = |log: &Arc<Mutex<Vec<Vec<u8>>>>| -> Box<Finalizer> {
+            let log = log.clone();
+            Box::new(move |auxiliary: &[u8]| {
+                log.lock().unwrap().push(auxiliary.to_vec())
+            })
+        };
+
+        let heap = Heap::new();
+        let datum_a = unsafe {
+            heap.allocate_with_finalizer(sigil, &[], b"a", finalizer(&log))
+        };
+        let datum_b = unsafe {
+            heap.allocate_with_finalizer(sigil, &[], b"b", finalizer(&log))
+        };
+        let datum_c = unsafe {
+            heap.allocate_with_finalizer(sigil, &[], b"c", finalizer(&log))
+        };
+
+        drop(datum_b);
+        heap.collect_garbage();
+        assert_eq!(*log.lock().unwrap(), vec![b"b".to_vec()]);
+
+        drop(datum_a);
+        drop(datum_c);
+        heap.collect_garbage();
+        assert_eq!(*log.lock().unwrap(), vec![b"b".to_vec(), b"c".to_vec(),
+                                              b"a".to_vec()]);
+
+        heap.collect_garbage();
+        assert_eq!(log.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_panicking_finalizer() {
+        let sigil = Sigil(0);
+
+        let heap = Heap::new();
+        let datum_a = unsafe { heap.allocate(sigil, &[], b"a") };
+        let datum_b = unsafe {
+            heap.allocate_with_finalizer(sigil, &[], b"b",
+                                         Box::new(|_: &[u8]| panic!()))
+        };
+
+        drop(datum_b);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            heap.collect_garbage()
+        }));
+        assert!(result.is_err());
+        assert_eq!(heap.len(), 1);
+
+        let datum_c = unsafe { heap.allocate(sigil, &[], b"c") };
+        drop(datum_a);
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 1) }
+        assert_eq!(datum_c.auxiliary(), b"c");
+    }
+
+    #[test]
+    fn test_finalizer_uses_heap() {
+        let sigil = Sigil(0);
+
+        // The finalizer must be 'static, so it cannot borrow a heap that is
+        // dropped at the end of the test.
+        let heap: &'static Heap = Box::leak(Box::new(Heap::new()));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let datum = unsafe {
+            let log = log.clone();
+            heap.allocate_with_finalizer(sigil, &[], b"a", Box::new(move |_| {
+                let finalized = heap.allocate(sigil, &[], b"finalized");
+                log.lock().unwrap().push((heap.len(), finalized.auxiliary()
+                                                          .to_vec()));
+            }))
+        };
+
+        drop(datum);
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 1) }
+        assert_eq!(*log.lock().unwrap(), vec![(1, b"finalized".to_vec())]);
+        assert_eq!(heap.len(), 1);
+
+        // The datum the finalizer allocated is garbage like any other.
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 1) }
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn test_len() {
+        let sigil = Sigil(0);
+
+        let heap = Heap::new();
+        assert!(heap.is_empty());
+
+        let datum_a = unsafe { heap.allocate(sigil, &[], b"a") };
+        let datum_b = unsafe { heap.allocate(sigil, &[], b"b") };
+        assert_eq!(heap.len(), 2);
+        assert!(!heap.is_empty());
+
+        drop(datum_a);
+        assert_eq!(heap.len(), 2);
+
+        heap.collect_garbage();
+        assert_eq!(heap.len(), 1);
+        assert_eq!(heap.total_roots(), 1);
+        assert_eq!(datum_b.auxiliary(), b"b");
+
+        drop(datum_b);
+        heap.collect_garbage();
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn test_limit() {
+        let sigil = Sigil(0);
+        let pointer = size_of::<NonNull<DatumInner>>();
+
+        let heap = Heap::with_limit(32);
+        assert_eq!(heap.limit(), Some(32));
+        let datum_a = heap.try_allocate(sigil, &[], &[0; 16]).unwrap();
+        drop(heap.try_allocate(sigil, &[], &[0; 16]).unwrap());
+
+        // Collecting the garbage makes room for the datum.
+        let datum_b = heap.try_allocate(sigil, &[], &[0; 16]).unwrap();
+        assert_eq!(heap.len(), 2);
+        assert_eq!(heap.bytes(), 32);
+
+        let result = heap.try_allocate(sigil, &[], &[0]);
+        let error = HeapLimitExceeded{size: 1, limit: 32};
+        assert_eq!(result.unwrap_err(), AllocateError::LimitExceeded(error));
+        assert_eq!(heap.len(), 2);
+
+        // Pointers count towards the limit.
+        heap.set_limit(Some(32 + pointer));
+        let datum_c = heap.try_allocate(sigil, slice::from_ref(&datum_a), &[])
+            .unwrap();
+        let result = unsafe { heap.allocate_within_limit(sigil, &[], &[0]) };
+        assert_eq!(result.unwrap_err().size, 1);
+
+        heap.set_limit(None);
+        let datum_d = unsafe { heap.allocate(sigil, &[], &[0; 1024]) };
+        assert_eq!(heap.bytes(), 32 + pointer + 1024);
+        drop((datum_b, datum_c, datum_d));
+    }
+
+    #[test]
+    #[should_panic(expected = "Heap limit exceeded")]
+    fn test_allocate_exceeds_limit() {
+        let heap = Heap::with_limit(0);
+        unsafe { heap.allocate(Sigil(0), &[], b"a") };
+    }
+
+    #[test]
+    fn test_total_roots() {
+        let sigil = Sigil(0);
+
+        let heap = Heap::new();
+        assert_eq!(heap.total_roots(), 0);
+
+        let datum_a = unsafe { heap.allocate(sigil, &[], &[]) };
+        let datum_b = unsafe { heap.allocate(sigil, slice::from_ref(&datum_a),
+                                             &[]) };
+        assert_eq!(heap.total_roots(), 2);
+
+        let frame: Box<[Datum]> = Box::new([datum_a.clone(), datum_b.clone()]);
+        assert_eq!(heap.total_roots(), 4);
+
+        drop(frame);
+        assert_eq!(heap.total_roots(), 2);
+
+        drop(datum_a);
+        drop(datum_b);
+        assert_eq!(heap.total_roots(), 0);
+    }
+
+    #[test]
+    fn test_immediates_heap() {
+        let sigil = Sigil(0);
+        let one = Datum::from_i64(1).unwrap();
+        let two = Datum::from_i64(2).unwrap();
+
+        let heap = Heap::new();
+        let datum_a = unsafe { heap.allocate(sigil, slice::from_ref(&one),
+                                             &[]) };
+        let datum_b = unsafe { heap.allocate(sigil, &[two, datum_a.clone(),
+                                                      one], &[]) };
+        assert_eq!(heap.total_roots(), 2);
+        assert_eq!(datum_b.pointers()[0].as_i64(), Some(2));
+        assert_eq!(datum_b.pointers()[2].as_i64(), Some(1));
+        assert_eq!(datum_b.pointers()[1].pointers()[0].as_i64(), Some(1));
+
+        drop(datum_a);
+
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 0) }
+
+        let immediate = datum_b.pointers()[0].clone();
+        drop(datum_b);
+
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 2) }
+
+        assert_eq!(immediate.as_i64(), Some(2));
+        assert_eq!(heap.total_roots(), 0);
+    }
+
+    #[test]
+    fn test_set_pointer() {
+        let sigil = Sigil(0);
+
+        let heap = Heap::new();
+        let datum_a = unsafe { heap.allocate(sigil, &[], b"a") };
+        let datum_b = unsafe { heap.allocate(sigil, &[], b"b") };
+        let datum_c = unsafe { heap.allocate(sigil, &[datum_a.clone(),
+                                                      datum_a.clone()], &[]) };
+
+        unsafe { datum_c.set_pointer(1, &datum_b) };
+        assert_eq!(datum_c.pointers()[0].auxiliary(), b"a");
+        assert_eq!(datum_c.pointers()[1].auxiliary(), b"b");
+
+        drop(datum_b);
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 0) }
+
+        unsafe { datum_c.set_pointer(0, &Datum::from_i64(4).unwrap()) };
+        assert_eq!(datum_c.pointers()[0].as_i64(), Some(4));
+
+        drop(datum_a);
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 1) }
+        assert_eq!(datum_c.pointers()[1].auxiliary(), b"b");
+    }
+
+    #[test]
+    #[should_panic(expected = "Pointer index out of bounds")]
+    fn test_set_pointer_out_of_bounds() {
+        let heap = Heap::new();
+        let datum = unsafe { heap.allocate(Sigil(0), &[], &[]) };
+        unsafe { datum.set_pointer(0, &datum) };
+    }
+
+    #[test]
+    fn test_forward_pointers() {
+        let sigil = Sigil(0);
+
+        let zero = [Datum::from_i64(0).unwrap()];
+
+        let heap = Heap::new();
+        let datum_a = unsafe { heap.allocate(sigil, &zero, b"a") };
+        let datum_b = unsafe { heap.allocate(sigil, &zero, b"b") };
+        let datum_c = unsafe { heap.allocate(sigil, &zero, b"c") };
+        let datum_d = unsafe { heap.allocate(sigil, slice::from_ref(&datum_a),
+                                             &[]) };
+
+        // Point from older data to newer data: a -> b -> c.
+        unsafe { datum_a.set_pointer(0, &datum_b) };
+        unsafe { datum_b.set_pointer(0, &datum_c) };
+
+        drop(datum_a);
+        drop(datum_b);
+        drop(datum_c);
+
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 0) }
+
+        assert_eq!(datum_d.pointers()[0].pointers()[0].pointers()[0]
+                          .auxiliary(), b"c");
+
+        drop(datum_d);
+
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 4) }
+    }
+
+    /// Allocate a ring of data that each point to the next, and return the
+    /// first datum of the ring.
+    fn ring<'a>(heap: &'a Heap, length: usize) -> Datum<'a> {
+        let first = unsafe {
+            heap.allocate(Sigil(0), &[Datum::from_i64(0).unwrap()], &[])
+        };
+        let mut last = first.clone();
+        for _ in 1 .. length {
+            last = unsafe { heap.allocate(Sigil(0), slice::from_ref(&last),
+                                          &[]) };
+        }
+        unsafe { first.set_pointer(0, &last) };
+        first
+    }
+
+    #[test]
+    fn test_unrooted_cycles() {
+        let heap = Heap::new();
+        drop(ring(&heap, 1));
+        drop(ring(&heap, 2));
+        drop(ring(&heap, 10));
+        assert_eq!(heap.find_cycles().len(), 3);
+
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 13)
+        ; assert_eq!(stat.data_live, 0) }
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn test_rooted_cycle() {
+        let heap = Heap::new();
+        let member = ring(&heap, 3).pointers()[0].clone();
+        let outside = unsafe {
+            heap.allocate(Sigil(0), slice::from_ref(&member), &[])
+        };
+        drop(member);
+
+        // A cycle survives as long as any of its members is reachable.
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 0) }
+
+        drop(outside);
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 4) }
+    }
+
+    #[test]
+    fn test_unrooted_cycle_weak_and_finalizer() {
+        let finalized = Arc::new(Mutex::new(false));
+        let heap = Heap::new();
+        let datum = unsafe {
+            let flag = finalized.clone();
+            heap.allocate_with_finalizer(
+                Sigil(0), &[Datum::from_i64(0).unwrap()], &[],
+                Box::new(move |_| *flag.lock().unwrap() = true),
+            )
+        };
+        unsafe { datum.set_pointer(0, &datum) };
+        let weak = datum.downgrade();
+        drop(datum);
+
+        heap.collect_garbage();
+        assert!(weak.upgrade().is_none());
+        assert!(*finalized.lock().unwrap());
+    }
+
+    #[test]
+    fn test_incremental_unrooted_cycle() {
+        let heap = Heap::new();
+        let kept = ring(&heap, 5);
+        drop(ring(&heap, 5));
+
+        let (stat, _) = collect_incrementally(&heap, 1);
+        assert_eq!(stat.data_freed, 5);
+        assert_eq!(stat.data_live, 5);
+        let cycles = heap.find_cycles();
+        assert_eq!(cycles.len(), 1);
+        assert_eq!(cycles[0].len(), 5);
+        assert!(cycles[0].contains(&kept.as_ptr()));
+    }
+
+    #[test]
+    fn test_pointers_heap() {
+        let sigil = Sigil(0);
+
+        let heap = Heap::new();
+        let datum_a = unsafe { heap.allocate(sigil, &[], &[]) };
+        let datum_b = unsafe { heap.allocate(sigil, slice::from_ref(&datum_a),
+                                             &[]) };
+        let datum_c = unsafe { heap.allocate(sigil, slice::from_ref(&datum_b),
+                                             &[]) };
+        let datum_d = unsafe { heap.allocate(sigil, &[datum_b.clone(),
+                                                      datum_c.clone()], &[]) };
+
+        drop(datum_a);
+        drop(datum_c);
+
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 0) }
+
+        drop(datum_d);
+
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 2) }
+
+        drop(datum_b);
+
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 2) }
+    }
+
+    /// Collect garbage incrementally until done, returning the statistics and
+    /// the number of calls it took.
+    fn collect_incrementally(heap: &Heap, budget: usize)
+        -> (CollectStatistics, usize)
+    {
+        let mut calls = 1;
+        loop {
+            match heap.collect_garbage_incremental(budget) {
+                CollectProgress::InProgress      => calls += 1,
+                CollectProgress::Complete(stat) => return (stat, calls),
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental() {
+        let sigil = Sigil(0);
+
+        let heap = Heap::new();
+        let mut chain = unsafe { heap.allocate(sigil, &[], &[]) };
+        for _ in 0 .. 9 {
+            chain = unsafe { heap.allocate(sigil, slice::from_ref(&chain),
+                                           &[]) };
+        }
+        for _ in 0 .. 5 {
+            unsafe { heap.allocate(sigil, &[], &[]) };
+        }
+
+        let (stat, calls) = collect_incrementally(&heap, 1);
+        assert_eq!(stat.data_freed, 5);
+        assert_eq!(stat.data_live, 10);
+        assert!(calls > 10);
+        assert_eq!(heap.len(), 10);
+
+        drop(chain);
+        let (stat, calls) = collect_incrementally(&heap, 100);
+        assert_eq!(stat.data_freed, 10);
+        assert_eq!(calls, 1);
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn test_incremental_len() {
+        let heap = Heap::new();
+        let datum = unsafe { heap.allocate(Sigil(0), &[], &[]) };
+        for _ in 0 .. 3 {
+            unsafe { heap.allocate(Sigil(0), &[], &[]) };
+        }
+
+        // Mark the root, then sweep the root and one garbage datum.
+        heap.collect_garbage_incremental(1);
+        heap.collect_garbage_incremental(2);
+        assert_eq!(heap.len(), 3);
+
+        // Data allocated during the sweep are not swept.
+        let late = unsafe { heap.allocate(Sigil(0), &[], &[]) };
+        drop(late);
+        let (stat, _) = collect_incrementally(&heap, 1);
+        assert_eq!(stat.data_freed, 3);
+        assert_eq!(heap.len(), 2);
+
+        drop(datum);
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 2) }
+    }
+
+    #[test]
+    fn test_incremental_allocate_during_marking() {
+        let sigil = Sigil(0);
+
+        let zero = [Datum::from_i64(0).unwrap()];
+
+        let heap = Heap::new();
+        let datum_b = unsafe { heap.allocate(sigil, &[], b"b") };
+        let datum_a = unsafe { heap.allocate(sigil, slice::from_ref(&datum_b),
+                                             b"a") };
+        drop(datum_b);
+
+        // Start marking without visiting a yet.
+        heap.collect_garbage_incremental(0);
+
+        // Move b from a into a new datum c, before a is visited.
+        let datum_c = unsafe { heap.allocate(sigil, &datum_a.pointers()[..1],
+                                             b"c") };
+        unsafe { datum_a.set_pointer(0, &zero[0]) };
+
+        let (stat, _) = collect_incrementally(&heap, 1);
+        assert_eq!(stat.data_freed, 0);
+        assert_eq!(datum_c.pointers()[0].auxiliary(), b"b");
+
+        drop(datum_a);
+        drop(datum_c);
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 3) }
+    }
+
+    #[test]
+    fn test_incremental_root_during_marking() {
+        let sigil = Sigil(0);
+
+        let zero = [Datum::from_i64(0).unwrap()];
+
+        let heap = Heap::new();
+        let datum_b = unsafe { heap.allocate(sigil, &[], b"b") };
+        let datum_a = unsafe { heap.allocate(sigil, slice::from_ref(&datum_b),
+                                             b"a") };
+        drop(datum_b);
+
+        heap.collect_garbage_incremental(0);
+
+        // Take b out of a before a is visited, so that b is only reachable
+        // through a root that did not exist when marking started.
+        let datum_b = datum_a.pointers()[0].clone();
+        unsafe { datum_a.set_pointer(0, &zero[0]) };
+        drop(datum_a);
+
+        let (stat, _) = collect_incrementally(&heap, 1);
+        assert_eq!(stat.data_freed, 0);
+        assert_eq!(datum_b.auxiliary(), b"b");
+
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 1) }
+        drop(datum_b);
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 1) }
+    }
+
+    #[test]
+    fn test_incremental_weak() {
+        let heap = Heap::new();
+        let root = unsafe { heap.allocate(Sigil(0), &[], &[]) };
+        let weak = unsafe { heap.allocate(Sigil(0), &[], &[]) }.downgrade();
+
+        // Marking completes, but the garbage is not yet swept.
+        let progress = heap.collect_garbage_incremental(1);
+        assert!(matches!(progress, CollectProgress::InProgress));
+        assert_eq!(heap.len(), 2);
+        assert!(weak.upgrade().is_none());
+
+        let (stat, _) = collect_incrementally(&heap, 1);
+        assert_eq!(stat.data_freed, 1);
+        drop(root);
+    }
+
+    #[test]
+    fn test_collect_garbage_during_incremental() {
+        let heap = Heap::new();
+        let datum = unsafe { heap.allocate(Sigil(0), &[], &[]) };
+        unsafe { heap.allocate(Sigil(0), &[], &[]) };
+
+        heap.collect_garbage_incremental(1);
+        drop(datum);
+
+        // The collection in progress only frees the garbage datum, after which
+        // a full collection frees the other one.
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 2)
+        ; assert_eq!(stat.data_live, 0) }
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn test_import() {
+        let source = Heap::new();
+        let shared = unsafe { source.allocate(Sigil(1), &[], b"shared") };
+        let one = Datum::from_i64(1).unwrap();
+        let root = unsafe {
+            source.allocate(Sigil(0), &[shared.clone(), one, shared], b"root")
+        };
+        unsafe { root.set_pointer(1, &root) };
+
+        let target = Heap::new();
+        let copy = target.import(&root);
+        assert_eq!(source.len(), 2);
+        assert_eq!(target.len(), 2);
+        assert!(!copy.ptr_eq(&root));
+        assert!(copy.structural_eq(&root));
+        assert!(copy.pointers()[0].ptr_eq(&copy.pointers()[2]));
+        assert!(copy.pointers()[1].ptr_eq(&copy));
+
+        // The copy does not depend on the source heap.
+        drop(root);
+        drop(source);
+        assert_eq!(copy.pointers()[0].auxiliary(), b"shared");
+        unsafe { target.allocate(Sigil(0), slice::from_ref(&copy), &[]) };
+
+        let immediate = target.import(&Datum::from_i64(7).unwrap());
+        assert_eq!(immediate.as_i64(), Some(7));
+        assert_eq!(target.len(), 3);
+
+        // The copy of the shared datum does not fit, after that of the root.
+        let pointer = size_of::<NonNull<DatumInner>>();
+        let bounded = Heap::with_limit(3 * pointer + b"root".len());
+        let result = bounded.try_import(&copy);
+        assert_eq!(result.unwrap_err().size, b"shared".len());
+        { let stat = bounded.collect_garbage()
+        ; assert_eq!(stat.data_freed, 1) }
+
+        drop(copy);
+        { let stat = target.collect_garbage()
+        ; assert_eq!(stat.data_freed, 3) }
+    }
+
+    #[test]
+    fn test_find_cycles() {
+        let heap = Heap::new();
+        let one = Datum::from_i64(1).unwrap();
+        let (a, b, c) = unsafe {
+            let a = heap.allocate(Sigil(0), slice::from_ref(&one), b"a");
+            let b = heap.allocate(Sigil(0), &[a.clone(), one.clone()], b"b");
+            let c = heap.allocate(Sigil(0), slice::from_ref(&one), b"c");
+            (a, b, c)
+        };
+        let tail = unsafe {
+            heap.allocate(Sigil(0), slice::from_ref(&b), b"tail")
+        };
+        assert!(heap.find_cycles().is_empty());
+
+        unsafe {
+            a.set_pointer(0, &b);
+            c.set_pointer(0, &c);
+        }
+        assert_eq!(heap.find_cycles(), vec![
+            vec![a.as_ptr(), b.as_ptr()],
+            vec![c.as_ptr()],
+        ]);
+
+        // Garbage is included until it is collected.
+        drop((a, b, c, tail));
+        assert_eq!(heap.find_cycles().len(), 2);
+        heap.collect_garbage();
+        assert!(heap.find_cycles().is_empty());
+    }
+
+    #[test]
+    fn test_live_set() {
+        let heap = Heap::new();
+        let one = Datum::from_i64(1).unwrap();
+        let (a, c) = unsafe {
+            let a = heap.allocate(Sigil(0), slice::from_ref(&one), b"a");
+            let b = heap.allocate(Sigil(0), slice::from_ref(&a), b"b");
+            let c = heap.allocate(Sigil(0), &[b, one], b"c");
+            let d = heap.allocate(Sigil(0), slice::from_ref(&c), b"d");
+            drop(d);
+            (a, c)
+        };
+        let live = heap.live_set();
+        let auxiliaries: Vec<&[u8]> =
+            live.iter().map(|datum| datum.auxiliary()).collect();
+        assert_eq!(auxiliaries, [b"a", b"b", b"c"]);
+
+        // The returned data are roots, and keep b alive once c is dropped.
+        drop(c);
+        assert_eq!(heap.collect_garbage().data_freed, 1);
+        drop(live);
+        assert_eq!(heap.live_set().len(), 1);
+        assert_eq!(heap.collect_garbage().data_freed, 2);
+        drop(a);
+        assert!(heap.live_set().is_empty());
+    }
+
+    #[test]
+    fn test_live_set_during_collection() {
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(Sigil(0), &[], b"a") };
+        let b = unsafe { heap.allocate(Sigil(0), &[], b"b") };
+
+        // Marking is in progress and has marked b, which must survive it.
+        heap.collect_garbage_incremental(0);
+        drop(b);
+        assert_eq!(heap.live_set().len(), 1);
+        match heap.collect_garbage_incremental(usize::MAX) {
+            CollectProgress::Complete(stat) => assert_eq!(stat.data_freed, 0),
+            CollectProgress::InProgress => panic!("Collection is incomplete"),
+        }
+        assert_eq!(heap.collect_garbage().data_freed, 1);
+
+        // Sweeping is in progress, having freed b but not yet reached c.
+        let b = unsafe { heap.allocate(Sigil(0), &[], b"b") };
+        let c = unsafe { heap.allocate(Sigil(0), &[], b"c") };
+        drop(b);
+        while !matches!(*heap.collection.borrow(),
+                        Collection::Sweeping{read: 2, ..}) {
+            heap.collect_garbage_incremental(1);
+        }
+        let live = heap.live_set();
+        let auxiliaries: Vec<&[u8]> =
+            live.iter().map(|datum| datum.auxiliary()).collect();
+        assert_eq!(auxiliaries, [b"a", b"c"]);
+        drop(live);
+        assert_eq!(heap.collect_garbage().data_freed, 1);
+        assert_eq!(heap.len(), 2);
+        drop((a, c));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let heap = Heap::with_capacity(10);
+        assert!(heap.data.borrow().capacity() >= 10);
+        assert!(heap.is_empty());
+
+        let datum = unsafe { heap.allocate(Sigil(0), &[], &[]) };
+        heap.reserve(100);
+        assert!(heap.data.borrow().capacity() >= 101);
+        assert_eq!(heap.len(), 1);
+
+        let capacity = heap.data.borrow().capacity();
+        for _ in 0 .. 100 {
+            unsafe { heap.allocate(Sigil(0), &[], &[]) };
+        }
+        assert_eq!(heap.data.borrow().capacity(), capacity);
+        drop(datum);
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let heap = Heap::new();
+        let datum = unsafe { heap.allocate(Sigil(0), &[], &[]) };
+        for _ in 0 .. 100 {
+            unsafe { heap.allocate(Sigil(0), &[], &[]) };
+        }
+        heap.collect_garbage();
+        assert!(heap.data.borrow().capacity() >= 101);
+
+        heap.shrink_to_fit();
+        assert!(heap.data.borrow().capacity() < 101);
+        assert_eq!(heap.len(), 1);
+        assert!(datum.auxiliary().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "sync")]
+    fn test_sync() {
+        use std::thread;
+
+        let heap = Heap::new();
+        let shared = unsafe { heap.allocate(Sigil(0), &[], b"shared") };
+
+        thread::scope(|scope| {
+            for _ in 0 .. 4 {
+                scope.spawn(|| {
+                    for _ in 0 .. 1000 {
+                        let pointers = [shared.clone(), shared.clone()];
+                        let datum = unsafe {
+                            heap.allocate(Sigil(1), &pointers, &[])
+                        };
+                        let weak = datum.downgrade();
+                        heap.collect_garbage_incremental(10);
+                        assert_eq!(weak.upgrade().unwrap().pointers()[1]
+                                       .auxiliary(), b"shared");
+                    }
+                });
+            }
+        });
+
+        assert_eq!(shared.root_count(), 1);
+        assert_eq!(heap.total_roots(), 1);
+        heap.collect_garbage();
+        assert_eq!(heap.len(), 1);
     }
 }
@@ -0,0 +1,60 @@
+//! Every heap has a lock, which is taken by all operations that read or write
+//! the bookkeeping of the heap or its data, such as root counts. With the
+//! `sync` feature enabled, this lock is a mutex, which makes it sound to share
+//! heaps and data between threads. Without it, the lock does nothing.
+//!
+//! The lock is not reentrant. Operations that take it must not call other
+//! operations that take it while holding it.
+
+#[cfg(feature = "sync")]
+use std::sync::Mutex;
+#[cfg(feature = "sync")]
+use std::sync::MutexGuard;
+#[cfg(feature = "sync")]
+use std::sync::PoisonError;
+
+/// The lock of a heap.
+#[cfg(feature = "sync")]
+pub(super) struct Lock(Mutex<()>);
+
+/// The lock of a heap.
+#[cfg(not(feature = "sync"))]
+pub(super) struct Lock;
+
+/// Proof that the lock of a heap is held. The lock is released when this is
+/// dropped.
+#[cfg(feature = "sync")]
+pub(super) type Guard<'a> = MutexGuard<'a, ()>;
+
+/// Proof that the lock of a heap is held. The lock is released when this is
+/// dropped.
+#[cfg(not(feature = "sync"))]
+pub(super) struct Guard;
+
+impl Lock {
+    #[cfg(feature = "sync")]
+    pub(super) fn new() -> Self {
+        Lock(Mutex::new(()))
+    }
+
+    #[cfg(not(feature = "sync"))]
+    pub(super) fn new() -> Self {
+        Lock
+    }
+
+    /// Take the lock, waiting for other threads to release it if necessary.
+    ///
+    /// A panic while the lock is held leaves the heap consistent, so the lock
+    /// is taken even if it is poisoned.
+    #[cfg(feature = "sync")]
+    pub(super) fn lock(&self) -> Guard<'_> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Take the lock, waiting for other threads to release it if necessary.
+    #[cfg(not(feature = "sync"))]
+    #[inline(always)]
+    pub(super) fn lock(&self) -> Guard {
+        Guard
+    }
+}
@@ -2,17 +2,46 @@
 //!
 //! [Data]: ../../../html/data.html
 
+mod arena;
+mod compare;
+mod display;
 mod heap;
+mod lock;
+mod observer;
+mod root_set;
+mod snapshot;
+mod weak;
 
 use std::cell::Cell;
+use std::cell::RefCell;
+use std::convert::TryFrom;
 use std::fmt;
 use std::marker::PhantomData;
+use std::mem::align_of;
+use std::mem::size_of;
 use std::mem::transmute;
 use std::ptr::NonNull;
+use std::str;
 
 use sigil::Sigil;
 
+pub use self::compare::*;
+pub use self::display::*;
 pub use self::heap::*;
+pub use self::observer::*;
+pub use self::root_set::*;
+pub use self::snapshot::*;
+pub use self::weak::*;
+
+use self::arena::*;
+use self::lock::*;
+
+/// The enchantment of immediate integers.
+///
+/// Sigil databases do not hand out this sigil in practice, as they would have
+/// to intern billions of sigils first. Spells for dynamic dispatch on
+/// immediate integers are defined in this spellbook.
+pub const IMMEDIATE_ENCHANTMENT: Sigil = Sigil(u32::MAX);
 
 /// A reference to a datum.
 ///
@@ -20,72 +49,401 @@ pub use self::heap::*;
 /// the value acts as a root. To create a new datum, you need to use a heap.
 /// See [Heap] for more information.
 ///
+/// Small integers may instead be represented as _immediates_, which are stored
+/// in the reference itself and do not live on any heap. See [from_i64] for
+/// more information.
+///
 /// [Heap]: struct.Heap.html
+/// [from_i64]: #method.from_i64
 #[repr(transparent)]
 pub struct Datum<'a> {
+    /// Either a pointer to the datum, or an immediate. Immediates are tagged
+    /// by having the lowest bit set, which pointers to data never have because
+    /// of their alignment. The remaining bits store the integer.
     ptr:     NonNull<DatumInner>,
     phantom: PhantomData<&'a ()>,
 }
 
+impl DatumInner {
+    /// The number of bytes taken up by the pointers and the auxiliary part.
+    fn size(&self) -> usize {
+        Self::size_for(self.pointers.len(), self.auxiliary.len())
+    }
+
+    /// The size of a datum with the given number of pointers and bytes of
+    /// auxiliary part; see [size].
+    ///
+    /// [size]: #method.size
+    fn size_for(pointers: usize, auxiliary: usize) -> usize {
+        pointers * size_of::<NonNull<DatumInner>>() + auxiliary
+    }
+
+    /// Take the lock of the heap the datum belongs to.
+    #[cfg(feature = "sync")]
+    fn lock(&self) -> Guard<'_> {
+        // This is safe because the lock lives as long as the heap, which in
+        // turn outlives its data.
+        unsafe { self.lock.as_ref() }.lock()
+    }
+
+    /// Take the lock of the heap the datum belongs to.
+    #[cfg(not(feature = "sync"))]
+    #[inline(always)]
+    fn lock(&self) -> Guard {
+        Guard
+    }
+}
+
+const IMMEDIATE_TAG: usize = 1;
+
+// Pointers to data must never look like immediates.
+const _: () = assert!(align_of::<DatumInner>() > IMMEDIATE_TAG);
+
+/// Whether a pointer stored in a datum is actually an immediate.
+fn is_immediate(ptr: NonNull<DatumInner>) -> bool {
+    ptr.as_ptr() as usize & IMMEDIATE_TAG != 0
+}
+
 struct DatumInner {
+    /// The id of the heap the datum belongs to.
+    #[cfg(feature = "checked")]
+    heap:        u64,
+
+    /// The lock of the heap the datum belongs to, which must be held when
+    /// accessing the cells below.
+    #[cfg(feature = "sync")]
+    lock:        NonNull<Lock>,
+
     mark:        Cell<bool>,
+
+    /// Whether the pointers were overwritten since the current garbage
+    /// collection started. Marking revisits such data once before it
+    /// completes.
+    dirty:       Cell<bool>,
+
     roots:       Cell<usize>,
     enchantment: Sigil,
-    pointers:    Box<[NonNull<DatumInner>]>,
+    pointers:    Box<[Cell<NonNull<DatumInner>>]>,
     auxiliary:   Box<[u8]>,
+
+    /// The target of weak references to the datum, if any were created.
+    weak:        RefCell<Option<WeakTarget>>,
+
+    /// The function to call when the datum is garbage collected, if any.
+    finalizer:   Option<Box<Finalizer>>,
+}
+
+impl Datum<'static> {
+    /// Create an immediate integer.
+    ///
+    /// Immediates are not allocated on a heap, and so they are never garbage
+    /// collected and do not count as roots. Returns `None` if the integer does
+    /// not fit in an immediate, which can hold one bit less than a pointer.
+    pub fn from_i64(value: i64) -> Option<Self> {
+        let narrowed = value as isize;
+        let shifted = narrowed << 1;
+        if narrowed as i64 != value || shifted >> 1 != narrowed {
+            return None;
+        }
+
+        let ptr = (shifted as usize | IMMEDIATE_TAG) as *mut DatumInner;
+
+        // This is safe because the tag makes the pointer non-null.
+        let ptr = unsafe { NonNull::new_unchecked(ptr) };
+
+        Some(Datum{ptr, phantom: PhantomData})
+    }
 }
 
-impl Datum<'_> {
+impl<'a> Datum<'a> {
+    /// Whether the datum is an immediate rather than allocated on a heap.
+    pub fn is_immediate(&self) -> bool {
+        is_immediate(self.ptr)
+    }
+
+    /// Get the integer stored in the datum.
+    ///
+    /// This is the integer of an immediate, or the auxiliary part read as a
+    /// 64-bit little-endian two's complement integer, which is how arithmetic
+    /// instructions represent integers that do not fit in an immediate. The
+    /// enchantment is not consulted. Returns `None` if the auxiliary part is
+    /// not exactly eight bytes long.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self.immediate() {
+            Some(value) => Some(value),
+            None        => self.auxiliary_bytes().map(i64::from_le_bytes),
+        }
+    }
+
+    /// Get the auxiliary part read as a 64-bit little-endian IEEE 754 floating
+    /// point number. The enchantment is not consulted. Returns `None` if the
+    /// datum is an immediate, or if the auxiliary part is not exactly eight
+    /// bytes long.
+    pub fn as_f64(&self) -> Option<f64> {
+        self.auxiliary_bytes().map(f64::from_le_bytes)
+    }
+
+    /// Get the auxiliary part as a string. The enchantment is not consulted.
+    /// Returns `None` if the datum is an immediate, or if the auxiliary part is
+    /// not valid UTF-8.
+    pub fn as_utf8(&self) -> Option<&str> {
+        self.inner().and_then(|inner| str::from_utf8(&inner.auxiliary).ok())
+    }
+
+    /// Get the integer stored in an immediate, or `None` if the datum is not
+    /// an immediate.
+    fn immediate(&self) -> Option<i64> {
+        if self.is_immediate() {
+            Some((self.ptr.as_ptr() as isize >> 1) as i64)
+        } else {
+            None
+        }
+    }
+
+    /// Get the auxiliary part if it is exactly eight bytes long, and the datum
+    /// is not an immediate.
+    fn auxiliary_bytes(&self) -> Option<[u8; 8]> {
+        let inner = self.inner()?;
+        <[u8; 8]>::try_from(&inner.auxiliary[..]).ok()
+    }
+
+    /// The enchantment of the datum. Immediates are enchanted with
+    /// [IMMEDIATE_ENCHANTMENT].
+    ///
+    /// [IMMEDIATE_ENCHANTMENT]: constant.IMMEDIATE_ENCHANTMENT.html
     pub fn enchantment(&self) -> Sigil {
-        // This is safe because the enchantment is copied out of the datum.
-        unsafe { self.ptr.as_ref() }.enchantment
+        match self.inner() {
+            Some(inner) => inner.enchantment,
+            None        => IMMEDIATE_ENCHANTMENT,
+        }
     }
 
-    pub fn pointers(&self) -> &[Datum] {
-        // This is safe because the returned reference cannot outlive the root,
-        // which in turn cannot outlive the heap.
-        let pointers = &unsafe { self.ptr.as_ref() }.pointers;
+    /// The pointers of the datum. Immediates have no pointers.
+    pub fn pointers(&self) -> &[Datum<'a>] {
+        let pointers = match self.inner() {
+            Some(inner) => &inner.pointers,
+            None        => return &[],
+        };
 
         // This is safe because the representation of Datum is equivalent to
-        // that of DatumInner.
-        unsafe { transmute::<&[NonNull<DatumInner>], &[Datum]>(pointers) }
+        // that of Cell<NonNull<DatumInner>>, and the pointers are not changed
+        // while the returned reference is in use; see set_pointer.
+        unsafe {
+            transmute::<&[Cell<NonNull<DatumInner>>], &[Datum<'a>]>(pointers)
+        }
+    }
+
+    /// Overwrite one of the pointers of the datum.
+    ///
+    /// Panics if the datum is an immediate or the index is out of bounds.
+    ///
+    /// # Safety
+    ///
+    /// The value must be an immediate, or it must belong to the same heap as
+    /// the datum. It may have been allocated later than the datum, so
+    /// mutation can create cycles. Under the `checked` feature, pointing to a
+    /// datum from a different heap panics.
+    ///
+    /// In addition, no slice returned by [pointers] on this datum may be in
+    /// use while the pointer is overwritten. With the `sync` feature, this
+    /// includes slices in use by other threads.
+    ///
+    /// [pointers]: #method.pointers
+    pub unsafe fn set_pointer(&self, index: usize, value: &Datum) {
+        let inner = self.inner().expect("Cannot set pointer of immediate");
+        let _guard = inner.lock();
+
+        #[cfg(feature = "checked")]
+        {
+            if let Some(value) = value.inner() {
+                assert_eq!(inner.heap, value.heap,
+                           "Pointer belongs to a different heap");
+            }
+        }
+
+        inner.pointers.get(index)
+            .expect("Pointer index out of bounds")
+            .set(value.ptr);
+        inner.dirty.set(true);
     }
 
+    /// The auxiliary part of the datum. Immediates have an empty auxiliary
+    /// part; use [as_i64] to get at their integer. See also [as_f64] and
+    /// [as_utf8] for common interpretations of the auxiliary part.
+    ///
+    /// [as_i64]: #method.as_i64
+    /// [as_f64]: #method.as_f64
+    /// [as_utf8]: #method.as_utf8
     pub fn auxiliary(&self) -> &[u8] {
-        // This is safe because the returned reference cannot outlive the root,
-        // which in turn cannot outlive the heap.
-        &unsafe { self.ptr.as_ref() }.auxiliary
+        match self.inner() {
+            Some(inner) => &inner.auxiliary,
+            None        => &[],
+        }
     }
 
+    /// The number of `Datum` values that currently refer to the datum,
+    /// including this one. Immediates are not roots, so their root count is
+    /// always zero.
+    ///
+    /// This is meant for debugging; for example, for finding out why a datum
+    /// is not garbage collected. Pointers from other data do not count.
+    pub fn root_count(&self) -> usize {
+        self.inner().map_or(0, |inner| {
+            let _guard = inner.lock();
+            inner.roots.get()
+        })
+    }
+
+    /// Get the datum on the heap, or `None` if the datum is an immediate.
+    fn inner(&self) -> Option<&DatumInner> {
+        if self.is_immediate() {
+            None
+        } else {
+            // This is safe because the returned reference cannot outlive the
+            // root, which in turn cannot outlive the heap.
+            Some(unsafe { self.ptr.as_ref() })
+        }
+    }
+
+    /// Whether the datum is truthy, as far as conditional branches are
+    /// concerned.
+    ///
+    /// A datum is falsy if its enchantment is the given false sigil, and
+    /// truthy otherwise. Its pointers and auxiliary part are not consulted.
+    pub fn is_truthy(&self, falsy: Sigil) -> bool {
+        self.enchantment() != falsy
+    }
+
+    /// The pointer must be an immediate or point to a datum that is not yet
+    /// garbage collected, and the lock of its heap must be held.
     unsafe fn enroot(ptr: NonNull<DatumInner>) -> Self {
-        // TODO: Use Cell::update once stable.
-        let roots = &ptr.as_ref().roots;
-        roots.set(roots.get() + 1);
+        if !is_immediate(ptr) {
+            // TODO: Use Cell::update once stable.
+            let roots = &ptr.as_ref().roots;
+            roots.set(roots.get() + 1);
+        }
         Datum{ptr, phantom: PhantomData}
     }
 }
 
 impl Drop for Datum<'_> {
     fn drop(&mut self) {
-        // TODO: Use Cell::update once stable.
-        // This is safe because self.ptr is always a valid pointer.
-        let roots = &unsafe { self.ptr.as_ref() }.roots;
-        roots.set(roots.get() - 1);
+        if let Some(inner) = self.inner() {
+            let _guard = inner.lock();
+            // TODO: Use Cell::update once stable.
+            let roots = &inner.roots;
+            roots.set(roots.get() - 1);
+        }
     }
 }
 
 impl Clone for Datum<'_> {
     fn clone(&self) -> Self {
-        // This is safe because self.ptr is always a valid pointer.
+        let _guard = self.inner().map(DatumInner::lock);
+        // This is safe because self.ptr is an immediate or a valid pointer,
+        // and the lock is held.
         unsafe { Datum::enroot(self.ptr) }
     }
 }
 
+// With the sync feature, the cells of data are only accessed with the lock of
+// their heap held; see the lock module.
+#[cfg(feature = "sync")]
+unsafe impl Send for Datum<'_> {}
+#[cfg(feature = "sync")]
+unsafe impl Sync for Datum<'_> {}
+
 impl fmt::Debug for Datum<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "heap.allocate({:?}, {:?}, {:?})",
-               self.enchantment(),
-               self.pointers(),
-               self.auxiliary())
+        match self.immediate() {
+            Some(value) => write!(f, "Datum::from_i64({:?})", value),
+            None => write!(f, "heap.allocate({:?}, {:?}, {:?})",
+                           self.enchantment(),
+                           self.pointers(),
+                           self.auxiliary()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::slice;
+
+    #[test]
+    fn test_immediate_round_trip() {
+        for &value in &[0, 1, -1, 42, -123456, i32::MAX as i64,
+                        i32::MIN as i64] {
+            let datum = Datum::from_i64(value).unwrap();
+            assert!(datum.is_immediate());
+            assert_eq!(datum.as_i64(), Some(value));
+            assert_eq!(datum.enchantment(), IMMEDIATE_ENCHANTMENT);
+            assert!(datum.pointers().is_empty());
+            assert!(datum.auxiliary().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_immediate_out_of_range() {
+        assert!(Datum::from_i64(i64::MAX).is_none());
+        assert!(Datum::from_i64(i64::MIN).is_none());
+        assert!(Datum::from_i64(isize::MAX as i64 >> 1).is_some());
+        assert!(Datum::from_i64(isize::MIN as i64 >> 1).is_some());
+    }
+
+    #[test]
+    fn test_typed_accessors() {
+        let heap = Heap::new();
+        let allocate = |auxiliary: &[u8]| unsafe {
+            heap.allocate(Sigil(0), &[], auxiliary)
+        };
+
+        let integer = allocate(&(-3i64).to_le_bytes());
+        assert_eq!(integer.as_i64(), Some(-3));
+        assert!(integer.as_f64().unwrap().is_nan());
+
+        let float = allocate(&1.5f64.to_le_bytes());
+        assert_eq!(float.as_f64(), Some(1.5));
+
+        let text = allocate("héllo".as_bytes());
+        assert_eq!(text.as_utf8(), Some("héllo"));
+        assert_eq!(text.as_i64(), None);
+        assert_eq!(text.as_f64(), None);
+
+        assert_eq!(allocate(&[0xff, 0xfe]).as_utf8(), None);
+        assert_eq!(allocate(&[]).as_utf8(), Some(""));
+        assert_eq!(allocate(&[0; 7]).as_i64(), None);
+        assert_eq!(allocate(&[0; 9]).as_i64(), None);
+
+        let immediate = Datum::from_i64(5).unwrap();
+        assert_eq!(immediate.as_f64(), None);
+        assert_eq!(immediate.as_utf8(), None);
+    }
+
+    #[test]
+    fn test_root_count() {
+        let heap = Heap::new();
+        let datum = unsafe { heap.allocate(Sigil(0), &[], &[]) };
+        assert_eq!(datum.root_count(), 1);
+
+        let clone = datum.clone();
+        let pointer = unsafe {
+            heap.allocate(Sigil(0), slice::from_ref(&datum), &[])
+        };
+        assert_eq!(datum.root_count(), 2);
+        assert_eq!(pointer.pointers()[0].root_count(), 2);
+
+        drop(clone);
+        assert_eq!(datum.root_count(), 1);
+        assert_eq!(Datum::from_i64(1).unwrap().root_count(), 0);
+    }
+
+    #[test]
+    fn test_immediate_clone() {
+        let datum = Datum::from_i64(7).unwrap();
+        let clone = datum.clone();
+        drop(datum);
+        assert_eq!(clone.as_i64(), Some(7));
     }
 }
@@ -0,0 +1,104 @@
+use super::*;
+
+/// Receives the allocation and collection events of a heap; see
+/// [Heap::new_with_observer].
+///
+/// The methods are called without the lock of the heap held, so they may use
+/// the heap and the data passed to them. They must not panic.
+///
+/// [Heap::new_with_observer]: struct.Heap.html#method.new_with_observer
+pub trait HeapObserver {
+    /// Called after a datum is allocated, before it is returned to the caller
+    /// that allocated it.
+    fn on_allocate(&self, datum: &Datum);
+
+    /// Called after a garbage collection completes and the finalizers of the
+    /// data it freed have run, with the statistics of that collection.
+    ///
+    /// An incremental collection is reported once, by the call to
+    /// [Heap::collect_garbage_incremental] that completes it.
+    /// [Heap::collect_garbage] reports each collection it completes
+    /// separately: the one in progress, if any, and a full one.
+    ///
+    /// [Heap::collect_garbage_incremental]:
+    ///     struct.Heap.html#method.collect_garbage_incremental
+    /// [Heap::collect_garbage]: struct.Heap.html#method.collect_garbage
+    fn on_collect(&self, stats: &CollectStatistics);
+}
+
+/// A heap observer as installed in a heap. See [HeapObserver].
+///
+/// [HeapObserver]: trait.HeapObserver.html
+#[cfg(not(feature = "sync"))]
+pub type Observer = dyn HeapObserver;
+
+/// A heap observer as installed in a heap. See [HeapObserver]. With the
+/// `sync` feature, the events of a heap arrive on whichever thread uses it, so
+/// observers must be `Send` and `Sync`.
+///
+/// [HeapObserver]: trait.HeapObserver.html
+#[cfg(feature = "sync")]
+pub type Observer = dyn HeapObserver + Send + Sync;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::slice;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Eq, PartialEq)]
+    enum Event {
+        Allocate(Vec<u8>),
+        Collect{data_freed: usize, bytes_freed: usize},
+    }
+
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    impl HeapObserver for Recorder {
+        fn on_allocate(&self, datum: &Datum) {
+            let event = Event::Allocate(datum.auxiliary().to_vec());
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn on_collect(&self, stats: &CollectStatistics) {
+            let event = Event::Collect{data_freed:  stats.data_freed,
+                                       bytes_freed: stats.bytes_freed};
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let heap = Heap::new_with_observer(Box::new(Recorder(events.clone())));
+        let take = || events.lock().unwrap().drain(..).collect::<Vec<_>>();
+
+        let a = unsafe { heap.allocate(Sigil(0), &[], b"a") };
+        unsafe { heap.allocate(Sigil(0), slice::from_ref(&a), b"bc") };
+        assert_eq!(take(), [Event::Allocate(b"a".to_vec()),
+                            Event::Allocate(b"bc".to_vec())]);
+
+        let size = DatumInner::size_for(1, 2);
+        heap.collect_garbage();
+        assert_eq!(take(), [Event::Collect{data_freed: 1, bytes_freed: size}]);
+
+        // Incremental collections are reported when they complete, and
+        // finishing one in progress is reported apart from the full
+        // collection that follows.
+        heap.collect_garbage_incremental(0);
+        assert!(take().is_empty());
+        drop(a);
+        heap.collect_garbage();
+        let size = DatumInner::size_for(0, 1);
+        assert_eq!(take(), [Event::Collect{data_freed: 0, bytes_freed: 0},
+                            Event::Collect{data_freed: 1, bytes_freed: size}]);
+
+        // Failed allocations are not reported.
+        heap.set_limit(Some(0));
+        let error = unsafe { heap.allocate_within_limit(Sigil(0), &[], b"d") };
+        assert!(error.is_err());
+        assert_eq!(take(), [Event::Collect{data_freed: 0, bytes_freed: 0}]);
+    }
+}
@@ -0,0 +1,128 @@
+use super::*;
+
+use std::iter::FromIterator;
+use std::ops::Index;
+use std::slice;
+
+/// A collection of roots that are dropped together.
+///
+/// A root set keeps data from being garbage collected until it is dropped or
+/// cleared, which is useful for keeping temporaries alive during a computation
+/// without a binding for each of them. Roots are numbered in the order in
+/// which they were added.
+#[derive(Clone, Debug, Default)]
+pub struct RootSet<'a> {
+    roots: Vec<Datum<'a>>,
+}
+
+impl<'a> RootSet<'a> {
+    /// Create a root set with no roots.
+    pub fn new() -> Self {
+        RootSet{roots: Vec::new()}
+    }
+
+    /// Add a root to the set, and return its index.
+    pub fn push(&mut self, datum: Datum<'a>) -> usize {
+        self.roots.push(datum);
+        self.roots.len() - 1
+    }
+
+    /// Add a root for each of the data to the set, in order.
+    pub fn extend_from_slice(&mut self, data: &[Datum<'a>]) {
+        self.roots.extend_from_slice(data);
+    }
+
+    /// The root with the given index, or `None` if there is no such root.
+    pub fn get(&self, index: usize) -> Option<&Datum<'a>> {
+        self.roots.get(index)
+    }
+
+    /// The number of roots in the set.
+    pub fn len(&self) -> usize {
+        self.roots.len()
+    }
+
+    /// Whether the set has no roots.
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    /// The roots in the set, in order.
+    pub fn iter(&self) -> slice::Iter<'_, Datum<'a>> {
+        self.roots.iter()
+    }
+
+    /// The roots in the set, in order.
+    pub fn as_slice(&self) -> &[Datum<'a>] {
+        &self.roots
+    }
+
+    /// Drop all roots in the set.
+    pub fn clear(&mut self) {
+        self.roots.clear();
+    }
+}
+
+impl<'a> Index<usize> for RootSet<'a> {
+    type Output = Datum<'a>;
+
+    fn index(&self, index: usize) -> &Datum<'a> {
+        &self.roots[index]
+    }
+}
+
+impl<'a> Extend<Datum<'a>> for RootSet<'a> {
+    fn extend<I: IntoIterator<Item = Datum<'a>>>(&mut self, iter: I) {
+        self.roots.extend(iter);
+    }
+}
+
+impl<'a> FromIterator<Datum<'a>> for RootSet<'a> {
+    fn from_iter<I: IntoIterator<Item = Datum<'a>>>(iter: I) -> Self {
+        RootSet{roots: iter.into_iter().collect()}
+    }
+}
+
+impl<'s, 'a> IntoIterator for &'s RootSet<'a> {
+    type Item = &'s Datum<'a>;
+    type IntoIter = slice::Iter<'s, Datum<'a>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_set() {
+        let heap = Heap::new();
+        let list = unsafe {
+            let a = heap.allocate(Sigil(0), &[], b"a");
+            let b = heap.allocate(Sigil(0), &[], b"b");
+            heap.allocate(Sigil(1), &[a, b], &[])
+        };
+
+        let mut roots = RootSet::new();
+        roots.extend_from_slice(list.pointers());
+        let index = roots.push(Datum::from_i64(1).unwrap());
+        assert_eq!(index, 2);
+        assert_eq!(roots.len(), 3);
+        drop(list);
+
+        // The list is freed, but its elements are kept alive by the set.
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 1) }
+        assert_eq!(roots[0].auxiliary(), b"a");
+        assert_eq!(roots.get(1).unwrap().auxiliary(), b"b");
+        assert_eq!(roots.iter().filter(|root| root.is_immediate()).count(), 1);
+        assert_eq!(heap.total_roots(), 2);
+
+        roots.clear();
+        assert!(roots.is_empty());
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 2) }
+    }
+}
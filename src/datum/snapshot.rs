@@ -0,0 +1,221 @@
+use super::*;
+
+/// A copy of the live data of a heap, which can be restored into any heap.
+///
+/// A snapshot is taken with [Heap::snapshot], and holds the data that were
+/// rooted or reachable from roots at the time, independently of the heap; the
+/// heap can then change or be dropped without affecting the snapshot. Like
+/// [Heap::import], a snapshot keeps sharing and cycles, but not finalizers or
+/// weak references.
+///
+/// [Heap::snapshot]: struct.Heap.html#method.snapshot
+/// [Heap::import]: struct.Heap.html#method.import
+#[derive(Clone, Debug)]
+pub struct HeapSnapshot {
+    /// The data, in allocation order.
+    pub(crate) data:      Vec<SnapshotDatum>,
+
+    /// The indices in `data` of the data that were rooted.
+    pub(crate) roots:     Vec<usize>,
+
+    /// The addresses of the data that were rooted, parallel to `roots`.
+    pub(crate) addresses: Vec<*const ()>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct SnapshotDatum {
+    pub(crate) enchantment: Sigil,
+    pub(crate) pointers:    Vec<SnapshotPointer>,
+    pub(crate) auxiliary:   Box<[u8]>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum SnapshotPointer {
+    /// A pointer to the datum with the given index in the snapshot.
+    Datum(usize),
+
+    /// An immediate, which does not live on any heap.
+    Immediate(Datum<'static>),
+}
+
+impl HeapSnapshot {
+    /// The number of data in the snapshot.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Whether the snapshot has no data, which is the case if the heap had no
+    /// roots when the snapshot was taken.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The addresses of the data that were rooted when the snapshot was
+    /// taken, in allocation order. These are the same as the addresses of the
+    /// data in [find_cycles], and the roots returned by [Heap::restore] are
+    /// in the same order, so together they map roots into the original heap
+    /// to roots into the restored one.
+    ///
+    /// [find_cycles]: struct.Heap.html#method.find_cycles
+    /// [Heap::restore]: struct.Heap.html#method.restore
+    pub fn roots(&self) -> &[*const ()] {
+        &self.addresses
+    }
+}
+
+impl Heap {
+    /// Copy the data in a snapshot into this heap, and return a root to each
+    /// datum that was rooted when the snapshot was taken, in the order of
+    /// [HeapSnapshot::roots].
+    ///
+    /// The snapshot may have been taken of any heap, including this one, and
+    /// may be restored any number of times. Restoring only adds data, so roots
+    /// into the heap remain valid. To fork a heap, restore a snapshot of it
+    /// into a new heap. To reset a heap to a snapshot of it instead, drop all
+    /// roots into the heap before restoring, after which the data that were
+    /// in the heap are garbage.
+    ///
+    /// Panics if a copy does not fit within the limit of the heap, even after
+    /// collecting garbage; see [try_restore].
+    ///
+    /// [HeapSnapshot::roots]: struct.HeapSnapshot.html#method.roots
+    /// [try_restore]: #method.try_restore
+    pub fn restore(&self, snapshot: &HeapSnapshot) -> Vec<Datum<'_>> {
+        self.try_restore(snapshot).expect("Heap limit exceeded")
+    }
+
+    /// Copy the data in a snapshot into this heap, as [restore] does, or
+    /// return an error if a copy does not fit within the limit of the heap.
+    /// The data copied before the error are garbage.
+    ///
+    /// [restore]: #method.restore
+    pub fn try_restore(&self, snapshot: &HeapSnapshot)
+        -> Result<Vec<Datum<'_>>, HeapLimitExceeded>
+    {
+        // Allocate the copies with immediates as placeholder pointers, since a
+        // copy may point to copies that are allocated later.
+        let placeholder = Datum::from_i64(0).unwrap();
+        let copies = snapshot.data.iter().map(|datum| {
+            let pointers = vec![placeholder.clone(); datum.pointers.len()];
+            // This is safe because the pointers are immediates.
+            unsafe {
+                self.allocate_within_limit(datum.enchantment, &pointers,
+                                           &datum.auxiliary)
+            }
+        }).collect::<Result<Vec<_>, _>>()?;
+
+        for (datum, copy) in snapshot.data.iter().zip(&copies) {
+            for (index, pointer) in datum.pointers.iter().enumerate() {
+                let value = match pointer {
+                    SnapshotPointer::Datum(copied) => &copies[*copied],
+                    SnapshotPointer::Immediate(immediate) => immediate,
+                };
+                // This is safe because the value is an immediate or a copy,
+                // and no slice of pointers of the copy is in use.
+                unsafe { copy.set_pointer(index, value) };
+            }
+        }
+
+        Ok(snapshot.roots.iter().map(|&index| copies[index].clone()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::mem::size_of;
+    use std::slice;
+
+    /// Allocate a list of two elements, the second of which points back to
+    /// the list, and both of which point to the same shared datum.
+    fn allocate_list(heap: &Heap) -> Datum<'_> {
+        unsafe {
+            let one = Datum::from_i64(1).unwrap();
+            let shared = heap.allocate(Sigil(0), slice::from_ref(&one),
+                                       b"shared");
+            let second = heap.allocate(Sigil(1), &[one, shared.clone()],
+                                       b"second");
+            let first = heap.allocate(Sigil(1), &[shared, second.clone()],
+                                      b"first");
+            second.set_pointer(0, &first);
+            first
+        }
+    }
+
+    fn check_list(first: &Datum) {
+        let second = &first.pointers()[1];
+        assert_eq!(first.auxiliary(), b"first");
+        assert_eq!(second.auxiliary(), b"second");
+        assert_eq!(first.pointers()[0].auxiliary(), b"shared");
+        assert_eq!(first.pointers()[0].pointers()[0].as_i64(), Some(1));
+        assert_eq!(second.pointers()[0].ptr, first.ptr);
+        assert_eq!(second.pointers()[1].ptr, first.pointers()[0].ptr);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let heap = Heap::new();
+        let first = allocate_list(&heap);
+        let garbage = unsafe { heap.allocate(Sigil(2), &[], &[]) };
+        drop(garbage);
+
+        let snapshot = heap.snapshot();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot.roots(),
+                   &[first.ptr.as_ptr() as *const ()][..]);
+
+        // Changing the heap does not change the snapshot.
+        unsafe { first.set_pointer(1, &Datum::from_i64(2).unwrap()) };
+        drop(allocate_list(&heap));
+
+        let other = Heap::new();
+        let roots = other.restore(&snapshot);
+        assert_eq!(roots.len(), 1);
+        assert_eq!(other.len(), 3);
+        check_list(&roots[0]);
+        assert_eq!(roots[0].pointers()[1].pointers()[0].ptr, roots[0].ptr);
+        assert_eq!(roots[0].pointers()[1].enchantment(), Sigil(1));
+    }
+
+    #[test]
+    fn test_restore_resets() {
+        let heap = Heap::new();
+        let snapshot = {
+            let first = allocate_list(&heap);
+            let snapshot = heap.snapshot();
+            unsafe { first.set_pointer(0, &Datum::from_i64(3).unwrap()) };
+            snapshot
+        };
+
+        // With all roots dropped, only the restored data survive.
+        let roots = heap.restore(&snapshot);
+        assert_eq!(heap.len(), 6);
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 3) }
+        assert_eq!(heap.len(), 3);
+        check_list(&roots[0]);
+
+        drop(roots);
+        heap.collect_garbage();
+        assert!(heap.snapshot().is_empty());
+        assert!(heap.restore(&heap.snapshot()).is_empty());
+    }
+
+    #[test]
+    fn test_try_restore_exceeds_limit() {
+        let heap = Heap::new();
+        let snapshot = {
+            let _first = allocate_list(&heap);
+            heap.snapshot()
+        };
+
+        // The first datum of the list is allocated last, and does not fit.
+        let pointer = size_of::<NonNull<DatumInner>>();
+        let bounded = Heap::with_limit(3 * pointer + 12);
+        let error = bounded.try_restore(&snapshot).unwrap_err();
+        assert_eq!(error.size, 2 * pointer + b"first".len());
+        { let stat = bounded.collect_garbage()
+        ; assert_eq!(stat.data_freed, 2) }
+    }
+}
@@ -0,0 +1,139 @@
+use super::*;
+
+#[cfg(not(feature = "sync"))]
+use std::rc::Rc as Shared;
+#[cfg(feature = "sync")]
+use std::sync::Arc as Shared;
+
+/// The target of weak references to a datum.
+///
+/// A weak target is shared between a datum and all weak references to it. It
+/// points to the datum until the datum is garbage collected, after which it is
+/// cleared. This lets weak references outlive the datum they refer to.
+///
+/// The cell must only be accessed with the lock of the heap held, but the
+/// reference count is updated without it.
+pub(super) type WeakTarget = Shared<Cell<Option<NonNull<DatumInner>>>>;
+
+/// A reference to a datum that does not keep the datum alive.
+///
+/// Unlike a `Datum`, a weak datum is not a root, so the datum it refers to may
+/// be garbage collected while the weak datum exists. To access the datum, use
+/// [upgrade].
+///
+/// [upgrade]: #method.upgrade
+#[derive(Clone)]
+pub struct WeakDatum<'a> {
+    target:  WeakTarget,
+
+    /// The lock of the heap the datum belongs to, or `None` for immediates.
+    #[cfg(feature = "sync")]
+    lock:    Option<NonNull<Lock>>,
+
+    phantom: PhantomData<&'a ()>,
+}
+
+impl<'a> Datum<'a> {
+    /// Create a weak reference to the datum.
+    // The weak target is shared between threads even though it is a cell,
+    // because it is only accessed with the lock held.
+    #[cfg_attr(feature = "sync", allow(clippy::arc_with_non_send_sync))]
+    pub fn downgrade(&self) -> WeakDatum<'a> {
+        let target = match self.inner() {
+            // Immediates are never garbage collected, so their weak target is
+            // never cleared and need not be shared.
+            None => Shared::new(Cell::new(Some(self.ptr))),
+
+            Some(inner) => {
+                let _guard = inner.lock();
+                let mut weak = inner.weak.borrow_mut();
+                let ptr = self.ptr;
+                weak.get_or_insert_with(|| Shared::new(Cell::new(Some(ptr))))
+                    .clone()
+            },
+        };
+        WeakDatum{
+            target,
+            #[cfg(feature = "sync")]
+            lock: self.inner().map(|inner| inner.lock),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a> WeakDatum<'a> {
+    /// Get a root to the datum, or `None` if the datum was garbage collected.
+    pub fn upgrade(&self) -> Option<Datum<'a>> {
+        // This is safe because the lock lives as long as the heap, which the
+        // weak datum cannot outlive.
+        #[cfg(feature = "sync")]
+        let _guard = self.lock.map(|lock| unsafe { lock.as_ref() }.lock());
+
+        // This is safe because the weak target is cleared when the datum is
+        // garbage collected, so the pointer is valid if it is still there.
+        self.target.get().map(|ptr| unsafe { Datum::enroot(ptr) })
+    }
+}
+
+// With the sync feature, the weak target is only accessed with the lock of the
+// heap held; see the lock module.
+#[cfg(feature = "sync")]
+unsafe impl Send for WeakDatum<'_> {}
+#[cfg(feature = "sync")]
+unsafe impl Sync for WeakDatum<'_> {}
+
+impl fmt::Debug for WeakDatum<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.upgrade() {
+            Some(datum) => write!(f, "{:?}.downgrade()", datum),
+            None        => write!(f, "WeakDatum(<collected>)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_live() {
+        let heap = Heap::new();
+        let datum = unsafe { heap.allocate(Sigil(0), &[], b"a") };
+        let weak = datum.downgrade();
+
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 0) }
+
+        let upgraded = weak.upgrade().unwrap();
+        assert_eq!(upgraded.auxiliary(), b"a");
+        assert_eq!(heap.total_roots(), 2);
+    }
+
+    #[test]
+    fn test_upgrade_collected() {
+        let heap = Heap::new();
+        let datum_a = unsafe { heap.allocate(Sigil(0), &[], b"a") };
+        let datum_b = unsafe { heap.allocate(Sigil(0), &[], b"b") };
+        let weak_a = datum_a.downgrade();
+        let weak_b = datum_b.downgrade();
+        let weak_a_again = datum_a.downgrade();
+
+        drop(datum_a);
+        assert!(weak_a.upgrade().is_some());
+
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 1) }
+
+        assert!(weak_a.upgrade().is_none());
+        assert!(weak_a_again.upgrade().is_none());
+        assert_eq!(weak_b.upgrade().unwrap().auxiliary(), b"b");
+    }
+
+    #[test]
+    fn test_upgrade_immediate() {
+        let heap = Heap::new();
+        let weak = Datum::from_i64(3).unwrap().downgrade();
+        heap.collect_garbage();
+        assert_eq!(weak.upgrade().unwrap().as_i64(), Some(3));
+    }
+}
@@ -0,0 +1,127 @@
+use std::error::Error;
+use std::fmt;
+
+use datum::Datum;
+use datum::Heap;
+use interpret::InterpretError;
+use interpret::Runtime;
+use interpret::TypeSigils;
+use interpret::try_run;
+use sigil::Sigils;
+use spell::SpellId;
+use spell::Spells;
+
+/// Run the spell with the given spellbook name and spell name to completion,
+/// with the given arguments, and return the datum it returns.
+///
+/// This is the shortest way to run a program, such as one returned by
+/// [assemble]: the names are looked up in the sigil database the program was
+/// built against, the arity is the number of arguments, and interpretation is
+/// not limited. The data are allocated on the given heap, which the
+/// arguments must belong to; the result lives on it as well, so the heap is
+/// passed in rather than created here.
+///
+/// The sigils instructions give a meaning to have their conventional names;
+/// see [TypeSigils::intern], which must have been called on the sigil
+/// database. For more control over the run, see [Runtime] and [try_run].
+///
+/// [assemble]: spell/fn.assemble.html
+/// [TypeSigils::intern]: interpret/struct.TypeSigils.html#method.intern
+/// [Runtime]: interpret/struct.Runtime.html
+/// [try_run]: interpret/fn.try_run.html
+pub fn execute<'a>(program:         &'a Spells,
+                   sigils:          &Sigils,
+                   heap:            &'a Heap,
+                   entry_spellbook: &str,
+                   entry_spell:     &str,
+                   arguments:       &[Datum<'a>],
+                   ) -> Result<Datum<'a>, ExecuteError> {
+    let lookup = |name: &str| sigils.lookup(name.as_bytes())
+        .ok_or_else(|| ExecuteError::UnknownSigil(name.into()));
+    let types = TypeSigils{
+        falsy:   lookup("false")?,
+        truthy:  lookup("true")?,
+        integer: lookup("integer")?,
+        string:  lookup("string")?,
+    };
+    let entry = SpellId{spellbook: lookup(entry_spellbook)?,
+                        spell:     lookup(entry_spell)?,
+                        arity:     arguments.len()};
+
+    let runtime = Runtime{spells: program, heap, types};
+    try_run(&runtime, entry, arguments).map_err(ExecuteError::Interpret)
+}
+
+/// This error is returned by [execute].
+///
+/// [execute]: fn.execute.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExecuteError {
+    /// The sigil database has no sigil with the name, which is either one of
+    /// the names of the entry spell or the conventional name of one of the
+    /// sigils instructions give a meaning to.
+    UnknownSigil(String),
+
+    /// Interpretation failed, for example because the entry spell does not
+    /// exist.
+    Interpret(InterpretError),
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::UnknownSigil(name) =>
+                write!(f, "no sigil is named {:?}", name),
+            ExecuteError::Interpret(error) =>
+                write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for ExecuteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExecuteError::Interpret(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use spell::assemble;
+
+    #[test]
+    fn test_execute() {
+        let mut sigils = Sigils::new();
+        TypeSigils::intern(&mut sigils);
+        let program = assemble("
+            spell book::main/2
+                v0 = add v0, v1
+                return v0
+        ", &mut sigils).unwrap();
+
+        let heap = Heap::new();
+        let arguments = [Datum::from_i64(40).unwrap(),
+                         Datum::from_i64(2).unwrap()];
+        let result = execute(&program, &sigils, &heap, "book", "main",
+                             &arguments).map(|result| result.as_i64());
+        assert_eq!(result, Ok(Some(42)));
+
+        let error = execute(&program, &sigils, &heap, "book", "main", &[])
+            .err();
+        assert!(matches!(error, Some(ExecuteError::Interpret(
+            InterpretError::ArityMismatch{..}))));
+        let error = execute(&program, &sigils, &heap, "book", "other", &[])
+            .err();
+        assert_eq!(error, Some(ExecuteError::UnknownSigil("other".into())));
+        assert_eq!(error.unwrap().to_string(), "no sigil is named \"other\"");
+
+        // The type sigils must be in the database.
+        let error = execute(&program, &Sigils::new(), &heap, "book", "main",
+                            &arguments).err();
+        assert_eq!(error, Some(ExecuteError::UnknownSigil("false".into())));
+    }
+}
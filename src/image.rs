@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::ptr;
+
+use datum::Datum;
+use datum::Heap;
+use datum::HeapLimitExceeded;
+use datum::HeapSnapshot;
+use datum::SnapshotDatum;
+use datum::SnapshotPointer;
+use sigil::Sigil;
+use sigil::Sigils;
+use spell::DeserializeError;
+use spell::Spells;
+use spell::read_bytes;
+use spell::read_isize;
+use spell::read_u32;
+use spell::read_usize;
+use spell::write_bytes;
+use spell::write_isize;
+use spell::write_u32;
+use spell::write_usize;
+
+/// The bytes every image starts with.
+const MAGIC: &[u8; 4] = b"MIMG";
+
+/// The version of the encoding written by [save_image]. Increment this
+/// whenever the encoding changes, including when the encoding of spell
+/// databases changes.
+///
+/// [save_image]: fn.save_image.html
+const VERSION: u32 = 1;
+
+// Tags of pointers, which are either to data in the image or immediates.
+const POINTER_DATUM:     u8 = 0;
+const POINTER_IMMEDIATE: u8 = 1;
+
+/// Write an image of a runtime to a file, replacing the file if it exists.
+///
+/// An image holds the sigil database, the spell database, and the data
+/// reachable from the given roots, so that the runtime can be resumed later
+/// with [load_image], possibly by another process. Sharing and cycles among
+/// the data are kept, but finalizers and weak references are not, as with
+/// [Heap::snapshot]. Data that are not reachable from the given roots are not
+/// written, even if they are rooted.
+///
+/// Fails with [io::ErrorKind::InvalidInput] if a sigil the spells or data use
+/// is not in the sigil database, or if a root does not belong to the heap.
+///
+/// [load_image]: fn.load_image.html
+/// [Heap::snapshot]: datum/struct.Heap.html#method.snapshot
+/// [io::ErrorKind::InvalidInput]:
+///     https://doc.rust-lang.org/std/io/enum.ErrorKind.html
+pub fn save_image(path:   impl AsRef<Path>,
+                  sigils: &Sigils,
+                  spells: &Spells,
+                  heap:   &Heap,
+                  roots:  &[Datum],
+                  ) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_image(&mut out, sigils, spells, heap, roots)?;
+    out.flush()
+}
+
+/// Read an image written by [save_image], and copy its data into the given
+/// heap. Returns the sigil database, the spell database, and a root to each
+/// datum that was passed as a root to [save_image], in the same order.
+///
+/// The heap is passed in rather than created here because the roots live on
+/// it. Loading only adds data, so the heap may already be in use.
+///
+/// [save_image]: fn.save_image.html
+pub fn load_image<'a>(path: impl AsRef<Path>, heap: &'a Heap)
+    -> Result<(Sigils, Spells, Vec<Datum<'a>>), ImageError>
+{
+    let mut input = BufReader::new(File::open(path)?);
+    read_image(&mut input, heap)
+}
+
+/// This error is returned by [load_image].
+///
+/// [load_image]: fn.load_image.html
+#[derive(Debug)]
+pub enum ImageError {
+    /// Reading failed, or the image ended prematurely.
+    Io(io::Error),
+
+    /// The image did not start with the expected magic bytes.
+    BadMagic,
+
+    /// The image was written with a version of the encoding that is not
+    /// supported.
+    UnsupportedVersion(u32),
+
+    /// The spell database in the image could not be deserialized.
+    Deserialize(DeserializeError),
+
+    /// A datum or root in the image refers to a sigil or datum that is not in
+    /// the image.
+    Dangling,
+
+    /// A datum in the image did not fit within the limit of the heap, even
+    /// after collecting garbage. See [Heap::set_limit].
+    ///
+    /// [Heap::set_limit]: datum/struct.Heap.html#method.set_limit
+    HeapLimitExceeded(HeapLimitExceeded),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(error) =>
+                write!(f, "{}", error),
+            ImageError::BadMagic =>
+                write!(f, "Not an image"),
+            ImageError::UnsupportedVersion(version) =>
+                write!(f, "Unsupported image version {}", version),
+            ImageError::Deserialize(error) =>
+                write!(f, "{}", error),
+            ImageError::Dangling =>
+                write!(f, "Reference to a sigil or datum not in the image"),
+            ImageError::HeapLimitExceeded(error) =>
+                write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for ImageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ImageError::Io(error) => Some(error),
+            ImageError::Deserialize(error) => Some(error),
+            ImageError::HeapLimitExceeded(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(other: io::Error) -> Self {
+        ImageError::Io(other)
+    }
+}
+
+impl From<HeapLimitExceeded> for ImageError {
+    fn from(other: HeapLimitExceeded) -> Self {
+        ImageError::HeapLimitExceeded(other)
+    }
+}
+
+impl From<DeserializeError> for ImageError {
+    fn from(other: DeserializeError) -> Self {
+        match other {
+            DeserializeError::Io(error) => ImageError::Io(error),
+            other => ImageError::Deserialize(other),
+        }
+    }
+}
+
+fn write_image(out:    &mut impl Write,
+               sigils: &Sigils,
+               spells: &Spells,
+               heap:   &Heap,
+               roots:  &[Datum],
+               ) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    write_u32(out, VERSION)?;
+
+    write_usize(out, sigils.len())?;
+    let mut sigil_indices = HashMap::new();
+    for (index, (sigil, name)) in sigils.iter().enumerate() {
+        sigil_indices.insert(sigil, index as u32);
+        write_bytes(out, name)?;
+    }
+    let sigil_index = |sigil: Sigil| sigil_indices.get(&sigil).cloned()
+        .ok_or_else(|| invalid_input("Sigil not in database"));
+
+    let mut serialized = Vec::new();
+    spells.serialize(sigils, &mut serialized)?;
+    write_bytes(out, &serialized)?;
+
+    // The snapshot has all live data, of which only those reachable from the
+    // given roots are written, renumbered in allocation order.
+    let snapshot = heap.snapshot();
+    let snapshot_indices: HashMap<*const (), usize> = snapshot.addresses
+        .iter().cloned().zip(snapshot.roots.iter().cloned()).collect();
+    let root_pointers = roots.iter().map(|root| {
+        if root.is_immediate() {
+            Ok(SnapshotPointer::Immediate(immediate(root)))
+        } else {
+            snapshot_indices.get(&root.as_ptr()).cloned()
+                .map(SnapshotPointer::Datum)
+                .ok_or_else(|| invalid_input("Root not in heap"))
+        }
+    }).collect::<io::Result<Vec<_>>>()?;
+
+    let mut reachable = vec![false; snapshot.data.len()];
+    let mut queue: VecDeque<usize> = root_pointers.iter()
+        .filter_map(|pointer| match pointer {
+            SnapshotPointer::Datum(index) => Some(*index),
+            SnapshotPointer::Immediate(_) => None,
+        })
+        .collect();
+    while let Some(index) = queue.pop_front() {
+        if reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+        for pointer in &snapshot.data[index].pointers {
+            if let SnapshotPointer::Datum(pointee) = pointer {
+                queue.push_back(*pointee);
+            }
+        }
+    }
+    let mut renumbered = vec![0; snapshot.data.len()];
+    let mut count = 0;
+    for (index, &is_reachable) in reachable.iter().enumerate() {
+        if is_reachable {
+            renumbered[index] = count;
+            count += 1;
+        }
+    }
+
+    write_usize(out, count)?;
+    for (datum, _) in snapshot.data.iter().zip(&reachable)
+        .filter(|(_, &is_reachable)| is_reachable) {
+        write_u32(out, sigil_index(datum.enchantment)?)?;
+        write_usize(out, datum.pointers.len())?;
+        for pointer in &datum.pointers {
+            write_pointer(out, pointer, &renumbered)?;
+        }
+        write_bytes(out, &datum.auxiliary)?;
+    }
+
+    write_usize(out, root_pointers.len())?;
+    for pointer in &root_pointers {
+        write_pointer(out, pointer, &renumbered)?;
+    }
+
+    Ok(())
+}
+
+fn read_image<'a>(input: &mut impl Read, heap: &'a Heap)
+    -> Result<(Sigils, Spells, Vec<Datum<'a>>), ImageError>
+{
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(ImageError::BadMagic);
+    }
+
+    let version = read_u32(input)?;
+    if version != VERSION {
+        return Err(ImageError::UnsupportedVersion(version));
+    }
+
+    let mut sigils = Sigils::new();
+    let mut sigil_table = Vec::new();
+    for _ in 0 .. read_usize(input)? {
+        sigil_table.push(sigils.intern_bytes(&read_bytes(input)?));
+    }
+
+    let serialized = read_bytes(input)?;
+    let spells = Spells::deserialize(&mut &serialized[..], &mut sigils)?;
+
+    // Do not trust the count with an allocation of that size; the image may
+    // be truncated or corrupt.
+    let count = read_usize(input)?;
+    let mut data = Vec::new();
+    for _ in 0 .. count {
+        let enchantment = *sigil_table.get(read_u32(input)? as usize)
+            .ok_or(ImageError::Dangling)?;
+        let mut pointers = Vec::new();
+        for _ in 0 .. read_usize(input)? {
+            pointers.push(read_pointer(input, count)?);
+        }
+        let auxiliary = read_bytes(input)?.into_boxed_slice();
+        data.push(SnapshotDatum{enchantment, pointers, auxiliary});
+    }
+
+    let mut root_pointers = Vec::new();
+    for _ in 0 .. read_usize(input)? {
+        root_pointers.push(read_pointer(input, count)?);
+    }
+
+    let roots: Vec<usize> = root_pointers.iter()
+        .filter_map(|pointer| match pointer {
+            SnapshotPointer::Datum(index) => Some(*index),
+            SnapshotPointer::Immediate(_) => None,
+        })
+        .collect();
+    let addresses = vec![ptr::null(); roots.len()];
+    let snapshot = HeapSnapshot{data, roots, addresses};
+    let mut restored = heap.try_restore(&snapshot)?.into_iter();
+
+    let roots = root_pointers.into_iter().map(|pointer| match pointer {
+        SnapshotPointer::Datum(_) => restored.next().unwrap(),
+        SnapshotPointer::Immediate(immediate) => immediate,
+    }).collect();
+
+    Ok((sigils, spells, roots))
+}
+
+fn write_pointer(out:        &mut impl Write,
+                 pointer:    &SnapshotPointer,
+                 renumbered: &[usize],
+                 ) -> io::Result<()> {
+    match pointer {
+        SnapshotPointer::Datum(index) => {
+            out.write_all(&[POINTER_DATUM])?;
+            write_usize(out, renumbered[*index])
+        },
+        SnapshotPointer::Immediate(immediate) => {
+            out.write_all(&[POINTER_IMMEDIATE])?;
+            // This cannot truncate, because an immediate is one bit smaller
+            // than a pointer.
+            write_isize(out, immediate.as_i64().unwrap() as isize)
+        },
+    }
+}
+
+fn read_pointer(input: &mut impl Read, count: usize)
+    -> Result<SnapshotPointer, ImageError>
+{
+    let mut tag = [0; 1];
+    input.read_exact(&mut tag)?;
+    match tag[0] {
+        POINTER_DATUM => {
+            let index = read_usize(input)?;
+            if index >= count {
+                return Err(ImageError::Dangling);
+            }
+            Ok(SnapshotPointer::Datum(index))
+        },
+        POINTER_IMMEDIATE => {
+            let value = read_isize(input)? as i64;
+            let immediate = Datum::from_i64(value)
+                .ok_or(DeserializeError::Overflow)?;
+            Ok(SnapshotPointer::Immediate(immediate))
+        },
+        _ => Err(ImageError::Dangling),
+    }
+}
+
+/// Copy an immediate, so that it no longer borrows its heap.
+fn immediate(datum: &Datum) -> Datum<'static> {
+    Datum::from_i64(datum.as_i64().unwrap()).unwrap()
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::slice;
+
+    use execute;
+    use interpret::TypeSigils;
+    use spell::assemble;
+
+    #[test]
+    fn test_image_round_trip() {
+        let mut sigils = Sigils::new();
+        TypeSigils::intern(&mut sigils);
+        let pair = sigils.intern_str("pair");
+        let spells = assemble("
+            spell main::second/1
+                v0 = get_pointer v0, 1
+                return v0
+        ", &mut sigils).unwrap();
+
+        let heap = Heap::new();
+        let roots = unsafe {
+            let one = Datum::from_i64(1).unwrap();
+            let shared = heap.allocate(pair, &[one.clone(), one.clone()],
+                                       b"shared");
+            let first = heap.allocate(pair, &[one.clone(), shared.clone()],
+                                      b"first");
+            shared.set_pointer(0, &first);
+            let _garbage = heap.allocate(pair, &[], b"unreachable");
+            vec![first, one, shared]
+        };
+
+        let path = env::temp_dir()
+            .join(format!("mana-image-{}", process::id()));
+        save_image(&path, &sigils, &spells, &heap, &roots).unwrap();
+
+        let restored_heap = Heap::new();
+        let loaded = load_image(&path, &restored_heap);
+        fs::remove_file(&path).unwrap();
+        let (restored_sigils, restored_spells, restored_roots) =
+            loaded.unwrap();
+
+        assert_eq!(restored_heap.total_roots(), 2);
+        assert_eq!(restored_roots.len(), 3);
+        assert_eq!(restored_roots[1].as_i64(), Some(1));
+        assert!(restored_sigils.lookup(b"pair").is_some());
+
+        let first = &restored_roots[0];
+        let shared = &restored_roots[2];
+        assert_eq!(first.auxiliary(), b"first");
+        assert_eq!(first.pointers()[1].as_ptr(), shared.as_ptr());
+        assert_eq!(shared.pointers()[0].as_ptr(), first.as_ptr());
+
+        let result = execute(&restored_spells, &restored_sigils,
+                             &restored_heap, "main", "second",
+                             slice::from_ref(first)).unwrap();
+        assert_eq!(result.as_ptr(), shared.as_ptr());
+        assert_eq!(result.auxiliary(), roots[2].auxiliary());
+    }
+
+    #[test]
+    fn test_image_bad_magic() {
+        let heap = Heap::new();
+        let result = read_image(&mut &b"MANA\x01\0\0\0"[..], &heap);
+        assert!(matches!(result, Err(ImageError::BadMagic)));
+    }
+}
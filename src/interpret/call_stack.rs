@@ -1,11 +1,122 @@
 use datum::Datum;
+use sigil::Sigils;
+use spell::ConstantValue;
 use spell::Instruction;
 use spell::Local;
+use spell::SourceSpan;
+use spell::Spell;
+use spell::SpellId;
+
+use super::InterpretError;
 
 /// A call stack is a sequence of stack frames.
 #[derive(Debug)]
 pub struct CallStack<'a> {
     pub stack_frames: Vec<StackFrame<'a>>,
+
+    /// The number of stack frames beyond which [push] fails.
+    ///
+    /// [push]: #method.push
+    pub max_depth: usize,
+
+    /// The local variables of exited stack frames, for reuse by new ones.
+    pub(super) pool: FramePool<'a>,
+
+    /// What the suspending native spell that last suspended the run waits
+    /// for, until the run is resumed; see [Suspension].
+    ///
+    /// [Suspension]: struct.Suspension.html
+    pub(super) pending: Option<Datum<'a>>,
+}
+
+impl<'a> CallStack<'a> {
+    /// Create a call stack without any stack frames, of unlimited depth.
+    pub fn new() -> Self {
+        Self::with_max_depth(usize::MAX)
+    }
+
+    /// Create a call stack without any stack frames, which can hold at most
+    /// the given number of stack frames.
+    pub fn with_max_depth(max_depth: usize) -> Self {
+        CallStack{stack_frames: Vec::new(), max_depth, pool: FramePool::new(),
+                  pending: None}
+    }
+
+    /// Push a stack frame, or return an error if the call stack is already at
+    /// its maximum depth. The local variables of a stack frame that is not
+    /// pushed are kept for reuse, as those of a popped one are.
+    pub fn push(&mut self, stack_frame: StackFrame<'a>)
+        -> Result<(), InterpretError>
+    {
+        if self.stack_frames.len() >= self.max_depth {
+            let spell = stack_frame.spell;
+            self.pool.release(stack_frame.local_variables);
+            return Err(InterpretError::StackOverflow(spell));
+        }
+        self.stack_frames.push(stack_frame);
+        Ok(())
+    }
+
+    /// Pop the active stack frame, if any, and keep its local variables for
+    /// reuse by stack frames that are pushed later.
+    pub(super) fn pop(&mut self) {
+        if let Some(frame) = self.stack_frames.pop() {
+            self.pool.release(frame.local_variables);
+        }
+    }
+
+    /// The number of roots the call stack owns: data on the heap that are in
+    /// the local variables of its stack frames or pending.
+    pub(super) fn roots(&self) -> usize {
+        self.stack_frames.iter()
+            .flat_map(|frame| frame.local_variables.iter())
+            .chain(Some(&self.pending))
+            .filter(|local| local.as_ref()
+                        .is_some_and(|datum| !datum.is_immediate()))
+            .count()
+    }
+
+    /// The spells of the stack frames along with the indices of their next
+    /// instructions, starting with the active stack frame.
+    ///
+    /// For the active stack frame, the index is that of the instruction that
+    /// is about to be interpreted, or that failed. For the other stack
+    /// frames, it is that of the instruction after the invocation.
+    pub fn backtrace(&self) -> Vec<(SpellId, usize)> {
+        self.stack_frames.iter().rev()
+            .map(|frame| (frame.spell, frame.program_counter.next_instruction))
+            .collect()
+    }
+
+    /// Like [backtrace], but with the source span of the instruction each
+    /// stack frame is at, if its spell has a source map.
+    ///
+    /// For the active stack frame, the span is that of the instruction that
+    /// is about to be interpreted, or that failed. For the other stack
+    /// frames, it is that of the invocation, rather than that of the
+    /// instruction after it.
+    ///
+    /// [backtrace]: #method.backtrace
+    pub fn source_backtrace(&self) -> Vec<(SpellId, usize, Option<SourceSpan>)>
+    {
+        let active = self.stack_frames.len().wrapping_sub(1);
+        self.stack_frames.iter().enumerate().rev()
+            .map(|(depth, frame)| {
+                let index = frame.program_counter.next_instruction;
+                let at = if depth == active { Some(index) }
+                         else { index.checked_sub(1) };
+                let span = frame.source_map.zip(at)
+                    .and_then(|(map, at)| map.get(at).cloned());
+                (frame.spell, index, span)
+            })
+            .collect()
+    }
+}
+
+impl Default for CallStack<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// A stack frame consists of a program counter and local variables.
@@ -13,42 +124,247 @@ pub struct CallStack<'a> {
 /// A stack frame represents an active spell invocation.
 #[derive(Debug)]
 pub struct StackFrame<'a> {
+    /// The spell that was invoked.
+    pub spell: SpellId,
+
     pub program_counter: ProgramCounter<'a>,
-    pub local_variables: Box<[Datum<'a>]>,
+
+    /// The local variables of the spell invocation. Local variables that have
+    /// not yet been assigned to are `None`.
+    pub local_variables: Box<[Option<Datum<'a>>]>,
 
     /// The local variable to store the result into when the callee returns. If
     /// this is the active stack frame, the value of this field is irrelevant.
     pub return_into: Local,
+
+    /// The installed exception handlers, the last of which catches the next
+    /// exception that is thrown in or unwinds through this stack frame.
+    pub handlers: Vec<Handler>,
+
+    /// The source map of the spell that was invoked, if it has one.
+    pub source_map: Option<&'a [SourceSpan]>,
+}
+
+/// An exception handler catches an exception that is thrown while it is
+/// installed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Handler {
+    /// The instruction to continue with after catching an exception.
+    pub target: usize,
+
+    /// The local variable the caught datum is stored into.
+    pub exception: Local,
+}
+
+impl<'a> StackFrame<'a> {
+    /// Create a stack frame for invoking a spell. The arguments are stored in
+    /// the first local variables, and the program counter points to the first
+    /// instruction of the spell.
+    ///
+    /// Panics if the spell has fewer local variables than there are
+    /// arguments.
+    pub fn new(id: SpellId, spell: &'a Spell, arguments: Box<[Datum<'a>]>)
+        -> Self
+    {
+        assert!(arguments.len() <= spell.local_variables,
+                "Spell has fewer local variables than arguments");
+
+        let mut local_variables = Vec::with_capacity(spell.local_variables);
+        local_variables.extend(arguments.into_vec().into_iter().map(Some));
+        local_variables.resize(spell.local_variables, None);
+        Self::with_local_variables(id, spell,
+                                   local_variables.into_boxed_slice())
+    }
+
+    /// Create a stack frame for invoking a spell, with the given local
+    /// variables, of which there must be as many as the spell has.
+    pub(super) fn with_local_variables(
+        id:              SpellId,
+        spell:           &'a Spell,
+        local_variables: Box<[Option<Datum<'a>>]>,
+    ) -> Self {
+        debug_assert_eq!(local_variables.len(), spell.local_variables);
+        StackFrame{
+            spell:           id,
+            program_counter: ProgramCounter{
+                instructions:     &spell.instructions,
+                constants:        &spell.constants,
+                next_instruction: 0,
+            },
+            local_variables,
+            return_into:     Local(0),
+            handlers:        Vec::new(),
+            source_map:      spell.source_map.as_deref(),
+        }
+    }
+
+    /// The datum in a local variable, or `None` if the local variable is out
+    /// of bounds or uninitialized.
+    pub fn local(&self, index: Local) -> Option<&Datum<'a>> {
+        self.local_variables.get(index.0 as usize)?.as_ref()
+    }
+
+    /// The local variables of the spell invocation, indexed by [Local].
+    /// Uninitialized local variables are `None`.
+    ///
+    /// [Local]: ../spell/struct.Local.html
+    pub fn locals(&self) -> &[Option<Datum<'a>>] {
+        &self.local_variables
+    }
+
+    /// Format every local variable as [Datum::display] does, in order.
+    /// Uninitialized local variables are written as `<uninitialized>`.
+    ///
+    /// [Datum::display]: ../datum/struct.Datum.html#method.display
+    pub fn render_locals(&self, sigils: &Sigils) -> Vec<String> {
+        self.local_variables.iter()
+            .map(|local| match local {
+                Some(datum) => datum.display(sigils).to_string(),
+                None        => "<uninitialized>".to_string(),
+            })
+            .collect()
+    }
+}
+
+/// A pool of local variables for stack frames, so that invoking a spell does
+/// not allocate them anew every time.
+///
+/// The local variables are pooled by their number. They are all `None` while
+/// they are in the pool, so that the pool does not keep any data alive.
+#[derive(Debug, Default)]
+pub(super) struct FramePool<'a> {
+    free: Vec<Vec<Box<[Option<Datum<'a>>]>>>,
+}
+
+impl<'a> FramePool<'a> {
+    pub(super) fn new() -> Self {
+        FramePool{free: Vec::new()}
+    }
+
+    /// Local variables of the given number, all of which are `None`.
+    #[inline(always)]
+    pub(super) fn acquire(&mut self, len: usize) -> Box<[Option<Datum<'a>>]> {
+        match self.free.get_mut(len).and_then(Vec::pop) {
+            Some(local_variables) => local_variables,
+            None => vec![None; len].into_boxed_slice(),
+        }
+    }
+
+    /// Return local variables to the pool, dropping the data in them.
+    #[inline(always)]
+    pub(super) fn release(&mut self,
+                          mut local_variables: Box<[Option<Datum<'a>>]>) {
+        let len = local_variables.len();
+        for variable in local_variables.iter_mut() {
+            *variable = None;
+        }
+        if self.free.len() <= len {
+            self.free.resize_with(len + 1, Vec::new);
+        }
+        self.free[len].push(local_variables);
+    }
 }
 
 /// A program counter points into the instructions of a spell, and tells the
-/// interpreter which instruction comes next.
+/// interpreter which instruction comes next. It also refers to the constant
+/// pool of the spell, which the instructions load from.
 #[derive(Clone, Copy, Debug)]
 pub struct ProgramCounter<'a> {
     pub instructions:     &'a [Instruction],
+    pub constants:        &'a [ConstantValue],
     pub next_instruction: usize,
 }
 
-impl ProgramCounter<'_> {
+impl<'a> ProgramCounter<'a> {
     /// Get the next instruction or panic.
     #[inline(always)]
     pub fn get(&self) -> &Instruction {
+        self.try_get().expect("Program counter out of bounds")
+    }
+
+    /// Get the next instruction, or `None` if the program counter is out of
+    /// bounds.
+    #[inline(always)]
+    pub fn try_get(&self) -> Option<&'a Instruction> {
         self.instructions.get(self.next_instruction)
-            .expect("Program counter out of bounds")
     }
 
     /// Jump to the next instruction.
+    ///
+    /// This does not overflow, as the program counter points at an
+    /// instruction when it advances. If that is the last instruction, [get]
+    /// will panic and [try_get] will return `None`, as after [jump].
+    ///
+    /// [get]: #method.get
+    /// [try_get]: #method.try_get
+    /// [jump]: #method.jump
     #[inline(always)]
     pub fn advance(&self) -> Self {
         self.jump(self.next_instruction + 1)
     }
 
     /// Jump to an arbitrary instruction.
+    ///
+    /// The target is not checked. If it is out of bounds, [get] will panic and
+    /// [try_get] will return `None`. Spells that pass the verifier never jump
+    /// out of bounds.
+    ///
+    /// [get]: #method.get
+    /// [try_get]: #method.try_get
     #[inline(always)]
     pub fn jump(&self, target: usize) -> Self {
         ProgramCounter{
             instructions:     self.instructions,
+            constants:        self.constants,
             next_instruction: target,
         }
     }
+
+    /// Jump to an instruction relative to the instruction the program counter
+    /// points at. An offset of zero does not move the program counter.
+    ///
+    /// Unlike [jump], the target is checked: returns `None` if it would lie
+    /// before the first instruction or beyond the last one.
+    ///
+    /// [jump]: #method.jump
+    #[inline(always)]
+    pub fn jump_relative(&self, offset: isize) -> Option<Self> {
+        let target = (self.next_instruction as isize).checked_add(offset)?;
+        if target < 0 || target as usize >= self.instructions.len() {
+            return None;
+        }
+        Some(self.jump(target as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use datum::Heap;
+
+    #[test]
+    fn test_inspect_locals() {
+        let mut sigils = Sigils::new();
+        let book = sigils.intern_str("book");
+        let main = sigils.intern_str("main");
+        let spell = Spell{instructions: Box::new([]), local_variables: 3,
+                          constants: Box::new([]), source_map: None};
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(book, &[], b"a") };
+        let arguments = vec![Datum::from_i64(1).unwrap(), a];
+        let id = SpellId{spellbook: book, spell: main, arity: 2};
+        let stack_frame = StackFrame::new(id, &spell,
+                                          arguments.into_boxed_slice());
+
+        assert_eq!(stack_frame.local(Local(0)).and_then(Datum::as_i64),
+                   Some(1));
+        assert_eq!(stack_frame.local(Local(1)).unwrap().auxiliary(), b"a");
+        assert!(stack_frame.local(Local(2)).is_none());
+        assert!(stack_frame.local(Local(3)).is_none());
+        assert_eq!(stack_frame.locals().len(), 3);
+        assert_eq!(stack_frame.render_locals(&sigils),
+                   ["1", "book \"a\"", "<uninitialized>"]);
+    }
 }
@@ -0,0 +1,193 @@
+use datum::CollectStatistics;
+use datum::Heap;
+
+/// When [run_with_options] collects garbage.
+///
+/// [run_with_options]: fn.run_with_options.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GcPolicy {
+    /// Never collect garbage.
+    Never,
+
+    /// Collect garbage once the given number of data were allocated since the
+    /// start of the run or the last collection.
+    EveryNAllocations(usize),
+
+    /// Collect garbage whenever the heap retains more than the given number
+    /// of bytes; see [Heap::bytes]. If the live data alone retain more than
+    /// that, garbage is collected after every allocation.
+    ///
+    /// [Heap::bytes]: ../datum/struct.Heap.html#method.bytes
+    WhenBytesExceed(usize),
+}
+
+/// Collects garbage between the steps of a run according to a policy, and
+/// adds up the statistics of the collections.
+///
+/// Every datum in use by the run is held by a local variable of some stack
+/// frame, and is therefore rooted, so collecting garbage never frees it.
+pub(super) struct Collector<'a> {
+    heap:         &'a Heap,
+    policy:       GcPolicy,
+
+    /// The number of data in the heap after the last collection.
+    len_after:    usize,
+
+    /// The number of bytes the heap retained before the last step.
+    bytes_before: usize,
+
+    /// The data and bytes freed by all collections, and the data and bytes
+    /// that survived the last one. If no garbage was collected, they are all
+    /// zero.
+    pub(super) total: CollectStatistics,
+}
+
+impl<'a> Collector<'a> {
+    pub(super) fn new(heap: &'a Heap, policy: GcPolicy) -> Self {
+        Collector{
+            heap,
+            policy,
+            len_after:    heap.len(),
+            bytes_before: heap.bytes(),
+            total:        CollectStatistics{
+                data_freed:  0,
+                data_live:   0,
+                bytes_freed: 0,
+                bytes_live:  0,
+            },
+        }
+    }
+
+    /// Collect garbage if the policy says so. This is called after every step.
+    pub(super) fn after_step(&mut self) {
+        let heap = self.heap;
+        let collect = match self.policy {
+            GcPolicy::Never => false,
+            GcPolicy::EveryNAllocations(n) =>
+                heap.len() >= self.len_after.saturating_add(n),
+            GcPolicy::WhenBytesExceed(limit) => {
+                let bytes = heap.bytes();
+                let allocated = bytes > self.bytes_before;
+                self.bytes_before = bytes;
+                bytes > limit && allocated
+            },
+        };
+        if collect {
+            let stat = heap.collect_garbage();
+            let total = &mut self.total;
+            total.data_freed  += stat.data_freed;
+            total.bytes_freed += stat.bytes_freed;
+            total.data_live    = stat.data_live;
+            total.bytes_live   = stat.bytes_live;
+            self.len_after = heap.len();
+            self.bytes_before = heap.bytes();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use interpret::*;
+
+    use spell::ConstantValue;
+    use spell::SpellBuilder;
+    use spell::Spells;
+
+    const BOOK:  Sigil = Sigil(0);
+    const MAIN:  Sigil = Sigil(1);
+    const FALSE: Sigil = Sigil(2);
+    const TRUE:  Sigil = Sigil(3);
+    const INT:   Sigil = Sigil(4);
+    const STR:   Sigil = Sigil(5);
+
+    const TYPES: TypeSigils = TypeSigils{falsy: FALSE, truthy: TRUE,
+                                         integer: INT, string: STR};
+
+    const MAIN_ID: SpellId = SpellId{spellbook: BOOK, spell: MAIN, arity: 2};
+
+    /// main(n, kept) allocates n data that point to kept, drops each right
+    /// away, and returns kept. Comparisons and constants allocate too.
+    fn spells() -> Spells {
+        let mut builder = SpellBuilder::new();
+        let n = builder.local();
+        let kept = builder.local();
+        let one = builder.local();
+        let zero = builder.local();
+        let less = builder.local();
+        let garbage = builder.local();
+        let top = builder.label();
+        let done = builder.label();
+        builder
+            .load_constant(one, 0)
+            .sub(zero, one, one)
+            .bind(top)
+            .less_than(less, zero, n)
+            .branch_if_falsy(less, done)
+            .allocate(garbage, BOOK, &[kept], &[0; 64])
+            .sub(n, n, one)
+            .jump(top)
+            .bind(done)
+            .ret(kept);
+        builder.constant(ConstantValue{
+            enchantment: INT,
+            auxiliary:   Box::new(1i64.to_le_bytes()),
+        });
+
+        let mut spells = Spells::new();
+        spells.insert(MAIN_ID, builder.build()).unwrap();
+        spells
+    }
+
+    fn run_main<'a>(spells: &'a Spells, heap: &'a Heap, policy: GcPolicy)
+        -> (Datum<'a>, CollectStatistics) {
+        let kept = unsafe { heap.allocate(BOOK, &[], b"kept") };
+        let arguments = [Datum::from_i64(1000).unwrap(), kept];
+        let runtime = Runtime{spells, heap, types: TYPES};
+        let options = RunOptions{gc: policy, ..RunOptions::default()};
+        let outcome = run_with_options(&runtime, MAIN_ID, &arguments,
+                                       &options);
+        (outcome.value.unwrap(), outcome.gc)
+    }
+
+    #[test]
+    fn test_gc_never() {
+        let spells = spells();
+        let heap = Heap::new();
+        let (result, stat) = run_main(&spells, &heap, GcPolicy::Never);
+        assert_eq!(result.auxiliary(), b"kept");
+        assert_eq!(stat.data_freed, 0);
+        assert!(heap.len() > 1000);
+    }
+
+    #[test]
+    fn test_gc_every_n_allocations() {
+        let spells = spells();
+        let heap = Heap::new();
+        let (result, stat) =
+            run_main(&spells, &heap, GcPolicy::EveryNAllocations(100));
+        assert_eq!(result.auxiliary(), b"kept");
+
+        // Each iteration allocates a datum and the result of a comparison,
+        // nearly all of which are freed during the run; the few allocated
+        // since the last collection are freed now.
+        assert!(stat.data_freed >= 1900, "{:?}", stat);
+        assert!(stat.data_live <= 8, "{:?}", stat);
+        assert!(heap.len() <= 108);
+        heap.collect_garbage();
+        assert_eq!(heap.len(), 1);
+        assert_eq!(result.auxiliary(), b"kept");
+    }
+
+    #[test]
+    fn test_gc_when_bytes_exceed() {
+        let spells = spells();
+        let heap = Heap::new();
+        let (result, stat) =
+            run_main(&spells, &heap, GcPolicy::WhenBytesExceed(4096));
+        assert_eq!(result.auxiliary(), b"kept");
+        assert!(stat.bytes_freed >= 900 * 72, "{:?}", stat);
+        assert!(heap.bytes() <= 4096 + 72 + 4);
+    }
+}
@@ -1,35 +1,153 @@
 mod call_stack;
+mod gc;
+mod outcome;
+mod profile;
+mod run;
+mod runtime;
 
-use std::iter;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
 
 use datum::Datum;
+use datum::Heap;
+use datum::HeapLimitExceeded;
+use spell::CLOSURE_ENCHANTMENT;
+use spell::InlineCache;
 use spell::Instruction;
 use spell::Local;
 use spell::SpellId;
+use sigil::Sigil;
+use sigil::Sigils;
 
 pub use self::call_stack::*;
+pub use self::gc::*;
+pub use self::outcome::*;
+pub use self::profile::*;
+pub use self::run::*;
+pub use self::runtime::*;
 
 /// Interpret a single instruction and return what should happen to the call
 /// stack.
+///
+/// The sigils give instructions their meaning; see [TypeSigils].
+///
+/// Panics if the code is malformed. See [try_interpret_instruction] for a
+/// variant that does not panic.
+///
+/// [TypeSigils]: struct.TypeSigils.html
+/// [try_interpret_instruction]: fn.try_interpret_instruction.html
 #[inline(always)]
 pub fn interpret_instruction<'a>(
+    heap:            &'a Heap,
+    types:           TypeSigils,
     program_counter: ProgramCounter<'a>,
-    local_variables: &'a mut [Datum],
+    local_variables: &mut [Option<Datum<'a>>],
 ) -> CallStackMutation<'a> {
+    try_interpret_instruction(heap, types, program_counter, local_variables)
+        .unwrap_or_else(|error| panic!("{:?}", error))
+}
+
+/// Interpret a single instruction and return what should happen to the call
+/// stack, or return an error if the code is malformed.
+#[inline(always)]
+pub fn try_interpret_instruction<'a>(
+    heap:            &'a Heap,
+    types:           TypeSigils,
+    program_counter: ProgramCounter<'a>,
+    local_variables: &mut [Option<Datum<'a>>],
+) -> Result<CallStackMutation<'a>, InterpretError> {
     macro_rules! local {
         ($l:expr) => {{
             local_variables.get($l.0 as usize)
-                .expect("Local variable out of bounds")
+                .ok_or(InterpretError::LocalOutOfBounds(*$l))?
                 .clone()
+                .ok_or(InterpretError::LocalUninitialized(*$l))?
         }};
         ($l:expr, $v:expr) => {{
             *local_variables.get_mut($l.0 as usize)
-                .expect("Local variable out of bounds")
-                = $v;
+                .ok_or(InterpretError::LocalOutOfBounds(*$l))?
+                = Some($v);
+        }};
+    }
+
+    macro_rules! integer {
+        ($l:expr) => {{
+            let value = local!($l);
+            value.as_i64().ok_or(InterpretError::NotAnInteger(*$l))?
+        }};
+    }
+
+    macro_rules! arithmetic {
+        ($result:expr, $lhs:expr, $rhs:expr, $op:expr) => {{
+            let lhs = integer!($lhs);
+            let rhs = integer!($rhs);
+            let value: i64 = $op(lhs, rhs)?;
+
+            // This is safe because the datum has no pointers.
+            let datum = unsafe {
+                heap.allocate_within_limit(types.integer, &[],
+                                           &value.to_le_bytes())
+            }.map_err(InterpretError::HeapLimitExceeded)?;
+            local!($result, datum);
+
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
         }};
     }
 
-    match program_counter.get() {
+    macro_rules! relative {
+        ($offset:expr) => {{
+            program_counter.jump_relative(*$offset)
+                .ok_or(InterpretError::ProgramCounterOutOfBounds)?
+        }};
+    }
+
+    macro_rules! comparison {
+        ($result:expr, $lhs:expr, $rhs:expr, $op:expr) => {{
+            let lhs = integer!($lhs);
+            let rhs = integer!($rhs);
+            local!($result, boolean_datum(heap, types, $op(lhs, rhs))?);
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        }};
+    }
+
+    macro_rules! invoke {
+        ($result:expr, $call:expr) => {{
+            // An invocation whose result is returned right away is a tail
+            // call.
+            let jump = program_counter.advance();
+            let tail_call = matches!(jump.try_get(),
+                                     Some(Instruction::Return{result})
+                                         if result == $result);
+            CallStackMutation{
+                jump,
+                exit: None,
+                call: Some($call),
+                tail_call,
+                handler: None,
+                throw: None,
+            }
+        }};
+    }
+
+    let instruction = program_counter.try_get()
+        .ok_or(InterpretError::ProgramCounterOutOfBounds)?;
+
+    let mutation = match instruction {
 
         Instruction::Copy{from, to} => {
             let value = local!(from);
@@ -38,53 +156,486 @@ pub fn interpret_instruction<'a>(
                 jump: program_counter.advance(),
                 exit: None,
                 call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::CopyRange{from, to, count} => {
+            for start in &[from, to] {
+                let end = (start.0 as usize).checked_add(*count);
+                if end.is_none_or(|end| end > local_variables.len()) {
+                    let local = (start.0 as usize).max(local_variables.len());
+                    return Err(InterpretError::LocalOutOfBounds(
+                        Local(local as u32)));
+                }
+            }
+            let (from, to) = (from.0 as usize, to.0 as usize);
+            let unassigned = local_variables[from .. from + count].iter()
+                .position(Option::is_none);
+            if let Some(offset) = unassigned {
+                return Err(InterpretError::LocalUninitialized(
+                    Local((from + offset) as u32)));
+            }
+
+            // Copy away from the overlap, so that every datum is copied
+            // before it is overwritten.
+            if to <= from {
+                for offset in 0 .. *count {
+                    local_variables[to + offset] =
+                        local_variables[from + offset].clone();
+                }
+            } else {
+                for offset in (0 .. *count).rev() {
+                    local_variables[to + offset] =
+                        local_variables[from + offset].clone();
+                }
+            }
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::Swap{a, b} => {
+            let length = local_variables.len();
+            for local in &[a, b] {
+                if local.0 as usize >= length {
+                    return Err(InterpretError::LocalOutOfBounds(**local));
+                }
+            }
+            local_variables.swap(a.0 as usize, b.0 as usize);
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::Nop => {
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
             }
         },
 
         Instruction::InvokeStatic{result, spellbook, spell, arguments} => {
-            let argument_values: Box<[Datum]> =
-                    arguments.iter().map(|l| local!(l)).collect();
+            check_locals(local_variables, arguments)?;
 
             let callee = SpellId{
                 spellbook: *spellbook,
                 spell:     *spell,
-                arity:     argument_values.len(),
+                arity:     arguments.len(),
             };
             let call = Call{
-                callee:      callee,
-                arguments:   argument_values,
+                callee,
+                arguments:   Arguments::Locals{closure:  None,
+                                               receiver: None,
+                                               locals:   arguments},
                 return_into: *result,
+                linked:      None,
+                cache:       None,
             };
 
+            invoke!(result, call)
+        },
+
+        Instruction::InvokeLinked{result, spellbook, spell, index,
+                                  arguments} => {
+            check_locals(local_variables, arguments)?;
+
+            let callee = SpellId{
+                spellbook: *spellbook,
+                spell:     *spell,
+                arity:     arguments.len(),
+            };
+            let call = Call{
+                callee,
+                arguments:   Arguments::Locals{closure:  None,
+                                               receiver: None,
+                                               locals:   arguments},
+                return_into: *result,
+                linked:      Some(*index),
+                cache:       None,
+            };
+
+            invoke!(result, call)
+        },
+
+        Instruction::InvokeDynamic{result, spell, receiver, arguments} => {
+            let enchantment =
+                read_local(local_variables, *receiver)?.enchantment();
+            check_locals(local_variables, arguments)?;
+
+            let callee = SpellId{
+                spellbook: enchantment,
+                spell:     *spell,
+                arity:     1 + arguments.len(),
+            };
+            let call = Call{
+                callee,
+                arguments:   Arguments::Locals{closure:  None,
+                                               receiver: Some(*receiver),
+                                               locals:   arguments},
+                return_into: *result,
+                linked:      None,
+                cache:       None,
+            };
+
+            invoke!(result, call)
+        },
+
+        Instruction::InvokeDynamicCached{result, spell, receiver, arguments,
+                                         cache} => {
+            let enchantment =
+                read_local(local_variables, *receiver)?.enchantment();
+            check_locals(local_variables, arguments)?;
+
+            let callee = SpellId{
+                spellbook: enchantment,
+                spell:     *spell,
+                arity:     1 + arguments.len(),
+            };
+            let call = Call{
+                callee,
+                arguments:   Arguments::Locals{closure:  None,
+                                               receiver: Some(*receiver),
+                                               locals:   arguments},
+                return_into: *result,
+                linked:      cache.get(enchantment),
+                cache:       Some(cache),
+            };
+
+            invoke!(result, call)
+        },
+
+        Instruction::MakeClosure{result, spellbook, spell, captures} => {
+            let capture_values: Vec<Datum> =
+                captures.iter().map(|l| Ok(local!(l)))
+                    .collect::<Result<_, InterpretError>>()?;
+            let mut auxiliary = [0; 8];
+            auxiliary[.. 4].copy_from_slice(&spellbook.0.to_le_bytes());
+            auxiliary[4 ..].copy_from_slice(&spell.0.to_le_bytes());
+
+            // This is safe because local variables only contain data that
+            // belong to the heap; see Instruction::MakeClosure.
+            let datum = unsafe {
+                heap.allocate_within_limit(CLOSURE_ENCHANTMENT,
+                                           &capture_values, &auxiliary)
+            }.map_err(InterpretError::HeapLimitExceeded)?;
+            local!(result, datum);
+
             CallStackMutation{
                 jump: program_counter.advance(),
                 exit: None,
-                call: Some(call),
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
             }
         },
 
-        Instruction::InvokeDynamic{result, spell, receiver, arguments} => {
-            let receiver_value = local!(receiver);
-            let argument_values: Box<[Datum]> =
-                iter::once(receiver_value.clone())
-                    .chain(arguments.iter().map(|l| local!(l)))
-                    .collect();
+        Instruction::InvokeClosure{result, closure, arguments} => {
+            let closure_value = local!(closure);
+            let (spellbook, spell) = closure_spell(&closure_value)
+                .ok_or(InterpretError::NotAClosure(*closure))?;
+            check_locals(local_variables, arguments)?;
 
             let callee = SpellId{
-                spellbook: receiver_value.enchantment(),
-                spell:     *spell,
-                arity:     argument_values.len(),
+                spellbook,
+                spell,
+                arity: closure_value.pointers().len() + arguments.len(),
             };
             let call = Call{
-                callee:      callee,
-                arguments:   argument_values,
+                callee,
+                arguments:   Arguments::Locals{closure:  Some(closure_value),
+                                               receiver: None,
+                                               locals:   arguments},
                 return_into: *result,
+                linked:      None,
+                cache:       None,
             };
 
+            invoke!(result, call)
+        },
+
+        Instruction::Jump{target} => {
+            CallStackMutation{
+                jump: program_counter.jump(*target),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::JumpRelative{offset} => {
+            CallStackMutation{
+                jump: relative!(offset),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::Switch{scrutinee, targets, default} => {
+            let value = integer!(scrutinee);
+            let target = usize::try_from(value).ok()
+                .and_then(|index| targets.get(index))
+                .unwrap_or(default);
+            CallStackMutation{
+                jump: program_counter.jump(*target),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::BranchIfTruthy{condition, target} => {
+            let value = local!(condition);
+            let jump =
+                if value.is_truthy(types.falsy) {
+                    program_counter.jump(*target)
+                } else {
+                    program_counter.advance()
+                };
+            CallStackMutation{jump, exit: None, call: None, tail_call: false,
+                              handler: None, throw: None}
+        },
+
+        Instruction::BranchIfFalsy{condition, target} => {
+            let value = local!(condition);
+            let jump =
+                if value.is_truthy(types.falsy) {
+                    program_counter.advance()
+                } else {
+                    program_counter.jump(*target)
+                };
+            CallStackMutation{jump, exit: None, call: None, tail_call: false,
+                              handler: None, throw: None}
+        },
+
+        Instruction::Add{result, lhs, rhs} =>
+            arithmetic!(result, lhs, rhs,
+                        |a: i64, b| Ok(a.wrapping_add(b))),
+
+        Instruction::Sub{result, lhs, rhs} =>
+            arithmetic!(result, lhs, rhs,
+                        |a: i64, b| Ok(a.wrapping_sub(b))),
+
+        Instruction::Mul{result, lhs, rhs} =>
+            arithmetic!(result, lhs, rhs,
+                        |a: i64, b| Ok(a.wrapping_mul(b))),
+
+        Instruction::Div{result, lhs, rhs} =>
+            arithmetic!(result, lhs, rhs, |a: i64, b| {
+                if b == 0 {
+                    Err(InterpretError::DivisionByZero)
+                } else {
+                    Ok(a.wrapping_div(b))
+                }
+            }),
+
+        Instruction::BranchIfTruthyRelative{condition, offset} => {
+            let value = local!(condition);
+            let jump =
+                if value.is_truthy(types.falsy) {
+                    relative!(offset)
+                } else {
+                    program_counter.advance()
+                };
+            CallStackMutation{jump, exit: None, call: None, tail_call: false,
+                              handler: None, throw: None}
+        },
+
+        Instruction::BranchIfFalsyRelative{condition, offset} => {
+            let value = local!(condition);
+            let jump =
+                if value.is_truthy(types.falsy) {
+                    program_counter.advance()
+                } else {
+                    relative!(offset)
+                };
+            CallStackMutation{jump, exit: None, call: None, tail_call: false,
+                              handler: None, throw: None}
+        },
+
+        Instruction::Equal{result, lhs, rhs} => {
+            let lhs_value = local!(lhs);
+            let rhs_value = local!(rhs);
+            let equal =
+                match (lhs_value.as_i64(), rhs_value.as_i64()) {
+                    (Some(a), Some(b)) => a == b,
+                    _ => lhs_value.structural_eq(&rhs_value),
+                };
+            local!(result, boolean_datum(heap, types, equal)?);
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::LessThan{result, lhs, rhs} =>
+            comparison!(result, lhs, rhs, |a: i64, b| a < b),
+
+        Instruction::GreaterThan{result, lhs, rhs} =>
+            comparison!(result, lhs, rhs, |a: i64, b| a > b),
+
+        Instruction::Allocate{result, enchantment, pointers, auxiliary} => {
+            let pointer_values: Vec<Datum> =
+                pointers.iter().map(|l| Ok(local!(l)))
+                    .collect::<Result<_, InterpretError>>()?;
+
+            // This is safe because local variables only contain data that
+            // belong to the heap; see Instruction::Allocate.
+            let datum = unsafe {
+                heap.allocate_within_limit(*enchantment, &pointer_values,
+                                           auxiliary)
+            }.map_err(InterpretError::HeapLimitExceeded)?;
+            local!(result, datum);
+
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::LoadConstant{result, index} => {
+            let constant = program_counter.constants.get(*index)
+                .ok_or(InterpretError::ConstantOutOfBounds(*index))?;
+
+            // This is safe because the datum has no pointers.
+            let datum = unsafe {
+                heap.allocate_within_limit(constant.enchantment, &[],
+                                           &constant.auxiliary)
+            }.map_err(InterpretError::HeapLimitExceeded)?;
+            local!(result, datum);
+
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::GetPointer{result, object, index} => {
+            let value = local!(object);
+            let pointer = value.pointers().get(*index)
+                .ok_or(InterpretError::PointerOutOfBounds(*index))?
+                .clone();
+            local!(result, pointer);
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::EnchantmentOf{result, object} => {
+            let value = local!(object);
+            let enchantment = value.enchantment().0 as i64;
+            local!(result, integer_datum(heap, types, enchantment)?);
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::IsEnchanted{result, object, enchantment} => {
+            let value = local!(object);
+            let is_enchanted = value.enchantment() == *enchantment;
+            local!(result, boolean_datum(heap, types, is_enchanted)?);
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::AuxiliaryLen{result, object} => {
+            let value = local!(object);
+            let length = value.auxiliary().len() as i64;
+            local!(result, integer_datum(heap, types, length)?);
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
+            }
+        },
+
+        Instruction::PushHandler{target, exception} => {
+            let handler = Handler{target: *target, exception: *exception};
+            CallStackMutation{
+                jump: program_counter.advance(),
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: Some(HandlerChange::Push(handler)),
+                throw: None,
+            }
+        },
+
+        Instruction::PopHandler => {
             CallStackMutation{
                 jump: program_counter.advance(),
                 exit: None,
-                call: Some(call),
+                call: None,
+                tail_call: false,
+                handler: Some(HandlerChange::Pop),
+                throw: None,
+            }
+        },
+
+        Instruction::Throw{value} => {
+            let value = local!(value);
+            CallStackMutation{
+                jump: program_counter,
+                exit: None,
+                call: None,
+                tail_call: false,
+                handler: None,
+                throw: Some(value),
             }
         },
 
@@ -94,16 +645,81 @@ pub fn interpret_instruction<'a>(
                 jump: program_counter,
                 exit: Some(value),
                 call: None,
+                tail_call: false,
+                handler: None,
+                throw: None,
             }
         },
 
+    };
+
+    Ok(mutation)
+}
+
+/// Create an integer, as an immediate if it fits in one and on the heap
+/// otherwise.
+fn integer_datum(heap: &Heap, types: TypeSigils, value: i64)
+    -> Result<Datum<'_>, InterpretError> {
+    match Datum::from_i64(value) {
+        Some(datum) => Ok(datum),
+        // This is safe because the datum has no pointers.
+        None => unsafe {
+            heap.allocate_within_limit(types.integer, &[],
+                                       &value.to_le_bytes())
+        }.map_err(InterpretError::HeapLimitExceeded),
     }
 }
 
+/// Create a datum that is truthy or falsy. It is allocated on the heap, and
+/// enchanted with the truthy or falsy sigil.
+fn boolean_datum(heap: &Heap, types: TypeSigils, value: bool)
+    -> Result<Datum<'_>, InterpretError> {
+    let enchantment = if value { types.truthy } else { types.falsy };
+    // This is safe because the datum has no pointers.
+    unsafe { heap.allocate_within_limit(enchantment, &[], &[]) }
+        .map_err(InterpretError::HeapLimitExceeded)
+}
+
+/// Read a local variable, as the `local!` macro in
+/// [try_interpret_instruction] does, but without cloning it.
+///
+/// [try_interpret_instruction]: fn.try_interpret_instruction.html
+fn read_local<'b, 'a>(local_variables: &'b [Option<Datum<'a>>], local: Local)
+    -> Result<&'b Datum<'a>, InterpretError> {
+    local_variables.get(local.0 as usize)
+        .ok_or(InterpretError::LocalOutOfBounds(local))?
+        .as_ref()
+        .ok_or(InterpretError::LocalUninitialized(local))
+}
+
+/// Check that local variables can be read, as for [read_local].
+///
+/// [read_local]: fn.read_local.html
+fn check_locals(local_variables: &[Option<Datum>], locals: &[Local])
+    -> Result<(), InterpretError> {
+    for &local in locals {
+        read_local(local_variables, local)?;
+    }
+    Ok(())
+}
+
+/// The spellbook and spell a closure refers to, or `None` if the datum is not
+/// a closure. See `Instruction::MakeClosure` for how closures are represented.
+fn closure_spell(datum: &Datum) -> Option<(Sigil, Sigil)> {
+    let auxiliary = datum.auxiliary();
+    if datum.enchantment() != CLOSURE_ENCHANTMENT || auxiliary.len() != 8 {
+        return None;
+    }
+    let sigil = |bytes: &[u8]| Sigil(u32::from_le_bytes(
+        <[u8; 4]>::try_from(bytes).unwrap()));
+    Some((sigil(&auxiliary[.. 4]), sigil(&auxiliary[4 ..])))
+}
+
 /// A description of what must happen to the call stack after interpreting an
 /// instruction.
 ///
-/// Both exit and call may be set. In that case, a tail call must occur.
+/// Exit and call are never both set. An invocation that is immediately
+/// followed by a return of its result sets call and tail_call.
 #[derive(Debug)]
 pub struct CallStackMutation<'a> {
     /// Which instruction to jump to before changing the active stack
@@ -115,12 +731,637 @@ pub struct CallStackMutation<'a> {
 
     /// Create a new stack frame, invoking a spell with some arguments.
     pub call: Option<Call<'a>>,
+
+    /// Whether the call is a tail call, in which case the new stack frame
+    /// replaces the active one, and returns directly into its caller. Not
+    /// relevant if call is not set.
+    pub tail_call: bool,
+
+    /// Change the exception handlers of the active stack frame. This happens
+    /// before any of the other changes.
+    pub handler: Option<HandlerChange>,
+
+    /// Throw a datum, unwinding the call stack until a stack frame with an
+    /// exception handler is found. If set, exit and call are not set.
+    pub throw: Option<Datum<'a>>,
+}
+
+/// A change to the exception handlers of a stack frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HandlerChange {
+    /// Install an exception handler, which takes precedence over those that
+    /// are already installed.
+    Push(Handler),
+
+    /// Remove the exception handler that was installed last.
+    Pop,
 }
 
 /// A call to be performed.
 #[derive(Debug)]
 pub struct Call<'a> {
     pub callee:      SpellId,
-    pub arguments:   Box<[Datum<'a>]>,
+    pub arguments:   Arguments<'a>,
     pub return_into: Local,
+
+    /// The index of the callee in the spell database, if the invocation was
+    /// linked. See `Instruction::InvokeLinked`.
+    pub linked:      Option<usize>,
+
+    /// The cache to remember the callee in, if the invocation is cached. See
+    /// `Instruction::InvokeDynamicCached`.
+    pub cache:       Option<&'a InlineCache>,
+}
+
+/// The arguments of a call.
+#[derive(Debug)]
+pub enum Arguments<'a> {
+    /// The arguments are these data.
+    Values(Box<[Datum<'a>]>),
+
+    /// The arguments are the data the closure points to, if there is a
+    /// closure, followed by the receiver, if there is one, followed by the
+    /// given local variables, all of the active stack frame.
+    ///
+    /// The local variables are read when the call is performed, rather than
+    /// collected beforehand, so that they can be written into the stack frame
+    /// of the callee directly. If the active stack frame is exited by the
+    /// call, they are moved rather than copied.
+    Locals{
+        closure:  Option<Datum<'a>>,
+        receiver: Option<Local>,
+        locals:   &'a [Local],
+    },
+}
+
+impl Arguments<'_> {
+    /// The number of arguments.
+    pub fn len(&self) -> usize {
+        match self {
+            Arguments::Values(values) => values.len(),
+            Arguments::Locals{closure, receiver, locals} =>
+                closure.as_ref().map_or(0, |c| c.pointers().len())
+                    + receiver.is_some() as usize
+                    + locals.len(),
+        }
+    }
+
+    /// Whether there are no arguments.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// An error that occurs when interpreting malformed code.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InterpretError {
+    /// An instruction referred to a local variable beyond the local variables
+    /// of the stack frame.
+    LocalOutOfBounds(Local),
+
+    /// An instruction read a local variable that was not yet assigned to.
+    LocalUninitialized(Local),
+
+    /// The program counter pointed beyond the instructions of the spell. This
+    /// is reported by the instruction that jumped out of bounds, or by the
+    /// exception handler whose target was out of bounds when it caught.
+    ProgramCounterOutOfBounds,
+
+    /// Interpretation continued past the last instruction of the spell, which
+    /// is not a return or another instruction that does not fall through.
+    /// Spells that pass the verifier never fall off the end.
+    FellOffEnd(SpellId),
+
+    /// A spell was invoked that does not exist at any arity.
+    SpellNotFound(SpellId),
+
+    /// A spell was invoked that exists, but not at the arity of the
+    /// invocation. The arities at which it does exist are listed in ascending
+    /// order.
+    ArityMismatch{spell: SpellId, expected: Vec<usize>},
+
+    /// A spell was invoked with more arguments than it has local variables.
+    TooFewLocalVariables(SpellId),
+
+    /// An arithmetic operand did not represent an integer.
+    NotAnInteger(Local),
+
+    /// The divisor of a division was zero.
+    DivisionByZero,
+
+    /// A spell was invoked while the call stack was at its maximum depth.
+    StackOverflow(SpellId),
+
+    /// A pointer was read at an index beyond the pointers of the datum.
+    PointerOutOfBounds(usize),
+
+    /// A closure was invoked, but the local variable did not contain one.
+    NotAClosure(Local),
+
+    /// A constant was loaded at an index beyond the constant pool of the
+    /// spell.
+    ConstantOutOfBounds(usize),
+
+    /// An exception handler was removed from a stack frame that had none.
+    NoHandler,
+
+    /// A datum was thrown, but no stack frame had an exception handler. The
+    /// error cannot refer to the heap, so only the enchantment and auxiliary
+    /// part of the datum are kept.
+    Uncaught{enchantment: Sigil, auxiliary: Box<[u8]>},
+
+    /// The next instruction cost more fuel than was left. See
+    /// [RunOptions::fuel].
+    ///
+    /// [RunOptions::fuel]: struct.RunOptions.html#structfield.fuel
+    OutOfFuel,
+
+    /// A run was resumed with a call stack that had no stack frames, so there
+    /// was no instruction to continue at. See [OutOfFuel::resume].
+    ///
+    /// [OutOfFuel::resume]: struct.OutOfFuel.html#method.resume
+    EmptyCallStack,
+
+    /// A datum was allocated that did not fit within the limit of the heap,
+    /// even after collecting garbage. See [Heap::set_limit].
+    ///
+    /// [Heap::set_limit]: ../datum/struct.Heap.html#method.set_limit
+    HeapLimitExceeded(HeapLimitExceeded),
+
+    /// A suspending native spell suspended the run. Only runs by
+    /// [run_with_options] can be resumed after this; see
+    /// [RunOutcome::suspension].
+    ///
+    /// [run_with_options]: fn.run_with_options.html
+    /// [RunOutcome::suspension]: struct.RunOutcome.html#structfield.suspension
+    Suspended,
+}
+
+impl InterpretError {
+    /// Describe the error as its `Display` implementation does, but with
+    /// sigils written by name, or as `Sigil(n)` if they are not in the sigil
+    /// database.
+    ///
+    /// When a dynamic invocation finds no spell, the spellbook of the spell
+    /// that was not found is the enchantment of the receiver, so that the
+    /// description names the enchantment for which the lookup failed.
+    pub fn describe(&self, sigils: &Sigils) -> String {
+        let mut output = String::new();
+        // Writing to a string cannot fail.
+        let _ = self.write(&mut output, Some(sigils));
+        output
+    }
+
+    fn write(&self, f: &mut impl fmt::Write, sigils: Option<&Sigils>)
+        -> fmt::Result {
+        let sigil = |sigil: Sigil| match sigils.and_then(|s| s.name(sigil)) {
+            Some(name) => String::from_utf8_lossy(name).into_owned(),
+            None       => format!("{:?}", sigil),
+        };
+        let id = |id: &SpellId| {
+            format!("{}::{}/{}", sigil(id.spellbook), sigil(id.spell), id.arity)
+        };
+
+        match self {
+            InterpretError::LocalOutOfBounds(local) =>
+                write!(f, "local variable v{} is out of bounds", local.0),
+            InterpretError::LocalUninitialized(local) =>
+                write!(f, "local variable v{} is uninitialized", local.0),
+            InterpretError::ProgramCounterOutOfBounds =>
+                write!(f, "program counter is out of bounds"),
+            InterpretError::FellOffEnd(spell) =>
+                write!(f, "spell {} fell off the end", id(spell)),
+            InterpretError::SpellNotFound(spell) =>
+                write!(f, "spell {} is not defined", id(spell)),
+            InterpretError::ArityMismatch{spell, expected} =>
+                write!(f, "spell {} is not defined, but exists with arity {}",
+                       id(spell), expected.iter()
+                           .map(|arity| arity.to_string())
+                           .collect::<Vec<_>>()
+                           .join(", ")),
+            InterpretError::TooFewLocalVariables(spell) =>
+                write!(f, "spell {} has fewer local variables than arguments",
+                       id(spell)),
+            InterpretError::NotAnInteger(local) =>
+                write!(f, "local variable v{} is not an integer", local.0),
+            InterpretError::DivisionByZero =>
+                write!(f, "division by zero"),
+            InterpretError::StackOverflow(spell) =>
+                write!(f, "stack overflow when invoking spell {}", id(spell)),
+            InterpretError::PointerOutOfBounds(index) =>
+                write!(f, "pointer {} is out of bounds", index),
+            InterpretError::NotAClosure(local) =>
+                write!(f, "local variable v{} is not a closure", local.0),
+            InterpretError::ConstantOutOfBounds(index) =>
+                write!(f, "constant {} is out of bounds", index),
+            InterpretError::NoHandler =>
+                write!(f, "there is no exception handler to remove"),
+            InterpretError::Uncaught{enchantment, ..} =>
+                write!(f, "uncaught exception enchanted with {}",
+                       sigil(*enchantment)),
+            InterpretError::OutOfFuel =>
+                write!(f, "out of fuel"),
+            InterpretError::EmptyCallStack =>
+                write!(f, "call stack is empty"),
+            InterpretError::HeapLimitExceeded(error) =>
+                write!(f, "{}", error),
+            InterpretError::Suspended =>
+                write!(f, "suspended by a native spell"),
+        }
+    }
+}
+
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, None)
+    }
+}
+
+impl Error for InterpretError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::slice;
+
+    use datum::IMMEDIATE_ENCHANTMENT;
+
+    const FALSE:   Sigil = Sigil(0);
+    const TRUE:    Sigil = Sigil(1);
+    const INTEGER: Sigil = Sigil(2);
+    const STRING:  Sigil = Sigil(3);
+
+    const TYPES: TypeSigils = TypeSigils{falsy: FALSE, truthy: TRUE,
+                                         integer: INTEGER, string: STRING};
+
+    fn interpret<'a>(heap:            &'a Heap,
+                     instruction:     &'a Instruction,
+                     local_variables: &mut [Option<Datum<'a>>],
+                     ) -> Result<CallStackMutation<'a>, InterpretError> {
+        let program_counter = ProgramCounter{
+            instructions:     slice::from_ref(instruction),
+            constants:        &[],
+            next_instruction: 0,
+        };
+        try_interpret_instruction(heap, TYPES, program_counter,
+                                  local_variables)
+    }
+
+    fn next_instruction<'a>(heap:            &'a Heap,
+                            instruction:     &'a Instruction,
+                            local_variables: &mut [Option<Datum<'a>>],
+                            ) -> usize {
+        let mutation = interpret(heap, instruction, local_variables).unwrap();
+        mutation.jump.next_instruction
+    }
+
+    #[test]
+    fn test_jump() {
+        let jump = Instruction::Jump{target: 3};
+        let heap = Heap::new();
+        assert_eq!(next_instruction(&heap, &jump, &mut []), 3);
+    }
+
+    #[test]
+    fn test_branch_if_truthy() {
+        let taken =
+            Instruction::BranchIfTruthy{condition: Local(0), target: 5};
+        let not_taken =
+            Instruction::BranchIfTruthy{condition: Local(1), target: 5};
+
+        let heap = Heap::new();
+        let truthy = unsafe { heap.allocate(TRUE,  &[], &[]) };
+        let falsy  = unsafe { heap.allocate(FALSE, &[], &[]) };
+        let mut locals = [Some(truthy), Some(falsy)];
+
+        assert_eq!(next_instruction(&heap, &taken,     &mut locals), 5);
+        assert_eq!(next_instruction(&heap, &not_taken, &mut locals), 1);
+    }
+
+    #[test]
+    fn test_branch_if_falsy() {
+        let taken =
+            Instruction::BranchIfFalsy{condition: Local(1), target: 5};
+        let not_taken =
+            Instruction::BranchIfFalsy{condition: Local(0), target: 5};
+
+        let heap = Heap::new();
+        let truthy = unsafe { heap.allocate(TRUE,  &[], &[]) };
+        let falsy  = unsafe { heap.allocate(FALSE, &[], &[]) };
+        let mut locals = [Some(truthy), Some(falsy)];
+
+        assert_eq!(next_instruction(&heap, &taken,     &mut locals), 5);
+        assert_eq!(next_instruction(&heap, &not_taken, &mut locals), 1);
+    }
+
+    #[test]
+    fn test_truthiness_follows_falsy_sigil() {
+        let heap = Heap::new();
+        let datum = unsafe { heap.allocate(TRUE, &[], &[]) };
+        assert!(datum.is_truthy(FALSE));
+        assert!(!datum.is_truthy(TRUE));
+    }
+
+    fn arithmetic<F>(make: F, lhs: i64, rhs: i64)
+        -> Result<i64, InterpretError>
+        where F: Fn(Local, Local, Local) -> Instruction {
+        let instruction = make(Local(2), Local(0), Local(1));
+
+        let heap = Heap::new();
+        let lhs = unsafe { heap.allocate(INTEGER, &[], &lhs.to_le_bytes()) };
+        let rhs = unsafe { heap.allocate(INTEGER, &[], &rhs.to_le_bytes()) };
+        let mut locals = [Some(lhs), Some(rhs), None];
+
+        interpret(&heap, &instruction, &mut locals)?;
+
+        let result = locals[2].take().unwrap();
+        assert_eq!(result.enchantment(), INTEGER);
+        assert_eq!(result.auxiliary().len(), 8);
+        Ok(result.as_i64().unwrap())
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let add = |result, lhs, rhs| Instruction::Add{result, lhs, rhs};
+        let sub = |result, lhs, rhs| Instruction::Sub{result, lhs, rhs};
+        let mul = |result, lhs, rhs| Instruction::Mul{result, lhs, rhs};
+        let div = |result, lhs, rhs| Instruction::Div{result, lhs, rhs};
+
+        assert_eq!(arithmetic(add, 3, 4), Ok(7));
+        assert_eq!(arithmetic(sub, 3, 4), Ok(-1));
+        assert_eq!(arithmetic(mul, -3, 4), Ok(-12));
+        assert_eq!(arithmetic(div, 13, 4), Ok(3));
+        assert_eq!(arithmetic(add, i64::MAX, 1), Ok(i64::MIN));
+        assert_eq!(arithmetic(div, 1, 0), Err(InterpretError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_arithmetic_operands() {
+        let instruction = Instruction::Add{
+            result: Local(0),
+            lhs:    Local(0),
+            rhs:    Local(1),
+        };
+
+        let heap = Heap::new();
+        let immediate = Datum::from_i64(2).unwrap();
+        let boxed = unsafe { heap.allocate(TRUE, &[], &5i64.to_le_bytes()) };
+        let other = unsafe { heap.allocate(TRUE, &[], b"five") };
+
+        let mut locals = [Some(immediate), Some(boxed)];
+        interpret(&heap, &instruction, &mut locals).unwrap();
+        assert_eq!(locals[0].as_ref().and_then(Datum::as_i64), Some(7));
+
+        let mut locals = [Some(other.clone()), Some(other)];
+        assert_eq!(interpret(&heap, &instruction, &mut locals).unwrap_err(),
+                   InterpretError::NotAnInteger(Local(0)));
+    }
+
+    #[test]
+    fn test_allocate() {
+        let allocate = Instruction::Allocate{
+            result:      Local(2),
+            enchantment: TRUE,
+            pointers:    Box::new([Local(1), Local(0), Local(1)]),
+            auxiliary:   Box::new(*b"abc"),
+        };
+        let uninitialized = Instruction::Allocate{
+            result:      Local(2),
+            enchantment: TRUE,
+            pointers:    Box::new([Local(2)]),
+            auxiliary:   Box::new([]),
+        };
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(FALSE, &[], b"a") };
+        let mut locals = [Some(a), Datum::from_i64(1), None];
+
+        assert_eq!(next_instruction(&heap, &allocate, &mut locals), 1);
+        let datum = locals[2].take().unwrap();
+        assert_eq!(datum.enchantment(), TRUE);
+        assert_eq!(datum.auxiliary(), b"abc");
+        assert_eq!(datum.pointers()[0].as_i64(), Some(1));
+        assert_eq!(datum.pointers()[1].auxiliary(), b"a");
+        assert_eq!(datum.pointers()[2].as_i64(), Some(1));
+
+        let error = interpret(&heap, &uninitialized, &mut locals);
+        assert_eq!(error.unwrap_err(),
+                   InterpretError::LocalUninitialized(Local(2)));
+    }
+
+    #[test]
+    fn test_allocate_exceeds_limit() {
+        let allocate = Instruction::Allocate{
+            result:      Local(0),
+            enchantment: TRUE,
+            pointers:    Box::new([]),
+            auxiliary:   Box::new(*b"abc"),
+        };
+        let less_than = Instruction::LessThan{result: Local(0), lhs: Local(1),
+                                              rhs: Local(1)};
+
+        let heap = Heap::with_limit(2);
+        let mut locals = [None, Datum::from_i64(1)];
+
+        let error = interpret(&heap, &allocate, &mut locals);
+        let exceeded = HeapLimitExceeded{size: 3, limit: 2};
+        assert_eq!(error.unwrap_err(),
+                   InterpretError::HeapLimitExceeded(exceeded));
+        assert!(locals[0].is_none());
+        assert!(heap.is_empty());
+
+        // Booleans take no bytes, so they still fit.
+        assert_eq!(next_instruction(&heap, &less_than, &mut locals), 1);
+        assert_eq!(locals[0].as_ref().unwrap().enchantment(), FALSE);
+    }
+
+    #[test]
+    fn test_inspect() {
+        let get_pointer =
+            Instruction::GetPointer{result: Local(1), object: Local(0),
+                                    index: 1};
+        let out_of_bounds =
+            Instruction::GetPointer{result: Local(1), object: Local(0),
+                                    index: 2};
+        let enchantment_of =
+            Instruction::EnchantmentOf{result: Local(1), object: Local(0)};
+        let auxiliary_len =
+            Instruction::AuxiliaryLen{result: Local(1), object: Local(0)};
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(FALSE, &[], b"a") };
+        let datum = unsafe {
+            heap.allocate(Sigil(7), &[Datum::from_i64(0).unwrap(), a],
+                          b"abc")
+        };
+        let mut locals = [Some(datum), None];
+
+        next_instruction(&heap, &get_pointer, &mut locals);
+        assert_eq!(locals[1].as_ref().unwrap().auxiliary(), b"a");
+
+        next_instruction(&heap, &enchantment_of, &mut locals);
+        assert_eq!(locals[1].as_ref().unwrap().as_i64(), Some(7));
+
+        next_instruction(&heap, &auxiliary_len, &mut locals);
+        assert_eq!(locals[1].as_ref().unwrap().as_i64(), Some(3));
+
+        let error = interpret(&heap, &out_of_bounds, &mut locals);
+        assert_eq!(error.unwrap_err(), InterpretError::PointerOutOfBounds(2));
+
+        locals[0] = Datum::from_i64(1);
+        next_instruction(&heap, &enchantment_of, &mut locals);
+        assert_eq!(locals[1].as_ref().unwrap().as_i64(),
+                   Some(IMMEDIATE_ENCHANTMENT.0 as i64));
+    }
+
+    #[test]
+    fn test_swap() {
+        let swap = Instruction::Swap{a: Local(0), b: Local(2)};
+        let out_of_bounds = Instruction::Swap{a: Local(0), b: Local(3)};
+        let nop = Instruction::Nop;
+
+        let heap = Heap::new();
+        let mut locals = [Datum::from_i64(1), Datum::from_i64(2), None];
+
+        next_instruction(&heap, &swap, &mut locals);
+        assert!(locals[0].is_none());
+        assert_eq!(locals[2].as_ref().and_then(Datum::as_i64), Some(1));
+
+        let error = interpret(&heap, &out_of_bounds, &mut locals);
+        assert_eq!(error.unwrap_err(),
+                   InterpretError::LocalOutOfBounds(Local(3)));
+        assert_eq!(locals[2].as_ref().and_then(Datum::as_i64), Some(1));
+
+        assert_eq!(next_instruction(&heap, &nop, &mut locals), 1);
+        assert!(locals[0].is_none());
+        assert_eq!(locals[1].as_ref().and_then(Datum::as_i64), Some(2));
+    }
+
+    #[test]
+    fn test_copy_to_itself() {
+        let copy = Instruction::Copy{from: Local(0), to: Local(0)};
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(FALSE, &[], b"a") };
+        let mut locals = [Some(a), None];
+
+        assert_eq!(next_instruction(&heap, &copy, &mut locals), 1);
+        assert_eq!(locals[0].as_ref().unwrap().auxiliary(), b"a");
+        assert!(locals[1].is_none());
+        assert_eq!(heap.total_roots(), 1);
+    }
+
+    #[test]
+    fn test_copy_range() {
+        let heap = Heap::new();
+        let copy_range = |from, to, count| {
+            let instruction = Instruction::CopyRange{from: Local(from),
+                                                     to: Local(to), count};
+            let mut locals: Vec<Option<Datum>> =
+                (0 .. 5).map(Datum::from_i64).collect();
+            locals[4] = None;
+            interpret(&heap, &instruction, &mut locals)
+                .map(|_| locals.iter()
+                         .map(|local| local.as_ref().and_then(Datum::as_i64))
+                         .collect::<Vec<_>>())
+        };
+        let some = |values: &[i64]| -> Vec<Option<i64>> {
+            values.iter().cloned().map(Some).chain(Some(None)).collect()
+        };
+
+        // Overlapping ranges, in either direction.
+        assert_eq!(copy_range(0, 1, 3), Ok(some(&[0, 0, 1, 2])));
+        assert_eq!(copy_range(1, 0, 3), Ok(some(&[1, 2, 3, 3])));
+        assert_eq!(copy_range(0, 2, 2), Ok(some(&[0, 1, 0, 1])));
+        assert_eq!(copy_range(2, 2, 2), Ok(some(&[0, 1, 2, 3])));
+        assert_eq!(copy_range(5, 0, 0), Ok(some(&[0, 1, 2, 3])));
+
+        // The unassigned variable may be copied over, but not copied from.
+        assert_eq!(copy_range(0, 3, 2), Ok(vec![Some(0), Some(1), Some(2),
+                                                Some(0), Some(1)]));
+        assert_eq!(copy_range(2, 0, 3),
+                   Err(InterpretError::LocalUninitialized(Local(4))));
+        assert_eq!(copy_range(3, 4, 2),
+                   Err(InterpretError::LocalOutOfBounds(Local(5))));
+        assert_eq!(copy_range(6, 0, 1),
+                   Err(InterpretError::LocalOutOfBounds(Local(6))));
+        assert_eq!(copy_range(0, 1, usize::MAX),
+                   Err(InterpretError::LocalOutOfBounds(Local(5))));
+    }
+
+    #[test]
+    fn test_local_out_of_bounds() {
+        let heap = Heap::new();
+        let out_of_bounds = |instruction: Instruction, local: u32| {
+            let mut locals = [Datum::from_i64(1), Datum::from_i64(2)];
+            let error = interpret(&heap, &instruction, &mut locals).err();
+            assert_eq!(error, Some(InterpretError::LocalOutOfBounds(
+                Local(local))), "{:?}", instruction);
+            assert_eq!(locals[0].as_ref().and_then(Datum::as_i64), Some(1));
+            assert_eq!(locals[1].as_ref().and_then(Datum::as_i64), Some(2));
+        };
+
+        // Reads.
+        out_of_bounds(Instruction::Copy{from: Local(2), to: Local(0)}, 2);
+        out_of_bounds(Instruction::Add{result: Local(0), lhs: Local(0),
+                                       rhs: Local(9)}, 9);
+        out_of_bounds(Instruction::BranchIfTruthy{condition: Local(2),
+                                                  target: 0}, 2);
+        out_of_bounds(Instruction::InvokeStatic{result: Local(0),
+                                                spellbook: Sigil(0),
+                                                spell: Sigil(0),
+                                                arguments: Box::new([
+                                                    Local(1), Local(3),
+                                                ])}, 3);
+        out_of_bounds(Instruction::Return{result: Local(u32::MAX)},
+                      u32::MAX);
+
+        // Writes.
+        out_of_bounds(Instruction::Copy{from: Local(0), to: Local(2)}, 2);
+        out_of_bounds(Instruction::Sub{result: Local(5), lhs: Local(0),
+                                       rhs: Local(1)}, 5);
+        out_of_bounds(Instruction::Allocate{result: Local(2),
+                                            enchantment: Sigil(0),
+                                            pointers: Box::new([Local(0)]),
+                                            auxiliary: Box::new([])}, 2);
+    }
+
+    #[test]
+    fn test_comparison() {
+        let compare = |instruction: Instruction, lhs: Datum, rhs: Datum| {
+            let heap = Heap::new();
+            let mut locals = [Some(lhs), Some(rhs), None];
+            interpret(&heap, &instruction, &mut locals)
+                .map(|_| locals[2].as_ref().unwrap().enchantment())
+        };
+        let equal = Instruction::Equal{result: Local(2), lhs: Local(0),
+                                       rhs: Local(1)};
+        let less_than = Instruction::LessThan{result: Local(2), lhs: Local(0),
+                                              rhs: Local(1)};
+        let greater_than = Instruction::GreaterThan{result: Local(2),
+                                                    lhs: Local(0),
+                                                    rhs: Local(1)};
+
+        let heap = Heap::new();
+        let int = |value: i64| Datum::from_i64(value).unwrap();
+        let boxed = |value: i64| unsafe {
+            heap.allocate(INTEGER, &[], &value.to_le_bytes())
+        };
+        let text = |text: &[u8]| unsafe { heap.allocate(FALSE, &[], text) };
+
+        assert_eq!(compare(equal.clone(), int(3), boxed(3)), Ok(TRUE));
+        assert_eq!(compare(equal.clone(), int(3), int(4)), Ok(FALSE));
+        assert_eq!(compare(equal.clone(), text(b"a"), text(b"a")), Ok(TRUE));
+        assert_eq!(compare(equal.clone(), text(b"a"), text(b"b")), Ok(FALSE));
+        assert_eq!(compare(equal, text(b"a"), int(3)), Ok(FALSE));
+
+        assert_eq!(compare(less_than.clone(), int(3), boxed(4)), Ok(TRUE));
+        assert_eq!(compare(less_than.clone(), int(4), int(4)), Ok(FALSE));
+        assert_eq!(compare(less_than, text(b"a"), int(4)),
+                   Err(InterpretError::NotAnInteger(Local(0))));
+
+        assert_eq!(compare(greater_than.clone(), int(5), int(4)), Ok(TRUE));
+        assert_eq!(compare(greater_than, int(4), int(4)), Ok(FALSE));
+    }
 }
@@ -0,0 +1,360 @@
+use super::*;
+
+use datum::CollectStatistics;
+
+/// How [run_with_options] runs a spell.
+///
+/// [run_with_options]: fn.run_with_options.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RunOptions {
+    /// The maximum number of stack frames; see [try_run_with_max_depth].
+    ///
+    /// [try_run_with_max_depth]: fn.try_run_with_max_depth.html
+    pub max_depth: usize,
+
+    /// How much fuel the run may consume, if that is limited; see
+    /// [run_with_fuel]. A run that runs out of fuel fails with
+    /// [InterpretError::OutOfFuel], and cannot be resumed.
+    ///
+    /// [run_with_fuel]: fn.run_with_fuel.html
+    /// [InterpretError::OutOfFuel]: enum.InterpretError.html#variant.OutOfFuel
+    pub fuel: Option<u64>,
+
+    /// When to collect garbage between instructions.
+    pub gc: GcPolicy,
+}
+
+impl Default for RunOptions {
+    /// The options under which [try_run] runs a spell: without limits, and
+    /// without collecting garbage.
+    ///
+    /// [try_run]: fn.try_run.html
+    fn default() -> Self {
+        RunOptions{max_depth: usize::MAX, fuel: None, gc: GcPolicy::Never}
+    }
+}
+
+/// What came of a run by [run_with_options].
+///
+/// [run_with_options]: fn.run_with_options.html
+#[derive(Debug)]
+pub struct RunOutcome<'a> {
+    /// The datum the entry spell returned, or the error that ended the run.
+    pub value: Result<Datum<'a>, InterpretError>,
+
+    /// The number of instructions that were interpreted, including one that
+    /// failed.
+    pub instructions_executed: u64,
+
+    /// The data and bytes freed by all garbage collections during the run,
+    /// and the data and bytes that survived the last one. If no garbage was
+    /// collected, they are all zero.
+    pub gc: CollectStatistics,
+
+    /// The greatest number of stack frames that were on the call stack at the
+    /// same time. This is zero if the entry spell is native.
+    pub max_stack_depth: usize,
+
+    /// The suspended run, if a suspending native spell suspended it, in which
+    /// case the value is [InterpretError::Suspended].
+    ///
+    /// [InterpretError::Suspended]: enum.InterpretError.html#variant.Suspended
+    pub suspension: Option<Suspension<'a>>,
+}
+
+/// A run that was suspended by a suspending native spell, which can be
+/// resumed once the host has the result of the invocation. See
+/// [SuspendingNativeSpell].
+///
+/// [SuspendingNativeSpell]: ../spell/type.SuspendingNativeSpell.html
+#[derive(Debug)]
+pub struct Suspension<'a> {
+    /// The call stack at the time the run was suspended. The program counter
+    /// of the active stack frame points to the instruction after the
+    /// invocation, and the active stack frame stores the result of the
+    /// invocation in its return_into local variable on resumption. If the
+    /// invocation was a tail call, the invoking stack frame was already
+    /// exited.
+    pub call_stack: CallStack<'a>,
+
+    /// What the native spell waits for, as it said when it suspended the run.
+    pub request: Datum<'a>,
+}
+
+impl<'a> Suspension<'a> {
+    /// Continue the run, with the given datum as the result of the invocation
+    /// that suspended it, and report on the rest of the run as
+    /// [run_with_options] does. The runtime must be the same as that of the
+    /// original run. The report only covers the run since it was resumed.
+    ///
+    /// [run_with_options]: fn.run_with_options.html
+    pub fn resume(self, runtime: &Runtime<'a>, value: Datum<'a>,
+                  options: &RunOptions) -> RunOutcome<'a> {
+        let mut call_stack = self.call_stack;
+        call_stack.max_depth = options.max_depth;
+
+        // The value is given to the run, so it is cloned inside it, and the
+        // root it was is released only once the roots have been checked.
+        drive(runtime, call_stack, options, |call_stack| {
+            match call_stack.stack_frames.last_mut() {
+                None => Ok(Some(value.clone())),
+                Some(frame) => {
+                    let return_into = frame.return_into;
+                    store(frame, return_into, value.clone()).map(|()| None)
+                },
+            }
+        })
+    }
+}
+
+/// Run a spell to completion, as [try_run] does, but limited and with garbage
+/// collected as the options say, and report on the run.
+///
+/// When the run ends, the entire call stack is discarded, unless a suspending
+/// native spell suspended the run; see [RunOutcome::suspension].
+///
+/// [try_run]: fn.try_run.html
+/// [RunOutcome::suspension]: struct.RunOutcome.html#structfield.suspension
+pub fn run_with_options<'a>(runtime:   &Runtime<'a>,
+                            entry:     SpellId,
+                            arguments: &[Datum<'a>],
+                            options:   &RunOptions,
+                            ) -> RunOutcome<'a> {
+    let call_stack = CallStack::with_max_depth(options.max_depth);
+    drive(runtime, call_stack, options, |call_stack| {
+        start(runtime, entry, arguments, call_stack)
+    })
+}
+
+/// Start or resume the run with the given function, which returns the datum
+/// the entry spell returned if the run is already over, then interpret
+/// instructions until the run ends, as the options say, and report on the
+/// run.
+fn drive<'a, F>(runtime:        &Runtime<'a>,
+                mut call_stack: CallStack<'a>,
+                options:        &RunOptions,
+                begin:          F,
+                ) -> RunOutcome<'a>
+    where F: FnOnce(&mut CallStack<'a>)
+                    -> Result<Option<Datum<'a>>, InterpretError> {
+    let mut instructions_executed = 0;
+    let mut max_stack_depth = 0;
+    let mut fuel = options.fuel;
+
+    let mut collector = Collector::new(runtime.heap, options.gc);
+    let value = check_roots(runtime.heap, &mut call_stack, |call_stack| {
+        if let Some(value) = begin(call_stack)? {
+            return Ok(Some(value));
+        }
+        loop {
+            let depth = call_stack.stack_frames.len();
+            max_stack_depth = max_stack_depth.max(depth);
+
+            if let Some(fuel) = &mut fuel {
+                let cost = active_stack_frame(call_stack).program_counter
+                    .try_get().map_or(0, fuel_cost);
+                if cost > *fuel {
+                    return Err(InterpretError::OutOfFuel);
+                }
+                *fuel -= cost;
+            }
+
+            instructions_executed += 1;
+            if let Some(value) = step(runtime, call_stack)? {
+                return Ok(Some(value));
+            }
+            collector.after_step();
+        }
+    }).map(finished);
+
+    let suspension = match (&value, call_stack.pending.take()) {
+        (Err(InterpretError::Suspended), Some(request)) =>
+            Some(Suspension{call_stack, request}),
+        _ => None,
+    };
+    RunOutcome{value, instructions_executed, gc: collector.total,
+               max_stack_depth, suspension}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use datum::Heap;
+    use sigil::Sigils;
+    use spell::NativeResult;
+    use spell::Spells;
+    use spell::assemble;
+
+    /// main(n) returns 1 + main(n - 1), or n if it is less than one, so that
+    /// it nests n + 1 stack frames.
+    const SOURCE: &str = "
+        spell book::main/1
+            constant integer [01 00 00 00 00 00 00 00]
+            v1 = load_constant 0
+            v2 = less_than v0, v1
+            branch_if_truthy v2, done
+            v0 = sub v0, v1
+            v0 = invoke_static book::main(v0)
+            v0 = add v0, v1
+        done:
+            return v0
+    ";
+
+    fn run_main(options: &RunOptions,
+                check: impl FnOnce(RunOutcome, SpellId)) {
+        let mut sigils = Sigils::new();
+        let spells: Spells = assemble(SOURCE, &mut sigils).unwrap();
+        let id = SpellId{spellbook: sigils.intern_str("book"),
+                         spell: sigils.intern_str("main"), arity: 1};
+        let heap = Heap::new();
+        let types = TypeSigils::intern(&mut sigils);
+        let runtime = Runtime{spells: &spells, heap: &heap, types};
+        let arguments = [Datum::from_i64(3).unwrap()];
+        check(run_with_options(&runtime, id, &arguments, options), id);
+    }
+
+    #[test]
+    fn test_run_with_options() {
+        run_main(&RunOptions::default(), |outcome, _| {
+            assert_eq!(outcome.value.unwrap().as_i64(), Some(3));
+            // Seven instructions for each of the three nested invocations,
+            // and four for the innermost one.
+            assert_eq!(outcome.instructions_executed, 3 * 7 + 4);
+            assert_eq!(outcome.max_stack_depth, 4);
+            assert_eq!(outcome.gc.data_freed, 0);
+        });
+    }
+
+    #[test]
+    fn test_run_with_options_limits() {
+        // Loading the constant, comparing, and branching cost five units, and
+        // subtracting would cost two more.
+        let options = RunOptions{fuel: Some(6), ..RunOptions::default()};
+        run_main(&options, |outcome, _| {
+            assert_eq!(outcome.value.unwrap_err(), InterpretError::OutOfFuel);
+            assert_eq!(outcome.instructions_executed, 3);
+            assert_eq!(outcome.max_stack_depth, 1);
+        });
+
+        let options = RunOptions{max_depth: 2, ..RunOptions::default()};
+        run_main(&options, |outcome, id| {
+            assert_eq!(outcome.value.unwrap_err(),
+                       InterpretError::StackOverflow(id));
+            assert_eq!(outcome.instructions_executed, 2 * 5);
+            assert_eq!(outcome.max_stack_depth, 2);
+        });
+
+        let options = RunOptions{max_depth: 4, fuel: Some(1000),
+                                 gc: GcPolicy::EveryNAllocations(1)};
+        run_main(&options, |outcome, _| {
+            assert_eq!(outcome.value.unwrap().as_i64(), Some(3));
+            assert_eq!(outcome.max_stack_depth, 4);
+            assert!(outcome.gc.data_freed > 0, "{:?}", outcome.gc);
+        });
+    }
+
+    /// io::read(n) suspends the run, asking the host to read n.
+    fn read<'a>(_: &'a Heap, arguments: &[Datum<'a>]) -> NativeResult<'a> {
+        NativeResult::Pending(arguments[0].clone())
+    }
+
+    /// Run book::main/1 on 3, and have the host answer every read of n with
+    /// 10 * n, checking the reads asked for.
+    fn run_suspending(source: &str, reads: &[i64],
+                      check: impl FnOnce(RunOutcome)) {
+        let mut sigils = Sigils::new();
+        let mut spells: Spells = assemble(source, &mut sigils).unwrap();
+        let read_id = SpellId{spellbook: sigils.intern_str("io"),
+                              spell: sigils.intern_str("read"), arity: 1};
+        spells.insert_suspending_native(read_id, read).unwrap();
+        let id = SpellId{spellbook: sigils.intern_str("book"),
+                         spell: sigils.intern_str("main"), arity: 1};
+        let types = TypeSigils::intern(&mut sigils);
+        let heap = Heap::new();
+        let runtime = Runtime{spells: &spells, heap: &heap, types};
+
+        let options = RunOptions::default();
+        let arguments = [Datum::from_i64(3).unwrap()];
+        let mut outcome = run_with_options(&runtime, id, &arguments, &options);
+        for &expected in reads {
+            assert_eq!(outcome.value.as_ref().err(),
+                       Some(&InterpretError::Suspended));
+            let suspension = outcome.suspension.take().unwrap();
+            let request = suspension.request.as_i64().unwrap();
+            assert_eq!(request, expected);
+
+            // The host does its work while the run is suspended, and may
+            // use the heap in the meantime.
+            let value = unsafe {
+                heap.allocate(types.integer, &[],
+                              &(10 * request).to_le_bytes())
+            };
+            outcome = suspension.resume(&runtime, value, &options);
+        }
+        assert!(outcome.suspension.is_none());
+        check(outcome);
+    }
+
+    #[test]
+    fn test_suspend_and_resume() {
+        run_suspending("
+            spell book::main/1
+                v1 = invoke_static io::read(v0)
+                v2 = invoke_static io::read(v1)
+                v0 = add v1, v2
+                return v0
+        ", &[3, 30], |outcome| {
+            assert_eq!(outcome.value.unwrap().as_i64(), Some(330));
+            assert_eq!(outcome.instructions_executed, 2);
+            assert_eq!(outcome.max_stack_depth, 1);
+        });
+
+        // A tail call to the native spell exits the stack frame before the
+        // run is suspended, so that resuming returns the result right away.
+        run_suspending("
+            spell book::main/1
+                v0 = invoke_static book::inner(v0)
+                return v0
+
+            spell book::inner/1
+                v0 = invoke_static io::read(v0)
+                return v0
+        ", &[3], |outcome| {
+            assert_eq!(outcome.value.unwrap().as_i64(), Some(30));
+            assert_eq!(outcome.instructions_executed, 0);
+        });
+    }
+
+    #[test]
+    fn test_suspend_without_support() {
+        let mut sigils = Sigils::new();
+        let mut spells: Spells = assemble("
+            spell book::main/1
+                v0 = invoke_static io::read(v0)
+                v0 = add v0, v0
+                return v0
+        ", &mut sigils).unwrap();
+        let read_id = SpellId{spellbook: sigils.intern_str("io"),
+                              spell: sigils.intern_str("read"), arity: 1};
+        spells.insert_suspending_native(read_id, read).unwrap();
+        let heap = Heap::new();
+        let types = TypeSigils::intern(&mut sigils);
+        let runtime = Runtime{spells: &spells, heap: &heap, types};
+        let arguments = [Datum::from_i64(3).unwrap()];
+
+        // The entry spell may suspend the run too.
+        let outcome = run_with_options(&runtime, read_id, &arguments,
+                                       &RunOptions::default());
+        let suspension = outcome.suspension.unwrap();
+        assert!(suspension.call_stack.stack_frames.is_empty());
+        let outcome = suspension.resume(&runtime, Datum::from_i64(4).unwrap(),
+                                        &RunOptions::default());
+        assert_eq!(outcome.value.unwrap().as_i64(), Some(4));
+
+        let id = SpellId{spellbook: sigils.intern_str("book"),
+                         spell: sigils.intern_str("main"), arity: 1};
+        assert_eq!(try_run(&runtime, id, &arguments).unwrap_err(),
+                   InterpretError::Suspended);
+    }
+}
@@ -0,0 +1,117 @@
+use super::*;
+
+use std::collections::HashMap;
+
+use sigil::Sigils;
+use spell::Spell;
+use spell::disassemble;
+
+/// How often each instruction was interpreted, by spell and instruction index.
+/// Instructions that were never interpreted are absent.
+pub type Profile = HashMap<(SpellId, usize), u64>;
+
+/// Like [try_run], but also count how often each instruction is interpreted.
+///
+/// If interpretation fails, the counts are discarded along with the call
+/// stack.
+///
+/// [try_run]: fn.try_run.html
+pub fn run_profiled<'a>(runtime:   &Runtime<'a>,
+                        entry:     SpellId,
+                        arguments: &[Datum<'a>],
+                        ) -> Result<(Datum<'a>, Profile), InterpretError> {
+    let mut profile = Profile::new();
+    let mut call_stack = CallStack::new();
+    let result = check_roots(runtime.heap, &mut call_stack, |call_stack| {
+        if let Some(result) = start(runtime, entry, arguments, call_stack)? {
+            return Ok(Some(result));
+        }
+        loop {
+            {
+                let frame = active_stack_frame(call_stack);
+                let index = frame.program_counter.next_instruction;
+                *profile.entry((frame.spell, index)).or_insert(0) += 1;
+            }
+            if let Some(result) = step(runtime, call_stack)? {
+                return Ok(Some(result));
+            }
+        }
+    })?;
+    Ok((finished(result), profile))
+}
+
+/// Like [disassemble], but prefix every line with how often the instruction
+/// was interpreted according to the profile.
+///
+/// [disassemble]: ../spell/fn.disassemble.html
+pub fn disassemble_profiled(id:      SpellId,
+                            spell:   &Spell,
+                            sigils:  &Sigils,
+                            profile: &Profile,
+                            ) -> String {
+    let counts: Vec<u64> =
+        (0 .. spell.instructions.len())
+            .map(|index| profile.get(&(id, index)).cloned().unwrap_or(0))
+            .collect();
+    let width = counts.iter().max().map_or(0, |max| max.to_string().len());
+
+    let mut output = String::new();
+    for (count, line) in counts.iter().zip(disassemble(spell, sigils).lines()) {
+        output.push_str(&format!("{:>width$} {}\n", count, line,
+                                 width = width));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use datum::Heap;
+    use spell::Spells;
+
+    #[test]
+    fn test_run_profiled() {
+        let id = SpellId{spellbook: Sigil(0), spell: Sigil(1), arity: 2};
+        let mut spells = Spells::new();
+        spells.insert(id, Spell{
+            instructions: Box::new([
+                Instruction::BranchIfFalsy{condition: Local(0), target: 3},
+                Instruction::Copy{from: Local(1), to: Local(0)},
+                Instruction::Jump{target: 0},
+                Instruction::Return{result: Local(0)},
+            ]),
+            local_variables: 2,
+            constants: Box::new([]),
+            source_map: None,
+        }).ok().unwrap();
+
+        let heap = Heap::new();
+        let t = unsafe { heap.allocate(Sigil(0), &[], b"t") };
+        let f = unsafe { heap.allocate(Sigil(2), &[], b"f") };
+
+        let types = TypeSigils{falsy: Sigil(2), truthy: Sigil(4),
+                               integer: Sigil(3), string: Sigil(5)};
+        let runtime = Runtime{spells: &spells, heap: &heap, types};
+        let (result, profile) =
+            run_profiled(&runtime, id, &[t, f]).unwrap();
+        assert_eq!(result.auxiliary(), b"f");
+        assert_eq!(profile.len(), 4);
+        assert_eq!(profile[&(id, 0)], 2);
+        assert_eq!(profile[&(id, 1)], 1);
+        assert_eq!(profile[&(id, 2)], 1);
+        assert_eq!(profile[&(id, 3)], 1);
+
+        let mut profile = profile;
+        profile.insert((id, 0), 10);
+        profile.remove(&(id, 2));
+        let spell = spells.get(id).unwrap();
+        assert_eq!(disassemble_profiled(id, spell, &Sigils::new(), &profile),
+                   concat!(
+            "10 0: branch_if_falsy v0, 3\n",
+            " 1 1: v0 = copy v1\n",
+            " 0 2: jump 0\n",
+            " 1 3: return v0\n",
+        ));
+    }
+}
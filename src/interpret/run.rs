@@ -0,0 +1,2211 @@
+use super::*;
+
+use std::fmt;
+
+use spell::Instruction;
+use spell::NativeResult;
+use spell::Spells;
+
+/// Run a spell to completion and return the datum it returns.
+///
+/// The entry spell is invoked with the given arguments, as if by a static
+/// invocation. Interpretation proceeds until the entry spell returns. Invoked
+/// spells are looked up in the spell database of the runtime, and the sigils
+/// of the runtime give instructions their meaning; see [TypeSigils].
+///
+/// Panics if the code is malformed or invokes a spell that does not exist.
+/// See [try_run] for a variant that does not panic.
+///
+/// [TypeSigils]: struct.TypeSigils.html
+/// [try_run]: fn.try_run.html
+pub fn run<'a>(runtime:   &Runtime<'a>,
+               entry:     SpellId,
+               arguments: &[Datum<'a>],
+               ) -> Datum<'a> {
+    try_run(runtime, entry, arguments)
+        .unwrap_or_else(|error| panic!("{:?}", error))
+}
+
+/// Run a spell to completion and return the datum it returns, or return an
+/// error if interpretation fails.
+///
+/// When an error is returned, the entire call stack is discarded.
+pub fn try_run<'a>(runtime:   &Runtime<'a>,
+                   entry:     SpellId,
+                   arguments: &[Datum<'a>],
+                   ) -> Result<Datum<'a>, InterpretError> {
+    try_run_with_max_depth(runtime, entry, arguments, usize::MAX)
+}
+
+/// Like [try_run], but fail with [InterpretError::StackOverflow] when an
+/// invocation would make the call stack deeper than the given number of stack
+/// frames. Tail calls do not make the call stack deeper.
+///
+/// [try_run]: fn.try_run.html
+/// [InterpretError::StackOverflow]:
+///     enum.InterpretError.html#variant.StackOverflow
+pub fn try_run_with_max_depth<'a>(runtime:   &Runtime<'a>,
+                                  entry:     SpellId,
+                                  arguments: &[Datum<'a>],
+                                  max_depth: usize,
+                                  ) -> Result<Datum<'a>, InterpretError> {
+    let mut call_stack = CallStack::with_max_depth(max_depth);
+    check_roots(runtime.heap, &mut call_stack, |call_stack| {
+        if let Some(result) = start(runtime, entry, arguments, call_stack)? {
+            return Ok(Some(result));
+        }
+        loop {
+            if let Some(result) = step(runtime, call_stack)? {
+                return Ok(Some(result));
+            }
+        }
+    }).map(finished)
+}
+
+/// Run a spell until it returns or the fuel runs out.
+///
+/// Every interpreted instruction consumes fuel; see [fuel_cost] for how much.
+/// If the next instruction costs more fuel than is left, interpretation stops
+/// before it, and the suspended call stack is returned so that it can be
+/// resumed with more fuel. Otherwise this behaves like [try_run].
+///
+/// [fuel_cost]: fn.fuel_cost.html
+/// [try_run]: fn.try_run.html
+pub fn run_with_fuel<'a>(runtime:   &Runtime<'a>,
+                         entry:     SpellId,
+                         arguments: &[Datum<'a>],
+                         fuel:      u64,
+                         ) -> Result<Result<Datum<'a>, OutOfFuel<'a>>,
+                                     InterpretError> {
+    let mut call_stack = CallStack::new();
+    let result = check_roots(runtime.heap, &mut call_stack, |call_stack| {
+        match start(runtime, entry, arguments, call_stack)? {
+            Some(result) => Ok(Some(result)),
+            None         => run_fuel(runtime, call_stack, fuel),
+        }
+    })?;
+    Ok(result.ok_or(OutOfFuel{call_stack}))
+}
+
+/// The state of a run that ran out of fuel. See [run_with_fuel].
+///
+/// [run_with_fuel]: fn.run_with_fuel.html
+#[derive(Debug)]
+pub struct OutOfFuel<'a> {
+    /// The call stack at the time the fuel ran out. The program counter of the
+    /// active stack frame points to the instruction that was not interpreted.
+    pub call_stack: CallStack<'a>,
+}
+
+impl<'a> OutOfFuel<'a> {
+    /// Continue the run with more fuel. The runtime must be the same as that
+    /// of the original run.
+    ///
+    /// Returns [InterpretError::EmptyCallStack] if the call stack has no stack
+    /// frames, which is never the case for a call stack returned by
+    /// [run_with_fuel], but may be for one that was built or changed since.
+    ///
+    /// [InterpretError::EmptyCallStack]:
+    ///     enum.InterpretError.html#variant.EmptyCallStack
+    /// [run_with_fuel]: fn.run_with_fuel.html
+    pub fn resume(self, runtime: &Runtime<'a>, fuel: u64)
+        -> Result<Result<Datum<'a>, OutOfFuel<'a>>, InterpretError> {
+        let mut call_stack = self.call_stack;
+        if call_stack.stack_frames.is_empty() {
+            return Err(InterpretError::EmptyCallStack);
+        }
+        let result = check_roots(runtime.heap, &mut call_stack,
+                                 |call_stack| run_fuel(runtime, call_stack,
+                                                       fuel))?;
+        Ok(result.ok_or(OutOfFuel{call_stack}))
+    }
+}
+
+/// Interpret instructions until the outermost stack frame returns, or return
+/// `None` if the next instruction costs more fuel than is left.
+fn run_fuel<'a>(runtime:    &Runtime<'a>,
+                call_stack: &mut CallStack<'a>,
+                mut fuel:   u64,
+                ) -> Result<Option<Datum<'a>>, InterpretError> {
+    loop {
+        let cost = active_stack_frame(call_stack).program_counter
+            .try_get().map_or(0, fuel_cost);
+        if cost > fuel {
+            return Ok(None);
+        }
+        fuel -= cost;
+
+        if let Some(result) = step(runtime, call_stack)? {
+            return Ok(Some(result));
+        }
+    }
+}
+
+/// Like [try_run], but call a function before interpreting each instruction.
+///
+/// The function is given the program counter of the active stack frame, the
+/// instruction it points to, and the local variables of the active stack
+/// frame. This can be used for logging, profiling, or measuring coverage.
+///
+/// [try_run]: fn.try_run.html
+pub fn run_traced<'a, F>(runtime:   &Runtime<'a>,
+                         entry:     SpellId,
+                         arguments: &[Datum<'a>],
+                         mut trace: F,
+                         ) -> Result<Datum<'a>, InterpretError>
+    where F: FnMut(&ProgramCounter<'a>, &Instruction, &[Option<Datum<'a>>]) {
+    let mut call_stack = CallStack::new();
+    check_roots(runtime.heap, &mut call_stack, |call_stack| {
+        if let Some(result) = start(runtime, entry, arguments, call_stack)? {
+            return Ok(Some(result));
+        }
+        loop {
+            {
+                let frame = active_stack_frame(call_stack);
+                if let Some(instruction) = frame.program_counter.try_get() {
+                    trace(&frame.program_counter, instruction,
+                          &frame.local_variables);
+                }
+            }
+            if let Some(result) = step(runtime, call_stack)? {
+                return Ok(Some(result));
+            }
+        }
+    }).map(finished)
+}
+
+/// An interpreter runs a spell one instruction at a time.
+///
+/// Between steps, the call stack can be inspected and modified, for example to
+/// implement breakpoints. The runtime is the one given to [new].
+///
+/// [new]: #method.new
+pub struct Interpreter<'a> {
+    runtime: Runtime<'a>,
+
+    pub call_stack: CallStack<'a>,
+
+    /// The datum returned by a native entry spell, which is returned by the
+    /// first step.
+    returned: Option<Datum<'a>>,
+}
+
+impl fmt::Debug for Interpreter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Interpreter")
+            .field("call_stack", &self.call_stack)
+            .field("returned", &self.returned)
+            .finish()
+    }
+}
+
+/// The result of a single step of an [Interpreter].
+///
+/// [Interpreter]: struct.Interpreter.html
+#[derive(Debug)]
+pub enum StepResult<'a> {
+    /// The entry spell has not yet returned.
+    Running,
+
+    /// The entry spell returned this datum. The call stack is now empty.
+    Returned(Datum<'a>),
+
+    /// Interpretation failed. The call stack is left in place, so that the
+    /// stack frame in which the failure occurred can be inspected.
+    Error(InterpretError),
+}
+
+impl<'a> Interpreter<'a> {
+    /// Create an interpreter that is about to interpret the first instruction
+    /// of the entry spell, or return an error if the entry spell cannot be
+    /// invoked. See [run] for the meaning of the arguments.
+    ///
+    /// [run]: fn.run.html
+    pub fn new(runtime:   &Runtime<'a>,
+               entry:     SpellId,
+               arguments: &[Datum<'a>],
+               ) -> Result<Self, InterpretError> {
+        let mut call_stack = CallStack::new();
+        let returned = check_roots(runtime.heap, &mut call_stack,
+                                   |call_stack| start(runtime, entry,
+                                                      arguments, call_stack))?;
+        Ok(Interpreter{runtime: *runtime, call_stack, returned})
+    }
+
+    /// The runtime the interpreter runs the spell with.
+    pub fn runtime(&self) -> &Runtime<'a> {
+        &self.runtime
+    }
+
+    /// Interpret exactly one instruction.
+    ///
+    /// If the entry spell is native, the first step returns its result without
+    /// interpreting any instructions. Panics if the entry spell has already
+    /// returned.
+    pub fn step(&mut self) -> StepResult<'a> {
+        if let Some(value) = self.returned.take() {
+            return StepResult::Returned(value);
+        }
+        let runtime = &self.runtime;
+        match check_roots(runtime.heap, &mut self.call_stack,
+                          |call_stack| step(runtime, call_stack)) {
+            Ok(None)        => StepResult::Running,
+            Ok(Some(value)) => StepResult::Returned(value),
+            Err(error)      => StepResult::Error(error),
+        }
+    }
+}
+
+/// How much fuel interpreting an instruction consumes.
+///
+/// Most instructions cost one unit. Allocation, and arithmetic, comparisons,
+/// enchantment tests and creating closures, which allocate, cost two units.
+/// Copying a range of variables costs one unit per variable, and at least one.
+/// Invocations set up a stack frame, and cost four units. So does throwing,
+/// which may exit many stack frames.
+pub fn fuel_cost(instruction: &Instruction) -> u64 {
+    match instruction {
+        Instruction::Copy{..}           => 1,
+        Instruction::CopyRange{count, ..} => (*count as u64).max(1),
+        Instruction::Swap{..}           => 1,
+        Instruction::Nop                => 1,
+        Instruction::InvokeStatic{..}   => 4,
+        Instruction::InvokeLinked{..}   => 4,
+        Instruction::InvokeDynamic{..}  => 4,
+        Instruction::InvokeDynamicCached{..} => 4,
+        Instruction::MakeClosure{..}    => 2,
+        Instruction::InvokeClosure{..}  => 4,
+        Instruction::Jump{..}           => 1,
+        Instruction::JumpRelative{..}   => 1,
+        Instruction::Switch{..}         => 1,
+        Instruction::BranchIfTruthy{..} => 1,
+        Instruction::BranchIfFalsy{..}  => 1,
+        Instruction::BranchIfTruthyRelative{..} => 1,
+        Instruction::BranchIfFalsyRelative{..}  => 1,
+        Instruction::Add{..}            => 2,
+        Instruction::Sub{..}            => 2,
+        Instruction::Mul{..}            => 2,
+        Instruction::Div{..}            => 2,
+        Instruction::Equal{..}          => 2,
+        Instruction::LessThan{..}       => 2,
+        Instruction::GreaterThan{..}    => 2,
+        Instruction::Allocate{..}       => 2,
+        Instruction::LoadConstant{..}   => 2,
+        Instruction::GetPointer{..}     => 1,
+        Instruction::EnchantmentOf{..}  => 1,
+        Instruction::IsEnchanted{..}    => 2,
+        Instruction::AuxiliaryLen{..}   => 1,
+        Instruction::PushHandler{..}    => 1,
+        Instruction::PopHandler         => 1,
+        Instruction::Throw{..}          => 4,
+        Instruction::Return{..}         => 1,
+    }
+}
+
+/// Interpret (part of) a run on a call stack with the given function, which
+/// returns the datum the entry spell returned, or `None` if the run is not
+/// over. Every driver runs through here.
+///
+/// In debug builds, check that the interpreter gave back every root it took:
+/// that the heap has as many roots as before, except for those the call
+/// stack gained or lost and the result. This catches root accounting bugs in
+/// control flow, such as stack frames that are popped while unwinding.
+pub(super) fn check_roots<'a, F>(heap:       &Heap,
+                                 call_stack: &mut CallStack<'a>,
+                                 interpret:  F,
+                                 ) -> Result<Option<Datum<'a>>, InterpretError>
+    where F: FnOnce(&mut CallStack<'a>)
+                    -> Result<Option<Datum<'a>>, InterpretError> {
+    if !cfg!(debug_assertions) {
+        return interpret(call_stack);
+    }
+
+    let others = heap.total_roots() - call_stack.roots();
+    let result = interpret(call_stack);
+    let result_roots = match result {
+        Ok(Some(ref datum)) => !datum.is_immediate() as usize,
+        _                   => 0,
+    };
+    debug_assert_eq!(heap.total_roots(),
+                     others + call_stack.roots() + result_roots,
+                     "Interpreter leaked or released roots");
+    result
+}
+
+/// The datum returned by a run that does not stop before it is over.
+pub(super) fn finished(result: Option<Datum>) -> Datum {
+    result.expect("Run stopped before it was over")
+}
+
+/// Invoke the entry spell, pushing its stack frame onto the call stack. If the
+/// entry spell is native, return the datum it returned instead.
+pub(super) fn start<'a>(runtime:    &Runtime<'a>,
+                        entry:      SpellId,
+                        arguments:  &[Datum<'a>],
+                        call_stack: &mut CallStack<'a>,
+                        ) -> Result<Option<Datum<'a>>, InterpretError> {
+    let call = Call{
+        callee:      entry,
+        arguments:   Arguments::Values(arguments.into()),
+        return_into: Local(0),
+        linked:      None,
+        cache:       None,
+    };
+    match invoke(runtime, call, &mut [], false, &mut call_stack.pool)? {
+        Invocation::Frame(frame) => {
+            call_stack.push(frame)?;
+            Ok(None)
+        },
+        Invocation::Value(value) => Ok(Some(value)),
+        Invocation::Pending(request) => {
+            call_stack.pending = Some(request);
+            Err(InterpretError::Suspended)
+        },
+    }
+}
+
+/// Interpret the next instruction of the active stack frame and apply the
+/// resulting mutation. If this exits the outermost stack frame, return the
+/// datum it returned.
+pub(super) fn step<'a>(runtime:    &Runtime<'a>,
+                       call_stack: &mut CallStack<'a>,
+                       ) -> Result<Option<Datum<'a>>, InterpretError> {
+    let mutation = {
+        let frame = active_stack_frame(call_stack);
+        let spell = frame.spell;
+        let program_counter = frame.program_counter;
+
+        // Returning exits the stack frame, so the result is moved out of its
+        // local variables rather than cloned. It stays rooted throughout, as
+        // moving a datum does not change its root count.
+        if let Some(Instruction::Return{result}) = program_counter.try_get() {
+            let value = frame.local_variables.get_mut(result.0 as usize)
+                .ok_or(InterpretError::LocalOutOfBounds(*result))?
+                .take()
+                .ok_or(InterpretError::LocalUninitialized(*result))?;
+            return exit_stack_frame(call_stack, value);
+        }
+
+        let mutation = try_interpret_instruction(runtime.heap, runtime.types,
+                                                 program_counter,
+                                                 &mut frame.local_variables)
+            .map_err(|error| match error {
+                InterpretError::ProgramCounterOutOfBounds
+                    if fell_off_end(program_counter) =>
+                    InterpretError::FellOffEnd(spell),
+                _ => error,
+            })?;
+
+        // A jump out of bounds is reported where it happens, so that only
+        // advancing past the last instruction counts as falling off the end.
+        let advanced = program_counter.advance().next_instruction;
+        let jump = mutation.jump;
+        if jump.try_get().is_none() && jump.next_instruction != advanced {
+            return Err(InterpretError::ProgramCounterOutOfBounds);
+        }
+        mutation
+    };
+    apply_mutation(runtime, call_stack, mutation)
+}
+
+/// Whether the program counter is just past the last instruction, and that
+/// instruction falls through to it. This is how spells without a return at
+/// the end end up out of bounds, as jumps out of bounds are reported where
+/// they happen; a jump from the last instruction to just past it is only
+/// told apart from advancing by the kind of instruction. Empty spells fall
+/// off the end right away.
+fn fell_off_end(program_counter: ProgramCounter) -> bool {
+    let instructions = program_counter.instructions;
+    program_counter.next_instruction == instructions.len() &&
+        instructions.last().is_none_or(Instruction::falls_through)
+}
+
+/// Apply a call stack mutation. If this exits the outermost stack frame,
+/// return the datum it returned.
+fn apply_mutation<'a>(runtime:    &Runtime<'a>,
+                      call_stack: &mut CallStack<'a>,
+                      mutation:   CallStackMutation<'a>,
+                      ) -> Result<Option<Datum<'a>>, InterpretError> {
+    let CallStackMutation{jump, exit, call, tail_call, handler, throw} =
+        mutation;
+
+    match handler {
+        None => (),
+        Some(HandlerChange::Push(handler)) =>
+            active_stack_frame(call_stack).handlers.push(handler),
+        Some(HandlerChange::Pop) => {
+            active_stack_frame(call_stack).handlers.pop()
+                .ok_or(InterpretError::NoHandler)?;
+        },
+    }
+
+    if let Some(value) = throw {
+        return throw_datum(call_stack, value);
+    }
+
+    // Exiting the stack frame would lose its exception handlers, so a tail
+    // call becomes an ordinary call followed by the return.
+    let tail_call = tail_call &&
+        active_stack_frame(call_stack).handlers.is_empty();
+
+    match (exit, call) {
+
+        (None, None) => {
+            active_stack_frame(call_stack).program_counter = jump;
+            Ok(None)
+        },
+
+        (None, Some(call)) if !tail_call => {
+            let return_into = call.return_into;
+            let CallStack{stack_frames, pool, pending, ..} = &mut *call_stack;
+            let caller = stack_frames.last_mut().expect("Call stack is empty");
+            let callee = invoke(runtime, call, &mut caller.local_variables,
+                                false, pool)?;
+
+            // The program counter of the caller is only advanced past the
+            // invocation once it succeeds, so that a backtrace after an error
+            // points at the invocation.
+            match callee {
+                Invocation::Frame(callee) => {
+                    call_stack.push(callee)?;
+                    let depth = call_stack.stack_frames.len();
+                    let caller = &mut call_stack.stack_frames[depth - 2];
+                    caller.program_counter = jump;
+                    caller.return_into = return_into;
+                },
+                Invocation::Value(value) => {
+                    store(caller, return_into, value)?;
+                    caller.program_counter = jump;
+                },
+                Invocation::Pending(request) => {
+                    // Resuming stores the result as returning would.
+                    caller.program_counter = jump;
+                    caller.return_into = return_into;
+                    *pending = Some(request);
+                    return Err(InterpretError::Suspended);
+                },
+            }
+            Ok(None)
+        },
+
+        (Some(value), None) =>
+            exit_stack_frame(call_stack, value),
+
+        (Some(_), Some(_)) => panic!("Exit and call are both set"),
+
+        (None, Some(call)) => {
+            // The callee replaces the active stack frame, so that it returns
+            // directly into the caller of the active stack frame, whose
+            // return_into is left untouched. The arguments can be moved out of
+            // the active stack frame, as it is exited.
+            let CallStack{stack_frames, pool, ..} = &mut *call_stack;
+            let caller = stack_frames.last_mut().expect("Call stack is empty");
+            match invoke(runtime, call, &mut caller.local_variables, true,
+                         pool)? {
+                Invocation::Frame(callee) => {
+                    call_stack.pop();
+                    call_stack.stack_frames.push(callee);
+                    Ok(None)
+                },
+                Invocation::Value(value) =>
+                    exit_stack_frame(call_stack, value),
+                Invocation::Pending(request) => {
+                    call_stack.pop();
+                    call_stack.pending = Some(request);
+                    Err(InterpretError::Suspended)
+                },
+            }
+        },
+
+    }
+}
+
+/// Exit stack frames until one has an exception handler, and let that handler
+/// catch the datum.
+fn throw_datum<'a>(call_stack: &mut CallStack<'a>, value: Datum<'a>)
+    -> Result<Option<Datum<'a>>, InterpretError> {
+    while let Some(frame) = call_stack.stack_frames.last_mut() {
+        if let Some(handler) = frame.handlers.pop() {
+            frame.program_counter = frame.program_counter.jump(handler.target);
+            if frame.program_counter.try_get().is_none() {
+                return Err(InterpretError::ProgramCounterOutOfBounds);
+            }
+            store(frame, handler.exception, value)?;
+            return Ok(None);
+        }
+        call_stack.pop();
+    }
+    Err(InterpretError::Uncaught{
+        enchantment: value.enchantment(),
+        auxiliary:   value.auxiliary().into(),
+    })
+}
+
+/// Pop the active stack frame and return a datum into its caller. If there is
+/// no caller, return the datum instead.
+fn exit_stack_frame<'a>(call_stack: &mut CallStack<'a>, value: Datum<'a>)
+    -> Result<Option<Datum<'a>>, InterpretError> {
+    call_stack.pop();
+    match call_stack.stack_frames.last_mut() {
+        None => Ok(Some(value)),
+        Some(caller) => {
+            let return_into = caller.return_into;
+            store(caller, return_into, value)?;
+            Ok(None)
+        },
+    }
+}
+
+/// Write a datum to a local variable of a stack frame.
+pub(super) fn store<'a>(frame: &mut StackFrame<'a>,
+                        local: Local,
+                        value: Datum<'a>,
+                        ) -> Result<(), InterpretError> {
+    *frame.local_variables
+        .get_mut(local.0 as usize)
+        .ok_or(InterpretError::LocalOutOfBounds(local))?
+        = Some(value);
+    Ok(())
+}
+
+/// What invoking a spell yields.
+enum Invocation<'a> {
+    /// The spell consists of instructions, which are to be interpreted in
+    /// this stack frame.
+    Frame(StackFrame<'a>),
+
+    /// The spell is native, and has already returned this datum.
+    Value(Datum<'a>),
+
+    /// The spell is native, and suspended the run waiting for this datum.
+    Pending(Datum<'a>),
+}
+
+/// Invoke a spell, reading the arguments from the local variables of the
+/// caller where the call says so. Native spells are called right away, and for
+/// other spells the stack frame is created, with local variables from the
+/// pool, and the arguments written into it directly. If the caller is exiting,
+/// which is the case for tail calls, the arguments are moved out of its local
+/// variables.
+fn invoke<'a>(runtime: &Runtime<'a>,
+              call:    Call<'a>,
+              caller:  &mut [Option<Datum<'a>>],
+              exiting: bool,
+              pool:    &mut FramePool<'a>,
+              ) -> Result<Invocation<'a>, InterpretError> {
+    let Call{callee, arguments, linked, cache, ..} = call;
+    let spells = runtime.spells;
+
+    // Linked invocations only ever refer to spells consisting of
+    // instructions, so neither lookup by id is needed.
+    let spell = match linked.and_then(|i| spells.get_linked(i, callee)) {
+        Some(spell) => spell,
+        None => {
+            if let Some(native) = spells.get_any_native(callee) {
+                debug_assert_eq!(arguments.len(), callee.arity,
+                                 "Native spell invoked with wrong number of \
+                                  arguments");
+                let mut values = Vec::with_capacity(arguments.len());
+                pass_arguments(arguments, caller, exiting,
+                               |value| values.push(value))?;
+                return Ok(match native.call(runtime.heap, &values) {
+                    NativeResult::Return(value) => Invocation::Value(value),
+                    NativeResult::Pending(request) =>
+                        Invocation::Pending(request),
+                });
+            }
+            let spell = spells.get(callee)
+                .ok_or_else(|| spell_not_found(spells, callee))?;
+            if let Some(cache) = cache {
+                if let Some(index) = spells.index_of(callee) {
+                    cache.set(callee.spellbook, index);
+                }
+            }
+            spell
+        },
+    };
+
+    if arguments.len() > spell.local_variables {
+        return Err(InterpretError::TooFewLocalVariables(callee));
+    }
+    let mut local_variables = pool.acquire(spell.local_variables);
+    let mut next = local_variables.iter_mut();
+    pass_arguments(arguments, caller, exiting, |value| {
+        *next.next().expect("Arguments were counted") = Some(value);
+    })?;
+    Ok(Invocation::Frame(StackFrame::with_local_variables(callee, spell,
+                                                          local_variables)))
+}
+
+/// Pass each argument of a call to a function, in order.
+///
+/// If the caller is exiting, arguments are moved out of its local variables;
+/// a local variable that is passed more than once is copied all but the last
+/// time. Every local variable is checked before any is moved, so that the
+/// caller is left intact if this fails.
+fn pass_arguments<'a, F>(arguments: Arguments<'a>,
+                         caller:    &mut [Option<Datum<'a>>],
+                         exiting:   bool,
+                         mut pass:  F,
+                         ) -> Result<(), InterpretError>
+    where F: FnMut(Datum<'a>) {
+    let (closure, receiver, locals) = match arguments {
+        Arguments::Values(values) => {
+            values.into_vec().into_iter().for_each(pass);
+            return Ok(());
+        },
+        Arguments::Locals{closure, receiver, locals} =>
+            (closure, receiver, locals),
+    };
+
+    check_locals(caller, receiver.as_slice())?;
+    check_locals(caller, locals)?;
+
+    if let Some(closure) = closure {
+        closure.pointers().iter().cloned().for_each(&mut pass);
+    }
+    let offset = receiver.is_some() as usize;
+    for (position, &local) in receiver.iter().chain(locals).enumerate() {
+        let passed_later = locals[position + 1 - offset ..].contains(&local);
+        let variable = &mut caller[local.0 as usize];
+        let value = if exiting && !passed_later {
+            variable.take()
+        } else {
+            variable.clone()
+        };
+        pass(value.expect("Argument was checked"));
+    }
+    Ok(())
+}
+
+/// The error for invoking a spell that is not in the spell database.
+fn spell_not_found(spells: &Spells, callee: SpellId) -> InterpretError {
+    let expected = spells.arities(callee.spellbook, callee.spell);
+    if expected.is_empty() {
+        InterpretError::SpellNotFound(callee)
+    } else {
+        InterpretError::ArityMismatch{spell: callee, expected}
+    }
+}
+
+pub(super) fn active_stack_frame<'a, 'b>(call_stack: &'b mut CallStack<'a>)
+    -> &'b mut StackFrame<'a> {
+    call_stack.stack_frames.last_mut().expect("Call stack is empty")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::slice;
+
+    use datum::IMMEDIATE_ENCHANTMENT;
+    use sigil::Sigils;
+    use spell::ConstantValue;
+    use spell::SourceSpan;
+    use spell::Spell;
+
+    const BOOK:   Sigil = Sigil(0);
+    const FIRST:  Sigil = Sigil(1);
+    const SECOND: Sigil = Sigil(2);
+    const MAIN:   Sigil = Sigil(3);
+    const FALSE:  Sigil = Sigil(4);
+    const INT:    Sigil = Sigil(5);
+    const TRUE:   Sigil = Sigil(6);
+    const STR:    Sigil = Sigil(7);
+
+    const TYPES: TypeSigils = TypeSigils{falsy: FALSE, truthy: TRUE,
+                                         integer: INT, string: STR};
+
+    fn runtime<'a>(spells: &'a Spells, heap: &'a Heap) -> Runtime<'a> {
+        Runtime{spells, heap, types: TYPES}
+    }
+
+    fn id(spellbook: Sigil, spell: Sigil, arity: usize) -> SpellId {
+        SpellId{spellbook, spell, arity}
+    }
+
+    fn spell(local_variables: usize, instructions: Vec<Instruction>) -> Spell {
+        Spell{instructions: instructions.into_boxed_slice(), local_variables,
+              constants: Box::new([]), source_map: None}
+    }
+
+    fn run_main<'a>(spells:    &'a Spells,
+                    heap:      &'a Heap,
+                    arity:     usize,
+                    arguments: &[Datum<'a>],
+                    ) -> Datum<'a> {
+        run(&runtime(spells, heap), id(BOOK, MAIN, arity), arguments)
+    }
+
+    fn try_run_main<'a>(spells:    &'a Spells,
+                        heap:      &'a Heap,
+                        arity:     usize,
+                        arguments: &[Datum<'a>],
+                        ) -> Result<Datum<'a>, InterpretError> {
+        try_run(&runtime(spells, heap), id(BOOK, MAIN, arity), arguments)
+    }
+
+    #[test]
+    fn test_run_return_argument() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(1, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let datum = unsafe { heap.allocate(BOOK, &[], b"a") };
+
+        let result = run_main(&spells, &heap, 1, &[datum]);
+        assert_eq!(result.auxiliary(), b"a");
+    }
+
+    #[test]
+    fn test_run_invoke_static() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 2), spell(3, vec![
+            Instruction::InvokeStatic{
+                result:    Local(2),
+                spellbook: BOOK,
+                spell:     SECOND,
+                arguments: Box::new([Local(0), Local(1)]),
+            },
+            Instruction::Return{result: Local(2)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, SECOND, 2), spell(2, vec![
+            Instruction::Copy{from: Local(1), to: Local(0)},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+        let b = unsafe { heap.allocate(BOOK, &[], b"b") };
+
+        let result = run_main(&spells, &heap, 2, &[a, b]);
+        assert_eq!(result.auxiliary(), b"b");
+    }
+
+    #[test]
+    fn test_run_invoke_dynamic() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 2), spell(3, vec![
+            Instruction::InvokeDynamic{
+                result:    Local(2),
+                spell:     FIRST,
+                receiver:  Local(1),
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Return{result: Local(2)},
+        ])).ok().unwrap();
+        spells.insert(id(SECOND, FIRST, 2), spell(2, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK,   &[], b"a") };
+        let b = unsafe { heap.allocate(SECOND, &[], b"b") };
+
+        let result = run_main(&spells, &heap, 2, &[a, b]);
+        assert_eq!(result.auxiliary(), b"b");
+    }
+
+    #[test]
+    fn test_run_invoke_result_is_argument() {
+        // main(a) = first(a), where first(a) wraps a. The invocation is not a
+        // tail call, so the old value of the local is passed, and the wrapper
+        // replaces it once first returns.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::InvokeStatic{
+                result:    Local(0),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Copy{from: Local(0), to: Local(1)},
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 1), spell(2, vec![
+            Instruction::Allocate{result:      Local(1),
+                                  enchantment: FIRST,
+                                  pointers:    Box::new([Local(0)]),
+                                  auxiliary:   Box::new([])},
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+
+        let result = run_main(&spells, &heap, 1, &[a]);
+        assert_eq!(result.enchantment(), FIRST);
+        assert_eq!(result.pointers()[0].auxiliary(), b"a");
+    }
+
+    #[test]
+    fn test_run_nested_invocations() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::InvokeStatic{
+                result:    Local(1),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::InvokeStatic{
+                result:    Local(1),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(1)]),
+            },
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 1), spell(1, vec![
+            Instruction::InvokeStatic{
+                result:    Local(0),
+                spellbook: BOOK,
+                spell:     SECOND,
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, SECOND, 1), spell(1, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+
+        let result = run_main(&spells, &heap, 1, slice::from_ref(&a));
+        assert_eq!(result.auxiliary(), b"a");
+
+        drop(a);
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 0) }
+
+        drop(result);
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 1) }
+    }
+
+    #[test]
+    fn test_apply_mutation_tail_call() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 1), spell(1, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+
+        let mut call_stack = CallStack::new();
+        let main = spells.get(id(BOOK, MAIN, 1)).unwrap();
+        let mut caller = StackFrame::new(id(BOOK, MAIN, 1), main,
+                                         Box::new([a.clone()]));
+        caller.return_into = Local(1);
+        call_stack.stack_frames.push(caller);
+        let callee = StackFrame::new(id(BOOK, MAIN, 1), main,
+                                     Box::new([a.clone()]));
+        call_stack.stack_frames.push(callee);
+
+        let jump = active_stack_frame(&mut call_stack).program_counter;
+        let mutation = CallStackMutation{
+            jump,
+            exit: None,
+            call: Some(Call{
+                callee:      id(BOOK, FIRST, 1),
+                arguments:   Arguments::Values(Box::new([a.clone()])),
+                return_into: Local(0),
+                linked:      None,
+                cache:       None,
+            }),
+            tail_call: true,
+            handler: None,
+            throw: None,
+        };
+
+        let result = apply_mutation(&runtime(&spells, &heap), &mut call_stack,
+                                    mutation);
+        assert!(result.unwrap().is_none());
+        assert_eq!(call_stack.stack_frames.len(), 2);
+        assert_eq!(call_stack.stack_frames[0].return_into, Local(1));
+        assert_eq!(call_stack.stack_frames[1].local_variables.len(), 1);
+    }
+
+    #[test]
+    fn test_frame_pool() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        let main = spells.get(id(BOOK, MAIN, 1)).unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+        let mut call_stack = CallStack::new();
+        call_stack.push(StackFrame::new(id(BOOK, MAIN, 1), main,
+                                        Box::new([a]))).unwrap();
+        let pooled = call_stack.stack_frames[0].local_variables.as_ptr();
+
+        // Popping the stack frame keeps its local variables, but not the
+        // data in them.
+        call_stack.pop();
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 1) }
+
+        let local_variables = call_stack.pool.acquire(2);
+        assert_eq!(local_variables.as_ptr(), pooled);
+        assert!(local_variables.iter().all(Option::is_none));
+        let fresh = call_stack.pool.acquire(2);
+        assert_ne!(fresh.as_ptr(), pooled);
+    }
+
+    #[test]
+    fn test_try_run_spell_not_found() {
+        let spells = Spells::new();
+        let heap = Heap::new();
+        let result = try_run_main(&spells, &heap, 0, &[]);
+        assert_eq!(result.unwrap_err(),
+                   InterpretError::SpellNotFound(id(BOOK, MAIN, 0)));
+    }
+
+    #[test]
+    fn test_try_run_invoke_static_not_found() {
+        let mut sigils = Sigils::new();
+        let other = sigils.intern_str("other");
+        let missing = sigils.intern_str("missing");
+
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 0), spell(1, vec![
+            Instruction::InvokeStatic{
+                result:    Local(0),
+                spellbook: other,
+                spell:     missing,
+                arguments: Box::new([]),
+            },
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let error = try_run_main(&spells, &heap, 0, &[]).unwrap_err();
+        assert_eq!(error,
+                   InterpretError::SpellNotFound(id(other, missing, 0)));
+        assert_eq!(error.describe(&sigils),
+                   "spell other::missing/0 is not defined");
+
+        // The error unwinds the run, which releases the roots it held.
+        assert_eq!(heap.total_roots(), 0);
+    }
+
+    #[test]
+    fn test_try_run_invoke_dynamic_not_found() {
+        let mut sigils = Sigils::new();
+        let point = sigils.intern_str("point");
+        let norm = sigils.intern_str("norm");
+
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::InvokeDynamic{
+                result:    Local(1),
+                spell:     norm,
+                receiver:  Local(0),
+                arguments: Box::new([]),
+            },
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+        spells.insert(id(SECOND, norm, 1), spell(1, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        // The receiver is enchanted with point, for which norm is not
+        // defined.
+        let heap = Heap::new();
+        let receiver = unsafe { heap.allocate(point, &[], &[]) };
+        let error = try_run_main(&spells, &heap, 1, &[receiver])
+            .unwrap_err();
+        assert_eq!(error, InterpretError::SpellNotFound(id(point, norm, 1)));
+        assert_eq!(error.describe(&sigils),
+                   "spell point::norm/1 is not defined");
+        assert_eq!(error.to_string(),
+                   format!("spell {:?}::{:?}/1 is not defined", point, norm));
+        assert_eq!(heap.total_roots(), 0);
+    }
+
+    #[test]
+    fn test_try_run_error_releases_roots() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::InvokeStatic{
+                result:    Local(1),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 1), spell(2, vec![
+            Instruction::Copy{from: Local(0), to: Local(1)},
+            Instruction::Return{result: Local(2)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+        let roots_before = heap.total_roots();
+
+        let result = try_run_main(&spells, &heap, 1, slice::from_ref(&a));
+        assert_eq!(result.unwrap_err(),
+                   InterpretError::LocalOutOfBounds(Local(2)));
+        assert_eq!(heap.total_roots(), roots_before);
+    }
+
+    #[test]
+    fn test_drivers_check_roots() {
+        // Every driver checks the roots when the run fails, after an exception
+        // unwound a stack frame that allocated, and when it runs out of fuel.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(3, vec![
+            Instruction::PushHandler{target: 2, exception: Local(2)},
+            Instruction::InvokeStatic{result: Local(1), spellbook: BOOK,
+                                      spell: FIRST,
+                                      arguments: Box::new([Local(0)])},
+            Instruction::Return{result: Local(3)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 1), spell(2, vec![
+            Instruction::Allocate{result: Local(1), enchantment: BOOK,
+                                  pointers: Box::new([Local(0)]),
+                                  auxiliary: Box::new(*b"b")},
+            Instruction::Throw{value: Local(1)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+        let roots_before = heap.total_roots();
+        let runtime = runtime(&spells, &heap);
+        let entry = id(BOOK, MAIN, 1);
+        let arguments = slice::from_ref(&a);
+        let error = InterpretError::LocalOutOfBounds(Local(3));
+
+        let out_of_fuel = run_with_fuel(&runtime, entry, arguments, 3)
+            .unwrap().unwrap_err();
+        assert_eq!(heap.total_roots(), roots_before + 1);
+        assert_eq!(out_of_fuel.resume(&runtime, 100).unwrap_err(), error);
+        assert_eq!(heap.total_roots(), roots_before);
+
+        let outcome = run_with_options(&runtime, entry, arguments,
+                                       &RunOptions::default());
+        assert_eq!(outcome.value.unwrap_err(), error);
+        assert_eq!(run_traced(&runtime, entry, arguments, |_, _, _| ())
+                       .unwrap_err(), error);
+        assert_eq!(run_profiled(&runtime, entry, arguments).unwrap_err(),
+                   error);
+        assert_eq!(heap.total_roots(), roots_before);
+
+        let mut interpreter = Interpreter::new(&runtime, entry, arguments)
+            .unwrap();
+        let result = loop {
+            match interpreter.step() {
+                StepResult::Running      => (),
+                StepResult::Returned(_) => panic!("Spell returned"),
+                StepResult::Error(error) => break error,
+            }
+        };
+        assert_eq!(result, error);
+        drop(interpreter);
+        assert_eq!(heap.total_roots(), roots_before);
+    }
+
+    #[test]
+    fn test_run_return_moves_result() {
+        // The result of the callee is moved out of its stack frame and into
+        // the caller, and then out of the entry stack frame.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 0), spell(2, vec![
+            Instruction::Allocate{result: Local(0), enchantment: BOOK,
+                                  pointers: Box::new([]),
+                                  auxiliary: Box::new(*b"inner")},
+            Instruction::InvokeStatic{result: Local(1), spellbook: BOOK,
+                                      spell: FIRST,
+                                      arguments: Box::new([Local(0)])},
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 1), spell(2, vec![
+            Instruction::Allocate{result: Local(1), enchantment: BOOK,
+                                  pointers: Box::new([Local(0)]),
+                                  auxiliary: Box::new(*b"outer")},
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let result = run_main(&spells, &heap, 0, &[]);
+        assert_eq!(result.root_count(), 1);
+        assert_eq!(heap.total_roots(), 1);
+
+        // The result remains rooted, and keeps what it points to alive.
+        assert_eq!(heap.collect_garbage().data_freed, 0);
+        assert_eq!(result.auxiliary(), b"outer");
+        assert_eq!(result.pointers()[0].auxiliary(), b"inner");
+
+        drop(result);
+        assert_eq!(heap.collect_garbage().data_freed, 2);
+    }
+
+    #[test]
+    fn test_try_run_return_into_out_of_bounds() {
+        let heap = Heap::new();
+        let invoke = |spell| Instruction::InvokeStatic{
+            result:    Local(1),
+            spellbook: BOOK,
+            spell,
+            arguments: Box::new([]),
+        };
+
+        // The result of a native spell is stored right away, and that of a
+        // spell consisting of instructions when it returns.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 0), spell(1, vec![
+            invoke(FIRST),
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert_native(id(BOOK, FIRST, 0),
+                             |_, _| Datum::from_i64(0).unwrap())
+            .ok().unwrap();
+        let result = try_run_main(&spells, &heap, 0, &[]);
+        assert_eq!(result.unwrap_err(),
+                   InterpretError::LocalOutOfBounds(Local(1)));
+
+        spells.remove(id(BOOK, MAIN, 0));
+        spells.insert(id(BOOK, MAIN, 0), spell(1, vec![
+            invoke(SECOND),
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, SECOND, 0), spell(1, vec![
+            Instruction::Allocate{result:      Local(0),
+                                  enchantment: BOOK,
+                                  pointers:    Box::new([]),
+                                  auxiliary:   Box::new([])},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        let result = try_run_main(&spells, &heap, 0, &[]);
+        assert_eq!(result.unwrap_err(),
+                   InterpretError::LocalOutOfBounds(Local(1)));
+    }
+
+    #[test]
+    fn test_try_run_uninitialized_local() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 0), spell(1, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let result = try_run_main(&spells, &heap, 0, &[]);
+        assert_eq!(result.unwrap_err(),
+                   InterpretError::LocalUninitialized(Local(0)));
+    }
+
+    #[test]
+    fn test_try_run_empty_spell() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 0), spell(0, vec![])).ok().unwrap();
+
+        let heap = Heap::new();
+        let result = try_run_main(&spells, &heap, 0, &[]);
+        assert_eq!(result.unwrap_err(),
+                   InterpretError::FellOffEnd(id(BOOK, MAIN, 0)));
+    }
+
+    #[test]
+    fn test_try_run_fell_off_end() {
+        // The invoked spell has no return at the end.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(1, vec![
+            Instruction::InvokeStatic{result: Local(0), spellbook: BOOK,
+                                      spell: FIRST,
+                                      arguments: Box::new([Local(0)])},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 1), spell(1, vec![
+            Instruction::Add{result: Local(0), lhs: Local(0), rhs: Local(0)},
+            Instruction::BranchIfFalsy{condition: Local(0), target: 0},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let argument = Datum::from_i64(1).unwrap();
+        let error = try_run_main(&spells, &heap, 1, &[argument]).unwrap_err();
+        assert_eq!(error, InterpretError::FellOffEnd(id(BOOK, FIRST, 1)));
+        assert_eq!(error.to_string(),
+                   "spell Sigil(0)::Sigil(1)/1 fell off the end");
+    }
+
+    #[test]
+    fn test_try_run_jump_out_of_bounds() {
+        // Jumping just past the last instruction is not falling off the end,
+        // and neither is catching an exception there.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 0), spell(0, vec![
+            Instruction::Jump{target: 2},
+            Instruction::Nop,
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, MAIN, 1), spell(1, vec![
+            Instruction::PushHandler{target: 2, exception: Local(0)},
+            Instruction::Throw{value: Local(0)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, MAIN, 2), spell(2, vec![
+            Instruction::Nop,
+            Instruction::Jump{target: 2},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let argument = Datum::from_i64(1).unwrap();
+        let pair = [argument.clone(), argument.clone()];
+        for arguments in &[&[], slice::from_ref(&argument), &pair] {
+            let result = try_run_main(&spells, &heap, arguments.len(),
+                                      arguments);
+            assert_eq!(result.unwrap_err(),
+                       InterpretError::ProgramCounterOutOfBounds);
+        }
+    }
+
+    #[test]
+    fn test_run_branch() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 3), spell(3, vec![
+            Instruction::BranchIfTruthy{condition: Local(0), target: 2},
+            Instruction::Return{result: Local(2)},
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let t = unsafe { heap.allocate(BOOK,  &[], b"t") };
+        let f = unsafe { heap.allocate(FALSE, &[], b"f") };
+        let a = unsafe { heap.allocate(BOOK,  &[], b"a") };
+        let b = unsafe { heap.allocate(BOOK,  &[], b"b") };
+
+        let result = run_main(&spells, &heap, 3, &[t, a.clone(), b.clone()]);
+        assert_eq!(result.auxiliary(), b"a");
+
+        let result = run_main(&spells, &heap, 3, &[f, a, b]);
+        assert_eq!(result.auxiliary(), b"b");
+    }
+
+    #[test]
+    fn test_run_jump_out_of_bounds() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 0), spell(0, vec![
+            Instruction::Jump{target: 1},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let result = try_run_main(&spells, &heap, 0, &[]);
+        assert_eq!(result.unwrap_err(),
+                   InterpretError::ProgramCounterOutOfBounds);
+    }
+
+    #[test]
+    fn test_run_loop() {
+        // Skip over an infinite loop by jumping past it.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(1, vec![
+            Instruction::Jump{target: 2},
+            Instruction::Jump{target: 1},
+            Instruction::BranchIfFalsy{condition: Local(0), target: 1},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+
+        let result = run_main(&spells, &heap, 1, &[a]);
+        assert_eq!(result.auxiliary(), b"a");
+    }
+
+    #[test]
+    fn test_run_immediates() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::InvokeDynamic{
+                result:    Local(1),
+                spell:     FIRST,
+                receiver:  Local(0),
+                arguments: Box::new([]),
+            },
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+        spells.insert(id(IMMEDIATE_ENCHANTMENT, FIRST, 1), spell(1, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let value = Datum::from_i64(-5).unwrap();
+
+        let result = run_main(&spells, &heap, 1, &[value]);
+        assert_eq!(result.as_i64(), Some(-5));
+    }
+
+    #[test]
+    fn test_run_arithmetic() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 2), spell(3, vec![
+            Instruction::Add{result: Local(2), lhs: Local(0), rhs: Local(1)},
+            Instruction::Mul{result: Local(2), lhs: Local(2), rhs: Local(0)},
+            Instruction::Return{result: Local(2)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = Datum::from_i64(3).unwrap();
+        let b = unsafe { heap.allocate(INT, &[], &4i64.to_le_bytes()) };
+
+        let result = run_main(&spells, &heap, 2, &[a, b]);
+        assert_eq!(result.enchantment(), INT);
+        assert_eq!(result.auxiliary(), &21i64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_run_with_fuel() {
+        // Count down to zero, then return.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 2), spell(2, vec![
+            Instruction::BranchIfFalsy{condition: Local(0), target: 3},
+            Instruction::Sub{result: Local(0), lhs: Local(0), rhs: Local(1)},
+            Instruction::Jump{target: 0},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let n = unsafe { heap.allocate(INT, &[], &3i64.to_le_bytes()) };
+        let one = Datum::from_i64(1).unwrap();
+
+        // Integers are never falsy here, so the loop runs forever.
+        let result = run_with_fuel(&runtime(&spells, &heap),
+                                   id(BOOK, MAIN, 2), &[n, one], 101);
+        let out_of_fuel = result.unwrap().unwrap_err();
+        let frame = &out_of_fuel.call_stack.stack_frames[0];
+        assert_eq!(frame.program_counter.next_instruction, 1);
+        assert_eq!(frame.local_variables[0].as_ref().unwrap().auxiliary(),
+                   &(3i64 - 25).to_le_bytes());
+
+        // Falsify the counter and top up the fuel.
+        let mut out_of_fuel = out_of_fuel;
+        let f = unsafe { heap.allocate(FALSE, &[], &[]) };
+        let frame = &mut out_of_fuel.call_stack.stack_frames[0];
+        frame.program_counter = frame.program_counter.jump(0);
+        frame.local_variables[0] = Some(f);
+
+        let result = out_of_fuel.resume(&runtime(&spells, &heap), 1);
+        let out_of_fuel = result.unwrap().unwrap_err();
+        let result = out_of_fuel.resume(&runtime(&spells, &heap), 2);
+        assert_eq!(result.unwrap().unwrap().enchantment(), FALSE);
+    }
+
+    #[test]
+    fn test_run_with_fuel_error() {
+        let spells = Spells::new();
+        let heap = Heap::new();
+        let result = run_with_fuel(&runtime(&spells, &heap),
+                                   id(BOOK, MAIN, 0), &[], 100);
+        assert_eq!(result.unwrap_err(),
+                   InterpretError::SpellNotFound(id(BOOK, MAIN, 0)));
+    }
+
+    #[test]
+    fn test_out_of_fuel_resume_empty() {
+        let spells = Spells::new();
+        let heap = Heap::new();
+        let out_of_fuel = OutOfFuel{call_stack: CallStack::new()};
+        let result = out_of_fuel.resume(&runtime(&spells, &heap), 100);
+        assert_eq!(result.unwrap_err(), InterpretError::EmptyCallStack);
+    }
+
+    #[test]
+    fn test_try_run_stack_overflow() {
+        // Recurse until the argument is falsy, without tail calls.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::BranchIfFalsy{condition: Local(0), target: 3},
+            Instruction::InvokeStatic{
+                result:    Local(1),
+                spellbook: BOOK,
+                spell:     MAIN,
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Copy{from: Local(1), to: Local(0)},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+        let f = unsafe { heap.allocate(FALSE, &[], b"f") };
+
+        let result = try_run_with_max_depth(&runtime(&spells, &heap),
+                                            id(BOOK, MAIN, 1), &[a], 10);
+        assert_eq!(result.unwrap_err(),
+                   InterpretError::StackOverflow(id(BOOK, MAIN, 1)));
+
+        let result = try_run_with_max_depth(&runtime(&spells, &heap),
+                                            id(BOOK, MAIN, 1),
+                                            slice::from_ref(&f), 1);
+        assert_eq!(result.unwrap().auxiliary(), b"f");
+
+        let result = try_run_with_max_depth(&runtime(&spells, &heap),
+                                            id(BOOK, MAIN, 1), &[f], 0);
+        assert_eq!(result.unwrap_err(),
+                   InterpretError::StackOverflow(id(BOOK, MAIN, 1)));
+    }
+
+    #[test]
+    fn test_stack_overflow_backtrace() {
+        // Recurse forever, without tail calls, as the result of the
+        // invocation is not returned.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::Nop,
+            Instruction::InvokeStatic{
+                result:    Local(1),
+                spellbook: BOOK,
+                spell:     MAIN,
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+        let mut interpreter =
+            Interpreter::new(&runtime(&spells, &heap),
+                             id(BOOK, MAIN, 1), slice::from_ref(&a)).unwrap();
+        interpreter.call_stack.max_depth = 3;
+        let error = loop {
+            match interpreter.step() {
+                StepResult::Running      => (),
+                StepResult::Error(error) => break error,
+                StepResult::Returned(_)  => panic!("Returned"),
+            }
+        };
+        assert_eq!(error, InterpretError::StackOverflow(id(BOOK, MAIN, 1)));
+
+        // The active stack frame is still at the invocation that failed.
+        let main = id(BOOK, MAIN, 1);
+        assert_eq!(interpreter.call_stack.backtrace(),
+                   [(main, 1), (main, 2), (main, 2)]);
+        assert_eq!(interpreter.call_stack.stack_frames[2].return_into,
+                   Local(0));
+
+        // The local variables of the stack frame that was not pushed are
+        // pooled.
+        let call_stack = &mut interpreter.call_stack;
+        let spell = spells.get(main).unwrap();
+        let callee = StackFrame::new(main, spell, Box::new([a]));
+        let pooled = callee.local_variables.as_ptr();
+        assert!(call_stack.push(callee).is_err());
+        let local_variables = call_stack.pool.acquire(2);
+        assert_eq!(local_variables.as_ptr(), pooled);
+    }
+
+    #[test]
+    fn test_run_tail_call() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(1, vec![
+            Instruction::InvokeStatic{
+                result:    Local(0),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 1), spell(1, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+
+        // Without the tail call, the call stack would need two stack frames.
+        let result = try_run_with_max_depth(&runtime(&spells, &heap),
+                                            id(BOOK, MAIN, 1), &[a], 1);
+        assert_eq!(result.unwrap().auxiliary(), b"a");
+    }
+
+    #[test]
+    fn test_run_tail_recursion() {
+        // Count down forever, calling itself in tail position.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 2), spell(2, vec![
+            Instruction::Sub{result: Local(0), lhs: Local(0), rhs: Local(1)},
+            Instruction::InvokeStatic{
+                result:    Local(0),
+                spellbook: BOOK,
+                spell:     MAIN,
+                arguments: Box::new([Local(0), Local(1)]),
+            },
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 2), spell(2, vec![
+            Instruction::Sub{result: Local(0), lhs: Local(0), rhs: Local(1)},
+            Instruction::InvokeStatic{
+                result:    Local(0),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(0), Local(1)]),
+            },
+            Instruction::Copy{from: Local(0), to: Local(1)},
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let n = Datum::from_i64(1_000_000).unwrap();
+        let one = Datum::from_i64(1).unwrap();
+
+        let result = run_with_fuel(&runtime(&spells, &heap),
+                                   id(BOOK, MAIN, 2),
+                                   &[n.clone(), one.clone()], 60_000);
+        let out_of_fuel = result.unwrap().unwrap_err();
+        assert_eq!(out_of_fuel.call_stack.stack_frames.len(), 1);
+
+        // The same spell without a tail call grows the call stack.
+        let result = run_with_fuel(&runtime(&spells, &heap),
+                                   id(BOOK, FIRST, 2), &[n, one], 60_000);
+        let out_of_fuel = result.unwrap().unwrap_err();
+        assert_eq!(out_of_fuel.call_stack.stack_frames.len(), 10_001);
+    }
+
+    fn native_add<'a>(heap: &'a Heap, arguments: &[Datum<'a>]) -> Datum<'a> {
+        let [a, b] = [&arguments[0], &arguments[1]].map(|datum| {
+            datum.as_i64().unwrap()
+        });
+        Datum::from_i64(a + b)
+            .unwrap_or_else(|| unsafe {
+                heap.allocate(INT, &[], &(a + b).to_le_bytes())
+            })
+    }
+
+    #[test]
+    fn test_run_native() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 2), spell(3, vec![
+            Instruction::InvokeStatic{
+                result:    Local(2),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(0), Local(1)]),
+            },
+            Instruction::Add{result: Local(2), lhs: Local(2), rhs: Local(0)},
+            Instruction::Return{result: Local(2)},
+        ])).ok().unwrap();
+        spells.insert_native(id(BOOK, FIRST, 2), native_add).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = Datum::from_i64(3).unwrap();
+        let b = Datum::from_i64(4).unwrap();
+
+        // The native spell does not get a stack frame of its own.
+        let result = try_run_with_max_depth(&runtime(&spells, &heap),
+                                            id(BOOK, MAIN, 2), &[a, b], 1);
+        assert_eq!(result.unwrap().auxiliary(), &10i64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_run_native_tail_call() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 2), spell(2, vec![
+            Instruction::InvokeStatic{
+                result:    Local(0),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(0), Local(1)]),
+            },
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert_native(id(BOOK, FIRST, 2), native_add).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = Datum::from_i64(3).unwrap();
+        let b = Datum::from_i64(4).unwrap();
+
+        let result = run_main(&spells, &heap, 2, &[a, b]);
+        assert_eq!(result.as_i64(), Some(7));
+    }
+
+    #[test]
+    fn test_run_native_entry() {
+        let mut spells = Spells::new();
+        spells.insert_native(id(BOOK, MAIN, 2), native_add).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = Datum::from_i64(3).unwrap();
+        let b = Datum::from_i64(4).unwrap();
+
+        let result = run_main(&spells, &heap, 2, &[a.clone(), b.clone()]);
+        assert_eq!(result.as_i64(), Some(7));
+
+        let result = run_with_fuel(&runtime(&spells, &heap),
+                                   id(BOOK, MAIN, 2), &[a, b], 0);
+        assert_eq!(result.unwrap().unwrap().as_i64(), Some(7));
+    }
+
+    #[test]
+    fn test_run_native_arity() {
+        // Arity is part of the spell id, so a native spell is never called with
+        // the wrong number of arguments.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::InvokeStatic{
+                result:    Local(1),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+        spells.insert_native(id(BOOK, FIRST, 2), native_add).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = Datum::from_i64(3).unwrap();
+
+        let result = try_run_main(&spells, &heap, 1, &[a]);
+        assert_eq!(result.unwrap_err(), InterpretError::ArityMismatch{
+            spell:    id(BOOK, FIRST, 1),
+            expected: vec![2],
+        });
+    }
+
+    #[test]
+    fn test_try_run_arity_mismatch() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 2), spell(2, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, MAIN, 0), spell(0, vec![])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = Datum::from_i64(1).unwrap();
+
+        let error = try_run_main(&spells, &heap, 1, &[a]).unwrap_err();
+        assert_eq!(error, InterpretError::ArityMismatch{
+            spell:    id(BOOK, MAIN, 1),
+            expected: vec![0, 2],
+        });
+        assert_eq!(error.to_string(), "spell Sigil(0)::Sigil(3)/1 is not \
+                                       defined, but exists with arity 0, 2");
+    }
+
+    #[test]
+    fn test_run_traced() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::InvokeStatic{
+                result:    Local(1),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 1), spell(1, vec![
+            Instruction::Jump{target: 1},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = Datum::from_i64(7).unwrap();
+
+        let mut log = Vec::new();
+        let result = run_traced(&runtime(&spells, &heap),
+                                id(BOOK, MAIN, 1), &[a],
+                                |program_counter, instruction,
+                                 local_variables| {
+            log.push((program_counter.next_instruction,
+                      fuel_cost(instruction),
+                      local_variables.iter()
+                          .map(|local| local.as_ref().and_then(Datum::as_i64))
+                          .collect::<Vec<_>>()));
+        });
+        assert_eq!(result.unwrap().as_i64(), Some(7));
+
+        // The invocation is a tail call, so the caller never runs its return.
+        assert_eq!(log, vec![
+            (0, 4, vec![Some(7), None]),
+            (0, 1, vec![Some(7)]),
+            (1, 1, vec![Some(7)]),
+        ]);
+    }
+
+    #[test]
+    fn test_tail_call_arguments() {
+        // main(a) = first(a, a), and first(a, b) = b.second(a), which returns
+        // a. Both are tail calls, which move their arguments, including those
+        // passed twice.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(1, vec![
+            Instruction::InvokeStatic{
+                result:    Local(0),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(0), Local(0)]),
+            },
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 2), spell(2, vec![
+            Instruction::InvokeDynamic{
+                result:    Local(0),
+                spell:     SECOND,
+                receiver:  Local(1),
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, SECOND, 2), spell(3, vec![
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+        let mut interpreter =
+            Interpreter::new(&runtime(&spells, &heap),
+                             id(BOOK, MAIN, 1), slice::from_ref(&a)).unwrap();
+        let locals = |interpreter: &Interpreter| {
+            let frame = &interpreter.call_stack.stack_frames[0];
+            frame.local_variables.iter()
+                .map(|local| local.as_ref().map(|l| l.auxiliary().to_vec()))
+                .collect::<Vec<_>>()
+        };
+        let some_a = Some(b"a".to_vec());
+
+        assert!(matches!(interpreter.step(), StepResult::Running));
+        assert_eq!(locals(&interpreter), [some_a.clone(), some_a.clone()]);
+        assert!(matches!(interpreter.step(), StepResult::Running));
+        assert_eq!(locals(&interpreter), [some_a.clone(), some_a, None]);
+        assert_eq!(heap.total_roots(), 3);
+        match interpreter.step() {
+            StepResult::Returned(value) => assert_eq!(value.auxiliary(), b"a"),
+            other => panic!("Unexpected step result {:?}", other),
+        }
+        assert_eq!(heap.total_roots(), 1);
+    }
+
+    #[test]
+    fn test_interpreter_step() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::InvokeStatic{
+                result:    Local(1),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Copy{from: Local(1), to: Local(0)},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 1), spell(1, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = Datum::from_i64(7).unwrap();
+        let mut interpreter =
+            Interpreter::new(&runtime(&spells, &heap),
+                             id(BOOK, MAIN, 1), &[a]).unwrap();
+
+        assert!(matches!(interpreter.step(), StepResult::Running));
+        assert_eq!(interpreter.call_stack.stack_frames.len(), 2);
+        assert!(matches!(interpreter.step(), StepResult::Running));
+        assert_eq!(interpreter.call_stack.stack_frames.len(), 1);
+        let frame = &interpreter.call_stack.stack_frames[0];
+        assert_eq!(frame.program_counter.next_instruction, 1);
+        assert_eq!(frame.local_variables[1].as_ref().unwrap().as_i64(),
+                   Some(7));
+
+        // Change a local variable between steps.
+        interpreter.call_stack.stack_frames[0].local_variables[1] =
+            Datum::from_i64(8);
+        assert!(matches!(interpreter.step(), StepResult::Running));
+        match interpreter.step() {
+            StepResult::Returned(value) => assert_eq!(value.as_i64(), Some(8)),
+            other => panic!("{:?}", other),
+        }
+        assert!(interpreter.call_stack.stack_frames.is_empty());
+    }
+
+    #[test]
+    fn test_interpreter_error() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 0), spell(1, vec![
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let mut interpreter =
+            Interpreter::new(&runtime(&spells, &heap),
+                             id(BOOK, MAIN, 0), &[]).unwrap();
+        match interpreter.step() {
+            StepResult::Error(error) =>
+                assert_eq!(error, InterpretError::LocalUninitialized(Local(0))),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(interpreter.call_stack.stack_frames.len(), 1);
+
+        let error =
+            Interpreter::new(&runtime(&spells, &heap),
+                             id(BOOK, FIRST, 0), &[]).unwrap_err();
+        assert_eq!(error, InterpretError::SpellNotFound(id(BOOK, FIRST, 0)));
+    }
+
+    #[test]
+    fn test_interpreter_native_entry() {
+        let mut spells = Spells::new();
+        spells.insert_native(id(BOOK, MAIN, 2), native_add).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = Datum::from_i64(3).unwrap();
+        let b = Datum::from_i64(4).unwrap();
+        let mut interpreter =
+            Interpreter::new(&runtime(&spells, &heap),
+                             id(BOOK, MAIN, 2), &[a, b]).unwrap();
+        match interpreter.step() {
+            StepResult::Returned(value) => assert_eq!(value.as_i64(), Some(7)),
+            other => panic!("{:?}", other),
+        };
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let invoke = |callee| Instruction::InvokeStatic{
+            result:    Local(1),
+            spellbook: BOOK,
+            spell:     callee,
+            arguments: Box::new([Local(0)]),
+        };
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            invoke(FIRST),
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        let mut first = spell(2, vec![
+            Instruction::Jump{target: 1},
+            invoke(SECOND),
+            Instruction::Return{result: Local(0)},
+        ]);
+        let span = |line| SourceSpan{file: BOOK, line, column: 1};
+        first.source_map = Some(Box::new([span(1), span(2), span(3)]));
+        spells.insert(id(BOOK, FIRST, 1), first).ok().unwrap();
+        spells.insert(id(BOOK, SECOND, 1), spell(2, vec![
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = Datum::from_i64(7).unwrap();
+        let mut interpreter =
+            Interpreter::new(&runtime(&spells, &heap),
+                             id(BOOK, MAIN, 1), &[a]).unwrap();
+        assert_eq!(interpreter.call_stack.backtrace(),
+                   vec![(id(BOOK, MAIN, 1), 0)]);
+
+        let error = loop {
+            match interpreter.step() {
+                StepResult::Running      => continue,
+                StepResult::Error(error) => break error,
+                other                    => panic!("{:?}", other),
+            }
+        };
+        assert_eq!(error, InterpretError::LocalUninitialized(Local(1)));
+        assert_eq!(interpreter.call_stack.backtrace(), vec![
+            (id(BOOK, SECOND, 1), 0),
+            (id(BOOK, FIRST,  1), 2),
+            (id(BOOK, MAIN,   1), 1),
+        ]);
+
+        // The span of the calling stack frame is that of the invocation.
+        assert_eq!(interpreter.call_stack.source_backtrace(), vec![
+            (id(BOOK, SECOND, 1), 0, None),
+            (id(BOOK, FIRST,  1), 2, Some(span(2))),
+            (id(BOOK, MAIN,   1), 1, None),
+        ]);
+    }
+
+    #[test]
+    fn test_run_throw() {
+        // Catch an exception thrown by a callee of the callee.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(3, vec![
+            Instruction::PushHandler{target: 3, exception: Local(2)},
+            Instruction::InvokeStatic{
+                result:    Local(1),
+                spellbook: BOOK,
+                spell:     FIRST,
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Return{result: Local(1)},
+            Instruction::Return{result: Local(2)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, FIRST, 1), spell(2, vec![
+            Instruction::InvokeStatic{
+                result:    Local(1),
+                spellbook: BOOK,
+                spell:     SECOND,
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Copy{from: Local(0), to: Local(1)},
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, SECOND, 1), spell(1, vec![
+            Instruction::Throw{value: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+
+        // The invocation from the main spell would be a tail call if it had
+        // no exception handler.
+        let result = run_main(&spells, &heap, 1, slice::from_ref(&a));
+        assert!(result.ptr_eq(&a));
+
+        drop((a, result));
+        { let stat = heap.collect_garbage()
+        ; assert_eq!(stat.data_freed, 1) }
+    }
+
+    #[test]
+    fn test_run_pop_handler() {
+        // The handler is removed before the exception is thrown, so the outer
+        // handler catches it. A handler is removed once it catches.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::PushHandler{target: 4, exception: Local(1)},
+            Instruction::PushHandler{target: 5, exception: Local(1)},
+            Instruction::PopHandler,
+            Instruction::Throw{value: Local(0)},
+            Instruction::Throw{value: Local(1)},
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let a = Datum::from_i64(7).unwrap();
+        let result = try_run_main(&spells, &heap, 1, &[a]);
+        assert_eq!(result.unwrap_err(), InterpretError::Uncaught{
+            enchantment: IMMEDIATE_ENCHANTMENT,
+            auxiliary:   Box::new([]),
+        });
+    }
+
+    #[test]
+    fn test_run_throw_errors() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 0), spell(0, vec![
+            Instruction::PopHandler,
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, MAIN, 1), spell(1, vec![
+            Instruction::Throw{value: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let result = try_run_main(&spells, &heap, 0, &[]);
+        assert_eq!(result.unwrap_err(), InterpretError::NoHandler);
+
+        let a = unsafe { heap.allocate(BOOK, &[], b"a") };
+        let result = try_run_main(&spells, &heap, 1, &[a]);
+        assert_eq!(result.unwrap_err(), InterpretError::Uncaught{
+            enchantment: BOOK,
+            auxiliary:   Box::new(*b"a"),
+        });
+    }
+
+    #[test]
+    fn test_run_comparison() {
+        // Return the lesser of the two arguments.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 2), spell(3, vec![
+            Instruction::LessThan{result: Local(2), lhs: Local(0),
+                                  rhs: Local(1)},
+            Instruction::BranchIfTruthy{condition: Local(2), target: 3},
+            Instruction::Return{result: Local(1)},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let min = |a: i64, b: i64| {
+            let arguments = [Datum::from_i64(a).unwrap(),
+                             Datum::from_i64(b).unwrap()];
+            run_main(&spells, &heap, 2, &arguments).as_i64().unwrap()
+        };
+        assert_eq!(min(3, 4), 3);
+        assert_eq!(min(4, 3), 3);
+        assert_eq!(min(-1, -1), -1);
+    }
+
+    #[test]
+    fn test_run_switch() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 4), spell(4, vec![
+            Instruction::Switch{scrutinee: Local(0), targets: Box::new([1, 2]),
+                                default: 3},
+            Instruction::Return{result: Local(1)},
+            Instruction::Return{result: Local(2)},
+            Instruction::Return{result: Local(3)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let switch = |scrutinee: Datum| {
+            let arguments = [scrutinee,
+                             unsafe { heap.allocate(BOOK, &[], b"a") },
+                             unsafe { heap.allocate(BOOK, &[], b"b") },
+                             unsafe { heap.allocate(BOOK, &[], b"c") }];
+            let result = try_run_main(&spells, &heap, 4, &arguments);
+            result.map(|result| result.auxiliary().to_vec())
+        };
+        let int = |value| Datum::from_i64(value).unwrap();
+
+        assert_eq!(switch(int(0)), Ok(b"a".to_vec()));
+        assert_eq!(switch(int(1)), Ok(b"b".to_vec()));
+        assert_eq!(switch(int(2)), Ok(b"c".to_vec()));
+        assert_eq!(switch(int(-1)), Ok(b"c".to_vec()));
+        assert_eq!(switch(int(i64::MAX >> 1)), Ok(b"c".to_vec()));
+        let boxed = unsafe { heap.allocate(INT, &[], &1i64.to_le_bytes()) };
+        assert_eq!(switch(boxed), Ok(b"b".to_vec()));
+        let text = unsafe { heap.allocate(BOOK, &[], b"text") };
+        assert_eq!(switch(text), Err(InterpretError::NotAnInteger(Local(0))));
+    }
+
+    #[test]
+    fn test_run_relative() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 3), spell(3, vec![
+            Instruction::BranchIfTruthyRelative{condition: Local(0), offset: 2},
+            Instruction::Return{result: Local(1)},
+            Instruction::JumpRelative{offset: 2},
+            Instruction::Return{result: Local(0)},
+            Instruction::BranchIfFalsyRelative{condition: Local(1),
+                                               offset: -3},
+            Instruction::Return{result: Local(2)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, MAIN, 0), spell(0, vec![
+            Instruction::JumpRelative{offset: -1},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let t = unsafe { heap.allocate(BOOK, &[], b"t") };
+        let f = unsafe { heap.allocate(FALSE, &[], b"f") };
+        let u = unsafe { heap.allocate(BOOK, &[], b"u") };
+        let run = |arguments: &[Datum]| {
+            try_run_main(&spells, &heap, 3, arguments)
+                .map(|result| result.auxiliary().to_vec())
+        };
+
+        assert_eq!(run(&[f.clone(), t.clone(), u.clone()]),
+                   Ok(b"t".to_vec()));
+        assert_eq!(run(&[t.clone(), t.clone(), u.clone()]),
+                   Ok(b"u".to_vec()));
+        assert_eq!(run(&[t, f, u]), Ok(b"f".to_vec()));
+        assert_eq!(try_run_main(&spells, &heap, 0, &[]).map(|_| ()),
+                   Err(InterpretError::ProgramCounterOutOfBounds));
+    }
+
+    #[test]
+    fn test_run_load_constant() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 0), Spell{
+            instructions: Box::new([
+                Instruction::LoadConstant{result: Local(0), index: 1},
+                Instruction::LoadConstant{result: Local(1), index: 1},
+                Instruction::Allocate{result: Local(0), enchantment: BOOK,
+                                      pointers: Box::new([Local(0),
+                                                          Local(1)]),
+                                      auxiliary: Box::new([])},
+                Instruction::Return{result: Local(0)},
+            ]),
+            local_variables: 2,
+            constants: Box::new([
+                ConstantValue{enchantment: BOOK, auxiliary: Box::new(*b"a")},
+                ConstantValue{enchantment: INT, auxiliary: Box::new(*b"b")},
+            ]),
+            source_map: None,
+        }).ok().unwrap();
+        spells.insert(id(BOOK, MAIN, 1), spell(1, vec![
+            Instruction::LoadConstant{result: Local(0), index: 0},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let result = run_main(&spells, &heap, 0, &[]);
+        let constants = result.pointers();
+        assert_eq!(constants[0].enchantment(), INT);
+        assert_eq!(constants[0].auxiliary(), b"b");
+        assert!(constants[0].structural_eq(&constants[1]));
+        assert!(!constants[0].ptr_eq(&constants[1]));
+
+        let argument = Datum::from_i64(0).unwrap();
+        assert_eq!(try_run_main(&spells, &heap, 1, &[argument]).map(|_| ()),
+                   Err(InterpretError::ConstantOutOfBounds(0)));
+    }
+
+    #[test]
+    fn test_run_closure() {
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 2), spell(3, vec![
+            Instruction::MakeClosure{result: Local(2), spellbook: BOOK,
+                                     spell: FIRST,
+                                     captures: Box::new([Local(0)])},
+            Instruction::InvokeClosure{result: Local(0), closure: Local(2),
+                                       arguments: Box::new([Local(1)])},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+        spells.insert(id(BOOK, MAIN, 1), spell(1, vec![
+            Instruction::InvokeClosure{result: Local(0), closure: Local(0),
+                                       arguments: Box::new([])},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        // The captured datum is passed before the argument.
+        spells.insert(id(BOOK, FIRST, 2), spell(2, vec![
+            Instruction::Sub{result: Local(0), lhs: Local(0), rhs: Local(1)},
+            Instruction::Return{result: Local(0)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let arguments = [Datum::from_i64(50).unwrap(),
+                         Datum::from_i64(8).unwrap()];
+        let result = run_main(&spells, &heap, 2, &arguments);
+        assert_eq!(result.as_i64(), Some(42));
+
+        let argument = Datum::from_i64(0).unwrap();
+        assert_eq!(try_run_main(&spells, &heap, 1, &[argument]).map(|_| ()),
+                   Err(InterpretError::NotAClosure(Local(0))));
+    }
+
+    #[test]
+    fn test_run_is_enchanted() {
+        // Return the length of a string, twice an immediate integer, and
+        // false for anything else.
+        let mut spells = Spells::new();
+        spells.insert(id(BOOK, MAIN, 1), spell(2, vec![
+            Instruction::IsEnchanted{result: Local(1), object: Local(0),
+                                     enchantment: STR},
+            Instruction::BranchIfTruthy{condition: Local(1), target: 5},
+            Instruction::IsEnchanted{result: Local(1), object: Local(0),
+                                     enchantment: IMMEDIATE_ENCHANTMENT},
+            Instruction::BranchIfTruthy{condition: Local(1), target: 7},
+            Instruction::Return{result: Local(1)},
+            Instruction::AuxiliaryLen{result: Local(1), object: Local(0)},
+            Instruction::Return{result: Local(1)},
+            Instruction::Add{result: Local(1), lhs: Local(0), rhs: Local(0)},
+            Instruction::Return{result: Local(1)},
+        ])).ok().unwrap();
+
+        let heap = Heap::new();
+        let dispatch = |argument: Datum| {
+            let result = run_main(&spells, &heap, 1, &[argument]);
+            result.as_i64().ok_or(result.enchantment())
+        };
+        let string = unsafe { heap.allocate(STR, &[], b"abc") };
+        let boxed = unsafe { heap.allocate(INT, &[], &21i64.to_le_bytes()) };
+        let other = unsafe { heap.allocate(BOOK, &[], &[]) };
+
+        // Boxed integers are not immediates, even though they represent
+        // integers.
+        assert_eq!(dispatch(string), Ok(3));
+        assert_eq!(dispatch(Datum::from_i64(21).unwrap()), Ok(42));
+        assert_eq!(dispatch(boxed), Err(FALSE));
+        assert_eq!(dispatch(other), Err(FALSE));
+    }
+}
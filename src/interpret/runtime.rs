@@ -0,0 +1,57 @@
+use super::*;
+
+use std::fmt;
+
+use datum::Heap;
+use spell::Spells;
+
+/// The sigils that instructions give a meaning to.
+///
+/// Data enchanted with the falsy sigil are falsy; see [Datum::is_truthy].
+/// Comparisons produce data enchanted with the truthy or falsy sigil. Integers
+/// produced by arithmetic are allocated on the heap and enchanted with the
+/// integer sigil. No instruction produces strings, but native spells that do
+/// should enchant them with the string sigil, so that all spells agree on it.
+///
+/// [Datum::is_truthy]: ../datum/struct.Datum.html#method.is_truthy
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TypeSigils {
+    pub falsy:   Sigil,
+    pub truthy:  Sigil,
+    pub integer: Sigil,
+    pub string:  Sigil,
+}
+
+impl TypeSigils {
+    /// Intern the conventional names of the sigils, which are `false`,
+    /// `true`, `integer`, and `string`.
+    pub fn intern(sigils: &mut Sigils) -> Self {
+        TypeSigils{
+            falsy:   sigils.intern_str("false"),
+            truthy:  sigils.intern_str("true"),
+            integer: sigils.intern_str("integer"),
+            string:  sigils.intern_str("string"),
+        }
+    }
+}
+
+/// Everything a run needs besides the entry spell and its arguments: the spell
+/// database invoked spells are looked up in, the heap data are allocated on,
+/// and the sigils instructions give a meaning to.
+///
+/// A run that is suspended must be continued with the same runtime.
+#[derive(Clone, Copy)]
+pub struct Runtime<'a> {
+    pub spells: &'a Spells,
+    pub heap:   &'a Heap,
+    pub types:  TypeSigils,
+}
+
+impl fmt::Debug for Runtime<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Runtime")
+            .field("spells", &self.spells)
+            .field("types", &self.types)
+            .finish()
+    }
+}
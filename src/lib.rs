@@ -2,3 +2,9 @@ pub mod datum;
 pub mod interpret;
 pub mod sigil;
 pub mod spell;
+
+mod execute;
+mod image;
+
+pub use execute::*;
+pub use image::*;
@@ -1,5 +1,16 @@
+use std::borrow::Cow;
+#[cfg(feature = "checked-sigils")]
+use std::cmp::Ordering;
 use std::collections::HashMap;
+#[cfg(feature = "checked-sigils")]
+use std::hash::Hash;
+#[cfg(feature = "checked-sigils")]
+use std::hash::Hasher;
 use std::sync::Arc;
+#[cfg(feature = "checked-sigils")]
+use std::sync::atomic;
+#[cfg(feature = "checked-sigils")]
+use std::sync::atomic::AtomicU32;
 
 /// A sigil is some sort of identifier.
 ///
@@ -14,48 +25,304 @@ use std::sync::Arc;
 /// To create a new sigil, you need a sigil database. See [Sigils] for more
 /// information.
 ///
+/// With the `checked-sigils` feature enabled, the upper eight bits of a sigil
+/// created by a sigil database identify the database, and the lower bits
+/// number the sigil within it. Comparing sigils from different databases
+/// panics, and so does looking up a sigil in a database other than its own.
+/// Sigils whose upper eight bits are all zeros or all ones, such as those
+/// written as literals in code, belong to no database, and may be compared to
+/// any sigil.
+///
 /// [Sigils]: struct.Sigils.html
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(feature = "checked-sigils"),
+           derive(Eq, Hash, Ord, PartialEq, PartialOrd))]
 pub struct Sigil(pub u32);
 
+#[cfg(feature = "checked-sigils")]
+impl Sigil {
+    /// The number of bits that number a sigil within its database.
+    const INDEX_BITS: u32 = 24;
+
+    /// The database the sigil belongs to, if any.
+    fn database(self) -> Option<u32> {
+        match self.0 >> Self::INDEX_BITS {
+            0 | 0xFF => None,
+            database => Some(database),
+        }
+    }
+
+    /// The number of the sigil within its database.
+    fn index(self) -> u32 {
+        self.0 & ((1 << Self::INDEX_BITS) - 1)
+    }
+
+    fn check_database(self, other: Sigil) {
+        if let (Some(a), Some(b)) = (self.database(), other.database()) {
+            assert_eq!(a, b, "Sigils belong to different sigil databases");
+        }
+    }
+}
+
+#[cfg(feature = "checked-sigils")]
+impl PartialEq for Sigil {
+    #[inline(always)]
+    fn eq(&self, other: &Sigil) -> bool {
+        self.check_database(*other);
+        self.0 == other.0
+    }
+}
+
+#[cfg(feature = "checked-sigils")]
+impl Eq for Sigil {}
+
+#[cfg(feature = "checked-sigils")]
+impl PartialOrd for Sigil {
+    fn partial_cmp(&self, other: &Sigil) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(feature = "checked-sigils")]
+impl Ord for Sigil {
+    fn cmp(&self, other: &Sigil) -> Ordering {
+        self.check_database(*other);
+        self.0.cmp(&other.0)
+    }
+}
+
+#[cfg(feature = "checked-sigils")]
+impl Hash for Sigil {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
 /// A sigil database maps sigils to their names and vice versa.
 ///
 /// A sigil database automatically creates new sigils that were previously
 /// unknown. Sigils are not released until the entire sigil database is
 /// released, so you should not create them from untrusted input.
+///
+/// A sigil database may have a normalizer, in which case names that normalize
+/// to the same bytes are the same sigil. See [with_normalizer] for more
+/// information.
+///
+/// [with_normalizer]: #method.with_normalizer
 #[derive(Debug)]
 pub struct Sigils {
-    by_id:   Vec<Arc<[u8]>>,
-    by_name: HashMap<Arc<[u8]>, Sigil>,
+    /// The names of the sigils as they were first interned.
+    by_id:      Vec<Arc<[u8]>>,
+
+    /// The sigils by their normalized names.
+    by_name:    HashMap<Arc<[u8]>, Sigil>,
+
+    normalizer: Option<Normalizer>,
+
+    /// The number that identifies the database in the upper bits of its
+    /// sigils.
+    #[cfg(feature = "checked-sigils")]
+    database:   u32,
 }
 
+/// The number of sigil databases created so far, from which the numbers that
+/// identify them are derived. Only 254 numbers are available, so mixing up
+/// two databases goes unnoticed if they happen to get the same number.
+#[cfg(feature = "checked-sigils")]
+static DATABASES: AtomicU32 = AtomicU32::new(0);
+
+/// A function that maps names to the bytes by which they are identified. See
+/// [Sigils::with_normalizer].
+///
+/// [Sigils::with_normalizer]: struct.Sigils.html#method.with_normalizer
+pub type Normalizer = fn(&[u8]) -> Vec<u8>;
+
 impl Sigils {
     /// Create an empty sigil database.
     ///
     /// The returned database is distinct from other databases, and sigils
     /// created using one database should not be queried using another
-    /// database.
+    /// database. With the `checked-sigils` feature enabled, doing so panics;
+    /// see [Sigil].
+    ///
+    /// [Sigil]: struct.Sigil.html
     pub fn new() -> Self {
-        Sigils{by_id: Vec::new(), by_name: HashMap::new()}
+        Sigils{
+            by_id:      Vec::new(),
+            by_name:    HashMap::new(),
+            normalizer: None,
+            #[cfg(feature = "checked-sigils")]
+            database:   1 + DATABASES.fetch_add(1, atomic::Ordering::Relaxed)
+                              % 254,
+        }
+    }
+
+    /// Create an empty sigil database with a normalizer.
+    ///
+    /// Names are normalized before they are looked up, so that names with the
+    /// same normalized form are the same sigil. For example, a normalizer that
+    /// converts to lowercase makes the database case-insensitive. The name of
+    /// a sigil is still the name with which it was first interned, not its
+    /// normalized form.
+    ///
+    /// The normalizer must be deterministic. There is no way to change the
+    /// normalizer of a database once it has sigils in it.
+    pub fn with_normalizer(normalizer: Normalizer) -> Self {
+        Sigils{normalizer: Some(normalizer), ..Sigils::new()}
     }
 
     /// Get the name of a sigil in the database.
     pub fn name(&self, sigil: Sigil) -> Option<&Arc<[u8]>> {
-        self.by_id.get(sigil.0 as usize)
+        self.by_id.get(self.index(sigil)? as usize)
+    }
+
+    /// The number of sigils in the database.
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    /// Whether the database contains no sigils.
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+
+    /// Iterate over all sigils in the database along with their names, in the
+    /// order in which they were created.
+    pub fn iter(&self) -> impl Iterator<Item = (Sigil, &Arc<[u8]>)> {
+        self.by_id.iter().enumerate()
+            .map(move |(id, name)| (self.sigil(id), name))
+    }
+
+    /// Whether a sigil with the given name exists in the database. Unlike
+    /// [intern], this does not create the sigil if it does not exist.
+    ///
+    /// [intern]: #method.intern
+    pub fn contains(&self, name: &[u8]) -> bool {
+        self.by_name.contains_key(&self.normalize(name)[..])
+    }
+
+    /// The sigil with the given name, if it exists in the database. Unlike
+    /// [intern], this does not create the sigil if it does not exist.
+    ///
+    /// [intern]: #method.intern
+    pub fn lookup(&self, name: &[u8]) -> Option<Sigil> {
+        self.by_name.get(&self.normalize(name)[..]).cloned()
+    }
+
+    /// The sigil with the given raw id, if the database defines it.
+    ///
+    /// This checks ids from elsewhere, such as those in bytecode read from a
+    /// file, before they are used as sigils. Unlike [name], this never
+    /// panics: with the `checked-sigils` feature enabled, the ids of sigils
+    /// from other databases and of literal sigils are simply not defined.
+    ///
+    /// [name]: #method.name
+    pub fn validate(&self, raw: u32) -> Option<Sigil> {
+        #[cfg(not(feature = "checked-sigils"))]
+        let index = raw;
+        #[cfg(feature = "checked-sigils")]
+        let index = match Sigil(raw).database() {
+            Some(database) if database == self.database => Sigil(raw).index(),
+            _ => return None,
+        };
+        Some(Sigil(raw)).filter(|_| (index as usize) < self.by_id.len())
     }
 
     /// Get a sigil by its name. If the sigil does not yet exist in the
     /// database, it is first created.
     pub fn intern(&mut self, name: &Arc<[u8]>) -> Sigil {
-        if let Some(&sigil) = self.by_name.get(name) {
+        let key = self.normalize(name);
+        if let Some(&sigil) = self.by_name.get(&key[..]) {
             sigil
         } else {
-            let sigil = Sigil(self.by_id.len() as u32);
+            let key = match key {
+                Cow::Borrowed(_) => name.clone(),
+                Cow::Owned(key)  => Arc::from(key),
+            };
+            let sigil = self.sigil(self.by_id.len());
             self.by_id.push(name.clone());
-            self.by_name.insert(name.clone(), sigil);
+            self.by_name.insert(key, sigil);
+            sigil
+        }
+    }
+
+    /// Like [intern], but only allocates a name when the sigil does not yet
+    /// exist in the database.
+    ///
+    /// [intern]: #method.intern
+    pub fn intern_bytes(&mut self, name: &[u8]) -> Sigil {
+        if let Some(&sigil) = self.by_name.get(&self.normalize(name)[..]) {
             sigil
+        } else {
+            self.intern(&Arc::from(name))
         }
     }
+
+    /// Like [intern_bytes], for names that are strings.
+    ///
+    /// [intern_bytes]: #method.intern_bytes
+    pub fn intern_str(&mut self, name: &str) -> Sigil {
+        self.intern_bytes(name.as_bytes())
+    }
+
+    /// Intern every sigil of another database into this one, and return for
+    /// every sigil of the other database the sigil with the same name in this
+    /// one.
+    ///
+    /// This is how to combine code that was compiled against different sigil
+    /// databases: the sigils in the code are rewritten using the returned map.
+    /// Names are interned with the normalizer of this database, so sigils that
+    /// are distinct in the other database may map to the same sigil.
+    pub fn merge(&mut self, other: &Sigils) -> HashMap<Sigil, Sigil> {
+        other.iter()
+            .map(|(sigil, name)| (sigil, self.intern(name)))
+            .collect()
+    }
+
+    /// The sigil with the given number in the database.
+    #[cfg(not(feature = "checked-sigils"))]
+    fn sigil(&self, index: usize) -> Sigil {
+        Sigil(index as u32)
+    }
+
+    /// The sigil with the given number in the database.
+    #[cfg(feature = "checked-sigils")]
+    fn sigil(&self, index: usize) -> Sigil {
+        assert!(index < 1 << Sigil::INDEX_BITS,
+                "Too many sigils in sigil database");
+        Sigil(self.database << Sigil::INDEX_BITS | index as u32)
+    }
+
+    /// The number of a sigil in the database, or `None` if it belongs to no
+    /// database.
+    #[cfg(not(feature = "checked-sigils"))]
+    fn index(&self, sigil: Sigil) -> Option<u32> {
+        Some(sigil.0)
+    }
+
+    /// The number of a sigil in the database, or `None` if it belongs to no
+    /// database. Panics if it belongs to a different database.
+    #[cfg(feature = "checked-sigils")]
+    fn index(&self, sigil: Sigil) -> Option<u32> {
+        let database = sigil.database()?;
+        assert_eq!(database, self.database,
+                   "Sigil belongs to a different sigil database");
+        Some(sigil.index())
+    }
+
+    /// Apply the normalizer to a name, if there is one.
+    fn normalize<'n>(&self, name: &'n [u8]) -> Cow<'n, [u8]> {
+        match self.normalizer {
+            Some(normalizer) => Cow::Owned(normalizer(name)),
+            None             => Cow::Borrowed(name),
+        }
+    }
+}
+
+impl Default for Sigils {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(test)]
@@ -85,4 +352,130 @@ mod tests {
         assert_ne!(sigil_a_1, sigil_b  );
         assert_ne!(sigil_a_2, sigil_b  );
     }
+
+    #[test]
+    fn test_enumerate() {
+        let mut sigils = Sigils::new();
+        assert!(sigils.is_empty());
+
+        let name_a = Arc::from("foo".as_bytes());
+        let name_b = Arc::from("bar".as_bytes());
+
+        let sigil_a = sigils.intern(&name_a);
+        let sigil_b = sigils.intern(&name_b);
+        sigils.intern(&name_a);
+
+        assert_eq!(sigils.len(), 2);
+        assert_eq!(sigils.iter().collect::<Vec<_>>(),
+                   vec![(sigil_a, &name_a), (sigil_b, &name_b)]);
+    }
+
+    #[test]
+    fn test_contains() {
+        let mut sigils = Sigils::new();
+        let foo = sigils.intern(&Arc::from("foo".as_bytes()));
+        assert!( sigils.contains(b"foo"));
+        assert!(!sigils.contains(b"bar"));
+        assert_eq!(sigils.lookup(b"foo"), Some(foo));
+        assert_eq!(sigils.lookup(b"bar"), None);
+        assert_eq!(sigils.len(), 1);
+    }
+
+    #[test]
+    fn test_intern_bytes() {
+        let mut sigils = Sigils::new();
+        let name = Arc::from("foo".as_bytes());
+        let sigil = sigils.intern(&name);
+        assert_eq!(sigils.intern_bytes(b"foo"), sigil);
+        assert_eq!(sigils.intern_str("foo"), sigil);
+
+        let other = sigils.intern_str("bar");
+        assert_ne!(other, sigil);
+        assert_eq!(sigils.name(other).map(|name| &name[..]),
+                   Some(&b"bar"[..]));
+        assert_eq!(sigils.len(), 2);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut sigils = Sigils::new();
+        let foo = sigils.intern_str("foo");
+
+        let mut other = Sigils::new();
+        let other_bar = other.intern_str("bar");
+        let other_foo = other.intern_str("foo");
+
+        let map = sigils.merge(&other);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&other_foo], foo);
+        assert_eq!(sigils.name(map[&other_bar]).map(|name| &name[..]),
+                   Some(&b"bar"[..]));
+        assert_eq!(sigils.len(), 2);
+    }
+
+    #[test]
+    fn test_normalizer() {
+        let mut sigils = Sigils::with_normalizer(|name| {
+            name.to_ascii_lowercase()
+        });
+
+        let name = Arc::from("Foo".as_bytes());
+        let sigil = sigils.intern(&name);
+        assert_eq!(sigils.intern_str("FOO"), sigil);
+        assert_eq!(sigils.intern(&Arc::from("foo".as_bytes())), sigil);
+        assert_ne!(sigils.intern_str("bar"), sigil);
+        assert!(sigils.contains(b"fOO"));
+
+        assert_eq!(sigils.name(sigil), Some(&name));
+        assert_eq!(sigils.len(), 2);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut sigils = Sigils::new();
+        let foo = sigils.intern_str("foo");
+        let bar = sigils.intern_str("bar");
+        assert_eq!(sigils.validate(foo.0), Some(foo));
+        assert_eq!(sigils.validate(bar.0), Some(bar));
+        assert_eq!(sigils.validate(bar.0 + 1), None);
+        assert_eq!(sigils.validate(u32::MAX), None);
+
+        // With checked sigils, the ids of other databases are not defined,
+        // even if they number a sigil that exists.
+        let mut other = Sigils::new();
+        let other_foo = other.intern_str("foo");
+        assert_eq!(sigils.validate(other_foo.0).is_some(),
+                   cfg!(not(feature = "checked-sigils")));
+    }
+
+    #[test]
+    #[cfg(feature = "checked-sigils")]
+    #[should_panic(expected = "Sigils belong to different sigil databases")]
+    fn test_compare_foreign_sigil() {
+        let mut a = Sigils::new();
+        let mut b = Sigils::new();
+        let _ = a.intern_str("foo") == b.intern_str("foo");
+    }
+
+    #[test]
+    #[cfg(feature = "checked-sigils")]
+    #[should_panic(expected = "Sigil belongs to a different sigil database")]
+    fn test_name_foreign_sigil() {
+        let a = Sigils::new();
+        let mut b = Sigils::new();
+        a.name(b.intern_str("foo"));
+    }
+
+    #[test]
+    #[cfg(feature = "checked-sigils")]
+    fn test_literal_sigil() {
+        // Literal sigils belong to no database, so they can be compared to
+        // any sigil, but have no name.
+        let mut sigils = Sigils::new();
+        let foo = sigils.intern_str("foo");
+        assert_ne!(foo, Sigil(0));
+        assert_ne!(foo, Sigil(u32::MAX));
+        assert_eq!(sigils.name(Sigil(0)), None);
+        assert_eq!(sigils.iter().next().map(|(sigil, _)| sigil), Some(foo));
+    }
 }
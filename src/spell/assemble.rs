@@ -0,0 +1,790 @@
+use super::*;
+
+use std::error::Error;
+use std::fmt;
+
+use sigil::Sigils;
+
+/// Build a spell database from assembly text.
+///
+/// The text consists of lines, and everything from a `;` to the end of a line
+/// is a comment. A line `spell book::name/arity` starts the definition of a
+/// spell, and the lines that follow, up to the next such line, define it. Each
+/// of them is an instruction, a label, or a constant. Instructions are written
+/// as [disassemble] writes them, without the index:
+///
+/// ```text
+/// spell book::count/1
+///     constant integer [01 00 00 00 00 00 00 00]
+///     v1 = load_constant 0
+/// loop:
+///     v2 = less_than v0, v1
+///     branch_if_truthy v2, done
+///     v0 = sub v0, v1
+///     jump loop
+/// done:
+///     return v0
+/// ```
+///
+/// A label is a name followed by a colon, and stands for the index of the
+/// instruction after it. Jump targets and offsets can be given as labels or as
+/// numbers. Labels are local to the spell they are defined in. A constant is
+/// the word `constant`, followed by an enchantment and an auxiliary part in
+/// hexadecimal bytes, and is appended to the constant pool of the spell.
+///
+/// Names are interned in the sigil database; `Sigil(n)` stands for the sigil
+/// with number _n_ instead. Every spell allocates as many local variables as
+/// its arity, or one more than the highest numbered local variable it refers
+/// to, if that is more. The spells are not verified, so that malformed spells
+/// can be written too; see [verify_as].
+///
+/// [disassemble]: fn.disassemble.html
+/// [verify_as]: fn.verify_as.html
+pub fn assemble(source: &str, sigils: &mut Sigils)
+    -> Result<Spells, AssembleError> {
+    let lines: Vec<(usize, Vec<&str>)> = source.lines().enumerate()
+        .map(|(index, line)| (index + 1, tokenize(line)))
+        .filter(|(_, tokens)| !tokens.is_empty())
+        .collect();
+
+    let labels = find_labels(&lines)?;
+
+    let mut spells = Spells::new();
+    let mut current: Option<Definition> = None;
+    let mut definitions = 0;
+    for (line, tokens) in &lines {
+        let mut parser = Parser{tokens, position: 0, line: *line};
+        if tokens[0] == "spell" {
+            if let Some(definition) = current.take() {
+                definition.define(&mut spells)?;
+            }
+            parser.position = 1;
+            let id = parser.spell_id(sigils)?;
+            parser.end()?;
+            current = Some(Definition{line: *line, id,
+                                      instructions: Vec::new(),
+                                      constants:    Vec::new()});
+            definitions += 1;
+            continue;
+        }
+
+        // Labels were found for every spell, so there is a current spell.
+        let definition = current.as_mut().expect("Line outside spell");
+        let labels = &labels[definitions - 1];
+        if tokens[0] == "constant" {
+            parser.position = 1;
+            let enchantment = parser.sigil(sigils)?;
+            let auxiliary = parser.bytes()?;
+            parser.end()?;
+            definition.constants.push(ConstantValue{enchantment, auxiliary});
+            continue;
+        }
+        if tokens.get(1) == Some(&":") {
+            parser.position = 2;
+            if parser.at_end() {
+                continue;
+            }
+        }
+        let index = definition.instructions.len();
+        let instruction = parser.instruction(sigils, labels, index)?;
+        parser.end()?;
+        definition.instructions.push(instruction);
+    }
+    if let Some(definition) = current {
+        definition.define(&mut spells)?;
+    }
+    Ok(spells)
+}
+
+/// A spell that is being assembled.
+struct Definition {
+    /// The line the definition starts at.
+    line:         usize,
+    id:           SpellId,
+    instructions: Vec<Instruction>,
+    constants:    Vec<ConstantValue>,
+}
+
+impl Definition {
+    /// Insert the spell into the database.
+    fn define(self, spells: &mut Spells) -> Result<(), AssembleError> {
+        let Definition{line, id, instructions, constants} = self;
+        let mut local_variables = id.arity;
+        for instruction in &instructions {
+            instruction.for_each_local(|local| {
+                local_variables = local_variables.max(local.0 as usize + 1);
+            });
+        }
+        let spell = Spell{instructions: instructions.into_boxed_slice(),
+                          local_variables,
+                          constants: constants.into_boxed_slice(),
+                          source_map: None};
+        spells.insert(id, spell).map_err(|RedefinitionError{id}| {
+            AssembleError{line, reason: AssembleErrorReason::Redefinition(id)}
+        })
+    }
+}
+
+/// Find the labels of every spell, and the index of the instruction each of
+/// them stands for.
+fn find_labels<'s>(lines: &[(usize, Vec<&'s str>)])
+    -> Result<Vec<HashMap<&'s str, usize>>, AssembleError> {
+    let mut labels: Vec<HashMap<&str, usize>> = Vec::new();
+    let mut instructions = 0;
+    for (line, tokens) in lines {
+        let error = |reason| AssembleError{line: *line, reason};
+        match tokens[0] {
+            "spell" => {
+                labels.push(HashMap::new());
+                instructions = 0;
+                continue;
+            },
+            "constant" => continue,
+            _ => (),
+        }
+
+        let current = labels.last_mut()
+            .ok_or_else(|| error(AssembleErrorReason::OutsideSpell))?;
+        if tokens.get(1) == Some(&":") {
+            let name = tokens[0];
+            if !is_name(name) || parse_local(name).is_some() {
+                return Err(error(AssembleErrorReason::Expected("a label")));
+            }
+            if current.insert(name, instructions).is_some() {
+                let reason = AssembleErrorReason::DuplicateLabel(name.into());
+                return Err(error(reason));
+            }
+            if tokens.len() == 2 {
+                continue;
+            }
+        }
+        instructions += 1;
+    }
+    Ok(labels)
+}
+
+/// Split a line into tokens, leaving out the comment. Punctuation is a token
+/// on its own, with `::` being a single token, and everything else is split
+/// at whitespace.
+fn tokenize(line: &str) -> Vec<&str> {
+    let line = line.split(';').next().unwrap_or("");
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut chars = line.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let punctuation = ",()[]=.@/:".contains(c);
+        if c.is_whitespace() || punctuation {
+            if let Some(start) = start.take() {
+                tokens.push(&line[start .. index]);
+            }
+        } else if start.is_none() {
+            start = Some(index);
+        }
+        if punctuation {
+            if c == ':' && chars.peek().map(|&(_, c)| c) == Some(':') {
+                chars.next();
+                tokens.push(&line[index .. index + 2]);
+            } else {
+                tokens.push(&line[index .. index + c.len_utf8()]);
+            }
+        }
+    }
+    if let Some(start) = start {
+        tokens.push(&line[start ..]);
+    }
+    tokens
+}
+
+/// Whether a token is a word rather than punctuation.
+fn is_name(token: &str) -> bool {
+    token != "::" && !(token.len() == 1 && ",()[]=.@/:".contains(token))
+}
+
+/// Parse a token of the form `v0`, `v1`, and so on.
+fn parse_local(token: &str) -> Option<Local> {
+    let digits = token.strip_prefix('v')?;
+    if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok().map(Local)
+    } else {
+        None
+    }
+}
+
+/// The tokens of a line, and how many of them were parsed already.
+struct Parser<'t, 's> {
+    tokens:   &'t [&'s str],
+    position: usize,
+    line:     usize,
+}
+
+impl<'t, 's> Parser<'t, 's> {
+    fn error(&self, reason: AssembleErrorReason) -> AssembleError {
+        AssembleError{line: self.line, reason}
+    }
+
+    fn expected(&self, what: &'static str) -> AssembleError {
+        self.error(AssembleErrorReason::Expected(what))
+    }
+
+    fn at_end(&self) -> bool {
+        self.position == self.tokens.len()
+    }
+
+    fn end(&self) -> Result<(), AssembleError> {
+        if self.at_end() { Ok(()) } else { Err(self.expected("end of line")) }
+    }
+
+    fn peek(&self) -> Option<&'s str> {
+        self.tokens.get(self.position).cloned()
+    }
+
+    fn next(&mut self, what: &'static str) -> Result<&'s str, AssembleError> {
+        let token = self.peek().ok_or_else(|| self.expected(what))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn punctuation(&mut self, token: &'static str)
+        -> Result<(), AssembleError> {
+        if self.peek() == Some(token) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.expected(token))
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, AssembleError> {
+        let token = self.next("a number")?;
+        if !token.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(self.expected("a number"));
+        }
+        token.parse().map_err(|_| self.error(AssembleErrorReason::Overflow))
+    }
+
+    fn local(&mut self) -> Result<Local, AssembleError> {
+        let token = self.next("a local variable")?;
+        parse_local(token).ok_or_else(|| self.expected("a local variable"))
+    }
+
+    /// Parse local variables separated by commas, between two tokens.
+    fn locals(&mut self, open: &'static str, close: &'static str)
+        -> Result<Box<[Local]>, AssembleError> {
+        self.punctuation(open)?;
+        let mut locals = Vec::new();
+        if self.peek() != Some(close) {
+            locals.push(self.local()?);
+            while self.peek() == Some(",") {
+                self.position += 1;
+                locals.push(self.local()?);
+            }
+        }
+        self.punctuation(close)?;
+        Ok(locals.into_boxed_slice())
+    }
+
+    fn sigil(&mut self, sigils: &mut Sigils) -> Result<Sigil, AssembleError> {
+        let name = self.next("a sigil")?;
+        if !is_name(name) {
+            return Err(self.expected("a sigil"));
+        }
+        let numbered = self.tokens.get(self.position ..)
+            .and_then(|rest| rest.get(.. 3))
+            .filter(|rest| rest[0] == "(" && rest[2] == ")")
+            .and_then(|rest| rest[1].parse().ok());
+        match numbered {
+            Some(number) if name == "Sigil" => {
+                self.position += 3;
+                Ok(Sigil(number))
+            },
+            _ => Ok(sigils.intern_str(name)),
+        }
+    }
+
+    /// Parse a spellbook and a spell separated by `::`.
+    fn path(&mut self, sigils: &mut Sigils)
+        -> Result<(Sigil, Sigil), AssembleError> {
+        let spellbook = self.sigil(sigils)?;
+        self.punctuation("::")?;
+        let spell = self.sigil(sigils)?;
+        Ok((spellbook, spell))
+    }
+
+    fn spell_id(&mut self, sigils: &mut Sigils)
+        -> Result<SpellId, AssembleError> {
+        let (spellbook, spell) = self.path(sigils)?;
+        self.punctuation("/")?;
+        let arity = self.number()?;
+        Ok(SpellId{spellbook, spell, arity})
+    }
+
+    /// Parse hexadecimal bytes between brackets.
+    fn bytes(&mut self) -> Result<Box<[u8]>, AssembleError> {
+        self.punctuation("[")?;
+        let mut bytes = Vec::new();
+        while self.peek() != Some("]") {
+            let token = self.next("a byte")?;
+            let byte = Some(token)
+                .filter(|token| token.len() <= 2)
+                .and_then(|token| u8::from_str_radix(token, 16).ok())
+                .ok_or_else(|| self.expected("a byte"))?;
+            bytes.push(byte);
+        }
+        self.punctuation("]")?;
+        Ok(bytes.into_boxed_slice())
+    }
+
+    /// Parse a jump target, which is a label or an instruction index.
+    fn target(&mut self, labels: &HashMap<&str, usize>)
+        -> Result<usize, AssembleError> {
+        match self.peek() {
+            Some(token) if token.starts_with(|c: char| c.is_ascii_digit()) =>
+                self.number(),
+            _ => self.label(labels),
+        }
+    }
+
+    /// Parse a jump offset from the instruction with the given index, which is
+    /// a label or a signed number.
+    fn offset(&mut self, labels: &HashMap<&str, usize>, index: usize)
+        -> Result<isize, AssembleError> {
+        let token = self.peek().ok_or_else(|| self.expected("an offset"))?;
+        if !token.starts_with(|c: char| c == '+' || c == '-' ||
+                                        c.is_ascii_digit()) {
+            let target = self.label(labels)?;
+            return Ok(target as isize - index as isize);
+        }
+        self.position += 1;
+        token.parse().map_err(|_| self.error(AssembleErrorReason::Overflow))
+    }
+
+    fn label(&mut self, labels: &HashMap<&str, usize>)
+        -> Result<usize, AssembleError> {
+        let name = self.next("a label")?;
+        if !is_name(name) {
+            return Err(self.expected("a label"));
+        }
+        labels.get(name).cloned().ok_or_else(|| {
+            self.error(AssembleErrorReason::UndefinedLabel(name.into()))
+        })
+    }
+
+    /// Parse an instruction, which has the given index in its spell.
+    fn instruction(&mut self,
+                   sigils: &mut Sigils,
+                   labels: &HashMap<&str, usize>,
+                   index:  usize,
+                   ) -> Result<Instruction, AssembleError> {
+        let result = match self.tokens.get(self.position + 1) {
+            Some(&"=") => {
+                let result = self.local()?;
+                self.position += 1;
+                Some(result)
+            },
+            _ => None,
+        };
+        let mnemonic_at = self.position;
+        let mnemonic = self.next("an instruction")?;
+        let unknown = || AssembleError{
+            line:   self.line,
+            reason: AssembleErrorReason::UnknownMnemonic(mnemonic.into()),
+        };
+
+        if let Some(result) = result {
+            let instruction = match mnemonic {
+                "copy" => Instruction::Copy{from: self.local()?, to: result},
+                "copy_range" => {
+                    let from = self.local()?;
+                    self.punctuation(",")?;
+                    let count = self.number()?;
+                    Instruction::CopyRange{from, to: result, count}
+                },
+                "invoke_static" => {
+                    let (spellbook, spell) = self.path(sigils)?;
+                    let arguments = self.locals("(", ")")?;
+                    Instruction::InvokeStatic{result, spellbook, spell,
+                                              arguments}
+                },
+                "invoke_linked" => {
+                    let (spellbook, spell) = self.path(sigils)?;
+                    self.punctuation("@")?;
+                    let index = self.number()?;
+                    let arguments = self.locals("(", ")")?;
+                    Instruction::InvokeLinked{result, spellbook, spell, index,
+                                              arguments}
+                },
+                "invoke_dynamic" | "invoke_dynamic_cached" => {
+                    let receiver = self.local()?;
+                    self.punctuation(".")?;
+                    let spell = self.sigil(sigils)?;
+                    let arguments = self.locals("(", ")")?;
+                    if mnemonic == "invoke_dynamic" {
+                        Instruction::InvokeDynamic{result, spell, receiver,
+                                                   arguments}
+                    } else {
+                        Instruction::InvokeDynamicCached{
+                            result, spell, receiver, arguments,
+                            cache: InlineCache::new(),
+                        }
+                    }
+                },
+                "make_closure" => {
+                    let (spellbook, spell) = self.path(sigils)?;
+                    let captures = self.locals("[", "]")?;
+                    Instruction::MakeClosure{result, spellbook, spell,
+                                             captures}
+                },
+                "invoke_closure" => {
+                    let closure = self.local()?;
+                    let arguments = self.locals("(", ")")?;
+                    Instruction::InvokeClosure{result, closure, arguments}
+                },
+                "add" | "sub" | "mul" | "div" |
+                "equal" | "less_than" | "greater_than" => {
+                    let lhs = self.local()?;
+                    self.punctuation(",")?;
+                    let rhs = self.local()?;
+                    match mnemonic {
+                        "add" => Instruction::Add{result, lhs, rhs},
+                        "sub" => Instruction::Sub{result, lhs, rhs},
+                        "mul" => Instruction::Mul{result, lhs, rhs},
+                        "div" => Instruction::Div{result, lhs, rhs},
+                        "equal" => Instruction::Equal{result, lhs, rhs},
+                        "less_than" =>
+                            Instruction::LessThan{result, lhs, rhs},
+                        _ => Instruction::GreaterThan{result, lhs, rhs},
+                    }
+                },
+                "allocate" => {
+                    let enchantment = self.sigil(sigils)?;
+                    let pointers = self.locals("(", ")")?;
+                    let auxiliary = self.bytes()?;
+                    Instruction::Allocate{result, enchantment, pointers,
+                                          auxiliary}
+                },
+                "load_constant" =>
+                    Instruction::LoadConstant{result, index: self.number()?},
+                "get_pointer" => {
+                    let object = self.local()?;
+                    self.punctuation(",")?;
+                    let index = self.number()?;
+                    Instruction::GetPointer{result, object, index}
+                },
+                "enchantment_of" =>
+                    Instruction::EnchantmentOf{result, object: self.local()?},
+                "is_enchanted" => {
+                    let object = self.local()?;
+                    self.punctuation(",")?;
+                    let enchantment = self.sigil(sigils)?;
+                    Instruction::IsEnchanted{result, object, enchantment}
+                },
+                "auxiliary_len" =>
+                    Instruction::AuxiliaryLen{result, object: self.local()?},
+                _ => return Err(unknown()),
+            };
+            return Ok(instruction);
+        }
+
+        let instruction = match mnemonic {
+            "swap" => {
+                let a = self.local()?;
+                self.punctuation(",")?;
+                let b = self.local()?;
+                Instruction::Swap{a, b}
+            },
+            "nop" => Instruction::Nop,
+            "jump" => Instruction::Jump{target: self.target(labels)?},
+            "jump_relative" =>
+                Instruction::JumpRelative{offset: self.offset(labels, index)?},
+            "switch" => {
+                let scrutinee = self.local()?;
+                self.punctuation(",")?;
+                self.punctuation("[")?;
+                let mut targets = Vec::new();
+                if self.peek() != Some("]") {
+                    targets.push(self.target(labels)?);
+                    while self.peek() == Some(",") {
+                        self.position += 1;
+                        targets.push(self.target(labels)?);
+                    }
+                }
+                self.punctuation("]")?;
+                self.punctuation(",")?;
+                let default = self.target(labels)?;
+                Instruction::Switch{scrutinee, targets: targets.into(),
+                                    default}
+            },
+            "branch_if_truthy" | "branch_if_falsy" => {
+                let condition = self.local()?;
+                self.punctuation(",")?;
+                let target = self.target(labels)?;
+                if mnemonic == "branch_if_truthy" {
+                    Instruction::BranchIfTruthy{condition, target}
+                } else {
+                    Instruction::BranchIfFalsy{condition, target}
+                }
+            },
+            "branch_if_truthy_relative" | "branch_if_falsy_relative" => {
+                let condition = self.local()?;
+                self.punctuation(",")?;
+                let offset = self.offset(labels, index)?;
+                if mnemonic == "branch_if_truthy_relative" {
+                    Instruction::BranchIfTruthyRelative{condition, offset}
+                } else {
+                    Instruction::BranchIfFalsyRelative{condition, offset}
+                }
+            },
+            "push_handler" => {
+                let target = self.target(labels)?;
+                self.punctuation(",")?;
+                let exception = self.local()?;
+                Instruction::PushHandler{target, exception}
+            },
+            "pop_handler" => Instruction::PopHandler,
+            "throw" => Instruction::Throw{value: self.local()?},
+            "return" => Instruction::Return{result: self.local()?},
+            _ if parse_local(mnemonic).is_some() => {
+                self.position = mnemonic_at + 1;
+                return Err(self.expected("="));
+            },
+            _ => return Err(unknown()),
+        };
+        Ok(instruction)
+    }
+}
+
+/// This error is returned when assembly text is malformed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AssembleError {
+    /// The number of the offending line, counting from one.
+    pub line: usize,
+
+    /// Why the line is malformed.
+    pub reason: AssembleErrorReason,
+}
+
+/// Why a line of assembly text is malformed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AssembleErrorReason {
+    /// Something else was found where the parser expected this.
+    Expected(&'static str),
+
+    /// The instruction does not exist, or does not have a result if one was
+    /// given, or has one if none was given.
+    UnknownMnemonic(String),
+
+    /// A jump refers to a label that the spell does not define.
+    UndefinedLabel(String),
+
+    /// The spell defines the same label twice.
+    DuplicateLabel(String),
+
+    /// A number does not fit in the integer type it is read into.
+    Overflow,
+
+    /// An instruction, label, or constant occurs before the first spell.
+    OutsideSpell,
+
+    /// The same spell is defined more than once.
+    Redefinition(SpellId),
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl Error for AssembleError {}
+
+impl fmt::Display for AssembleErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssembleErrorReason::Expected(what) =>
+                write!(f, "expected {}", what),
+            AssembleErrorReason::UnknownMnemonic(mnemonic) =>
+                write!(f, "unknown instruction {}", mnemonic),
+            AssembleErrorReason::UndefinedLabel(label) =>
+                write!(f, "label {} is not defined", label),
+            AssembleErrorReason::DuplicateLabel(label) =>
+                write!(f, "label {} is defined more than once", label),
+            AssembleErrorReason::Overflow =>
+                write!(f, "number out of range"),
+            AssembleErrorReason::OutsideSpell =>
+                write!(f, "expected a spell definition"),
+            AssembleErrorReason::Redefinition(id) =>
+                write!(f, "spell {} is defined more than once", id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use datum::Heap;
+    use interpret::Runtime;
+    use interpret::TypeSigils;
+    use interpret::run;
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+            ; count(n) counts down to zero, and main(n) counts down from n.
+            spell book::count/1
+                constant integer [01 00 00 00 00 00 00 00]
+                v1 = load_constant 0
+            loop:
+                v2 = less_than v0, v1
+                branch_if_truthy v2, done
+                v0 = sub v0, v1
+                jump loop
+            done: return v0
+
+            spell book::main/1
+                v3 = invoke_static book::count(v0)  ; v1 and v2 are unused
+                return v3
+        ";
+        let mut sigils = Sigils::new();
+        let spells = assemble(source, &mut sigils).unwrap();
+        assert_eq!(spells.len(), 2);
+
+        let book = sigils.intern_str("book");
+        let count_id = SpellId{spellbook: book,
+                               spell: sigils.intern_str("count"), arity: 1};
+        let main_id = SpellId{spellbook: book,
+                              spell: sigils.intern_str("main"), arity: 1};
+        let count = spells.get(count_id).unwrap();
+        assert_eq!(count.local_variables, 3);
+        assert!(matches!(count.instructions[2],
+                         Instruction::BranchIfTruthy{condition: Local(2),
+                                                     target: 5}));
+        assert!(matches!(count.instructions[4], Instruction::Jump{target: 1}));
+        assert_eq!(&*count.constants, &[ConstantValue{
+            enchantment: sigils.intern_str("integer"),
+            auxiliary:   Box::new(1i64.to_le_bytes()),
+        }]);
+        assert_eq!(spells.get(main_id).unwrap().local_variables, 4);
+        assert!(verify_as(count_id, count).is_ok());
+
+        let heap = Heap::new();
+        let types = TypeSigils::intern(&mut sigils);
+        let runtime = Runtime{spells: &spells, heap: &heap, types};
+        let result = run(&runtime, main_id, &[Datum::from_i64(5).unwrap()])
+            .as_i64();
+        assert_eq!(result, Some(0));
+    }
+
+    #[test]
+    fn test_assemble_disassemble() {
+        let instructions = [
+            "v1 = copy v0",
+            "v1 = invoke_static book::double(v0, v1)",
+            "v2 = invoke_dynamic v1.Sigil(9)()",
+            "branch_if_truthy v2, 10",
+            "branch_if_falsy v2, 0",
+            "v0 = add v1, v2",
+            "v0 = sub v1, v2",
+            "v0 = mul v1, v2",
+            "v0 = div v1, v2",
+            "v2 = allocate book(v0, v1) [0a ff]",
+            "v1 = get_pointer v2, 1",
+            "v1 = enchantment_of v2",
+            "v1 = auxiliary_len v2",
+            "push_handler 23, v1",
+            "pop_handler",
+            "throw v2",
+            "swap v2, v0",
+            "nop",
+            "v0 = equal v1, v2",
+            "v0 = less_than v1, v2",
+            "v0 = greater_than v1, v2",
+            "switch v0, [0, 23], 22",
+            "jump 23",
+            "jump_relative +3",
+            "branch_if_truthy_relative v1, -2",
+            "branch_if_falsy_relative v2, +0",
+            "v0 = load_constant 4",
+            "v1 = make_closure book::double[v0, v2]",
+            "v0 = invoke_closure v1(v2)",
+            "v0 = invoke_linked book::double@7(v1)",
+            "v0 = invoke_dynamic_cached v1.double(v2)",
+            "v1 = copy_range v0, 2",
+            "v1 = is_enchanted v2, book",
+            "return v0",
+        ];
+        let source = format!("spell book::main/0\n{}", instructions.join("\n"));
+        let mut sigils = Sigils::new();
+        let spells = assemble(&source, &mut sigils).unwrap();
+        let (_, spell) = spells.iter().next().unwrap();
+        assert_eq!(spell.local_variables, 3);
+
+        let disassembly = disassemble(spell, &sigils);
+        let lines: Vec<&str> = disassembly.lines()
+            .map(|line| line.split(": ").nth(1).unwrap())
+            .collect();
+        assert_eq!(lines, instructions);
+    }
+
+    #[test]
+    fn test_assemble_relative_labels() {
+        let source = "
+            spell Sigil(0)::Sigil(1)/1
+            top:
+                branch_if_falsy_relative v0, bottom
+                jump_relative top
+            bottom:
+                return v0
+        ";
+        let mut sigils = Sigils::new();
+        let spells = assemble(source, &mut sigils).unwrap();
+        let id = SpellId{spellbook: Sigil(0), spell: Sigil(1), arity: 1};
+        assert!(matches!(&*spells.get(id).unwrap().instructions, [
+            Instruction::BranchIfFalsyRelative{condition: Local(0), offset: 2},
+            Instruction::JumpRelative{offset: -1},
+            Instruction::Return{result: Local(0)},
+        ]));
+        assert!(sigils.is_empty());
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error = |source: &str| {
+            let error = assemble(source, &mut Sigils::new()).unwrap_err();
+            (error.line, error.reason)
+        };
+        let header = "spell book::main/0\n";
+
+        assert_eq!(error("return v0"), (1, AssembleErrorReason::OutsideSpell));
+        assert_eq!(error(&format!("{}\n  frobnicate v0", header)),
+                   (3, AssembleErrorReason::UnknownMnemonic(
+                       "frobnicate".into())));
+        assert_eq!(error(&format!("{}v0 = return v0", header)),
+                   (2, AssembleErrorReason::UnknownMnemonic("return".into())));
+        assert_eq!(error(&format!("{}v0 copy v1", header)),
+                   (2, AssembleErrorReason::Expected("=")));
+        assert_eq!(error(&format!("{}jump nowhere", header)),
+                   (2, AssembleErrorReason::UndefinedLabel("nowhere".into())));
+        assert_eq!(error(&format!("{}a:\na: nop", header)),
+                   (3, AssembleErrorReason::DuplicateLabel("a".into())));
+        assert_eq!(error(&format!("{}v1 = add v0 v0", header)),
+                   (2, AssembleErrorReason::Expected(",")));
+        assert_eq!(error(&format!("{}return v0 v1", header)),
+                   (2, AssembleErrorReason::Expected("end of line")));
+        assert_eq!(error(&format!("{}v0 = allocate x() [100]", header)),
+                   (2, AssembleErrorReason::Expected("a byte")));
+        assert_eq!(error(&format!("{}jump 99999999999999999999", header)),
+                   (2, AssembleErrorReason::Overflow));
+        assert_eq!(error("spell book::main\n"),
+                   (1, AssembleErrorReason::Expected("/")));
+
+        let mut sigils = Sigils::new();
+        let error = assemble(&format!("{}nop\n{}", header, header),
+                             &mut sigils).unwrap_err();
+        let id = SpellId{spellbook: sigils.intern_str("book"),
+                         spell: sigils.intern_str("main"), arity: 0};
+        assert_eq!(error.reason, AssembleErrorReason::Redefinition(id));
+        assert_eq!(error.to_string(),
+                   format!("line 3: spell {} is defined more than once", id));
+    }
+}
@@ -0,0 +1,586 @@
+use super::*;
+
+use std::mem;
+
+/// A builder for spells, which keeps track of local variables and jump
+/// targets.
+///
+/// Local variables handed out by [local] are numbered from zero, so the first
+/// _n_ calls return the local variables that receive the arguments of a spell
+/// with arity _n_. Jump targets are given as labels, which may be bound to an
+/// instruction after the jumps to them have been emitted.
+///
+/// [local]: #method.local
+#[derive(Debug)]
+pub struct SpellBuilder {
+    instructions:    Vec<Instruction>,
+    local_variables: usize,
+
+    /// For each label, the index of the instruction it is bound to, if any.
+    labels:          Vec<Option<usize>>,
+
+    /// The instructions whose jump targets are labels, along with the
+    /// position of the jump target among those of the instruction.
+    fixups:          Vec<(usize, usize, Label)>,
+
+    constants:       Vec<ConstantValue>,
+
+    /// The source span of every instruction, if a source span was set before
+    /// the first instruction was emitted, and the span for the next one.
+    source_map:      Option<(Vec<SourceSpan>, SourceSpan)>,
+}
+
+/// A label stands for the index of an instruction in a spell that is being
+/// built. See [SpellBuilder::label].
+///
+/// [SpellBuilder::label]: struct.SpellBuilder.html#method.label
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Label(usize);
+
+impl SpellBuilder {
+    /// Create a builder for an empty spell.
+    pub fn new() -> Self {
+        SpellBuilder{
+            instructions:    Vec::new(),
+            local_variables: 0,
+            labels:          Vec::new(),
+            fixups:          Vec::new(),
+            constants:       Vec::new(),
+            source_map:      None,
+        }
+    }
+
+    /// Allocate a local variable that is not used by any instruction yet.
+    pub fn local(&mut self) -> Local {
+        let local = Local(self.local_variables as u32);
+        self.local_variables += 1;
+        local
+    }
+
+    /// Create a label that is not yet bound to an instruction.
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Bind a label to the next instruction to be emitted.
+    ///
+    /// Panics if the label is already bound.
+    pub fn bind(&mut self, label: Label) -> &mut Self {
+        let binding = &mut self.labels[label.0];
+        assert!(binding.is_none(), "Label is already bound");
+        *binding = Some(self.instructions.len());
+        self
+    }
+
+    /// Set the source span of the instructions emitted from now on. Once a
+    /// source span is set, the spell gets a source map.
+    ///
+    /// Panics if instructions were emitted before the first source span.
+    pub fn span(&mut self, span: SourceSpan) -> &mut Self {
+        match &mut self.source_map {
+            Some((_, next)) => *next = span,
+            None => {
+                assert!(self.instructions.is_empty(),
+                        "Instructions were emitted without a source span");
+                self.source_map = Some((Vec::new(), span));
+            },
+        }
+        self
+    }
+
+    /// Emit an instruction.
+    ///
+    /// Local variables the instruction refers to count towards the local
+    /// variables of the spell, even if they were not allocated with [local].
+    /// Jump targets are taken literally.
+    ///
+    /// [local]: #method.local
+    pub fn instruction(&mut self, instruction: Instruction) -> &mut Self {
+        let mut local_variables = self.local_variables;
+        instruction.for_each_local(|local| {
+            local_variables = local_variables.max(local.0 as usize + 1);
+        });
+        self.local_variables = local_variables;
+        if let Some((spans, next)) = &mut self.source_map {
+            spans.push(*next);
+        }
+        self.instructions.push(instruction);
+        self
+    }
+
+    /// Emit a [Copy](enum.Instruction.html#variant.Copy) instruction.
+    pub fn copy(&mut self, from: Local, to: Local) -> &mut Self {
+        self.instruction(Instruction::Copy{from, to})
+    }
+
+    /// Emit a [CopyRange](enum.Instruction.html#variant.CopyRange)
+    /// instruction.
+    pub fn copy_range(&mut self, from: Local, to: Local, count: usize)
+        -> &mut Self
+    {
+        self.instruction(Instruction::CopyRange{from, to, count})
+    }
+
+    /// Emit a [Swap](enum.Instruction.html#variant.Swap) instruction.
+    pub fn swap(&mut self, a: Local, b: Local) -> &mut Self {
+        self.instruction(Instruction::Swap{a, b})
+    }
+
+    /// Emit a [Nop](enum.Instruction.html#variant.Nop) instruction.
+    pub fn nop(&mut self) -> &mut Self {
+        self.instruction(Instruction::Nop)
+    }
+
+    /// Emit an [InvokeStatic](enum.Instruction.html#variant.InvokeStatic)
+    /// instruction.
+    pub fn invoke_static(&mut self,
+                         result:    Local,
+                         spellbook: Sigil,
+                         spell:     Sigil,
+                         arguments: &[Local],
+                         ) -> &mut Self {
+        self.instruction(Instruction::InvokeStatic{
+            result,
+            spellbook,
+            spell,
+            arguments: Box::from(arguments),
+        })
+    }
+
+    /// Emit an [InvokeDynamic](enum.Instruction.html#variant.InvokeDynamic)
+    /// instruction.
+    pub fn invoke_dynamic(&mut self,
+                          result:    Local,
+                          spell:     Sigil,
+                          receiver:  Local,
+                          arguments: &[Local],
+                          ) -> &mut Self {
+        self.instruction(Instruction::InvokeDynamic{
+            result,
+            spell,
+            receiver,
+            arguments: Box::from(arguments),
+        })
+    }
+
+    /// Emit a [MakeClosure](enum.Instruction.html#variant.MakeClosure)
+    /// instruction.
+    pub fn make_closure(&mut self,
+                        result:    Local,
+                        spellbook: Sigil,
+                        spell:     Sigil,
+                        captures:  &[Local],
+                        ) -> &mut Self {
+        self.instruction(Instruction::MakeClosure{
+            result,
+            spellbook,
+            spell,
+            captures: Box::from(captures),
+        })
+    }
+
+    /// Emit an [InvokeClosure](enum.Instruction.html#variant.InvokeClosure)
+    /// instruction.
+    pub fn invoke_closure(&mut self,
+                          result:    Local,
+                          closure:   Local,
+                          arguments: &[Local],
+                          ) -> &mut Self {
+        self.instruction(Instruction::InvokeClosure{
+            result,
+            closure,
+            arguments: Box::from(arguments),
+        })
+    }
+
+    /// Emit a [Jump](enum.Instruction.html#variant.Jump) instruction.
+    pub fn jump(&mut self, target: Label) -> &mut Self {
+        self.fixup(target);
+        self.instruction(Instruction::Jump{target: 0})
+    }
+
+    /// Emit a [JumpRelative](enum.Instruction.html#variant.JumpRelative)
+    /// instruction.
+    pub fn jump_relative(&mut self, target: Label) -> &mut Self {
+        self.fixup(target);
+        self.instruction(Instruction::JumpRelative{offset: 0})
+    }
+
+    /// Emit a [Switch](enum.Instruction.html#variant.Switch) instruction.
+    pub fn switch(&mut self,
+                  scrutinee: Local,
+                  targets:   &[Label],
+                  default:   Label,
+                  ) -> &mut Self {
+        for (position, &target) in targets.iter().enumerate() {
+            self.fixups.push((self.instructions.len(), position, target));
+        }
+        self.fixups.push((self.instructions.len(), targets.len(), default));
+        self.instruction(Instruction::Switch{
+            scrutinee,
+            targets: vec![0; targets.len()].into_boxed_slice(),
+            default: 0,
+        })
+    }
+
+    /// Emit a [BranchIfTruthy](enum.Instruction.html#variant.BranchIfTruthy)
+    /// instruction.
+    pub fn branch_if_truthy(&mut self, condition: Local, target: Label)
+        -> &mut Self
+    {
+        self.fixup(target);
+        self.instruction(Instruction::BranchIfTruthy{condition, target: 0})
+    }
+
+    /// Emit a [BranchIfFalsy](enum.Instruction.html#variant.BranchIfFalsy)
+    /// instruction.
+    pub fn branch_if_falsy(&mut self, condition: Local, target: Label)
+        -> &mut Self
+    {
+        self.fixup(target);
+        self.instruction(Instruction::BranchIfFalsy{condition, target: 0})
+    }
+
+    /// Emit a `BranchIfTruthyRelative` instruction.
+    pub fn branch_if_truthy_relative(&mut self, condition: Local, target: Label)
+        -> &mut Self
+    {
+        self.fixup(target);
+        self.instruction(Instruction::BranchIfTruthyRelative{condition,
+                                                             offset: 0})
+    }
+
+    /// Emit a `BranchIfFalsyRelative` instruction.
+    pub fn branch_if_falsy_relative(&mut self, condition: Local, target: Label)
+        -> &mut Self
+    {
+        self.fixup(target);
+        self.instruction(Instruction::BranchIfFalsyRelative{condition,
+                                                            offset: 0})
+    }
+
+    /// Emit an [Add](enum.Instruction.html#variant.Add) instruction.
+    pub fn add(&mut self, result: Local, lhs: Local, rhs: Local) -> &mut Self {
+        self.instruction(Instruction::Add{result, lhs, rhs})
+    }
+
+    /// Emit a [Sub](enum.Instruction.html#variant.Sub) instruction.
+    pub fn sub(&mut self, result: Local, lhs: Local, rhs: Local) -> &mut Self {
+        self.instruction(Instruction::Sub{result, lhs, rhs})
+    }
+
+    /// Emit a [Mul](enum.Instruction.html#variant.Mul) instruction.
+    pub fn mul(&mut self, result: Local, lhs: Local, rhs: Local) -> &mut Self {
+        self.instruction(Instruction::Mul{result, lhs, rhs})
+    }
+
+    /// Emit a [Div](enum.Instruction.html#variant.Div) instruction.
+    pub fn div(&mut self, result: Local, lhs: Local, rhs: Local) -> &mut Self {
+        self.instruction(Instruction::Div{result, lhs, rhs})
+    }
+
+    /// Emit an [Equal](enum.Instruction.html#variant.Equal) instruction.
+    pub fn equal(&mut self, result: Local, lhs: Local, rhs: Local)
+        -> &mut Self
+    {
+        self.instruction(Instruction::Equal{result, lhs, rhs})
+    }
+
+    /// Emit a [LessThan](enum.Instruction.html#variant.LessThan) instruction.
+    pub fn less_than(&mut self, result: Local, lhs: Local, rhs: Local)
+        -> &mut Self
+    {
+        self.instruction(Instruction::LessThan{result, lhs, rhs})
+    }
+
+    /// Emit a [GreaterThan](enum.Instruction.html#variant.GreaterThan)
+    /// instruction.
+    pub fn greater_than(&mut self, result: Local, lhs: Local, rhs: Local)
+        -> &mut Self
+    {
+        self.instruction(Instruction::GreaterThan{result, lhs, rhs})
+    }
+
+    /// Emit an [Allocate](enum.Instruction.html#variant.Allocate)
+    /// instruction.
+    pub fn allocate(&mut self,
+                    result:      Local,
+                    enchantment: Sigil,
+                    pointers:    &[Local],
+                    auxiliary:   &[u8],
+                    ) -> &mut Self {
+        self.instruction(Instruction::Allocate{
+            result,
+            enchantment,
+            pointers:  Box::from(pointers),
+            auxiliary: Box::from(auxiliary),
+        })
+    }
+
+    /// Add a constant to the constant pool of the spell, and return its index
+    /// for use with [load_constant]. Adding a constant that is already in the
+    /// pool returns the index of the existing one.
+    ///
+    /// [load_constant]: #method.load_constant
+    pub fn constant(&mut self, constant: ConstantValue) -> usize {
+        match self.constants.iter().position(|other| *other == constant) {
+            Some(index) => index,
+            None => {
+                self.constants.push(constant);
+                self.constants.len() - 1
+            },
+        }
+    }
+
+    /// Emit a [LoadConstant](enum.Instruction.html#variant.LoadConstant)
+    /// instruction.
+    pub fn load_constant(&mut self, result: Local, index: usize) -> &mut Self {
+        self.instruction(Instruction::LoadConstant{result, index})
+    }
+
+    /// Emit a [GetPointer](enum.Instruction.html#variant.GetPointer)
+    /// instruction.
+    pub fn get_pointer(&mut self, result: Local, object: Local, index: usize)
+        -> &mut Self
+    {
+        self.instruction(Instruction::GetPointer{result, object, index})
+    }
+
+    /// Emit an [EnchantmentOf](enum.Instruction.html#variant.EnchantmentOf)
+    /// instruction.
+    pub fn enchantment_of(&mut self, result: Local, object: Local)
+        -> &mut Self
+    {
+        self.instruction(Instruction::EnchantmentOf{result, object})
+    }
+
+    /// Emit an [IsEnchanted](enum.Instruction.html#variant.IsEnchanted)
+    /// instruction.
+    pub fn is_enchanted(&mut self,
+                        result:      Local,
+                        object:      Local,
+                        enchantment: Sigil,
+                        ) -> &mut Self {
+        self.instruction(Instruction::IsEnchanted{result, object,
+                                                  enchantment})
+    }
+
+    /// Emit an [AuxiliaryLen](enum.Instruction.html#variant.AuxiliaryLen)
+    /// instruction.
+    pub fn auxiliary_len(&mut self, result: Local, object: Local)
+        -> &mut Self
+    {
+        self.instruction(Instruction::AuxiliaryLen{result, object})
+    }
+
+    /// Emit a [PushHandler](enum.Instruction.html#variant.PushHandler)
+    /// instruction.
+    pub fn push_handler(&mut self, target: Label, exception: Local)
+        -> &mut Self
+    {
+        self.fixup(target);
+        self.instruction(Instruction::PushHandler{target: 0, exception})
+    }
+
+    /// Emit a [PopHandler](enum.Instruction.html#variant.PopHandler)
+    /// instruction.
+    pub fn pop_handler(&mut self) -> &mut Self {
+        self.instruction(Instruction::PopHandler)
+    }
+
+    /// Emit a [Throw](enum.Instruction.html#variant.Throw) instruction.
+    pub fn throw(&mut self, value: Local) -> &mut Self {
+        self.instruction(Instruction::Throw{value})
+    }
+
+    /// Emit a [Return](enum.Instruction.html#variant.Return) instruction.
+    pub fn ret(&mut self, result: Local) -> &mut Self {
+        self.instruction(Instruction::Return{result})
+    }
+
+    /// Resolve the labels and create the spell.
+    ///
+    /// Panics if a label that is jumped to was never bound. The spell is not
+    /// verified. The builder is left empty, so that it can be reused.
+    pub fn build(&mut self) -> Spell {
+        let mut instructions = mem::take(&mut self.instructions);
+        for (index, position, label) in self.fixups.drain(..) {
+            let resolved = self.labels[label.0].expect("Label is not bound");
+            match instructions[index] {
+                Instruction::Jump{ref mut target}              |
+                Instruction::BranchIfTruthy{ref mut target, ..} |
+                Instruction::BranchIfFalsy{ref mut target, ..}  |
+                Instruction::PushHandler{ref mut target, ..}    =>
+                    *target = resolved,
+                Instruction::JumpRelative{ref mut offset}              |
+                Instruction::BranchIfTruthyRelative{ref mut offset, ..} |
+                Instruction::BranchIfFalsyRelative{ref mut offset, ..}  =>
+                    *offset = resolved as isize - index as isize,
+                Instruction::Switch{ref mut targets, ref mut default, ..} =>
+                    *targets.get_mut(position).unwrap_or(default) = resolved,
+                _ => unreachable!(),
+            }
+        }
+
+        let local_variables = self.local_variables;
+        let constants = mem::take(&mut self.constants).into_boxed_slice();
+        let source_map = self.source_map.take()
+            .map(|(spans, _)| spans.into_boxed_slice());
+        *self = SpellBuilder::new();
+
+        Spell{instructions: instructions.into_boxed_slice(), local_variables,
+              constants, source_map}
+    }
+
+    /// Record that the next instruction jumps to a label.
+    fn fixup(&mut self, label: Label) {
+        self.fixups.push((self.instructions.len(), 0, label));
+    }
+}
+
+impl Default for SpellBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let mut builder = SpellBuilder::new();
+        let argument = builder.local();
+        let result = builder.local();
+        let done = builder.label();
+        let again = builder.label();
+
+        let spell =
+            builder
+                .bind(again)
+                .branch_if_falsy(argument, done)
+                .invoke_static(result, Sigil(0), Sigil(1), &[argument])
+                .jump(again)
+                .bind(done)
+                .ret(result)
+                .build();
+
+        assert_eq!(spell.local_variables, 2);
+        assert_eq!(spell.instructions[0].jump_targets(), &[3]);
+        assert_eq!(spell.instructions[2].jump_targets(), &[0]);
+        assert!(verify(&spell).is_ok());
+    }
+
+    #[test]
+    fn test_switch() {
+        let mut builder = SpellBuilder::new();
+        let scrutinee = builder.local();
+        let (a, b, other) = (builder.label(), builder.label(), builder.label());
+
+        let spell =
+            builder
+                .switch(scrutinee, &[a, b, a], other)
+                .bind(b)
+                .bind(a)
+                .ret(scrutinee)
+                .bind(other)
+                .ret(scrutinee)
+                .build();
+
+        assert_eq!(spell.instructions[0].jump_targets(), &[1, 1, 1, 2]);
+        assert!(verify(&spell).is_ok());
+    }
+
+    #[test]
+    fn test_relative() {
+        let mut builder = SpellBuilder::new();
+        let argument = builder.local();
+        let (again, done) = (builder.label(), builder.label());
+
+        let spell =
+            builder
+                .bind(again)
+                .branch_if_falsy_relative(argument, done)
+                .nop()
+                .jump_relative(again)
+                .bind(done)
+                .ret(argument)
+                .build();
+
+        assert_eq!(spell.instructions[0].jump_offset(), Some(3));
+        assert_eq!(spell.instructions[2].jump_offset(), Some(-2));
+        assert!(verify(&spell).is_ok());
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut builder = SpellBuilder::new();
+        let result = builder.local();
+        let constant = |auxiliary: &[u8]| ConstantValue{
+            enchantment: Sigil(0),
+            auxiliary:   Box::from(auxiliary),
+        };
+
+        let a = builder.constant(constant(b"a"));
+        let b = builder.constant(constant(b"b"));
+        assert_eq!(builder.constant(constant(b"a")), a);
+
+        let spell =
+            builder
+                .load_constant(result, b)
+                .ret(result)
+                .build();
+
+        assert_eq!(&*spell.constants, &[constant(b"a"), constant(b"b")]);
+        assert!(verify(&spell).is_ok());
+        assert!(builder.build().constants.is_empty());
+    }
+
+    #[test]
+    fn test_source_map() {
+        let span = |line| SourceSpan{file: Sigil(0), line, column: 1};
+
+        let mut builder = SpellBuilder::new();
+        let local = builder.local();
+        let spell =
+            builder
+                .span(span(1))
+                .nop()
+                .nop()
+                .span(span(2))
+                .ret(local)
+                .build();
+        assert_eq!(spell.source_map.as_deref(),
+                   Some(&[span(1), span(1), span(2)][..]));
+
+        let spell = builder.ret(local).build();
+        assert!(spell.source_map.is_none());
+    }
+
+    #[test]
+    fn test_explicit_locals() {
+        let mut builder = SpellBuilder::new();
+        builder.copy(Local(0), Local(4));
+        assert_eq!(builder.local(), Local(5));
+        let spell = builder.ret(Local(5)).build();
+        assert_eq!(spell.local_variables, 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "Label is not bound")]
+    fn test_unbound_label() {
+        let mut builder = SpellBuilder::new();
+        let label = builder.label();
+        builder.jump(label).build();
+    }
+
+    #[test]
+    #[should_panic(expected = "Label is already bound")]
+    fn test_rebound_label() {
+        let mut builder = SpellBuilder::new();
+        let label = builder.label();
+        builder.bind(label).bind(label);
+    }
+}
@@ -0,0 +1,351 @@
+use super::*;
+
+use std::ops::Range;
+use std::slice;
+
+/// The control-flow graph of a spell, which divides its instructions into
+/// basic blocks and tells which blocks interpretation may continue with after
+/// each.
+///
+/// A basic block is a run of instructions that is only entered at its first
+/// instruction and only left after its last, so that once the first
+/// instruction is interpreted, the others follow in order unless one of them
+/// fails or throws. A block starts at the first instruction, at every
+/// instruction that may be jumped to, and after every instruction that jumps,
+/// branches, or does not fall through. Blocks are numbered in the order of
+/// their instructions, so the first block is the entry.
+///
+/// The successors of a block are the blocks its last instruction may continue
+/// with, per [Instruction::successors]. The target of an exception handler
+/// counts as a successor of the block that installs the handler, as for
+/// [Instruction::jump_targets], even though the handler catches exceptions
+/// thrown later. Successors beyond the instructions of the spell are left
+/// out, so that control-flow graphs can be built for spells that were not
+/// verified.
+///
+/// [Instruction::successors]: enum.Instruction.html#method.successors
+/// [Instruction::jump_targets]: enum.Instruction.html#method.jump_targets
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cfg {
+    blocks: Vec<BasicBlock>,
+
+    /// The index of the block of each instruction.
+    block_of: Vec<usize>,
+}
+
+/// A basic block of a control-flow graph; see [Cfg].
+///
+/// [Cfg]: struct.Cfg.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BasicBlock {
+    /// The indices of the instructions in the block, which is never empty.
+    pub instructions: Range<usize>,
+
+    /// The blocks interpretation may continue with after this one, by index,
+    /// in ascending order and without duplicates.
+    pub successors: Vec<usize>,
+
+    /// The blocks that have this one as a successor, by index, in ascending
+    /// order and without duplicates.
+    pub predecessors: Vec<usize>,
+}
+
+impl Cfg {
+    /// Build the control-flow graph of a spell.
+    pub fn build(spell: &Spell) -> Self {
+        let instructions = &spell.instructions;
+        let length = instructions.len();
+
+        let mut starts = vec![false; length + 1];
+        starts[0] = true;
+        for (index, instruction) in instructions.iter().enumerate() {
+            let targets = instruction.jump_targets();
+            let relative = instruction.jump_offset()
+                .and_then(|offset| index.checked_add_signed(offset));
+            let branches = !targets.is_empty() || relative.is_some();
+            for target in targets.into_iter().chain(relative) {
+                if target < length {
+                    starts[target] = true;
+                }
+            }
+            if branches || !instruction.falls_through() {
+                starts[index + 1] = true;
+            }
+        }
+
+        let mut blocks = Vec::new();
+        let mut block_of = Vec::with_capacity(length);
+        for (index, &start) in starts[.. length].iter().enumerate() {
+            if start {
+                blocks.push(BasicBlock{instructions: index .. index + 1,
+                                       successors:   Vec::new(),
+                                       predecessors: Vec::new()});
+            }
+            // There is a block, as the first instruction starts one.
+            blocks.last_mut().unwrap().instructions.end = index + 1;
+            block_of.push(blocks.len() - 1);
+        }
+
+        for block in 0 .. blocks.len() {
+            let last = blocks[block].instructions.end - 1;
+            let mut successors: Vec<usize> = instructions[last]
+                .successors(last).into_iter()
+                .filter(|&successor| successor < length)
+                .map(|successor| block_of[successor])
+                .collect();
+            successors.sort_unstable();
+            successors.dedup();
+            for &successor in &successors {
+                blocks[successor].predecessors.push(block);
+            }
+            blocks[block].successors = successors;
+        }
+
+        Cfg{blocks, block_of}
+    }
+
+    /// The number of blocks, which is zero only for a spell without
+    /// instructions.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Whether there are no blocks.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// The blocks, in the order of their instructions.
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+
+    /// Iterate over the blocks, in the order of their instructions.
+    pub fn iter(&self) -> slice::Iter<'_, BasicBlock> {
+        self.blocks.iter()
+    }
+
+    /// The index of the block the instruction at the given index is in, or
+    /// `None` if there is no such instruction.
+    pub fn block_of(&self, instruction: usize) -> Option<usize> {
+        self.block_of.get(instruction).cloned()
+    }
+
+    /// Whether the instruction at the given index is the first of its block.
+    pub fn starts_block(&self, instruction: usize) -> bool {
+        self.block_of(instruction)
+            .is_some_and(|block| self.blocks[block].instructions.start ==
+                                     instruction)
+    }
+
+    /// Whether each block can be reached from the entry by following
+    /// successors, by index.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut pending: Vec<usize> = (0 .. self.blocks.len().min(1))
+            .collect();
+        while let Some(block) = pending.pop() {
+            if !reachable[block] {
+                reachable[block] = true;
+                pending.extend(&self.blocks[block].successors);
+            }
+        }
+        reachable
+    }
+
+    /// The blocks that can be reached from the entry, by index, in reverse
+    /// postorder: every block comes before its successors, except along back
+    /// edges of loops. Forward dataflow analyses converge fastest when they
+    /// visit blocks in this order.
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.blocks.len());
+        let mut visited = vec![false; self.blocks.len()];
+
+        // Each entry is a block and the number of its successors that were
+        // visited so far.
+        let mut stack: Vec<(usize, usize)> = Vec::new();
+        if !self.blocks.is_empty() {
+            visited[0] = true;
+            stack.push((0, 0));
+        }
+        while let Some((block, visited_successors)) = stack.pop() {
+            let successors = &self.blocks[block].successors;
+            match successors.get(visited_successors) {
+                Some(&successor) => {
+                    stack.push((block, visited_successors + 1));
+                    if !visited[successor] {
+                        visited[successor] = true;
+                        stack.push((successor, 0));
+                    }
+                },
+                None => order.push(block),
+            }
+        }
+
+        order.reverse();
+        order
+    }
+}
+
+impl<'c> IntoIterator for &'c Cfg {
+    type Item = &'c BasicBlock;
+    type IntoIter = slice::Iter<'c, BasicBlock>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sigil::Sigils;
+
+    /// Assemble a spell book::main/1 and build its control-flow graph.
+    fn build(source: &str) -> Cfg {
+        let mut sigils = Sigils::new();
+        let id = SpellId{spellbook: sigils.intern_str("book"),
+                         spell: sigils.intern_str("main"), arity: 1};
+        let spells = assemble(source, &mut sigils).unwrap();
+        Cfg::build(spells.get(id).unwrap())
+    }
+
+    fn spell(instructions: Vec<Instruction>) -> Spell {
+        Spell{instructions: instructions.into_boxed_slice(),
+              local_variables: 1, constants: Box::new([]), source_map: None}
+    }
+
+    /// The instructions, successors, and predecessors of every block.
+    fn edges(cfg: &Cfg) -> Vec<(Range<usize>, Vec<usize>, Vec<usize>)> {
+        cfg.iter()
+            .map(|block| (block.instructions.clone(), block.successors.clone(),
+                          block.predecessors.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_straight_line() {
+        let cfg = build("
+            spell book::main/1
+                v1 = copy v0
+                v1 = add v1, v0
+                return v1
+        ");
+        assert_eq!(edges(&cfg), [(0 .. 3, vec![], vec![])]);
+        assert_eq!(cfg.reverse_postorder(), [0]);
+        assert!(cfg.starts_block(0));
+        assert!(!cfg.starts_block(1));
+        assert_eq!(cfg.block_of(2), Some(0));
+        assert_eq!(cfg.block_of(3), None);
+    }
+
+    #[test]
+    fn test_diamond() {
+        let cfg = build("
+            spell book::main/1
+                branch_if_truthy v0, yes
+                v0 = add v0, v0
+                jump done
+            yes:
+                v0 = mul v0, v0
+            done:
+                return v0
+        ");
+        assert_eq!(edges(&cfg), [
+            (0 .. 1, vec![1, 2], vec![]),
+            (1 .. 3, vec![3],    vec![0]),
+            (3 .. 4, vec![3],    vec![0]),
+            (4 .. 5, vec![],     vec![1, 2]),
+        ]);
+        assert_eq!(cfg.len(), 4);
+        assert_eq!(cfg.reverse_postorder(), [0, 2, 1, 3]);
+        assert_eq!(cfg.block_of(2), Some(1));
+        assert!(cfg.starts_block(3));
+    }
+
+    #[test]
+    fn test_loop() {
+        let cfg = build("
+            spell book::main/1
+                v1 = copy v0
+            loop:
+                v1 = sub v1, v0
+                branch_if_falsy_relative v1, -1
+                return v1
+        ");
+        assert_eq!(edges(&cfg), [
+            (0 .. 1, vec![1],    vec![]),
+            (1 .. 3, vec![1, 2], vec![0, 1]),
+            (3 .. 4, vec![],     vec![1]),
+        ]);
+        assert_eq!(cfg.reverse_postorder(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_switch_and_handler() {
+        // The switch has duplicate targets, one of which is the next
+        // instruction, and the handler target starts a block.
+        let cfg = build("
+            spell book::main/1
+                push_handler caught, v1
+                switch v0, [next, next, other], other
+            next:
+                pop_handler
+                return v0
+            other:
+                throw v0
+            caught:
+                return v1
+        ");
+        assert_eq!(edges(&cfg), [
+            (0 .. 1, vec![1, 4], vec![]),
+            (1 .. 2, vec![2, 3], vec![0]),
+            (2 .. 4, vec![],     vec![1]),
+            (4 .. 5, vec![],     vec![1]),
+            (5 .. 6, vec![],     vec![0]),
+        ]);
+        assert_eq!(cfg.reverse_postorder(), [0, 4, 1, 3, 2]);
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let cfg = build("
+            spell book::main/1
+                jump end
+                v0 = add v0, v0
+                jump_relative -1
+            end:
+                return v0
+                nop
+        ");
+        assert_eq!(edges(&cfg), [
+            (0 .. 1, vec![2], vec![]),
+            (1 .. 3, vec![1], vec![1]),
+            (3 .. 4, vec![],  vec![0]),
+            (4 .. 5, vec![],  vec![]),
+        ]);
+        assert_eq!(cfg.reachable(), [true, false, true, false]);
+        assert_eq!(cfg.reverse_postorder(), [0, 2]);
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(Cfg::build(&spell(vec![])).is_empty());
+        assert!(Cfg::build(&spell(vec![])).reverse_postorder().is_empty());
+
+        // Targets beyond the instructions and falling off the end have no
+        // successors.
+        let cfg = Cfg::build(&spell(vec![
+            Instruction::BranchIfTruthy{condition: Local(0), target: 7},
+            Instruction::JumpRelative{offset: -3},
+            Instruction::Nop,
+        ]));
+        assert_eq!(edges(&cfg), [
+            (0 .. 1, vec![1], vec![]),
+            (1 .. 2, vec![],  vec![0]),
+            (2 .. 3, vec![],  vec![]),
+        ]);
+        assert_eq!((&cfg).into_iter().count(), 3);
+    }
+}
@@ -1,15 +1,76 @@
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::iter;
+
 use sigil::Sigil;
 
+/// The enchantment of closures; see [Instruction::MakeClosure].
+///
+/// Like [IMMEDIATE_ENCHANTMENT], this sigil is not handed out by sigil
+/// databases in practice.
+///
+/// [Instruction::MakeClosure]: enum.Instruction.html#variant.MakeClosure
+/// [IMMEDIATE_ENCHANTMENT]: ../datum/constant.IMMEDIATE_ENCHANTMENT.html
+pub const CLOSURE_ENCHANTMENT: Sigil = Sigil(u32::MAX - 1);
+
+/// The spellbook that [Spell::called_spells] reports for dynamic invocations,
+/// whose spellbook is the enchantment of the receiver and hence not known
+/// until they are interpreted.
+///
+/// Like [IMMEDIATE_ENCHANTMENT], this sigil is not handed out by sigil
+/// databases in practice.
+///
+/// [Spell::called_spells]: struct.Spell.html#method.called_spells
+/// [IMMEDIATE_ENCHANTMENT]: ../datum/constant.IMMEDIATE_ENCHANTMENT.html
+pub const UNKNOWN_SPELLBOOK: Sigil = Sigil(u32::MAX - 2);
+
 /// An instruction is the smallest unit of executable code.
 #[derive(Clone, Debug)]
 pub enum Instruction {
-    /// Copy the datum from one variable into another.
+    /// Copy the datum from one variable into another. Copying a variable into
+    /// itself does nothing.
     Copy{
         from: Local,
         to:   Local,
     },
 
+    /// Copy the data from a range of consecutive variables into another, as
+    /// many as the count says, in order: the first variable of the range
+    /// starting at `from` into the first variable of the range starting at
+    /// `to`, and so on. The ranges may overlap, in which case every datum is
+    /// copied before it is overwritten, so that the range starting at `to`
+    /// ends up with the data the other range had before.
+    ///
+    /// Interpretation fails if either range does not fit in the local
+    /// variables, or if a variable to copy from is unassigned, in which case
+    /// nothing is copied.
+    CopyRange{
+        from:  Local,
+        to:    Local,
+        count: usize,
+    },
+
+    /// Exchange the data in two variables.
+    ///
+    /// Either variable may be unassigned, in which case the other becomes
+    /// unassigned.
+    Swap{
+        a: Local,
+        b: Local,
+    },
+
+    /// Do nothing, and continue with the next instruction.
+    Nop,
+
     /// Invoke a spell using static dispatch.
+    ///
+    /// If the next instruction returns the result of the invocation, the
+    /// invocation is a tail call: the stack frame of the invoked spell
+    /// replaces that of the invoking spell, so that tail recursion takes
+    /// constant stack space. This holds for both kinds of invocation.
+    ///
+    /// The arguments are read before the spell is invoked, and the result is
+    /// written when it returns, so the result may be one of the arguments.
     InvokeStatic{
         result:    Local,
         spellbook: Sigil,
@@ -26,12 +87,658 @@ pub enum Instruction {
         arguments: Box<[Local]>,
     },
 
+    /// Invoke a spell using dynamic dispatch, like [InvokeDynamic], but
+    /// remember the spell that was found for the enchantment of the receiver.
+    /// As long as the receiver has that enchantment, as is usual, the spell
+    /// need not be looked up again. [Spells::link] replaces dynamic
+    /// invocations with cached ones.
+    ///
+    /// [InvokeDynamic]: #variant.InvokeDynamic
+    /// [Spells::link]: struct.Spells.html#method.link
+    InvokeDynamicCached{
+        result:    Local,
+        spell:     Sigil,
+        receiver:  Local,
+        arguments: Box<[Local]>,
+        cache:     InlineCache,
+    },
+
+    /// Create a closure: a datum that refers to a spell, together with the
+    /// data in the given local variables, which it captures.
+    ///
+    /// The closure is enchanted with [CLOSURE_ENCHANTMENT]. Its pointers are
+    /// the captured data, in order, and its auxiliary part is the spellbook
+    /// followed by the spell, each as a 32-bit little-endian integer. The
+    /// same requirements as for [Allocate] apply to the local variables.
+    ///
+    /// [CLOSURE_ENCHANTMENT]: constant.CLOSURE_ENCHANTMENT.html
+    /// [Allocate]: #variant.Allocate
+    MakeClosure{
+        result:    Local,
+        spellbook: Sigil,
+        spell:     Sigil,
+        captures:  Box<[Local]>,
+    },
+
+    /// Invoke the spell a closure refers to. The captured data are passed
+    /// before the arguments, so the arity of the invoked spell is the number
+    /// of captured data plus the number of arguments. See [MakeClosure].
+    ///
+    /// Interpreting this instruction fails if the datum is not a closure.
+    ///
+    /// [MakeClosure]: #variant.MakeClosure
+    InvokeClosure{
+        result:    Local,
+        closure:   Local,
+        arguments: Box<[Local]>,
+    },
+
+    /// Invoke a spell using static dispatch, like [InvokeStatic], but find
+    /// the spell by its index in the spell database rather than by its id,
+    /// which saves a lookup. [Spells::link] replaces static invocations with
+    /// linked ones.
+    ///
+    /// If the spell at the index does not have the id of the invocation, for
+    /// example because the spell database was changed after linking, the
+    /// spell is looked up by id instead.
+    ///
+    /// [InvokeStatic]: #variant.InvokeStatic
+    /// [Spells::link]: struct.Spells.html#method.link
+    InvokeLinked{
+        result:    Local,
+        spellbook: Sigil,
+        spell:     Sigil,
+        index:     usize,
+        arguments: Box<[Local]>,
+    },
+
+    /// Continue with the target instruction.
+    ///
+    /// The target is not checked when the instruction is interpreted. If it
+    /// lies beyond the instructions of the spell, interpreting the next
+    /// instruction fails. The verifier rejects such targets.
+    Jump{
+        target: usize,
+    },
+
+    /// Like [Jump], but the target is given relative to this instruction, so
+    /// that the code does not depend on its position in the spell.
+    ///
+    /// The target is checked when the instruction is interpreted. If it lies
+    /// beyond the instructions of the spell, interpretation fails right away.
+    ///
+    /// [Jump]: #variant.Jump
+    JumpRelative{
+        offset: isize,
+    },
+
+    /// Jump to one of the targets depending on an integer. See [Add] for the
+    /// representation of integers. If the integer is a valid index into the
+    /// targets, the target at that index is jumped to, and otherwise the
+    /// default target is.
+    ///
+    /// [Add]: #variant.Add
+    Switch{
+        scrutinee: Local,
+        targets:   Box<[usize]>,
+        default:   usize,
+    },
+
+    /// Jump to the target instruction if the condition is truthy, and
+    /// continue with the next instruction otherwise. See [Datum::is_truthy]
+    /// for which data are truthy.
+    ///
+    /// [Datum::is_truthy]: ../datum/struct.Datum.html#method.is_truthy
+    BranchIfTruthy{
+        condition: Local,
+        target:    usize,
+    },
+
+    /// Jump to the target instruction if the condition is falsy, and continue
+    /// with the next instruction otherwise.
+    BranchIfFalsy{
+        condition: Local,
+        target:    usize,
+    },
+
+    /// Like [BranchIfTruthy], with a target relative to this instruction as
+    /// for [JumpRelative].
+    ///
+    /// [BranchIfTruthy]: #variant.BranchIfTruthy
+    /// [JumpRelative]: #variant.JumpRelative
+    BranchIfTruthyRelative{
+        condition: Local,
+        offset:    isize,
+    },
+
+    /// Like [BranchIfFalsy], with a target relative to this instruction as
+    /// for [JumpRelative].
+    ///
+    /// [BranchIfFalsy]: #variant.BranchIfFalsy
+    /// [JumpRelative]: #variant.JumpRelative
+    BranchIfFalsyRelative{
+        condition: Local,
+        offset:    isize,
+    },
+
+    /// Add two integers.
+    ///
+    /// The operands of arithmetic instructions are either immediates or data
+    /// whose auxiliary part consists of exactly eight bytes, which are read as
+    /// a 64-bit little-endian two's complement integer. The enchantment of the
+    /// operands is not consulted. The result is a newly allocated datum whose
+    /// auxiliary part encodes the result in the same way. Arithmetic wraps on
+    /// overflow.
+    Add{
+        result: Local,
+        lhs:    Local,
+        rhs:    Local,
+    },
+
+    /// Subtract an integer from another. See [Add] for the representation of
+    /// integers.
+    ///
+    /// [Add]: #variant.Add
+    Sub{
+        result: Local,
+        lhs:    Local,
+        rhs:    Local,
+    },
+
+    /// Multiply two integers. See [Add] for the representation of integers.
+    ///
+    /// [Add]: #variant.Add
+    Mul{
+        result: Local,
+        lhs:    Local,
+        rhs:    Local,
+    },
+
+    /// Divide an integer by another, rounding towards zero. Division by zero
+    /// is an error. See [Add] for the representation of integers.
+    ///
+    /// [Add]: #variant.Add
+    Div{
+        result: Local,
+        lhs:    Local,
+        rhs:    Local,
+    },
+
+    /// Compare two data for equality, producing a datum enchanted with the
+    /// truthy sigil if they are equal and with the falsy sigil otherwise.
+    ///
+    /// If both operands represent integers, see [Add], they are compared as
+    /// integers. Otherwise they are equal if they have the same structure;
+    /// see [Datum::structural_eq].
+    ///
+    /// [Add]: #variant.Add
+    /// [Datum::structural_eq]: ../datum/struct.Datum.html#method.structural_eq
+    Equal{
+        result: Local,
+        lhs:    Local,
+        rhs:    Local,
+    },
+
+    /// Compare two integers, producing a truthy datum if the first is less
+    /// than the second and a falsy one otherwise. See [Equal] for the result
+    /// and [Add] for the representation of integers.
+    ///
+    /// [Equal]: #variant.Equal
+    /// [Add]: #variant.Add
+    LessThan{
+        result: Local,
+        lhs:    Local,
+        rhs:    Local,
+    },
+
+    /// Compare two integers, producing a truthy datum if the first is greater
+    /// than the second and a falsy one otherwise. See [LessThan].
+    ///
+    /// [LessThan]: #variant.LessThan
+    GreaterThan{
+        result: Local,
+        lhs:    Local,
+        rhs:    Local,
+    },
+
+    /// Allocate a datum on the heap with the given enchantment, pointers to
+    /// the data in the given local variables, and auxiliary part.
+    ///
+    /// The local variables must only contain data that belong to the heap
+    /// the interpreter allocates on, or immediates. Under the `checked`
+    /// feature, pointing to a datum from a different heap panics.
+    Allocate{
+        result:      Local,
+        enchantment: Sigil,
+        pointers:    Box<[Local]>,
+        auxiliary:   Box<[u8]>,
+    },
+
+    /// Load a constant from the constant pool of the spell. A datum without
+    /// pointers, with the enchantment and auxiliary part of the constant, is
+    /// allocated on the heap every time the instruction is interpreted. It is
+    /// an error if the constant pool has no constant at the index.
+    LoadConstant{
+        result: Local,
+        index:  usize,
+    },
+
+    /// Read one of the pointers of a datum. It is an error if the datum has
+    /// no pointer at the index.
+    GetPointer{
+        result: Local,
+        object: Local,
+        index:  usize,
+    },
+
+    /// Get the enchantment of a datum as an integer. See [Add] for the
+    /// representation of integers; the result is an immediate if possible.
+    ///
+    /// [Add]: #variant.Add
+    EnchantmentOf{
+        result: Local,
+        object: Local,
+    },
+
+    /// Test whether a datum has the given enchantment, producing a datum
+    /// enchanted with the truthy sigil if it does and with the falsy sigil
+    /// otherwise. Immediates have [IMMEDIATE_ENCHANTMENT].
+    ///
+    /// [IMMEDIATE_ENCHANTMENT]: ../datum/constant.IMMEDIATE_ENCHANTMENT.html
+    IsEnchanted{
+        result:      Local,
+        object:      Local,
+        enchantment: Sigil,
+    },
+
+    /// Get the length of the auxiliary part of a datum as an integer. See
+    /// [EnchantmentOf] for the representation of the result.
+    ///
+    /// [EnchantmentOf]: #variant.EnchantmentOf
+    AuxiliaryLen{
+        result: Local,
+        object: Local,
+    },
+
+    /// Install an exception handler in the stack frame, which continues with
+    /// the target instruction and stores the exception into the given local
+    /// variable when a datum is thrown. The handler is removed once it catches
+    /// an exception.
+    ///
+    /// While a stack frame has exception handlers, invocations from it are
+    /// never tail calls, because the handlers would be lost.
+    PushHandler{
+        target:    usize,
+        exception: Local,
+    },
+
+    /// Remove the exception handler that was installed last. It is an error
+    /// if the stack frame has no exception handlers.
+    PopHandler,
+
+    /// Throw a datum. Stack frames are exited until one with an exception
+    /// handler is found, which then catches the datum. It is an error if no
+    /// stack frame has an exception handler.
+    Throw{
+        value: Local,
+    },
+
     /// Return to the caller, giving it a datum.
     Return{
         result: Local,
     },
 }
 
+/// The spell a cached dynamic invocation found last, along with the
+/// enchantment of the receiver it was found for. See
+/// [Instruction::InvokeDynamicCached].
+///
+/// The spell is identified by its index in the spell database, as for
+/// [Instruction::InvokeLinked], so a stale cache is harmless: the spell is
+/// then looked up by id again.
+///
+/// [Instruction::InvokeDynamicCached]:
+///     enum.Instruction.html#variant.InvokeDynamicCached
+/// [Instruction::InvokeLinked]: enum.Instruction.html#variant.InvokeLinked
+#[derive(Clone, Debug, Default)]
+pub struct InlineCache(Cell<Option<(Sigil, usize)>>);
+
+impl InlineCache {
+    /// Create a cache that remembers no spell.
+    pub fn new() -> Self {
+        InlineCache(Cell::new(None))
+    }
+
+    /// The index of the spell that was found for the given enchantment, if
+    /// the cache remembers one.
+    #[inline(always)]
+    pub fn get(&self, enchantment: Sigil) -> Option<usize> {
+        self.0.get()
+            .filter(|&(cached, _)| cached == enchantment)
+            .map(|(_, index)| index)
+    }
+
+    /// Remember the spell that was found for an enchantment, forgetting the
+    /// one that was remembered before, if any.
+    pub fn set(&self, enchantment: Sigil, index: usize) {
+        self.0.set(Some((enchantment, index)));
+    }
+}
+
+impl Instruction {
+    /// Call a function for every local variable the instruction refers to,
+    /// whether it reads from it or writes to it.
+    pub fn for_each_local<F>(&self, mut f: F)
+        where F: FnMut(Local) {
+        match self {
+            Instruction::Copy{from, to} => {
+                f(*from);
+                f(*to);
+            },
+            Instruction::CopyRange{from, to, count} => {
+                local_range(*from, *count).for_each(&mut f);
+                local_range(*to, *count).for_each(f);
+            },
+            Instruction::Swap{a, b} => {
+                f(*a);
+                f(*b);
+            },
+            Instruction::Nop => (),
+            Instruction::InvokeStatic{result, arguments, ..} |
+            Instruction::InvokeLinked{result, arguments, ..} => {
+                f(*result);
+                arguments.iter().cloned().for_each(f);
+            },
+            Instruction::InvokeDynamic{result, receiver, arguments, ..} |
+            Instruction::InvokeDynamicCached{result, receiver, arguments,
+                                             ..} => {
+                f(*result);
+                f(*receiver);
+                arguments.iter().cloned().for_each(f);
+            },
+            Instruction::MakeClosure{result, captures, ..} => {
+                f(*result);
+                captures.iter().cloned().for_each(f);
+            },
+            Instruction::InvokeClosure{result, closure, arguments} => {
+                f(*result);
+                f(*closure);
+                arguments.iter().cloned().for_each(f);
+            },
+            Instruction::Jump{..} => (),
+            Instruction::JumpRelative{..} => (),
+            Instruction::Switch{scrutinee, ..} => f(*scrutinee),
+            Instruction::BranchIfTruthy{condition, ..} => f(*condition),
+            Instruction::BranchIfFalsy{condition, ..} => f(*condition),
+            Instruction::BranchIfTruthyRelative{condition, ..} => f(*condition),
+            Instruction::BranchIfFalsyRelative{condition, ..} => f(*condition),
+            Instruction::Add{result, lhs, rhs} |
+            Instruction::Sub{result, lhs, rhs} |
+            Instruction::Mul{result, lhs, rhs} |
+            Instruction::Div{result, lhs, rhs} |
+            Instruction::Equal{result, lhs, rhs} |
+            Instruction::LessThan{result, lhs, rhs} |
+            Instruction::GreaterThan{result, lhs, rhs} => {
+                f(*result);
+                f(*lhs);
+                f(*rhs);
+            },
+            Instruction::Allocate{result, pointers, ..} => {
+                f(*result);
+                pointers.iter().cloned().for_each(f);
+            },
+            Instruction::LoadConstant{result, ..} => f(*result),
+            Instruction::GetPointer{result, object, ..} |
+            Instruction::EnchantmentOf{result, object} |
+            Instruction::IsEnchanted{result, object, ..} |
+            Instruction::AuxiliaryLen{result, object} => {
+                f(*result);
+                f(*object);
+            },
+            Instruction::PushHandler{exception, ..} => f(*exception),
+            Instruction::PopHandler => (),
+            Instruction::Throw{value} => f(*value),
+            Instruction::Return{result} => f(*result),
+        }
+    }
+
+    /// Call a function for every local variable the instruction refers to,
+    /// allowing it to change the local variable. The local variables are
+    /// visited in the same order as by [for_each_local], except that only the
+    /// first local variable of each range of a [CopyRange] is visited, with
+    /// the rest of the range moving along with it.
+    ///
+    /// [for_each_local]: #method.for_each_local
+    /// [CopyRange]: #variant.CopyRange
+    pub fn for_each_local_mut<F>(&mut self, mut f: F)
+        where F: FnMut(&mut Local) {
+        match self {
+            Instruction::Copy{from, to} |
+            Instruction::CopyRange{from, to, ..} => {
+                f(from);
+                f(to);
+            },
+            Instruction::Swap{a, b} => {
+                f(a);
+                f(b);
+            },
+            Instruction::Nop => (),
+            Instruction::InvokeStatic{result, arguments, ..} |
+            Instruction::InvokeLinked{result, arguments, ..} => {
+                f(result);
+                arguments.iter_mut().for_each(f);
+            },
+            Instruction::InvokeDynamic{result, receiver, arguments, ..} |
+            Instruction::InvokeDynamicCached{result, receiver, arguments,
+                                             ..} => {
+                f(result);
+                f(receiver);
+                arguments.iter_mut().for_each(f);
+            },
+            Instruction::MakeClosure{result, captures, ..} => {
+                f(result);
+                captures.iter_mut().for_each(f);
+            },
+            Instruction::InvokeClosure{result, closure, arguments} => {
+                f(result);
+                f(closure);
+                arguments.iter_mut().for_each(f);
+            },
+            Instruction::Jump{..} => (),
+            Instruction::JumpRelative{..} => (),
+            Instruction::Switch{scrutinee, ..} => f(scrutinee),
+            Instruction::BranchIfTruthy{condition, ..} => f(condition),
+            Instruction::BranchIfFalsy{condition, ..} => f(condition),
+            Instruction::BranchIfTruthyRelative{condition, ..} => f(condition),
+            Instruction::BranchIfFalsyRelative{condition, ..} => f(condition),
+            Instruction::Add{result, lhs, rhs} |
+            Instruction::Sub{result, lhs, rhs} |
+            Instruction::Mul{result, lhs, rhs} |
+            Instruction::Div{result, lhs, rhs} |
+            Instruction::Equal{result, lhs, rhs} |
+            Instruction::LessThan{result, lhs, rhs} |
+            Instruction::GreaterThan{result, lhs, rhs} => {
+                f(result);
+                f(lhs);
+                f(rhs);
+            },
+            Instruction::Allocate{result, pointers, ..} => {
+                f(result);
+                pointers.iter_mut().for_each(f);
+            },
+            Instruction::LoadConstant{result, ..} => f(result),
+            Instruction::GetPointer{result, object, ..} |
+            Instruction::EnchantmentOf{result, object} |
+            Instruction::IsEnchanted{result, object, ..} |
+            Instruction::AuxiliaryLen{result, object} => {
+                f(result);
+                f(object);
+            },
+            Instruction::PushHandler{exception, ..} => f(exception),
+            Instruction::PopHandler => (),
+            Instruction::Throw{value} => f(value),
+            Instruction::Return{result} => f(result),
+        }
+    }
+
+    /// Call a function for every sigil the instruction refers to.
+    pub fn for_each_sigil<F>(&self, mut f: F)
+        where F: FnMut(Sigil) {
+        match self {
+            Instruction::InvokeStatic{spellbook, spell, ..} |
+            Instruction::InvokeLinked{spellbook, spell, ..} |
+            Instruction::MakeClosure{spellbook, spell, ..} => {
+                f(*spellbook);
+                f(*spell);
+            },
+            Instruction::InvokeDynamic{spell, ..} |
+            Instruction::InvokeDynamicCached{spell, ..} => f(*spell),
+            Instruction::Allocate{enchantment, ..} |
+            Instruction::IsEnchanted{enchantment, ..} => f(*enchantment),
+            Instruction::Copy{..} |
+            Instruction::CopyRange{..} |
+            Instruction::Swap{..} |
+            Instruction::Nop |
+            Instruction::InvokeClosure{..} |
+            Instruction::Jump{..} |
+            Instruction::JumpRelative{..} |
+            Instruction::Switch{..} |
+            Instruction::BranchIfTruthy{..} |
+            Instruction::BranchIfFalsy{..} |
+            Instruction::BranchIfTruthyRelative{..} |
+            Instruction::BranchIfFalsyRelative{..} |
+            Instruction::Add{..} |
+            Instruction::Sub{..} |
+            Instruction::Mul{..} |
+            Instruction::Div{..} |
+            Instruction::Equal{..} |
+            Instruction::LessThan{..} |
+            Instruction::GreaterThan{..} |
+            Instruction::LoadConstant{..} |
+            Instruction::GetPointer{..} |
+            Instruction::EnchantmentOf{..} |
+            Instruction::AuxiliaryLen{..} |
+            Instruction::PushHandler{..} |
+            Instruction::PopHandler |
+            Instruction::Throw{..} |
+            Instruction::Return{..} => (),
+        }
+    }
+
+    /// Call a function for every sigil the instruction refers to, allowing it
+    /// to change the sigil. The sigils are visited in the same order as by
+    /// [for_each_sigil].
+    ///
+    /// [for_each_sigil]: #method.for_each_sigil
+    pub fn for_each_sigil_mut<F>(&mut self, mut f: F)
+        where F: FnMut(&mut Sigil) {
+        match self {
+            Instruction::InvokeStatic{spellbook, spell, ..} |
+            Instruction::InvokeLinked{spellbook, spell, ..} |
+            Instruction::MakeClosure{spellbook, spell, ..} => {
+                f(spellbook);
+                f(spell);
+            },
+            Instruction::InvokeDynamic{spell, ..} |
+            Instruction::InvokeDynamicCached{spell, ..} => f(spell),
+            Instruction::Allocate{enchantment, ..} |
+            Instruction::IsEnchanted{enchantment, ..} => f(enchantment),
+            Instruction::Copy{..} |
+            Instruction::CopyRange{..} |
+            Instruction::Swap{..} |
+            Instruction::Nop |
+            Instruction::InvokeClosure{..} |
+            Instruction::Jump{..} |
+            Instruction::JumpRelative{..} |
+            Instruction::Switch{..} |
+            Instruction::BranchIfTruthy{..} |
+            Instruction::BranchIfFalsy{..} |
+            Instruction::BranchIfTruthyRelative{..} |
+            Instruction::BranchIfFalsyRelative{..} |
+            Instruction::Add{..} |
+            Instruction::Sub{..} |
+            Instruction::Mul{..} |
+            Instruction::Div{..} |
+            Instruction::Equal{..} |
+            Instruction::LessThan{..} |
+            Instruction::GreaterThan{..} |
+            Instruction::LoadConstant{..} |
+            Instruction::GetPointer{..} |
+            Instruction::EnchantmentOf{..} |
+            Instruction::AuxiliaryLen{..} |
+            Instruction::PushHandler{..} |
+            Instruction::PopHandler |
+            Instruction::Throw{..} |
+            Instruction::Return{..} => (),
+        }
+    }
+
+    /// The instructions the instruction may jump to, not counting the next
+    /// instruction. The target of an exception handler counts, as it is jumped
+    /// to when the handler catches an exception. Relative jumps are not
+    /// included; see [jump_offset].
+    ///
+    /// [jump_offset]: #method.jump_offset
+    pub fn jump_targets(&self) -> Vec<usize> {
+        match self {
+            Instruction::Jump{target}              => vec![*target],
+            Instruction::Switch{targets, default, ..} =>
+                targets.iter().chain(iter::once(default)).cloned().collect(),
+            Instruction::BranchIfTruthy{target, ..} => vec![*target],
+            Instruction::BranchIfFalsy{target, ..}  => vec![*target],
+            Instruction::PushHandler{target, ..}    => vec![*target],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The offset of the instruction the instruction may jump to, relative to
+    /// the instruction itself, if it is a relative jump.
+    pub fn jump_offset(&self) -> Option<isize> {
+        match self {
+            Instruction::JumpRelative{offset}               |
+            Instruction::BranchIfTruthyRelative{offset, ..} |
+            Instruction::BranchIfFalsyRelative{offset, ..}  => Some(*offset),
+            _ => None,
+        }
+    }
+
+    /// The instructions interpretation may continue with after interpreting
+    /// the instruction, given its index: its jump targets, the target of its
+    /// relative jump, and the next instruction if it falls through. The
+    /// successors are not checked to lie within the spell, but a relative
+    /// jump to before the first instruction has no successor.
+    pub fn successors(&self, index: usize) -> Vec<usize> {
+        let mut successors = self.jump_targets();
+        successors.extend(self.jump_offset()
+            .and_then(|offset| index.checked_add_signed(offset)));
+        if self.falls_through() {
+            successors.push(index + 1);
+        }
+        successors
+    }
+
+    /// Whether interpretation may continue with the next instruction after
+    /// interpreting the instruction.
+    pub fn falls_through(&self) -> bool {
+        !matches!(self, Instruction::Jump{..}         |
+                        Instruction::JumpRelative{..} |
+                        Instruction::Switch{..}       |
+                        Instruction::Throw{..}        |
+                        Instruction::Return{..})
+    }
+}
+
+/// The local variables in the range of the given length starting at the given
+/// local variable, as [Instruction::CopyRange] copies them. The range stops
+/// short at the greatest local variable there can be.
+///
+/// [Instruction::CopyRange]: enum.Instruction.html#variant.CopyRange
+pub(super) fn local_range(start: Local, count: usize)
+    -> impl Iterator<Item = Local> {
+    let count = u32::try_from(count).unwrap_or(u32::MAX);
+    (start.0 .. start.0.saturating_add(count)).map(Local)
+}
+
 /// A local variable indexes into the array of local variables on the stack
 /// frame.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -0,0 +1,301 @@
+use super::*;
+
+use std::fmt::Write;
+
+use sigil::Sigils;
+
+/// Format the instructions of a spell in a human-readable way.
+///
+/// Every instruction is written on its own line, prefixed with its index so
+/// that jump targets can be followed. Local variables are written as `v0`,
+/// `v1`, and so on. Sigils are written by name, or as `Sigil(n)` if they are
+/// not in the sigil database. If the spell has a source map, the source span
+/// of every instruction follows it as a comment. For example:
+///
+/// ```text
+/// 0: branch_if_falsy v0, 2               ; main.mana:1:1
+/// 1: v0 = invoke_static book::double(v0) ; main.mana:2:3
+/// 2: return v0                           ; main.mana:3:1
+/// ```
+///
+/// Comments are aligned to one column past the longest instruction.
+pub fn disassemble(spell: &Spell, sigils: &Sigils) -> String {
+    let width = spell.instructions.len().saturating_sub(1).to_string().len();
+
+    let sigil = |sigil: &Sigil| match sigils.name(*sigil) {
+        Some(name) => String::from_utf8_lossy(name).into_owned(),
+        None       => format!("{:?}", sigil),
+    };
+
+    let mut lines = Vec::with_capacity(spell.instructions.len());
+    for (index, instruction) in spell.instructions.iter().enumerate() {
+        let mut output = String::new();
+        let arguments = |arguments: &[Local]| {
+            arguments.iter()
+                .map(|argument| format!("v{}", argument.0))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        // Writing to a string cannot fail.
+        let _ = write!(output, "{:>width$}: ", index, width = width);
+        let _ = match instruction {
+            Instruction::Copy{from, to} =>
+                write!(output, "v{} = copy v{}", to.0, from.0),
+            Instruction::CopyRange{from, to, count} =>
+                write!(output, "v{} = copy_range v{}, {}", to.0, from.0, count),
+            Instruction::Swap{a, b} =>
+                write!(output, "swap v{}, v{}", a.0, b.0),
+            Instruction::Nop =>
+                write!(output, "nop"),
+            Instruction::InvokeStatic{result, spellbook, spell,
+                                      arguments: a} =>
+                write!(output, "v{} = invoke_static {}::{}({})",
+                       result.0, sigil(spellbook), sigil(spell),
+                       arguments(a)),
+            Instruction::InvokeLinked{result, spellbook, spell, index,
+                                      arguments: a} =>
+                write!(output, "v{} = invoke_linked {}::{}@{}({})",
+                       result.0, sigil(spellbook), sigil(spell), index,
+                       arguments(a)),
+            Instruction::InvokeDynamic{result, spell, receiver,
+                                       arguments: a} =>
+                write!(output, "v{} = invoke_dynamic v{}.{}({})",
+                       result.0, receiver.0, sigil(spell), arguments(a)),
+            Instruction::InvokeDynamicCached{result, spell, receiver,
+                                             arguments: a, ..} =>
+                write!(output, "v{} = invoke_dynamic_cached v{}.{}({})",
+                       result.0, receiver.0, sigil(spell), arguments(a)),
+            Instruction::MakeClosure{result, spellbook, spell, captures} =>
+                write!(output, "v{} = make_closure {}::{}[{}]",
+                       result.0, sigil(spellbook), sigil(spell),
+                       arguments(captures)),
+            Instruction::InvokeClosure{result, closure, arguments: a} =>
+                write!(output, "v{} = invoke_closure v{}({})",
+                       result.0, closure.0, arguments(a)),
+            Instruction::Jump{target} =>
+                write!(output, "jump {}", target),
+            Instruction::JumpRelative{offset} =>
+                write!(output, "jump_relative {:+}", offset),
+            Instruction::BranchIfTruthyRelative{condition, offset} =>
+                write!(output, "branch_if_truthy_relative v{}, {:+}",
+                       condition.0, offset),
+            Instruction::BranchIfFalsyRelative{condition, offset} =>
+                write!(output, "branch_if_falsy_relative v{}, {:+}",
+                       condition.0, offset),
+            Instruction::Switch{scrutinee, targets, default} =>
+                write!(output, "switch v{}, [{}], {}",
+                       scrutinee.0,
+                       targets.iter()
+                           .map(|target| target.to_string())
+                           .collect::<Vec<_>>()
+                           .join(", "),
+                       default),
+            Instruction::BranchIfTruthy{condition, target} =>
+                write!(output, "branch_if_truthy v{}, {}",
+                       condition.0, target),
+            Instruction::BranchIfFalsy{condition, target} =>
+                write!(output, "branch_if_falsy v{}, {}",
+                       condition.0, target),
+            Instruction::Add{result, lhs, rhs} =>
+                write!(output, "v{} = add v{}, v{}", result.0, lhs.0, rhs.0),
+            Instruction::Sub{result, lhs, rhs} =>
+                write!(output, "v{} = sub v{}, v{}", result.0, lhs.0, rhs.0),
+            Instruction::Mul{result, lhs, rhs} =>
+                write!(output, "v{} = mul v{}, v{}", result.0, lhs.0, rhs.0),
+            Instruction::Div{result, lhs, rhs} =>
+                write!(output, "v{} = div v{}, v{}", result.0, lhs.0, rhs.0),
+            Instruction::Equal{result, lhs, rhs} =>
+                write!(output, "v{} = equal v{}, v{}",
+                       result.0, lhs.0, rhs.0),
+            Instruction::LessThan{result, lhs, rhs} =>
+                write!(output, "v{} = less_than v{}, v{}",
+                       result.0, lhs.0, rhs.0),
+            Instruction::GreaterThan{result, lhs, rhs} =>
+                write!(output, "v{} = greater_than v{}, v{}",
+                       result.0, lhs.0, rhs.0),
+            Instruction::Allocate{result, enchantment, pointers,
+                                  auxiliary} =>
+                write!(output, "v{} = allocate {}({}) [{}]",
+                       result.0, sigil(enchantment), arguments(pointers),
+                       auxiliary.iter()
+                           .map(|byte| format!("{:02x}", byte))
+                           .collect::<Vec<_>>()
+                           .join(" ")),
+            Instruction::LoadConstant{result, index} =>
+                write!(output, "v{} = load_constant {}", result.0, index),
+            Instruction::GetPointer{result, object, index} =>
+                write!(output, "v{} = get_pointer v{}, {}",
+                       result.0, object.0, index),
+            Instruction::EnchantmentOf{result, object} =>
+                write!(output, "v{} = enchantment_of v{}", result.0, object.0),
+            Instruction::IsEnchanted{result, object, enchantment} =>
+                write!(output, "v{} = is_enchanted v{}, {}",
+                       result.0, object.0, sigil(enchantment)),
+            Instruction::AuxiliaryLen{result, object} =>
+                write!(output, "v{} = auxiliary_len v{}", result.0, object.0),
+            Instruction::PushHandler{target, exception} =>
+                write!(output, "push_handler {}, v{}", target, exception.0),
+            Instruction::PopHandler =>
+                write!(output, "pop_handler"),
+            Instruction::Throw{value} =>
+                write!(output, "throw v{}", value.0),
+            Instruction::Return{result} =>
+                write!(output, "return v{}", result.0),
+        };
+        lines.push(output);
+    }
+
+    let comment_column = lines.iter().map(String::len).max().unwrap_or(0) + 1;
+    let mut output = String::new();
+    for (index, line) in lines.iter().enumerate() {
+        output.push_str(line);
+        if let Some(span) = spell.source_span(index) {
+            let _ = write!(output, "{:pad$}; {}:{}:{}", "", sigil(&span.file),
+                           span.line, span.column,
+                           pad = comment_column - line.len());
+        }
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let mut sigils = Sigils::new();
+        let book = sigils.intern_str("book");
+        let double = sigils.intern_str("double");
+
+        let instructions = vec![
+            Instruction::Copy{from: Local(0), to: Local(1)},
+            Instruction::InvokeStatic{result: Local(1), spellbook: book,
+                                      spell: double,
+                                      arguments: Box::new([Local(0),
+                                                           Local(1)])},
+            Instruction::InvokeDynamic{result: Local(2), spell: Sigil(9),
+                                       receiver: Local(1),
+                                       arguments: Box::new([])},
+            Instruction::BranchIfTruthy{condition: Local(2), target: 10},
+            Instruction::BranchIfFalsy{condition: Local(2), target: 0},
+            Instruction::Add{result: Local(0), lhs: Local(1), rhs: Local(2)},
+            Instruction::Sub{result: Local(0), lhs: Local(1), rhs: Local(2)},
+            Instruction::Mul{result: Local(0), lhs: Local(1), rhs: Local(2)},
+            Instruction::Div{result: Local(0), lhs: Local(1), rhs: Local(2)},
+            Instruction::Allocate{result: Local(2), enchantment: book,
+                                  pointers: Box::new([Local(0), Local(1)]),
+                                  auxiliary: Box::new([0x0a, 0xff])},
+            Instruction::GetPointer{result: Local(1), object: Local(2),
+                                    index: 1},
+            Instruction::EnchantmentOf{result: Local(1), object: Local(2)},
+            Instruction::AuxiliaryLen{result: Local(1), object: Local(2)},
+            Instruction::PushHandler{target: 23, exception: Local(1)},
+            Instruction::PopHandler,
+            Instruction::Throw{value: Local(2)},
+            Instruction::Swap{a: Local(2), b: Local(0)},
+            Instruction::Nop,
+            Instruction::Equal{result: Local(0), lhs: Local(1), rhs: Local(2)},
+            Instruction::LessThan{result: Local(0), lhs: Local(1),
+                                  rhs: Local(2)},
+            Instruction::GreaterThan{result: Local(0), lhs: Local(1),
+                                     rhs: Local(2)},
+            Instruction::Switch{scrutinee: Local(0), targets: Box::new([0, 23]),
+                                default: 22},
+            Instruction::Jump{target: 23},
+            Instruction::JumpRelative{offset: 3},
+            Instruction::BranchIfTruthyRelative{condition: Local(1),
+                                                offset: -2},
+            Instruction::BranchIfFalsyRelative{condition: Local(2),
+                                               offset: 0},
+            Instruction::LoadConstant{result: Local(0), index: 4},
+            Instruction::MakeClosure{result: Local(1), spellbook: book,
+                                     spell: double,
+                                     captures: Box::new([Local(0), Local(2)])},
+            Instruction::InvokeClosure{result: Local(0), closure: Local(1),
+                                       arguments: Box::new([Local(2)])},
+            Instruction::InvokeLinked{result: Local(0), spellbook: book,
+                                      spell: double, index: 7,
+                                      arguments: Box::new([Local(1)])},
+            Instruction::InvokeDynamicCached{result: Local(0), spell: double,
+                                             receiver: Local(1),
+                                             arguments: Box::new([Local(2)]),
+                                             cache: InlineCache::new()},
+            Instruction::CopyRange{from: Local(0), to: Local(1), count: 2},
+            Instruction::IsEnchanted{result: Local(1), object: Local(2),
+                                     enchantment: book},
+            Instruction::Return{result: Local(0)},
+        ];
+        let spell = Spell{instructions: instructions.into_boxed_slice(),
+                          local_variables: 3, constants: Box::new([]),
+                          source_map: None};
+
+        assert_eq!(disassemble(&spell, &sigils), concat!(
+            " 0: v1 = copy v0\n",
+            " 1: v1 = invoke_static book::double(v0, v1)\n",
+            " 2: v2 = invoke_dynamic v1.Sigil(9)()\n",
+            " 3: branch_if_truthy v2, 10\n",
+            " 4: branch_if_falsy v2, 0\n",
+            " 5: v0 = add v1, v2\n",
+            " 6: v0 = sub v1, v2\n",
+            " 7: v0 = mul v1, v2\n",
+            " 8: v0 = div v1, v2\n",
+            " 9: v2 = allocate book(v0, v1) [0a ff]\n",
+            "10: v1 = get_pointer v2, 1\n",
+            "11: v1 = enchantment_of v2\n",
+            "12: v1 = auxiliary_len v2\n",
+            "13: push_handler 23, v1\n",
+            "14: pop_handler\n",
+            "15: throw v2\n",
+            "16: swap v2, v0\n",
+            "17: nop\n",
+            "18: v0 = equal v1, v2\n",
+            "19: v0 = less_than v1, v2\n",
+            "20: v0 = greater_than v1, v2\n",
+            "21: switch v0, [0, 23], 22\n",
+            "22: jump 23\n",
+            "23: jump_relative +3\n",
+            "24: branch_if_truthy_relative v1, -2\n",
+            "25: branch_if_falsy_relative v2, +0\n",
+            "26: v0 = load_constant 4\n",
+            "27: v1 = make_closure book::double[v0, v2]\n",
+            "28: v0 = invoke_closure v1(v2)\n",
+            "29: v0 = invoke_linked book::double@7(v1)\n",
+            "30: v0 = invoke_dynamic_cached v1.double(v2)\n",
+            "31: v1 = copy_range v0, 2\n",
+            "32: v1 = is_enchanted v2, book\n",
+            "33: return v0\n",
+        ));
+    }
+
+    #[test]
+    fn test_disassemble_source_map() {
+        let mut sigils = Sigils::new();
+        let file = sigils.intern_str("main.mana");
+
+        let span = |line| SourceSpan{file, line, column: 3};
+        let spell = Spell{
+            instructions: Box::new([
+                Instruction::Copy{from: Local(0), to: Local(1)},
+                Instruction::Return{result: Local(1)},
+            ]),
+            local_variables: 2,
+            constants: Box::new([]),
+            source_map: Some(Box::new([span(1), span(2)])),
+        };
+
+        assert_eq!(disassemble(&spell, &sigils), concat!(
+            "0: v1 = copy v0 ; main.mana:1:3\n",
+            "1: return v1    ; main.mana:2:3\n",
+        ));
+    }
+
+    #[test]
+    fn test_disassemble_empty() {
+        let spell = Spell{instructions: Box::new([]), local_variables: 0,
+                          constants: Box::new([]), source_map: None};
+        assert_eq!(disassemble(&spell, &Sigils::new()), "");
+    }
+}
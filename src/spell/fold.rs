@@ -0,0 +1,271 @@
+use super::*;
+
+use std::convert::TryFrom;
+
+use interpret::TypeSigils;
+use spell::pack::accesses;
+
+impl Spell {
+    /// Evaluate arithmetic and comparisons whose operands are constants, and
+    /// replace each of them with an instruction that loads its result, which
+    /// is added to the constant pool unless it is already there.
+    ///
+    /// An operand is constant if it was loaded from the constant pool earlier
+    /// in the same basic block, and not written since; copies of such local
+    /// variables and the results of folded instructions are constant too.
+    /// Nothing is assumed about local variables at the start of a basic block,
+    /// as a basic block may be jumped to from anywhere. The results are the
+    /// data the instructions would produce, given the sigils instructions give
+    /// a meaning to. Divisions by zero and operands that do not represent
+    /// integers are left alone, so that interpreting them still fails.
+    ///
+    /// The loads of the operands are kept, even if nothing reads them any
+    /// longer. Spells that are not well-formed are left unchanged; see
+    /// [verify].
+    ///
+    /// [verify]: fn.verify.html
+    pub fn fold_constants(&mut self, types: TypeSigils) {
+        if verify(self).is_err() {
+            return;
+        }
+
+        let leaders = self.leaders();
+        let mut constants = mem::take(&mut self.constants).into_vec();
+
+        // The index in the constant pool of the datum each local variable is
+        // known to hold, if any.
+        let mut known: Vec<Option<usize>> = vec![None; self.local_variables];
+        for (index, instruction) in self.instructions.iter_mut().enumerate() {
+            if leaders[index] {
+                known.iter_mut().for_each(|constant| *constant = None);
+            }
+
+            if let Some((result, value)) =
+                fold(instruction, &known, &constants, types) {
+                let index = constants.iter().position(|c| *c == value)
+                    .unwrap_or_else(|| {
+                        constants.push(value);
+                        constants.len() - 1
+                    });
+                *instruction = Instruction::LoadConstant{result, index};
+            }
+
+            match instruction {
+                Instruction::LoadConstant{result, index} =>
+                    known[result.0 as usize] =
+                        Some(*index).filter(|&i| i < constants.len()),
+                Instruction::Copy{from, to} =>
+                    known[to.0 as usize] = known[from.0 as usize],
+                Instruction::Swap{a, b} =>
+                    known.swap(a.0 as usize, b.0 as usize),
+                _ => for Local(write) in accesses(instruction).1 {
+                    known[write as usize] = None;
+                },
+            }
+        }
+
+        self.constants = constants.into_boxed_slice();
+    }
+
+    /// Whether each instruction starts a basic block: the first instruction,
+    /// every instruction that may be jumped to, and every instruction that
+    /// follows one that does not fall through.
+    fn leaders(&self) -> Vec<bool> {
+        let mut leaders = vec![false; self.instructions.len() + 1];
+        leaders[0] = true;
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            let relative = instruction.jump_offset()
+                .map(|offset| (index as isize + offset) as usize);
+            for target in instruction.jump_targets().into_iter()
+                .chain(relative) {
+                leaders[target] = true;
+            }
+            if !instruction.falls_through() {
+                leaders[index + 1] = true;
+            }
+        }
+        leaders
+    }
+}
+
+/// The result of an arithmetic instruction or comparison and the constant it
+/// produces, if its operands are known to hold constants and interpreting it
+/// would not fail.
+fn fold(instruction: &Instruction,
+        known:       &[Option<usize>],
+        constants:   &[ConstantValue],
+        types:       TypeSigils,
+        ) -> Option<(Local, ConstantValue)> {
+    let (result, lhs, rhs) = match instruction {
+        Instruction::Add{result, lhs, rhs} |
+        Instruction::Sub{result, lhs, rhs} |
+        Instruction::Mul{result, lhs, rhs} |
+        Instruction::Div{result, lhs, rhs} |
+        Instruction::Equal{result, lhs, rhs} |
+        Instruction::LessThan{result, lhs, rhs} |
+        Instruction::GreaterThan{result, lhs, rhs} => (*result, lhs, rhs),
+        _ => return None,
+    };
+    let lhs = &constants[known[lhs.0 as usize]?];
+    let rhs = &constants[known[rhs.0 as usize]?];
+
+    // Constants represent integers as data on the heap do; see
+    // Instruction::Add.
+    let integer = |constant: &ConstantValue| {
+        <[u8; 8]>::try_from(&constant.auxiliary[..]).ok()
+            .map(i64::from_le_bytes)
+    };
+    let integers = || Some((integer(lhs)?, integer(rhs)?));
+    let integer_constant = |value: i64| ConstantValue{
+        enchantment: types.integer,
+        auxiliary:   Box::new(value.to_le_bytes()),
+    };
+    let boolean_constant = |value: bool| ConstantValue{
+        enchantment: if value { types.truthy } else { types.falsy },
+        auxiliary:   Box::new([]),
+    };
+
+    let value = match instruction {
+        Instruction::Add{..} => {
+            let (a, b) = integers()?;
+            integer_constant(a.wrapping_add(b))
+        },
+        Instruction::Sub{..} => {
+            let (a, b) = integers()?;
+            integer_constant(a.wrapping_sub(b))
+        },
+        Instruction::Mul{..} => {
+            let (a, b) = integers()?;
+            integer_constant(a.wrapping_mul(b))
+        },
+        Instruction::Div{..} => {
+            let (a, b) = integers()?;
+            if b == 0 {
+                return None;
+            }
+            integer_constant(a.wrapping_div(b))
+        },
+        // Constants have no pointers, so they have the same structure if they
+        // have the same enchantment and auxiliary part.
+        Instruction::Equal{..} => boolean_constant(match integers() {
+            Some((a, b)) => a == b,
+            None         => lhs == rhs,
+        }),
+        Instruction::LessThan{..} => {
+            let (a, b) = integers()?;
+            boolean_constant(a < b)
+        },
+        Instruction::GreaterThan{..} => {
+            let (a, b) = integers()?;
+            boolean_constant(a > b)
+        },
+        _ => return None,
+    };
+    Some((result, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use spell::soundness::assemble_main;
+    use spell::soundness::check_pass;
+
+    /// Assemble a spell book::main, run it before and after folding its
+    /// constants, check that both give the same result, and return the
+    /// result and the folded spell.
+    fn run_folded(source: &str, arguments: &[i64]) -> (i64, Spell) {
+        let (spells, id, types) = assemble_main(source, arguments.len());
+        let (results, spell) = check_pass(spells, id, types, &[arguments],
+                                          |spell| spell.fold_constants(types));
+        (results[0], spell)
+    }
+
+    fn is_load_constant(instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::LoadConstant{..})
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let (result, spell) = run_folded("
+            spell book::main/0
+                constant integer [02 00 00 00 00 00 00 00]
+                constant integer [03 00 00 00 00 00 00 00]
+                v0 = load_constant 0
+                v1 = load_constant 1
+                v2 = add v0, v1
+                v3 = copy v2
+                v4 = mul v3, v2
+                v5 = less_than v4, v1
+                branch_if_truthy v5, done
+                v4 = sub v4, v0
+            done:
+                return v4
+        ", &[]);
+        assert_eq!(result, 23);
+        assert!(spell.instructions[.. 8].iter().filter(|i| !matches!(i,
+                    Instruction::Copy{..} | Instruction::BranchIfTruthy{..}))
+                .all(is_load_constant));
+        assert!(matches!(spell.instructions[4],
+                         Instruction::LoadConstant{result: Local(4),
+                                                   index: 3}));
+        assert_eq!(spell.constants.len(), 6);
+        assert_eq!(spell.constants[3].auxiliary[..], 25i64.to_le_bytes());
+        assert_eq!(spell.constants[4].auxiliary.len(), 0);
+        assert_eq!(spell.constants[5].auxiliary[..], 23i64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_fold_constants_reuses_constants() {
+        let (result, spell) = run_folded("
+            spell book::main/0
+                constant integer [02 00 00 00 00 00 00 00]
+                constant integer [04 00 00 00 00 00 00 00]
+                v0 = load_constant 0
+                v1 = add v0, v0
+                v2 = equal v1, v0
+                branch_if_falsy v2, done
+                v1 = div v1, v0
+            done:
+                return v1
+        ", &[]);
+        assert_eq!(result, 4);
+        assert!(matches!(spell.instructions[1],
+                         Instruction::LoadConstant{result: Local(1),
+                                                   index: 1}));
+        assert!(is_load_constant(&spell.instructions[2]));
+        assert_eq!(spell.constants.len(), 3);
+    }
+
+    #[test]
+    fn test_fold_constants_conservative() {
+        // The argument is not constant, neither is v1 after the join, nor v0
+        // after an invocation writes it, and dividing by zero must still
+        // fail when it is reached.
+        let (result, spell) = run_folded("
+            spell book::main/1
+                constant integer [01 00 00 00 00 00 00 00]
+                constant integer [00 00 00 00 00 00 00 00]
+                v1 = load_constant 0
+                v2 = add v0, v1
+                branch_if_truthy v2, join
+                v1 = load_constant 1
+                v3 = div v1, v1
+            join:
+                v3 = add v1, v1
+                v4 = load_constant 1
+                v4 = invoke_static book::id(v4)
+                v4 = add v4, v1
+                return v4
+
+            spell book::id/1
+                return v0
+        ", &[1]);
+        assert_eq!(result, 1);
+        for index in [1, 4, 5, 8] {
+            assert!(!is_load_constant(&spell.instructions[index]),
+                    "{:?}", spell.instructions[index]);
+        }
+        assert_eq!(spell.constants.len(), 2);
+    }
+}
@@ -0,0 +1,221 @@
+use super::*;
+
+impl Spells {
+    /// Replace every static invocation of a spell consisting of instructions
+    /// with a linked invocation, and every dynamic invocation with a cached
+    /// one, and return the number of invocations that were replaced. See
+    /// [InvokeLinked] and [InvokeDynamicCached].
+    ///
+    /// Static invocations of native spells and of spells that are not in the
+    /// database are left alone. Linking is idempotent, and changing the
+    /// database afterwards is allowed: inserting and replacing spells keeps
+    /// linked invocations linked, and removing a spell may move another spell
+    /// to a different index, so that invocations of it fall back to looking
+    /// it up by id until the database is linked again.
+    ///
+    /// [InvokeLinked]: enum.Instruction.html#variant.InvokeLinked
+    /// [InvokeDynamicCached]:
+    ///     enum.Instruction.html#variant.InvokeDynamicCached
+    pub fn link(&mut self) -> usize {
+        let indices = &self.indices;
+        let mut linked = 0;
+        for (_, spell) in self.spells.iter_mut() {
+            for instruction in spell.instructions.iter_mut() {
+                let replacement = match instruction {
+                    Instruction::InvokeStatic{result, spellbook, spell,
+                                              arguments} => {
+                        let callee = SpellId{spellbook: *spellbook,
+                                             spell:     *spell,
+                                             arity:     arguments.len()};
+                        let index = match indices.get(&callee) {
+                            Some(&index) => index,
+                            None         => continue,
+                        };
+                        Instruction::InvokeLinked{
+                            result:    *result,
+                            spellbook: *spellbook,
+                            spell:     *spell,
+                            index,
+                            arguments: mem::take(arguments),
+                        }
+                    },
+                    Instruction::InvokeDynamic{result, spell, receiver,
+                                               arguments} =>
+                        Instruction::InvokeDynamicCached{
+                            result:    *result,
+                            spell:     *spell,
+                            receiver:  *receiver,
+                            arguments: mem::take(arguments),
+                            cache:     InlineCache::new(),
+                        },
+                    _ => continue,
+                };
+                *instruction = replacement;
+                linked += 1;
+            }
+        }
+        linked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::slice;
+
+    use interpret::Runtime;
+    use interpret::TypeSigils;
+    use interpret::run;
+
+    const BOOK:  Sigil = Sigil(0);
+    const MAIN:  Sigil = Sigil(1);
+    const COUNT: Sigil = Sigil(2);
+    const NONE:  Sigil = Sigil(3);
+    const FALSE: Sigil = Sigil(4);
+    const TRUE:  Sigil = Sigil(5);
+    const INT:   Sigil = Sigil(6);
+    const STR:   Sigil = Sigil(9);
+
+    const TYPES: TypeSigils = TypeSigils{falsy: FALSE, truthy: TRUE,
+                                         integer: INT, string: STR};
+
+    fn id(spell: Sigil, arity: usize) -> SpellId {
+        SpellId{spellbook: BOOK, spell, arity}
+    }
+
+    fn invoke(spell: Sigil, arguments: &[Local]) -> Instruction {
+        Instruction::InvokeStatic{result: Local(0), spellbook: BOOK, spell,
+                                  arguments: Box::from(arguments)}
+    }
+
+    fn is_linked(spells: &Spells, id: SpellId, index: usize) -> bool {
+        matches!(spells.get(id).unwrap().instructions[index],
+                 Instruction::InvokeLinked{..})
+    }
+
+    #[test]
+    fn test_link() {
+        // count(n) counts down to zero by tail recursion.
+        let mut builder = SpellBuilder::new();
+        let n = builder.local();
+        let one = builder.local();
+        let less = builder.local();
+        let done = builder.label();
+        builder
+            .load_constant(one, 0)
+            .less_than(less, n, one)
+            .branch_if_truthy(less, done)
+            .sub(n, n, one)
+            .instruction(invoke(COUNT, &[n]))
+            .bind(done)
+            .ret(n);
+        let one_bytes = 1i64.to_le_bytes();
+        builder.constant(ConstantValue{enchantment: INT,
+                                       auxiliary: Box::new(one_bytes)});
+        let count = builder.build();
+
+        let mut spells = Spells::new();
+        spells.insert(id(MAIN, 1), Spell{
+            instructions: Box::new([
+                invoke(NONE, &[]),
+                invoke(COUNT, &[Local(0)]),
+                Instruction::Return{result: Local(0)},
+            ]),
+            local_variables: 1,
+            constants: Box::new([]),
+            source_map: None,
+        }).unwrap();
+        spells.insert(id(COUNT, 1), count).unwrap();
+        spells.insert_native(id(NONE, 0), |_, _| Datum::from_i64(0).unwrap())
+            .unwrap();
+
+        assert_eq!(spells.link(), 2);
+        assert_eq!(spells.link(), 0);
+        assert!(!is_linked(&spells, id(MAIN, 1), 0));
+        assert!(is_linked(&spells, id(MAIN, 1), 1));
+        assert!(is_linked(&spells, id(COUNT, 1), 4));
+
+        let heap = Heap::new();
+        let argument = Datum::from_i64(1000).unwrap();
+        let runtime = Runtime{spells: &spells, heap: &heap, types: TYPES};
+        let result = run(&runtime, id(MAIN, 1), slice::from_ref(&argument))
+            .as_i64();
+        assert_eq!(result, Some(0));
+
+        // Removing main moves count to its index, which the linked
+        // invocation in count no longer matches.
+        spells.remove(id(MAIN, 1));
+        assert!(spells.get_linked(0, id(COUNT, 1)).is_some());
+        assert!(spells.get_linked(1, id(COUNT, 1)).is_none());
+        let runtime = Runtime{spells: &spells, heap: &heap, types: TYPES};
+        let result = run(&runtime, id(COUNT, 1), &[argument]).as_i64();
+        assert_eq!(result, Some(0));
+    }
+
+    #[test]
+    fn test_inline_cache() {
+        const A: Sigil = Sigil(7);
+        const B: Sigil = Sigil(8);
+
+        // A::name and B::name return 1 and 2, and main dispatches to one of
+        // them on the enchantment of its argument.
+        let constant = |value: i64| {
+            let mut builder = SpellBuilder::new();
+            let result = builder.local();
+            builder.load_constant(result, 0).ret(result);
+            builder.constant(ConstantValue{
+                enchantment: INT,
+                auxiliary:   Box::new(value.to_le_bytes()),
+            });
+            builder.build()
+        };
+        let mut spells = Spells::new();
+        spells.insert(id(MAIN, 1), Spell{
+            instructions: Box::new([
+                Instruction::InvokeDynamic{result: Local(0), spell: NONE,
+                                           receiver: Local(0),
+                                           arguments: Box::new([])},
+                Instruction::Return{result: Local(0)},
+            ]),
+            local_variables: 1,
+            constants: Box::new([]),
+            source_map: None,
+        }).unwrap();
+        let a_name = SpellId{spellbook: A, spell: NONE, arity: 1};
+        let b_name = SpellId{spellbook: B, spell: NONE, arity: 1};
+        spells.insert(a_name, constant(1)).unwrap();
+        spells.insert(b_name, constant(2)).unwrap();
+        assert_eq!(spells.link(), 1);
+
+        let cached = |spells: &Spells, enchantment| {
+            match &spells.get(id(MAIN, 1)).unwrap().instructions[0] {
+                Instruction::InvokeDynamicCached{cache, ..} =>
+                    cache.get(enchantment),
+                _ => panic!("Dynamic invocation was not replaced"),
+            }
+        };
+        assert_eq!(cached(&spells, A), None);
+
+        let heap = Heap::new();
+        let a = unsafe { heap.allocate(A, &[], &[]) };
+        let b = unsafe { heap.allocate(B, &[], &[]) };
+        let run_main = |receiver: &Datum| {
+            let runtime = Runtime{spells: &spells, heap: &heap, types: TYPES};
+            run(&runtime, id(MAIN, 1), slice::from_ref(receiver)).as_i64()
+        };
+
+        // The first invocation fills the cache, and later ones hit it as long
+        // as the enchantment of the receiver stays the same.
+        assert_eq!(run_main(&a), Some(1));
+        assert_eq!(run_main(&a), Some(1));
+        assert_eq!(cached(&spells, A), spells.index_of(a_name));
+
+        // Another enchantment misses, and replaces the cached spell.
+        assert_eq!(run_main(&b), Some(2));
+        assert_eq!(cached(&spells, A), None);
+        assert_eq!(cached(&spells, B), spells.index_of(b_name));
+        assert_eq!(run_main(&a), Some(1));
+        assert_eq!(cached(&spells, A), spells.index_of(a_name));
+    }
+}
@@ -1,23 +1,60 @@
+mod assemble;
+mod builder;
+mod cfg;
 mod code;
+mod disassemble;
+mod fold;
+mod link;
+mod pack;
+mod peephole;
+mod prune;
+mod remap;
+mod serialize;
+#[cfg(test)]
+mod soundness;
+mod verify;
 
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::hash_map::Entry;
+use std::error::Error;
+use std::fmt;
+use std::mem;
 
+use datum::Datum;
+use datum::Heap;
 use sigil::Sigil;
 
+pub use spell::assemble::*;
+pub use spell::builder::*;
+pub use spell::cfg::*;
 pub use spell::code::*;
+pub use spell::disassemble::*;
+pub use spell::remap::*;
+pub use spell::serialize::*;
+pub use spell::verify::*;
 
-/// A spell is identified by the name of the spellbook it is defined in, the name
-/// of the spell, and the arity of the spell.
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+/// A spell is identified by the name of the spellbook it is defined in, the
+/// name of the spell, and the arity of the spell.
+///
+/// Spell ids are ordered by spellbook, then by spell, then by arity. Sigils
+/// are ordered by their numbers, not by their names.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct SpellId {
     pub spellbook: Sigil,
     pub spell: Sigil,
     pub arity: usize,
 }
 
+impl fmt::Display for SpellId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}::{:?}/{}", self.spellbook, self.spell, self.arity)
+    }
+}
+
 /// A spell is a sequence of instructions that can be executed using a single
 /// stack frame.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Spell {
     /// The sequence of instructions that are to be interpreted when the spell
     /// is invoked.
@@ -28,24 +65,222 @@ pub struct Spell {
     ///
     /// For _n_ the arity of the spell spell, the first _n_ local variables are
     /// filled with the values of the arguments when the spell is invoked.
+    /// There must be at least _n_ local variables; see [verify_as].
+    ///
+    /// [verify_as]: fn.verify_as.html
     pub local_variables: usize,
+
+    /// The constant pool of the spell, from which
+    /// [LoadConstant](enum.Instruction.html#variant.LoadConstant) loads by
+    /// index.
+    pub constants: Box<[ConstantValue]>,
+
+    /// Where in the source code each instruction came from, if known. When
+    /// present, the source map has a span for every instruction, at the same
+    /// index. Spells without a source map can only be reported on by
+    /// instruction index.
+    pub source_map: Option<Box<[SourceSpan]>>,
 }
 
-/// A spell database is a collection of spells.
+impl Spell {
+    /// The source span of the instruction at the given index, or `None` if
+    /// the spell has no source map or the source map has no such span.
+    pub fn source_span(&self, instruction: usize) -> Option<SourceSpan> {
+        self.source_map.as_ref()?.get(instruction).cloned()
+    }
+
+    /// The spellbook, spell, and arity of every spell the spell invokes, in
+    /// the order of the instructions, once for each invocation.
+    ///
+    /// Static and linked invocations yield the id of the invoked spell.
+    /// Dynamic invocations yield [UNKNOWN_SPELLBOOK] as the spellbook, and an
+    /// arity that includes the receiver. Invocations of closures are skipped,
+    /// as neither the spell nor its arity is known before they are
+    /// interpreted.
+    ///
+    /// [UNKNOWN_SPELLBOOK]: constant.UNKNOWN_SPELLBOOK.html
+    pub fn called_spells(&self)
+        -> impl Iterator<Item = (Sigil, Sigil, usize)> + '_ {
+        self.instructions.iter().filter_map(|instruction| match instruction {
+            Instruction::InvokeStatic{spellbook, spell, arguments, ..} |
+            Instruction::InvokeLinked{spellbook, spell, arguments, ..} =>
+                Some((*spellbook, *spell, arguments.len())),
+            Instruction::InvokeDynamic{spell, arguments, ..} |
+            Instruction::InvokeDynamicCached{spell, arguments, ..} =>
+                Some((UNKNOWN_SPELLBOOK, *spell, 1 + arguments.len())),
+            _ => None,
+        })
+    }
+}
+
+/// A location in the source code that a spell was compiled from.
+///
+/// Lines and columns are numbered from one. The file is identified by a sigil
+/// so that spans are cheap to copy.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SourceSpan {
+    pub file:   Sigil,
+    pub line:   u32,
+    pub column: u32,
+}
+
+/// A constant in the constant pool of a spell.
+///
+/// A constant describes a datum without pointers. It is not itself a datum, as
+/// spells do not belong to any heap.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConstantValue {
+    pub enchantment: Sigil,
+    pub auxiliary: Box<[u8]>,
+}
+
+/// A native spell is implemented in Rust rather than as instructions.
+///
+/// A native spell is called with the heap and the arguments of the
+/// invocation, and returns the result of the invocation. It does not get a
+/// stack frame of its own.
+pub type NativeSpell = dyn for<'a> Fn(&'a Heap, &[Datum<'a>]) -> Datum<'a>;
+
+/// A suspending native spell is a native spell that may suspend the run
+/// instead of returning, for example to wait for I/O done by the host.
+///
+/// Only runs by [run_with_options] can be suspended; see
+/// [RunOutcome::suspension].
+///
+/// [run_with_options]: ../interpret/fn.run_with_options.html
+/// [RunOutcome::suspension]:
+///     ../interpret/struct.RunOutcome.html#structfield.suspension
+pub type SuspendingNativeSpell =
+    dyn for<'a> Fn(&'a Heap, &[Datum<'a>]) -> NativeResult<'a>;
+
+/// What a suspending native spell returns.
 #[derive(Debug)]
+pub enum NativeResult<'a> {
+    /// The invocation returns this datum, as with ordinary native spells.
+    Return(Datum<'a>),
+
+    /// The run is suspended until the host resumes it with the result of the
+    /// invocation. The datum tells the host what the native spell waits for.
+    Pending(Datum<'a>),
+}
+
+/// A native spell of either kind, as stored in a spell database.
+pub enum Native {
+    /// An ordinary native spell, which always returns.
+    Returning(Box<NativeSpell>),
+
+    /// A native spell that may suspend the run.
+    Suspending(Box<SuspendingNativeSpell>),
+}
+
+impl Native {
+    /// Call the native spell. The result of an ordinary native spell is
+    /// always returned right away.
+    pub fn call<'a>(&self, heap: &'a Heap, arguments: &[Datum<'a>])
+        -> NativeResult<'a> {
+        match self {
+            Native::Returning(native)  =>
+                NativeResult::Return(native(heap, arguments)),
+            Native::Suspending(native) => native(heap, arguments),
+        }
+    }
+}
+
+/// A spell database is a collection of spells.
+///
+/// Every spell id is either undefined, defined as a spell consisting of
+/// instructions, or defined as a native spell. Only the former are visited by
+/// [iter] and counted by [len].
+///
+/// [iter]: #method.iter
+/// [len]: #method.len
 pub struct Spells {
-    spells: HashMap<SpellId, Spell>,
+    /// The spells consisting of instructions. Linked invocations refer to
+    /// spells by their index in this vector; see [link].
+    ///
+    /// [link]: #method.link
+    spells:  Vec<(SpellId, Spell)>,
+
+    /// The index in `spells` of every spell consisting of instructions.
+    indices: HashMap<SpellId, usize>,
+
+    natives: HashMap<SpellId, Native>,
 }
 
 impl Spells {
     /// Create an empty spell database.
     pub fn new() -> Self {
-        Spells{spells: HashMap::new()}
+        Spells{spells: Vec::new(), indices: HashMap::new(),
+               natives: HashMap::new()}
     }
 
     /// Get a spell by its spellbook name, spell name, and arity.
     pub fn get(&self, id: SpellId) -> Option<&Spell> {
-        self.spells.get(&id)
+        self.indices.get(&id).map(|&index| &self.spells[index].1)
+    }
+
+    /// The index of a spell consisting of instructions, as a linked
+    /// invocation would refer to it. See [link].
+    ///
+    /// [link]: #method.link
+    pub fn index_of(&self, id: SpellId) -> Option<usize> {
+        self.indices.get(&id).cloned()
+    }
+
+    /// Get a spell by the index a linked invocation refers to, provided that
+    /// the spell at that index still has the given id. See [link].
+    ///
+    /// [link]: #method.link
+    #[inline(always)]
+    pub fn get_linked(&self, index: usize, id: SpellId) -> Option<&Spell> {
+        self.spells.get(index)
+            .filter(|(linked, _)| *linked == id)
+            .map(|(_, spell)| spell)
+    }
+
+    /// The number of spells in the database.
+    pub fn len(&self) -> usize {
+        self.spells.len()
+    }
+
+    /// Whether the database contains no spells.
+    pub fn is_empty(&self) -> bool {
+        self.spells.is_empty()
+    }
+
+    /// Iterate over all spells in the database. The order is unspecified, and
+    /// may differ between databases with the same spells.
+    pub fn iter(&self) -> impl Iterator<Item = (SpellId, &Spell)> {
+        self.spells.iter().map(|(id, spell)| (*id, spell))
+    }
+
+    /// Iterate over all spells in the database, ordered by id. Unlike that of
+    /// [iter], this order depends only on which spells are in the database.
+    ///
+    /// [iter]: #method.iter
+    pub fn iter_sorted(&self) -> impl Iterator<Item = (SpellId, &Spell)> {
+        let mut spells: Vec<_> = self.iter().collect();
+        spells.sort_unstable_by_key(|&(id, _)| id);
+        spells.into_iter()
+    }
+
+    /// Iterate over the ids of all spells in the database. The order is that
+    /// of [iter].
+    ///
+    /// [iter]: #method.iter
+    pub fn ids(&self) -> impl Iterator<Item = SpellId> + '_ {
+        self.spells.iter().map(|(id, _)| *id)
+    }
+
+    /// Get a spell by its spellbook name, spell name, and arity, for
+    /// modification.
+    ///
+    /// Spells cannot be modified while they are being interpreted, as the
+    /// interpreter borrows the spell database for the duration of the
+    /// interpretation.
+    pub fn get_mut(&mut self, id: SpellId) -> Option<&mut Spell> {
+        let index = *self.indices.get(&id)?;
+        Some(&mut self.spells[index].1)
     }
 
     /// Insert a spell into the database, or return an error if the spell
@@ -54,15 +289,374 @@ impl Spells {
                   id: SpellId,
                   spell: Spell,
                   ) -> Result<(), RedefinitionError> {
-        if self.spells.contains_key(&id) {
-            Err(RedefinitionError)
-        } else {
-            self.spells.insert(id, spell);
-            Ok(())
+        if self.natives.contains_key(&id) {
+            return Err(RedefinitionError{id});
+        }
+        match self.indices.entry(id) {
+            Entry::Occupied(_) => Err(RedefinitionError{id}),
+            Entry::Vacant(entry) => {
+                entry.insert(self.spells.len());
+                self.spells.push((id, spell));
+                Ok(())
+            },
+        }
+    }
+
+    /// Insert several spells into the database, or return the ids of all the
+    /// spells that already exist if any do.
+    ///
+    /// A spell conflicts if the database already has a spell with its id, or
+    /// if an earlier spell in the same call has its id. Each conflicting id is
+    /// returned once, in the order in which the conflicts occur. The spells
+    /// are inserted all at once or not at all: when this method fails, the
+    /// database is left unchanged.
+    pub fn insert_all<I>(&mut self, spells: I) -> Result<(), Vec<SpellId>>
+        where I: IntoIterator<Item = (SpellId, Spell)> {
+        let spells: Vec<(SpellId, Spell)> = spells.into_iter().collect();
+
+        // Check everything before changing anything.
+        let mut ids = HashSet::new();
+        let mut conflicts = Vec::new();
+        for &(id, _) in &spells {
+            let defined = self.indices.contains_key(&id) ||
+                          self.natives.contains_key(&id);
+            if (defined || !ids.insert(id)) && !conflicts.contains(&id) {
+                conflicts.push(id);
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(conflicts);
         }
+
+        for (id, spell) in spells {
+            self.indices.insert(id, self.spells.len());
+            self.spells.push((id, spell));
+        }
+        Ok(())
+    }
+
+    /// Insert a spell into the database, returning the spell it replaces, if
+    /// any. A native spell with the same id is removed. As with [get_mut],
+    /// this cannot happen during interpretation.
+    ///
+    /// [get_mut]: #method.get_mut
+    pub fn replace(&mut self, id: SpellId, spell: Spell) -> Option<Spell> {
+        self.natives.remove(&id);
+        match self.indices.get(&id) {
+            Some(&index) =>
+                Some(mem::replace(&mut self.spells[index].1, spell)),
+            None => {
+                self.indices.insert(id, self.spells.len());
+                self.spells.push((id, spell));
+                None
+            },
+        }
+    }
+
+    /// Remove a spell from the database, returning it if it existed. A native
+    /// spell with the same id is removed too. As with [get_mut], this cannot
+    /// happen during interpretation.
+    ///
+    /// [get_mut]: #method.get_mut
+    pub fn remove(&mut self, id: SpellId) -> Option<Spell> {
+        self.natives.remove(&id);
+        let index = self.indices.remove(&id)?;
+        let (_, spell) = self.spells.swap_remove(index);
+        if let Some((moved, _)) = self.spells.get(index) {
+            self.indices.insert(*moved, index);
+        }
+        Some(spell)
+    }
+
+    /// The arities at which a spell with the given spellbook name and spell
+    /// name exists, in ascending order. Both bytecode and native spells are
+    /// included.
+    ///
+    /// This visits every spell in the database, so it is meant for reporting
+    /// errors rather than for use when invoking spells.
+    pub fn arities(&self, spellbook: Sigil, spell: Sigil) -> Vec<usize> {
+        let mut arities: Vec<usize> =
+            self.ids().chain(self.natives.keys().cloned())
+                .filter(|id| id.spellbook == spellbook && id.spell == spell)
+                .map(|id| id.arity)
+                .collect();
+        arities.sort();
+        arities
+    }
+
+    /// Get a native spell by its spellbook name, spell name, and arity.
+    /// Suspending native spells are not returned; see [get_any_native].
+    ///
+    /// [get_any_native]: #method.get_any_native
+    pub fn get_native(&self, id: SpellId) -> Option<&NativeSpell> {
+        match self.natives.get(&id)? {
+            Native::Returning(native) => Some(native.as_ref()),
+            Native::Suspending(_)     => None,
+        }
+    }
+
+    /// Get a native spell of either kind by its spellbook name, spell name,
+    /// and arity.
+    pub fn get_any_native(&self, id: SpellId) -> Option<&Native> {
+        self.natives.get(&id)
+    }
+
+    /// Insert a native spell into the database, or return an error if a spell
+    /// with the same id already exists. The native spell is called with
+    /// exactly as many arguments as the arity in its id.
+    pub fn insert_native<F>(&mut self,
+                            id: SpellId,
+                            native: F,
+                            ) -> Result<(), RedefinitionError>
+        where F: 'static + for<'a> Fn(&'a Heap, &[Datum<'a>]) -> Datum<'a> {
+        self.insert_any_native(id, Native::Returning(Box::new(native)))
+    }
+
+    /// Like [insert_native], but for a suspending native spell.
+    ///
+    /// [insert_native]: #method.insert_native
+    pub fn insert_suspending_native<F>(&mut self,
+                                       id: SpellId,
+                                       native: F,
+                                       ) -> Result<(), RedefinitionError>
+        where F: 'static +
+                 for<'a> Fn(&'a Heap, &[Datum<'a>]) -> NativeResult<'a> {
+        self.insert_any_native(id, Native::Suspending(Box::new(native)))
+    }
+
+    fn insert_any_native(&mut self,
+                         id: SpellId,
+                         native: Native,
+                         ) -> Result<(), RedefinitionError> {
+        if self.indices.contains_key(&id) {
+            return Err(RedefinitionError{id});
+        }
+        match self.natives.entry(id) {
+            Entry::Occupied(_) => Err(RedefinitionError{id}),
+            Entry::Vacant(entry) => {
+                entry.insert(native);
+                Ok(())
+            },
+        }
+    }
+}
+
+impl fmt::Debug for Spells {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Spells")
+            .field("spells", &self.spells)
+            .field("natives", &self.natives.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Default for Spells {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 /// This error is returned when attempting to define a spell that was already
 /// defined.
-pub struct RedefinitionError;
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RedefinitionError {
+    /// The id of the spell that was already defined.
+    pub id: SpellId,
+}
+
+impl fmt::Display for RedefinitionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "spell {} is already defined", self.id)
+    }
+}
+
+impl Error for RedefinitionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spell(local_variables: usize) -> Spell {
+        Spell{instructions: Box::new([]), local_variables,
+              constants: Box::new([]), source_map: None}
+    }
+
+    #[test]
+    fn test_redefinition() {
+        let id = SpellId{spellbook: Sigil(0), spell: Sigil(1), arity: 0};
+
+        let mut spells = Spells::new();
+        assert!(spells.insert(id, spell(1)).is_ok());
+        assert_eq!(spells.insert(id, spell(2)).unwrap_err().to_string(),
+                   "spell Sigil(0)::Sigil(1)/0 is already defined");
+        assert_eq!(spells.get(id).unwrap().local_variables, 1);
+
+        spells.get_mut(id).unwrap().local_variables = 3;
+        assert_eq!(spells.replace(id, spell(4)).unwrap().local_variables, 3);
+        assert_eq!(spells.remove(id).unwrap().local_variables, 4);
+        assert!(spells.remove(id).is_none());
+        assert!(spells.replace(id, spell(5)).is_none());
+        assert_eq!(spells.get(id).unwrap().local_variables, 5);
+    }
+
+    #[test]
+    fn test_insert_all() {
+        let id = |arity| SpellId{spellbook: Sigil(0), spell: Sigil(1), arity};
+
+        let mut spells = Spells::new();
+        spells.insert(id(0), spell(0)).unwrap();
+        spells.insert_native(id(1), |_, _| Datum::from_i64(0).unwrap())
+            .unwrap();
+
+        // Every conflict is reported, and nothing is inserted.
+        let batch = [2, 0, 1, 3, 2, 2].iter()
+            .map(|&arity| (id(arity), spell(arity)));
+        assert_eq!(spells.insert_all(batch), Err(vec![id(0), id(1), id(2)]));
+        assert_eq!(spells.len(), 1);
+        assert!(spells.get(id(2)).is_none());
+
+        spells.insert_all((2 .. 5).map(|arity| (id(arity), spell(arity))))
+            .unwrap();
+        assert_eq!(spells.len(), 4);
+        assert_eq!(spells.get(id(4)).unwrap().local_variables, 4);
+        assert_eq!(spells.insert_all(vec![]), Ok(()));
+    }
+
+    #[test]
+    fn test_iter() {
+        let id_a = SpellId{spellbook: Sigil(0), spell: Sigil(1), arity: 0};
+        let id_b = SpellId{spellbook: Sigil(0), spell: Sigil(1), arity: 1};
+
+        let mut spells = Spells::new();
+        assert!(spells.is_empty());
+        spells.insert(id_a, spell(1)).ok().unwrap();
+        spells.insert(id_b, spell(2)).ok().unwrap();
+        assert_eq!(spells.len(), 2);
+
+        let mut ids = spells.ids().collect::<Vec<_>>();
+        ids.sort_by_key(|id| id.arity);
+        assert_eq!(ids, vec![id_a, id_b]);
+
+        let mut iter = spells.iter()
+            .map(|(id, spell)| (id.arity, spell.local_variables))
+            .collect::<Vec<_>>();
+        iter.sort();
+        assert_eq!(iter, vec![(0, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn test_iter_sorted() {
+        let id = |spellbook, spell, arity| {
+            SpellId{spellbook: Sigil(spellbook), spell: Sigil(spell), arity}
+        };
+        let sorted = [id(0, 2, 1), id(0, 3, 0), id(1, 0, 0), id(1, 0, 2)];
+
+        // Removing a spell moves the last spell to its place in the storage,
+        // which must not affect the sorted order.
+        let mut spells = Spells::new();
+        for &id in sorted.iter().rev() {
+            spells.insert(id, spell(0)).ok().unwrap();
+        }
+        spells.insert(id(0, 0, 0), spell(0)).ok().unwrap();
+        spells.remove(id(0, 0, 0));
+        spells.remove(id(1, 0, 2));
+        spells.insert(id(1, 0, 2), spell(0)).ok().unwrap();
+
+        let ids: Vec<SpellId> = spells.iter_sorted().map(|(id, _)| id)
+            .collect();
+        assert_eq!(ids, sorted);
+    }
+
+    #[test]
+    fn test_called_spells() {
+        let invoke = |spell, arguments: &[Local]| Instruction::InvokeStatic{
+            result: Local(0), spellbook: Sigil(0), spell,
+            arguments: Box::from(arguments),
+        };
+        let mut spells = Spells::new();
+        let id = SpellId{spellbook: Sigil(0), spell: Sigil(1), arity: 1};
+        spells.insert(id, Spell{
+            instructions: Box::new([
+                invoke(Sigil(2), &[]),
+                Instruction::InvokeDynamic{result: Local(0), spell: Sigil(3),
+                                           receiver: Local(0),
+                                           arguments: Box::new([Local(0)])},
+                Instruction::InvokeClosure{result: Local(0),
+                                           closure: Local(0),
+                                           arguments: Box::new([])},
+                invoke(Sigil(1), &[Local(0)]),
+                invoke(Sigil(2), &[]),
+                Instruction::Return{result: Local(0)},
+            ]),
+            local_variables: 1,
+            constants: Box::new([]),
+            source_map: None,
+        }).unwrap();
+
+        let expected = vec![(Sigil(0), Sigil(2), 0),
+                            (UNKNOWN_SPELLBOOK, Sigil(3), 2),
+                            (Sigil(0), Sigil(1), 1),
+                            (Sigil(0), Sigil(2), 0)];
+        let called = |spells: &Spells| {
+            spells.get(id).unwrap().called_spells().collect::<Vec<_>>()
+        };
+        assert_eq!(called(&spells), expected);
+
+        // Linking does not change the spells that are called.
+        spells.link();
+        assert_eq!(called(&spells), expected);
+    }
+
+    #[test]
+    fn test_native_redefinition() {
+        let id = SpellId{spellbook: Sigil(0), spell: Sigil(1), arity: 1};
+        fn identity<'a>(_: &'a Heap, arguments: &[Datum<'a>]) -> Datum<'a> {
+            arguments[0].clone()
+        }
+
+        let mut spells = Spells::new();
+        assert!(spells.insert_native(id, identity).is_ok());
+        assert!(spells.insert_native(id, identity).is_err());
+        assert!(spells.insert(id, spell(1)).is_err());
+        assert!(spells.get(id).is_none());
+        assert!(spells.get_native(id).is_some());
+        assert_eq!(spells.len(), 0);
+
+        assert!(spells.remove(id).is_none());
+        assert!(spells.get_native(id).is_none());
+        assert!(spells.insert(id, spell(1)).is_ok());
+        assert!(spells.insert_native(id, identity).is_err());
+
+        // Suspending native spells share ids with the other kinds.
+        fn pending<'a>(_: &'a Heap, arguments: &[Datum<'a>])
+            -> NativeResult<'a> {
+            NativeResult::Pending(arguments[0].clone())
+        }
+        assert!(spells.insert_suspending_native(id, pending).is_err());
+        spells.remove(id);
+        assert!(spells.insert_suspending_native(id, pending).is_ok());
+        assert!(spells.insert_native(id, identity).is_err());
+        assert!(spells.get_native(id).is_none());
+        assert!(matches!(spells.get_any_native(id),
+                         Some(Native::Suspending(_))));
+    }
+
+    #[test]
+    fn test_arities() {
+        fn identity<'a>(_: &'a Heap, arguments: &[Datum<'a>]) -> Datum<'a> {
+            arguments[0].clone()
+        }
+        let id = |spell, arity| SpellId{spellbook: Sigil(0), spell, arity};
+
+        let mut spells = Spells::new();
+        spells.insert(id(Sigil(1), 3), spell(3)).unwrap();
+        spells.insert(id(Sigil(1), 0), spell(0)).unwrap();
+        spells.insert_native(id(Sigil(1), 1), identity).unwrap();
+        spells.insert(id(Sigil(2), 2), spell(2)).unwrap();
+
+        assert_eq!(spells.arities(Sigil(0), Sigil(1)), vec![0, 1, 3]);
+        assert_eq!(spells.arities(Sigil(0), Sigil(2)), vec![2]);
+        assert_eq!(spells.arities(Sigil(0), Sigil(3)), Vec::<usize>::new());
+        assert_eq!(spells.arities(Sigil(1), Sigil(1)), Vec::<usize>::new());
+    }
+}
@@ -0,0 +1,418 @@
+use super::*;
+
+impl Spell {
+    /// Renumber the local variables so that local variables that are never
+    /// live at the same time share a slot, and lower the number of local
+    /// variables the spell allocates accordingly.
+    ///
+    /// A local variable is live from where it is written until where it is
+    /// last read, along every path through the spell, including the paths to
+    /// exception handlers. Local variables that may be read before they are
+    /// written, such as the arguments, keep their numbers. Since the arity of
+    /// the spell is not recorded in the spell, it must be given, so that the
+    /// arguments are still passed into local variables the spell allocates.
+    ///
+    /// Spells that are not well-formed are left unchanged; see [verify].
+    ///
+    /// [verify]: fn.verify.html
+    pub fn pack_locals(&mut self, arity: usize) {
+        if verify(self).is_err() {
+            return;
+        }
+
+        let locals = self.local_variables;
+        let accesses: Vec<(Vec<Local>, Vec<Local>)> =
+            self.instructions.iter().map(accesses).collect();
+        let live_in = self.live_in(&accesses);
+
+        // Two local variables interfere if one is written while the other is
+        // live afterwards, or if both are written by the same instruction.
+        // Catching an exception writes the exception into a local variable,
+        // which interferes with the local variables live at the handler.
+        let mut interference = vec![LiveSet::new(locals); locals];
+        let mut interfere = |a: usize, b: usize| {
+            if a != b {
+                interference[a].insert(b);
+                interference[b].insert(a);
+            }
+        };
+        for (index, (_, writes)) in accesses.iter().enumerate() {
+            let live_out = self.live_out(index, &live_in);
+            for &Local(write) in writes {
+                for other in live_out.iter()
+                    .chain(writes.iter().map(|local| local.0 as usize)) {
+                    interfere(write as usize, other);
+                }
+            }
+        }
+        for instruction in self.instructions.iter() {
+            if let Instruction::PushHandler{target, exception} = instruction {
+                for other in live_in[*target].iter() {
+                    interfere(exception.0 as usize, other);
+                }
+            }
+        }
+
+        // Local variables that are live at the start keep their numbers, and
+        // so do those in the ranges of range copies, which must stay
+        // consecutive. The others are given the lowest number that no local
+        // variable they interfere with has, in the order in which they are
+        // first referred to.
+        let mut numbers: Vec<Option<u32>> = vec![None; locals];
+        for local in live_in.first().into_iter().flat_map(LiveSet::iter) {
+            numbers[local] = Some(local as u32);
+        }
+        for instruction in self.instructions.iter() {
+            if let Instruction::CopyRange{..} = instruction {
+                instruction.for_each_local(|Local(local)| {
+                    numbers[local as usize] = Some(local);
+                });
+            }
+        }
+        for instruction in self.instructions.iter() {
+            instruction.for_each_local(|Local(local)| {
+                let local = local as usize;
+                if numbers[local].is_none() {
+                    let taken: Vec<u32> = interference[local].iter()
+                        .filter_map(|other| numbers[other])
+                        .collect();
+                    numbers[local] =
+                        (0 ..).find(|number| !taken.contains(number));
+                }
+            });
+        }
+
+        for instruction in self.instructions.iter_mut() {
+            instruction.for_each_local_mut(|local| {
+                // Every local variable that is referred to has a number.
+                local.0 = numbers[local.0 as usize].unwrap();
+            });
+        }
+        self.local_variables = numbers.iter().flatten()
+            .map(|&number| number as usize + 1)
+            .fold(arity, usize::max);
+    }
+
+    /// The local variables that are live before each instruction, given the
+    /// local variables each instruction reads and writes.
+    fn live_in(&self, accesses: &[(Vec<Local>, Vec<Local>)]) -> Vec<LiveSet> {
+        let handlers: Vec<(usize, Local)> = self.instructions.iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::PushHandler{target, exception} =>
+                    Some((*target, *exception)),
+                _ => None,
+            })
+            .collect();
+
+        let mut live_in = vec![LiveSet::new(self.local_variables);
+                               self.instructions.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (index, (reads, writes)) in accesses.iter().enumerate().rev() {
+                let mut live = self.live_out(index, &live_in);
+                for write in writes {
+                    live.remove(write.0 as usize);
+                }
+                for read in reads {
+                    live.insert(read.0 as usize);
+                }
+
+                // An exception may be thrown by any instruction before it
+                // writes its results, and then be caught by any handler,
+                // which writes the exception.
+                for &(target, exception) in &handlers {
+                    let mut caught = live_in[target].clone();
+                    caught.remove(exception.0 as usize);
+                    live.union_with(&caught);
+                }
+
+                if live != live_in[index] {
+                    live_in[index] = live;
+                    changed = true;
+                }
+            }
+        }
+        live_in
+    }
+
+    /// The local variables that are live after an instruction, given those
+    /// that are live before each instruction.
+    fn live_out(&self, index: usize, live_in: &[LiveSet]) -> LiveSet {
+        let mut live = LiveSet::new(self.local_variables);
+        for successor in self.instructions[index].successors(index) {
+            live.union_with(&live_in[successor]);
+        }
+        live
+    }
+}
+
+/// The local variables an instruction reads, and those it writes.
+///
+/// Every local variable an instruction refers to is read, except for its
+/// results, which are written. A swap both reads and writes its local
+/// variables. The exception of an exception handler is neither, as it is
+/// written when the handler catches an exception rather than by the
+/// instruction.
+pub(super) fn accesses(instruction: &Instruction) -> (Vec<Local>, Vec<Local>) {
+    let writes = match instruction {
+        Instruction::Copy{to, ..} => vec![*to],
+        Instruction::CopyRange{to, count, ..} =>
+            local_range(*to, *count).collect(),
+        Instruction::Swap{a, b} => vec![*a, *b],
+        Instruction::InvokeStatic{result, ..} |
+        Instruction::InvokeDynamic{result, ..} |
+        Instruction::InvokeDynamicCached{result, ..} |
+        Instruction::MakeClosure{result, ..} |
+        Instruction::InvokeClosure{result, ..} |
+        Instruction::InvokeLinked{result, ..} |
+        Instruction::Add{result, ..} |
+        Instruction::Sub{result, ..} |
+        Instruction::Mul{result, ..} |
+        Instruction::Div{result, ..} |
+        Instruction::Equal{result, ..} |
+        Instruction::LessThan{result, ..} |
+        Instruction::GreaterThan{result, ..} |
+        Instruction::Allocate{result, ..} |
+        Instruction::LoadConstant{result, ..} |
+        Instruction::GetPointer{result, ..} |
+        Instruction::EnchantmentOf{result, ..} |
+        Instruction::IsEnchanted{result, ..} |
+        Instruction::AuxiliaryLen{result, ..} => vec![*result],
+        Instruction::PushHandler{..} |
+        Instruction::Nop |
+        Instruction::Jump{..} |
+        Instruction::JumpRelative{..} |
+        Instruction::Switch{..} |
+        Instruction::BranchIfTruthy{..} |
+        Instruction::BranchIfFalsy{..} |
+        Instruction::BranchIfTruthyRelative{..} |
+        Instruction::BranchIfFalsyRelative{..} |
+        Instruction::PopHandler |
+        Instruction::Throw{..} |
+        Instruction::Return{..} => Vec::new(),
+    };
+
+    // The results come first in the order of for_each_local, so skipping as
+    // many local variables as there are results leaves those that are read.
+    // Copies visit their sources first instead.
+    let mut reads = Vec::new();
+    instruction.for_each_local(|local| reads.push(local));
+    let reads = match instruction {
+        Instruction::Copy{from, ..}  => vec![*from],
+        Instruction::CopyRange{from, count, ..} =>
+            local_range(*from, *count).collect(),
+        Instruction::Swap{..}        => reads,
+        Instruction::PushHandler{..} => Vec::new(),
+        _                            => reads.split_off(writes.len()),
+    };
+    (reads, writes)
+}
+
+/// A set of local variables, by number.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct LiveSet(Vec<u64>);
+
+impl LiveSet {
+    fn new(locals: usize) -> Self {
+        LiveSet(vec![0; locals.div_ceil(64)])
+    }
+
+    fn insert(&mut self, local: usize) {
+        self.0[local / 64] |= 1 << (local % 64);
+    }
+
+    fn remove(&mut self, local: usize) {
+        self.0[local / 64] &= !(1 << (local % 64));
+    }
+
+    fn union_with(&mut self, other: &LiveSet) {
+        for (word, other) in self.0.iter_mut().zip(&other.0) {
+            *word |= other;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(index, &word)| {
+            (0 .. 64).filter(move |bit| word & 1 << bit != 0)
+                .map(move |bit| index * 64 + bit)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use interpret::TypeSigils;
+    use spell::soundness::check_pass;
+
+    const BOOK:  Sigil = Sigil(0);
+    const MAIN:  Sigil = Sigil(1);
+    const FALSE: Sigil = Sigil(2);
+    const TRUE:  Sigil = Sigil(3);
+    const INT:   Sigil = Sigil(4);
+    const STR:   Sigil = Sigil(5);
+
+    const TYPES: TypeSigils = TypeSigils{falsy: FALSE, truthy: TRUE,
+                                         integer: INT, string: STR};
+
+    fn integer(value: i64) -> ConstantValue {
+        ConstantValue{enchantment: INT,
+                      auxiliary:   Box::new(value.to_le_bytes())}
+    }
+
+    /// Run a spell before and after packing its local variables, check that
+    /// both give the same result, and return the result and the number of
+    /// local variables after packing.
+    fn run_packed(spell: Spell, arguments: &[i64]) -> (i64, usize) {
+        let arity = arguments.len();
+        let id = SpellId{spellbook: BOOK, spell: MAIN, arity};
+        let mut spells = Spells::new();
+        spells.insert(id, spell).unwrap();
+        let (results, spell) = check_pass(spells, id, TYPES, &[arguments],
+                                          |spell| spell.pack_locals(arity));
+        (results[0], spell.local_variables)
+    }
+
+    #[test]
+    fn test_pack_locals() {
+        // main(a, b) = ((a + b + a) + b) * 2, with a local variable for each
+        // temporary.
+        let mut builder = SpellBuilder::new();
+        let a = builder.local();
+        let b = builder.local();
+        let temporaries: Vec<Local> = (0 .. 5).map(|_| builder.local())
+            .collect();
+        builder
+            .add(temporaries[0], a, b)
+            .add(temporaries[1], temporaries[0], a)
+            .add(temporaries[2], temporaries[1], b)
+            .load_constant(temporaries[3], 0)
+            .mul(temporaries[4], temporaries[2], temporaries[3])
+            .ret(temporaries[4]);
+        builder.constant(integer(2));
+        let spell = builder.build();
+        assert_eq!(spell.local_variables, 7);
+
+        // The arguments stay in place, and the temporaries reuse their slots
+        // once they are no longer read.
+        assert_eq!(run_packed(spell, &[3, 4]), (28, 3));
+    }
+
+    #[test]
+    fn test_pack_locals_loop() {
+        // main(n) sums the numbers below n. The sum and the counter are live
+        // around the loop, and must not share a slot with the temporaries.
+        let mut builder = SpellBuilder::new();
+        let n = builder.local();
+        let one = builder.local();
+        let sum = builder.local();
+        let i = builder.local();
+        let less = builder.local();
+        let next_sum = builder.local();
+        let next_i = builder.local();
+        let top = builder.label();
+        let done = builder.label();
+        builder
+            .load_constant(one, 0)
+            .sub(sum, one, one)
+            .sub(i, one, one)
+            .bind(top)
+            .less_than(less, i, n)
+            .branch_if_falsy(less, done)
+            .add(next_sum, sum, i)
+            .copy(next_sum, sum)
+            .add(next_i, i, one)
+            .copy(next_i, i)
+            .jump(top)
+            .bind(done)
+            .ret(sum);
+        builder.constant(integer(1));
+
+        assert_eq!(run_packed(builder.build(), &[10]), (45, 5));
+    }
+
+    #[test]
+    fn test_pack_locals_handler() {
+        // main(a) = 1 + (a + a + a), where the sum is thrown and caught. The
+        // constant is only live on the path through the handler, so the
+        // temporaries must not take its slot.
+        let mut builder = SpellBuilder::new();
+        let a = builder.local();
+        let one = builder.local();
+        let exception = builder.local();
+        let double = builder.local();
+        let thrown = builder.local();
+        let result = builder.local();
+        let catch = builder.label();
+        builder
+            .load_constant(one, 0)
+            .push_handler(catch, exception)
+            .add(double, a, a)
+            .add(thrown, double, a)
+            .throw(thrown)
+            .bind(catch)
+            .add(result, one, exception)
+            .ret(result);
+        builder.constant(integer(1));
+
+        assert_eq!(run_packed(builder.build(), &[5]), (16, 3));
+    }
+
+    #[test]
+    fn test_pack_locals_copy_range() {
+        // main(a, b) = (a + b) * (a + b), where the arguments are copied into
+        // a range. The range keeps its slots, so that it stays consecutive,
+        // and the temporaries take the slots of the arguments instead.
+        let mut builder = SpellBuilder::new();
+        let a = builder.local();
+        let b = builder.local();
+        let sum = builder.local();
+        let range = builder.local();
+        let range_end = builder.local();
+        let other_sum = builder.local();
+        let product = builder.local();
+        builder
+            .add(sum, a, b)
+            .copy_range(a, range, 2)
+            .add(other_sum, range, range_end)
+            .mul(product, sum, other_sum)
+            .ret(product);
+
+        assert_eq!(run_packed(builder.build(), &[3, 4]), (49, 5));
+    }
+
+    #[test]
+    fn test_pack_locals_arity() {
+        // Arguments that are never read need not keep their slots, but the
+        // spell must still allocate a local variable for each argument.
+        let mut spell = Spell{
+            instructions: Box::new([
+                Instruction::LoadConstant{result: Local(2), index: 0},
+                Instruction::Return{result: Local(2)},
+            ]),
+            local_variables: 3,
+            constants: Box::new([integer(1)]),
+            source_map: None,
+        };
+        spell.pack_locals(2);
+        assert_eq!(spell.local_variables, 2);
+        assert!(matches!(spell.instructions[1],
+                         Instruction::Return{result: Local(0)}));
+
+        // Spells that are not well-formed are left alone.
+        let mut spell = Spell{
+            instructions: Box::new([
+                Instruction::Return{result: Local(3)},
+            ]),
+            local_variables: 1,
+            constants: Box::new([]),
+            source_map: None,
+        };
+        spell.pack_locals(0);
+        assert_eq!(spell.local_variables, 1);
+        assert!(matches!(spell.instructions[0],
+                         Instruction::Return{result: Local(3)}));
+    }
+}
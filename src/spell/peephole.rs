@@ -0,0 +1,239 @@
+use super::*;
+
+use spell::pack::accesses;
+
+impl Spell {
+    /// Remove copies, swaps, and no-ops that have no effect, and return how
+    /// many instructions were removed. Jumps are adjusted to match, as by
+    /// [eliminate_dead_code].
+    ///
+    /// These instructions are removed:
+    ///
+    ///  - every no-op, and every copy or swap of a local variable with itself;
+    ///  - a copy that undoes the copy right before it, as in `b = copy a`
+    ///    followed by `a = copy b`;
+    ///  - two swaps in a row of the same local variables, which cancel out;
+    ///  - a copy into a local variable that is written again before it is
+    ///    read, with only instructions in between that cannot jump, invoke,
+    ///    or throw.
+    ///
+    /// Instructions are only ever considered together within a basic block, so
+    /// nothing is removed from code that is jumped into between them.
+    /// Interpreting an instruction in between can still fail, after which the
+    /// copy is not missed, as nothing catches the failure. Spells that are not
+    /// well-formed are left unchanged; see [verify].
+    ///
+    /// [eliminate_dead_code]: #method.eliminate_dead_code
+    /// [verify]: fn.verify.html
+    pub fn peephole(&mut self) -> usize {
+        if verify(self).is_err() {
+            return 0;
+        }
+
+        let cfg = Cfg::build(self);
+        let mut keep = vec![true; self.instructions.len()];
+
+        // The last instruction that is kept in the current basic block.
+        let mut previous: Option<usize> = None;
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            if cfg.starts_block(index) {
+                previous = None;
+            }
+            let previous_instruction = previous.map(|p| &self.instructions[p]);
+
+            match (previous_instruction, instruction) {
+                (_, Instruction::Nop) => keep[index] = false,
+                (_, Instruction::Copy{from, to}) if from == to =>
+                    keep[index] = false,
+                (_, Instruction::Swap{a, b}) if a == b =>
+                    keep[index] = false,
+                (Some(Instruction::Copy{from, to}),
+                 Instruction::Copy{from: back_from, to: back_to})
+                    if from == back_to && to == back_from =>
+                    keep[index] = false,
+                (Some(Instruction::Swap{a, b}),
+                 Instruction::Swap{a: other_a, b: other_b})
+                    if (a, b) == (other_a, other_b) ||
+                       (a, b) == (other_b, other_a) => {
+                    keep[previous.unwrap()] = false;
+                    keep[index] = false;
+                },
+                (_, Instruction::Copy{to, ..})
+                    if self.overwritten(index + 1, *to, &cfg) =>
+                    keep[index] = false,
+                _ => (),
+            }
+
+            if keep[index] {
+                previous = Some(index);
+            } else if !keep[previous.unwrap_or(index)] {
+                previous = None;
+            }
+        }
+
+        let removed = keep.iter().filter(|&&keep| !keep).count();
+        if removed > 0 {
+            self.remove_instructions(&keep);
+        }
+        removed
+    }
+
+    /// Whether a local variable is written, starting at the given
+    /// instruction, before it is read, before the basic block ends, and
+    /// before an instruction that might let anything else read it.
+    fn overwritten(&self, start: usize, local: Local, cfg: &Cfg)
+        -> bool {
+        let instructions = self.instructions.iter().enumerate().skip(start);
+        for (index, instruction) in instructions {
+            if cfg.starts_block(index) {
+                return false;
+            }
+            let (reads, writes) = accesses(instruction);
+            if reads.contains(&local) {
+                return false;
+            }
+            if writes.contains(&local) {
+                return true;
+            }
+            if !is_local(instruction) {
+                return false;
+            }
+        }
+        false
+    }
+}
+
+/// Whether an instruction always continues with the next one, unless
+/// interpreting it fails.
+fn is_local(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::Copy{..}          |
+        Instruction::CopyRange{..}     |
+        Instruction::Swap{..}          |
+        Instruction::Nop               |
+        Instruction::MakeClosure{..}   |
+        Instruction::Add{..}           |
+        Instruction::Sub{..}           |
+        Instruction::Mul{..}           |
+        Instruction::Div{..}           |
+        Instruction::Equal{..}         |
+        Instruction::LessThan{..}      |
+        Instruction::GreaterThan{..}   |
+        Instruction::Allocate{..}      |
+        Instruction::LoadConstant{..}  |
+        Instruction::GetPointer{..}    |
+        Instruction::EnchantmentOf{..} |
+        Instruction::IsEnchanted{..}   |
+        Instruction::AuxiliaryLen{..}  => true,
+        Instruction::InvokeStatic{..}           |
+        Instruction::InvokeDynamic{..}          |
+        Instruction::InvokeDynamicCached{..}    |
+        Instruction::InvokeClosure{..}          |
+        Instruction::InvokeLinked{..}           |
+        Instruction::Jump{..}                   |
+        Instruction::JumpRelative{..}           |
+        Instruction::Switch{..}                 |
+        Instruction::BranchIfTruthy{..}         |
+        Instruction::BranchIfFalsy{..}          |
+        Instruction::BranchIfTruthyRelative{..} |
+        Instruction::BranchIfFalsyRelative{..}  |
+        Instruction::PushHandler{..}            |
+        Instruction::PopHandler                 |
+        Instruction::Throw{..}                  |
+        Instruction::Return{..}                 => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use spell::soundness::assemble_main;
+    use spell::soundness::check_pass;
+
+    /// Assemble a spell book::main, apply the peephole optimizer, check that
+    /// it gives the same result as before for the given arguments, and return
+    /// the number of instructions removed and the spell.
+    fn run_optimized(source: &str, arguments: &[i64]) -> (usize, Spell) {
+        let (spells, id, types) = assemble_main(source, arguments.len());
+        let mut removed = 0;
+        let (_, spell) = check_pass(spells, id, types, &[arguments],
+                                    |spell| removed = spell.peephole());
+        (removed, spell)
+    }
+
+    #[test]
+    fn test_peephole() {
+        let (removed, spell) = run_optimized("
+            spell book::main/2
+                v2 = copy v0
+                nop
+                v0 = copy v2
+                swap v0, v1
+                swap v1, v0
+                v1 = copy v1
+                v3 = copy v0
+                v4 = add v0, v1
+                v3 = sub v4, v1
+                v3 = mul v3, v2
+                return v3
+        ", &[3, 5]);
+        assert_eq!(removed, 6);
+        assert!(matches!(spell.instructions[0],
+                         Instruction::Copy{from: Local(0), to: Local(2)}));
+        assert!(matches!(spell.instructions[1], Instruction::Add{..}));
+        assert_eq!(spell.instructions.len(), 5);
+    }
+
+    #[test]
+    fn test_peephole_basic_blocks() {
+        // The copy back is jumped to, the first copy into v3 is followed by a
+        // branch, and the second one is read by the invocation.
+        let (removed, spell) = run_optimized("
+            spell book::main/2
+                v2 = copy v0
+            back:
+                v0 = copy v2
+                v3 = copy v2
+                branch_if_truthy v0, done
+                v2 = copy v0
+                jump_relative -4
+            done:
+                v3 = copy v1
+                v3 = invoke_static book::id(v3)
+                v3 = copy v1
+                nop
+                return v3
+
+            spell book::id/1
+                return v0
+        ", &[3, 5]);
+        assert_eq!(removed, 1);
+        assert_eq!(spell.instructions.len(), 10);
+        assert!(matches!(spell.instructions[5],
+                         Instruction::JumpRelative{offset: -4}));
+        assert!(matches!(spell.instructions[9],
+                         Instruction::Return{result: Local(3)}));
+    }
+
+    #[test]
+    fn test_peephole_copies() {
+        // Copies read their sources, so the first copy is not overwritten
+        // before it is read. The copy into v4 is overwritten by the range
+        // copy, which reads v2 and v3 first.
+        let (removed, spell) = run_optimized("
+            spell book::main/2
+                v2 = copy v0
+                v3 = copy v2
+                v2 = add v1, v1
+                v4 = copy v1
+                v4 = copy_range v2, 2
+                v3 = add v4, v5
+                return v3
+        ", &[3, 5]);
+        assert_eq!(removed, 1);
+        assert!(matches!(spell.instructions[3],
+                         Instruction::CopyRange{from: Local(2), to: Local(4),
+                                                count: 2}));
+    }
+}
@@ -0,0 +1,190 @@
+use super::*;
+
+impl Spell {
+    /// Remove the instructions that can never be interpreted, and adjust the
+    /// targets and offsets of the remaining jumps to match.
+    ///
+    /// An instruction can be interpreted if it is the first one, or if an
+    /// instruction that can be interpreted falls through to it or may jump to
+    /// it, including through a switch or an exception handler. The source map
+    /// loses the spans of the removed instructions, and the constant pool is
+    /// left alone. Spells without unreachable instructions are left unchanged,
+    /// and so are spells that are not well-formed; see [verify].
+    ///
+    /// [verify]: fn.verify.html
+    pub fn eliminate_dead_code(&mut self) {
+        if verify(self).is_err() {
+            return;
+        }
+
+        let reachable = self.reachable();
+        if reachable.iter().all(|&reachable| reachable) {
+            return;
+        }
+        self.remove_instructions(&reachable);
+    }
+
+    /// Remove the instructions that are not to be kept, along with their
+    /// source spans, and adjust the targets and offsets of the remaining
+    /// jumps to match. A jump to an instruction that is removed goes to the
+    /// next instruction that is kept instead, of which there must be one.
+    pub(super) fn remove_instructions(&mut self, keep: &[bool]) {
+        // The new index of every instruction that is kept, and of the next
+        // kept instruction for every instruction that is removed.
+        let mut new_indices = Vec::with_capacity(keep.len());
+        let mut kept = 0;
+        for &keep in keep {
+            new_indices.push(kept);
+            kept += keep as usize;
+        }
+
+        let instructions = mem::take(&mut self.instructions).into_vec();
+        self.instructions = instructions.into_iter().enumerate()
+            .filter(|&(index, _)| keep[index])
+            .map(|(index, mut instruction)| {
+                retarget(&mut instruction, index, &new_indices);
+                instruction
+            })
+            .collect();
+        if let Some(source_map) = &mut self.source_map {
+            *source_map = source_map.iter().enumerate()
+                .filter(|&(index, _)| keep[index])
+                .map(|(_, &span)| span)
+                .collect();
+        }
+    }
+
+    /// Whether each instruction can be interpreted. The spell must be
+    /// well-formed.
+    fn reachable(&self) -> Vec<bool> {
+        let cfg = Cfg::build(self);
+        let mut reachable = vec![false; self.instructions.len()];
+        for (block, _) in cfg.iter().zip(cfg.reachable())
+            .filter(|&(_, reachable)| reachable) {
+            for index in block.instructions.clone() {
+                reachable[index] = true;
+            }
+        }
+        reachable
+    }
+}
+
+/// Map the targets and offsets of the instruction at the given index to the
+/// new indices of the instructions.
+fn retarget(instruction: &mut Instruction,
+            index:       usize,
+            new_indices: &[usize]) {
+    let new_index = new_indices[index] as isize;
+    match instruction {
+        Instruction::Jump{target}                |
+        Instruction::BranchIfTruthy{target, ..}  |
+        Instruction::BranchIfFalsy{target, ..}   |
+        Instruction::PushHandler{target, ..}     =>
+            *target = new_indices[*target],
+        Instruction::Switch{targets, default, ..} => {
+            for target in targets.iter_mut() {
+                *target = new_indices[*target];
+            }
+            *default = new_indices[*default];
+        },
+        Instruction::JumpRelative{offset}               |
+        Instruction::BranchIfTruthyRelative{offset, ..} |
+        Instruction::BranchIfFalsyRelative{offset, ..}  => {
+            let target = (index as isize + *offset) as usize;
+            *offset = new_indices[target] as isize - new_index;
+        },
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::slice;
+
+    use spell::soundness::assemble_main;
+    use spell::soundness::check_pass;
+
+    /// Assemble a spell book::main/1, eliminate its dead code, check that it
+    /// gives the same results as before for the given arguments, and return
+    /// the spell.
+    fn run_pruned(source: &str, arguments: &[i64]) -> Spell {
+        let (spells, id, types) = assemble_main(source, 1);
+        let runs: Vec<&[i64]> = arguments.iter().map(slice::from_ref)
+            .collect();
+        check_pass(spells, id, types, &runs,
+                   |spell| spell.eliminate_dead_code()).1
+    }
+
+    #[test]
+    fn test_eliminate_dead_code() {
+        // The code after the first return is only kept alive by the branch
+        // to odd, and the code after each return is otherwise dead.
+        let spell = run_pruned("
+            spell book::main/1
+                constant integer [02 00 00 00 00 00 00 00]
+                v1 = load_constant 0
+                v2 = less_than v0, v1
+                branch_if_falsy v2, odd
+                return v1
+                v0 = add v0, v0
+                jump_relative -1
+            odd:
+                v0 = sub v0, v1
+                jump_relative +2
+                nop
+                return v0
+                switch v0, [odd], odd
+        ", &[0, 5]);
+        assert_eq!(spell.instructions.len(), 7);
+        assert!(matches!(spell.instructions[2],
+                         Instruction::BranchIfFalsy{condition: Local(2),
+                                                    target: 4}));
+        assert!(matches!(spell.instructions[3],
+                         Instruction::Return{result: Local(1)}));
+        assert!(matches!(spell.instructions[5],
+                         Instruction::JumpRelative{offset: 1}));
+        assert!(matches!(spell.instructions[6],
+                         Instruction::Return{result: Local(0)}));
+    }
+
+    #[test]
+    fn test_eliminate_dead_code_tight() {
+        let source = "
+            spell book::main/1
+                constant integer [01 00 00 00 00 00 00 00]
+                v1 = load_constant 0
+            loop:
+                v2 = less_than v0, v1
+                branch_if_truthy v2, done
+                push_handler done, v3
+                v0 = sub v0, v1
+                pop_handler
+                jump loop
+            done:
+                return v0
+        ";
+        let spell = run_pruned(source, &[0, 3]);
+        assert_eq!(spell.instructions.len(), 8);
+        assert!(matches!(spell.instructions[6], Instruction::Jump{target: 1}));
+    }
+
+    #[test]
+    fn test_eliminate_dead_code_source_map() {
+        let span = |line| SourceSpan{file: Sigil(0), line, column: 1};
+        let mut spell = Spell{
+            instructions:    Box::new([
+                Instruction::Jump{target: 2},
+                Instruction::Nop,
+                Instruction::Return{result: Local(0)},
+            ]),
+            local_variables: 1,
+            constants:       Box::new([]),
+            source_map:      Some(Box::new([span(1), span(2), span(3)])),
+        };
+        spell.eliminate_dead_code();
+        assert!(matches!(spell.instructions[0], Instruction::Jump{target: 1}));
+        assert_eq!(spell.source_map.as_deref(), Some(&[span(1), span(3)][..]));
+    }
+}
@@ -0,0 +1,285 @@
+use super::*;
+
+use std::collections::HashSet;
+
+use sigil::Sigils;
+
+/// What to do with sigils that a remapping does not map. See
+/// [Spell::remap_sigils].
+///
+/// [Spell::remap_sigils]: struct.Spell.html#method.remap_sigils
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Unmapped {
+    /// Leave unmapped sigils as they are.
+    Keep,
+
+    /// Fail with [RemapError::Unmapped].
+    ///
+    /// [RemapError::Unmapped]: enum.RemapError.html#variant.Unmapped
+    Fail,
+}
+
+impl Spell {
+    /// Rewrite every sigil the spell refers to through a map from old sigils
+    /// to new sigils, such as the one returned by [Sigils::merge]. This
+    /// includes the sigils in instructions, in the constant pool, and in the
+    /// source map.
+    ///
+    /// If a sigil is not in the map and unmapped sigils are to fail, the spell
+    /// is left unchanged.
+    ///
+    /// [Sigils::merge]: ../sigil/struct.Sigils.html#method.merge
+    pub fn remap_sigils(&mut self,
+                        map:      &HashMap<Sigil, Sigil>,
+                        unmapped: Unmapped,
+                        ) -> Result<(), RemapError> {
+        if unmapped == Unmapped::Fail {
+            if let Some(sigil) = self.unmapped_sigil(map) {
+                return Err(RemapError::Unmapped(sigil));
+            }
+        }
+
+        self.for_each_sigil_mut(|sigil| {
+            if let Some(&new) = map.get(sigil) {
+                *sigil = new;
+            }
+        });
+        Ok(())
+    }
+
+    /// Check that the sigil database defines every sigil the spell refers to,
+    /// as by [Sigils::validate]. This includes the sigils in instructions, in
+    /// the constant pool, and in the source map.
+    ///
+    /// Fails with the raw ids of the sigils that are not defined, each once,
+    /// in the order in which the spell first refers to them.
+    ///
+    /// [Sigils::validate]: ../sigil/struct.Sigils.html#method.validate
+    pub fn validate_sigils(&self, sigils: &Sigils) -> Result<(), Vec<u32>> {
+        let mut undefined = Vec::new();
+        self.for_each_sigil(|Sigil(raw)| {
+            if sigils.validate(raw).is_none() && !undefined.contains(&raw) {
+                undefined.push(raw);
+            }
+        });
+        if undefined.is_empty() { Ok(()) } else { Err(undefined) }
+    }
+
+    /// The first sigil the spell refers to that is not in the map, if any.
+    fn unmapped_sigil(&self, map: &HashMap<Sigil, Sigil>) -> Option<Sigil> {
+        let mut missing = None;
+        self.for_each_sigil(|sigil| {
+            if !map.contains_key(&sigil) {
+                missing = missing.or(Some(sigil));
+            }
+        });
+        missing
+    }
+
+    /// Call a function for every sigil the spell refers to.
+    fn for_each_sigil<F>(&self, mut f: F)
+        where F: FnMut(Sigil) {
+        for instruction in self.instructions.iter() {
+            instruction.for_each_sigil(&mut f);
+        }
+        for constant in self.constants.iter() {
+            f(constant.enchantment);
+        }
+        for span in self.source_map.iter().flat_map(|map| map.iter()) {
+            f(span.file);
+        }
+    }
+
+    /// Call a function for every sigil the spell refers to, allowing it to
+    /// change the sigil. The sigils are visited in the same order as by
+    /// for_each_sigil.
+    fn for_each_sigil_mut<F>(&mut self, mut f: F)
+        where F: FnMut(&mut Sigil) {
+        for instruction in self.instructions.iter_mut() {
+            instruction.for_each_sigil_mut(&mut f);
+        }
+        for constant in self.constants.iter_mut() {
+            f(&mut constant.enchantment);
+        }
+        for span in self.source_map.iter_mut().flat_map(|map| map.iter_mut()) {
+            f(&mut span.file);
+        }
+    }
+}
+
+impl Spells {
+    /// Rewrite every sigil in the spell database through a map, as with
+    /// [Spell::remap_sigils]. The ids of both bytecode and native spells are
+    /// rewritten too.
+    ///
+    /// Fails with [RemapError::Redefinition] if two spells end up with the
+    /// same id, which can happen if the map is not injective. When this
+    /// method fails, the database is left unchanged.
+    ///
+    /// [Spell::remap_sigils]: struct.Spell.html#method.remap_sigils
+    /// [RemapError::Redefinition]: enum.RemapError.html#variant.Redefinition
+    pub fn remap_sigils(&mut self,
+                        map:      &HashMap<Sigil, Sigil>,
+                        unmapped: Unmapped,
+                        ) -> Result<(), RemapError> {
+        let remap = |sigil: Sigil| match map.get(&sigil) {
+            Some(&new) => Ok(new),
+            None if unmapped == Unmapped::Fail =>
+                Err(RemapError::Unmapped(sigil)),
+            None => Ok(sigil),
+        };
+        let remap_id = |id: SpellId| -> Result<SpellId, RemapError> {
+            Ok(SpellId{spellbook: remap(id.spellbook)?,
+                       spell:     remap(id.spell)?,
+                       arity:     id.arity})
+        };
+
+        // Check everything before changing anything.
+        let mut ids = HashSet::new();
+        for &id in self.indices.keys().chain(self.natives.keys()) {
+            let new = remap_id(id)?;
+            if !ids.insert(new) {
+                return Err(RemapError::Redefinition(new));
+            }
+        }
+        if unmapped == Unmapped::Fail {
+            for (_, spell) in self.spells.iter() {
+                if let Some(sigil) = spell.unmapped_sigil(map) {
+                    return Err(RemapError::Unmapped(sigil));
+                }
+            }
+        }
+
+        // None of these can fail, as every sigil was checked above. The spells
+        // keep their indices, so linked invocations remain linked.
+        for (id, spell) in self.spells.iter_mut() {
+            let _ = spell.remap_sigils(map, Unmapped::Keep);
+            *id = remap_id(*id).unwrap();
+        }
+        self.indices = self.spells.iter().enumerate()
+            .map(|(index, (id, _))| (*id, index))
+            .collect();
+        self.natives = self.natives.drain()
+            .map(|(id, native)| (remap_id(id).unwrap(), native))
+            .collect();
+        Ok(())
+    }
+}
+
+/// This error is returned when remapping sigils fails.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RemapError {
+    /// A sigil is not in the map, and unmapped sigils are to fail.
+    Unmapped(Sigil),
+
+    /// Two spells would have the same id after remapping.
+    Redefinition(SpellId),
+}
+
+impl fmt::Display for RemapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemapError::Unmapped(sigil) =>
+                write!(f, "sigil {:?} is not mapped", sigil),
+            RemapError::Redefinition(id) =>
+                write!(f, "spell {} would be defined more than once", id),
+        }
+    }
+}
+
+impl Error for RemapError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(spellbook: u32, spell: u32) -> SpellId {
+        SpellId{spellbook: Sigil(spellbook), spell: Sigil(spell), arity: 0}
+    }
+
+    fn spell() -> Spell {
+        Spell{
+            instructions: Box::new([
+                Instruction::InvokeStatic{result: Local(0),
+                                          spellbook: Sigil(0),
+                                          spell: Sigil(1),
+                                          arguments: Box::new([])},
+                Instruction::Allocate{result: Local(0),
+                                      enchantment: Sigil(2),
+                                      pointers: Box::new([]),
+                                      auxiliary: Box::new([])},
+                Instruction::Return{result: Local(0)},
+            ]),
+            local_variables: 1,
+            constants: Box::new([
+                ConstantValue{enchantment: Sigil(1), auxiliary: Box::new([])},
+            ]),
+            source_map: None,
+        }
+    }
+
+    #[test]
+    fn test_remap_spell() {
+        let map: HashMap<Sigil, Sigil> =
+            vec![(Sigil(0), Sigil(10)), (Sigil(1), Sigil(11))]
+                .into_iter().collect();
+
+        let mut spell = spell();
+        assert_eq!(spell.remap_sigils(&map, Unmapped::Fail),
+                   Err(RemapError::Unmapped(Sigil(2))));
+        assert!(matches!(spell.instructions[0],
+                         Instruction::InvokeStatic{spellbook: Sigil(0), ..}));
+
+        spell.remap_sigils(&map, Unmapped::Keep).unwrap();
+        assert!(matches!(spell.instructions[0],
+                         Instruction::InvokeStatic{spellbook: Sigil(10),
+                                                   spell: Sigil(11), ..}));
+        assert!(matches!(spell.instructions[1],
+                         Instruction::Allocate{enchantment: Sigil(2), ..}));
+        assert_eq!(spell.constants[0].enchantment, Sigil(11));
+    }
+
+    #[test]
+    fn test_validate_sigils() {
+        let mut sigils = Sigils::new();
+        let mut spell = spell();
+        assert_eq!(spell.validate_sigils(&sigils), Err(vec![0, 1, 2]));
+
+        // Once the literal sigils in the spell are mapped to interned sigils,
+        // the database defines all of them.
+        let map: HashMap<Sigil, Sigil> = ["book", "spell", "enchantment"]
+            .iter().enumerate()
+            .map(|(raw, name)| (Sigil(raw as u32), sigils.intern_str(name)))
+            .collect();
+        spell.remap_sigils(&map, Unmapped::Fail).unwrap();
+        assert_eq!(spell.validate_sigils(&sigils), Ok(()));
+
+        let span = SourceSpan{file: Sigil(u32::MAX - 1), line: 1, column: 1};
+        spell.source_map = Some(vec![span; 3].into_boxed_slice());
+        spell.constants[0].enchantment = Sigil(u32::MAX);
+        assert_eq!(spell.validate_sigils(&sigils),
+                   Err(vec![u32::MAX, u32::MAX - 1]));
+    }
+
+    #[test]
+    fn test_remap_spells() {
+        let mut spells = Spells::new();
+        spells.insert(id(0, 1), spell()).unwrap();
+        spells.insert_native(id(0, 2), |_, _| Datum::from_i64(0).unwrap())
+            .unwrap();
+
+        let map: HashMap<Sigil, Sigil> =
+            (0 .. 3).map(|sigil| (Sigil(sigil), Sigil(sigil + 10))).collect();
+        spells.remap_sigils(&map, Unmapped::Fail).unwrap();
+        assert!(spells.get(id(0, 1)).is_none());
+        assert!(spells.get(id(10, 11)).is_some());
+        assert!(spells.get_native(id(10, 12)).is_some());
+
+        // Mapping both spells to the same id fails without changing anything.
+        let collapse: HashMap<Sigil, Sigil> =
+            (10 .. 13).map(|sigil| (Sigil(sigil), Sigil(0))).collect();
+        assert_eq!(spells.remap_sigils(&collapse, Unmapped::Keep),
+                   Err(RemapError::Redefinition(id(0, 0))));
+        assert!(spells.get(id(10, 11)).is_some());
+    }
+}
@@ -0,0 +1,889 @@
+use super::*;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use sigil::Sigils;
+
+/// The bytes every serialized spell database starts with.
+const MAGIC: &[u8; 4] = b"MANA";
+
+/// The version of the encoding written by [Spells::serialize]. Increment this
+/// whenever the encoding changes.
+///
+/// [Spells::serialize]: struct.Spells.html#method.serialize
+const VERSION: u32 = 3;
+
+// Opcodes of the instructions. These are part of the encoding and must never
+// be reassigned.
+const OP_COPY:             u8 = 0;
+const OP_INVOKE_STATIC:    u8 = 1;
+const OP_INVOKE_DYNAMIC:   u8 = 2;
+const OP_JUMP:             u8 = 3;
+const OP_BRANCH_IF_TRUTHY: u8 = 4;
+const OP_BRANCH_IF_FALSY:  u8 = 5;
+const OP_ADD:              u8 = 6;
+const OP_SUB:              u8 = 7;
+const OP_MUL:              u8 = 8;
+const OP_DIV:              u8 = 9;
+const OP_RETURN:           u8 = 10;
+const OP_ALLOCATE:         u8 = 11;
+const OP_GET_POINTER:      u8 = 12;
+const OP_ENCHANTMENT_OF:   u8 = 13;
+const OP_AUXILIARY_LEN:    u8 = 14;
+const OP_PUSH_HANDLER:     u8 = 15;
+const OP_POP_HANDLER:      u8 = 16;
+const OP_THROW:            u8 = 17;
+const OP_SWAP:             u8 = 18;
+const OP_NOP:              u8 = 19;
+const OP_EQUAL:            u8 = 20;
+const OP_LESS_THAN:        u8 = 21;
+const OP_GREATER_THAN:     u8 = 22;
+const OP_SWITCH:           u8 = 23;
+const OP_JUMP_RELATIVE:    u8 = 24;
+const OP_BRANCH_IF_TRUTHY_RELATIVE: u8 = 25;
+const OP_BRANCH_IF_FALSY_RELATIVE:  u8 = 26;
+const OP_LOAD_CONSTANT:    u8 = 27;
+const OP_MAKE_CLOSURE:     u8 = 28;
+const OP_INVOKE_CLOSURE:   u8 = 29;
+const OP_COPY_RANGE:       u8 = 30;
+const OP_IS_ENCHANTED:     u8 = 31;
+
+impl Spells {
+    /// Write the spell database to a byte stream.
+    ///
+    /// The encoding starts with a magic number and a version, followed by the
+    /// spells, ordered by id as by [iter_sorted], so that the same spells
+    /// are always written the same way. Sigils are written by name, so that
+    /// the spells can be loaded into a process with a different sigil
+    /// database. All integers are little-endian. Native spells cannot be
+    /// written, and are left out. Linked invocations are written as static
+    /// invocations.
+    ///
+    /// Fails with [io::ErrorKind::InvalidInput] if a spell refers to a sigil
+    /// that is not in the sigil database.
+    ///
+    /// [iter_sorted]: struct.Spells.html#method.iter_sorted
+    /// [io::ErrorKind::InvalidInput]:
+    ///     https://doc.rust-lang.org/std/io/enum.ErrorKind.html
+    pub fn serialize(&self, sigils: &Sigils, out: &mut impl Write)
+        -> io::Result<()>
+    {
+        out.write_all(MAGIC)?;
+        write_u32(out, VERSION)?;
+
+        write_usize(out, self.spells.len())?;
+        for (id, spell) in self.iter_sorted() {
+            write_sigil(out, sigils, id.spellbook)?;
+            write_sigil(out, sigils, id.spell)?;
+            write_usize(out, id.arity)?;
+            write_usize(out, spell.local_variables)?;
+
+            write_usize(out, spell.constants.len())?;
+            for constant in spell.constants.iter() {
+                write_sigil(out, sigils, constant.enchantment)?;
+                write_bytes(out, &constant.auxiliary)?;
+            }
+
+            match &spell.source_map {
+                None => out.write_all(&[0])?,
+                Some(source_map) => {
+                    out.write_all(&[1])?;
+                    write_usize(out, source_map.len())?;
+                    for span in source_map.iter() {
+                        write_sigil(out, sigils, span.file)?;
+                        write_u32(out, span.line)?;
+                        write_u32(out, span.column)?;
+                    }
+                },
+            }
+
+            write_usize(out, spell.instructions.len())?;
+            for instruction in spell.instructions.iter() {
+                write_instruction(out, sigils, instruction)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a spell database written by [serialize].
+    ///
+    /// Sigils are interned into the given sigil database by name. The spells
+    /// are not verified.
+    ///
+    /// [serialize]: #method.serialize
+    pub fn deserialize(input: &mut impl Read, sigils: &mut Sigils)
+        -> Result<Spells, DeserializeError>
+    {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(DeserializeError::BadMagic);
+        }
+
+        let version = read_u32(input)?;
+        if version != VERSION {
+            return Err(DeserializeError::UnsupportedVersion(version));
+        }
+
+        let mut spells = Spells::new();
+        for _ in 0 .. read_usize(input)? {
+            let spellbook = read_sigil(input, sigils)?;
+            let spell = read_sigil(input, sigils)?;
+            let arity = read_usize(input)?;
+            let local_variables = read_usize(input)?;
+
+            let mut constants = Vec::new();
+            for _ in 0 .. read_usize(input)? {
+                let enchantment = read_sigil(input, sigils)?;
+                let auxiliary = read_bytes(input)?.into_boxed_slice();
+                constants.push(ConstantValue{enchantment, auxiliary});
+            }
+
+            let mut has_source_map = [0];
+            input.read_exact(&mut has_source_map)?;
+            let source_map = match has_source_map[0] {
+                0 => None,
+                _ => {
+                    let mut source_map = Vec::new();
+                    for _ in 0 .. read_usize(input)? {
+                        let file = read_sigil(input, sigils)?;
+                        let line = read_u32(input)?;
+                        let column = read_u32(input)?;
+                        source_map.push(SourceSpan{file, line, column});
+                    }
+                    Some(source_map.into_boxed_slice())
+                },
+            };
+
+            let mut instructions = Vec::new();
+            for _ in 0 .. read_usize(input)? {
+                instructions.push(read_instruction(input, sigils)?);
+            }
+
+            let id = SpellId{spellbook, spell, arity};
+            let spell = Spell{instructions: instructions.into_boxed_slice(),
+                              local_variables,
+                              constants: constants.into_boxed_slice(),
+                              source_map};
+            spells.insert(id, spell)
+                .map_err(|_| DeserializeError::Redefinition(id))?;
+        }
+
+        Ok(spells)
+    }
+}
+
+/// This error is returned when reading a spell database fails.
+#[derive(Debug)]
+pub enum DeserializeError {
+    /// Reading from the byte stream failed, or it ended prematurely.
+    Io(io::Error),
+
+    /// The byte stream does not start with the magic number.
+    BadMagic,
+
+    /// The byte stream was written using an unsupported version of the
+    /// encoding.
+    UnsupportedVersion(u32),
+
+    /// An instruction has an opcode that does not exist.
+    UnknownOpcode(u8),
+
+    /// A number does not fit in the integer type it is read into.
+    Overflow,
+
+    /// The same spell occurs more than once.
+    Redefinition(SpellId),
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeserializeError::Io(error) =>
+                write!(f, "{}", error),
+            DeserializeError::BadMagic =>
+                write!(f, "not a spell database"),
+            DeserializeError::UnsupportedVersion(version) =>
+                write!(f, "unsupported version {}", version),
+            DeserializeError::UnknownOpcode(opcode) =>
+                write!(f, "unknown opcode {}", opcode),
+            DeserializeError::Overflow =>
+                write!(f, "number out of range"),
+            DeserializeError::Redefinition(id) =>
+                write!(f, "spell {} is defined more than once", id),
+        }
+    }
+}
+
+impl Error for DeserializeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DeserializeError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DeserializeError {
+    fn from(error: io::Error) -> Self {
+        DeserializeError::Io(error)
+    }
+}
+
+fn write_instruction(out:         &mut impl Write,
+                     sigils:      &Sigils,
+                     instruction: &Instruction,
+                     ) -> io::Result<()> {
+    match instruction {
+        Instruction::Copy{from, to} => {
+            out.write_all(&[OP_COPY])?;
+            write_local(out, *from)?;
+            write_local(out, *to)?;
+        },
+        Instruction::CopyRange{from, to, count} => {
+            out.write_all(&[OP_COPY_RANGE])?;
+            write_local(out, *from)?;
+            write_local(out, *to)?;
+            write_usize(out, *count)?;
+        },
+        Instruction::Swap{a, b} => {
+            out.write_all(&[OP_SWAP])?;
+            write_local(out, *a)?;
+            write_local(out, *b)?;
+        },
+        Instruction::Nop => {
+            out.write_all(&[OP_NOP])?;
+        },
+        // The index of a linked invocation is meaningless in another spell
+        // database, so linked invocations are written as static ones.
+        Instruction::InvokeStatic{result, spellbook, spell, arguments} |
+        Instruction::InvokeLinked{result, spellbook, spell, arguments, ..} => {
+            out.write_all(&[OP_INVOKE_STATIC])?;
+            write_local(out, *result)?;
+            write_sigil(out, sigils, *spellbook)?;
+            write_sigil(out, sigils, *spell)?;
+            write_locals(out, arguments)?;
+        },
+        // Likewise, inline caches are not written.
+        Instruction::InvokeDynamic{result, spell, receiver, arguments} |
+        Instruction::InvokeDynamicCached{result, spell, receiver, arguments,
+                                         ..} => {
+            out.write_all(&[OP_INVOKE_DYNAMIC])?;
+            write_local(out, *result)?;
+            write_sigil(out, sigils, *spell)?;
+            write_local(out, *receiver)?;
+            write_locals(out, arguments)?;
+        },
+        Instruction::MakeClosure{result, spellbook, spell, captures} => {
+            out.write_all(&[OP_MAKE_CLOSURE])?;
+            write_local(out, *result)?;
+            write_sigil(out, sigils, *spellbook)?;
+            write_sigil(out, sigils, *spell)?;
+            write_locals(out, captures)?;
+        },
+        Instruction::InvokeClosure{result, closure, arguments} => {
+            out.write_all(&[OP_INVOKE_CLOSURE])?;
+            write_local(out, *result)?;
+            write_local(out, *closure)?;
+            write_locals(out, arguments)?;
+        },
+        Instruction::Jump{target} => {
+            out.write_all(&[OP_JUMP])?;
+            write_usize(out, *target)?;
+        },
+        Instruction::JumpRelative{offset} => {
+            out.write_all(&[OP_JUMP_RELATIVE])?;
+            write_isize(out, *offset)?;
+        },
+        Instruction::BranchIfTruthyRelative{condition, offset} => {
+            out.write_all(&[OP_BRANCH_IF_TRUTHY_RELATIVE])?;
+            write_local(out, *condition)?;
+            write_isize(out, *offset)?;
+        },
+        Instruction::BranchIfFalsyRelative{condition, offset} => {
+            out.write_all(&[OP_BRANCH_IF_FALSY_RELATIVE])?;
+            write_local(out, *condition)?;
+            write_isize(out, *offset)?;
+        },
+        Instruction::Switch{scrutinee, targets, default} => {
+            out.write_all(&[OP_SWITCH])?;
+            write_local(out, *scrutinee)?;
+            write_usize(out, targets.len())?;
+            for &target in targets.iter() {
+                write_usize(out, target)?;
+            }
+            write_usize(out, *default)?;
+        },
+        Instruction::BranchIfTruthy{condition, target} => {
+            out.write_all(&[OP_BRANCH_IF_TRUTHY])?;
+            write_local(out, *condition)?;
+            write_usize(out, *target)?;
+        },
+        Instruction::BranchIfFalsy{condition, target} => {
+            out.write_all(&[OP_BRANCH_IF_FALSY])?;
+            write_local(out, *condition)?;
+            write_usize(out, *target)?;
+        },
+        Instruction::Add{result, lhs, rhs} |
+        Instruction::Sub{result, lhs, rhs} |
+        Instruction::Mul{result, lhs, rhs} |
+        Instruction::Div{result, lhs, rhs} |
+        Instruction::Equal{result, lhs, rhs} |
+        Instruction::LessThan{result, lhs, rhs} |
+        Instruction::GreaterThan{result, lhs, rhs} => {
+            let opcode = match instruction {
+                Instruction::Add{..}      => OP_ADD,
+                Instruction::Sub{..}      => OP_SUB,
+                Instruction::Mul{..}      => OP_MUL,
+                Instruction::Div{..}      => OP_DIV,
+                Instruction::Equal{..}    => OP_EQUAL,
+                Instruction::LessThan{..} => OP_LESS_THAN,
+                _                         => OP_GREATER_THAN,
+            };
+            out.write_all(&[opcode])?;
+            write_local(out, *result)?;
+            write_local(out, *lhs)?;
+            write_local(out, *rhs)?;
+        },
+        Instruction::Allocate{result, enchantment, pointers, auxiliary} => {
+            out.write_all(&[OP_ALLOCATE])?;
+            write_local(out, *result)?;
+            write_sigil(out, sigils, *enchantment)?;
+            write_locals(out, pointers)?;
+            write_bytes(out, auxiliary)?;
+        },
+        Instruction::IsEnchanted{result, object, enchantment} => {
+            out.write_all(&[OP_IS_ENCHANTED])?;
+            write_local(out, *result)?;
+            write_local(out, *object)?;
+            write_sigil(out, sigils, *enchantment)?;
+        },
+        Instruction::LoadConstant{result, index} => {
+            out.write_all(&[OP_LOAD_CONSTANT])?;
+            write_local(out, *result)?;
+            write_usize(out, *index)?;
+        },
+        Instruction::GetPointer{result, object, index} => {
+            out.write_all(&[OP_GET_POINTER])?;
+            write_local(out, *result)?;
+            write_local(out, *object)?;
+            write_usize(out, *index)?;
+        },
+        Instruction::EnchantmentOf{result, object} => {
+            out.write_all(&[OP_ENCHANTMENT_OF])?;
+            write_local(out, *result)?;
+            write_local(out, *object)?;
+        },
+        Instruction::AuxiliaryLen{result, object} => {
+            out.write_all(&[OP_AUXILIARY_LEN])?;
+            write_local(out, *result)?;
+            write_local(out, *object)?;
+        },
+        Instruction::PushHandler{target, exception} => {
+            out.write_all(&[OP_PUSH_HANDLER])?;
+            write_usize(out, *target)?;
+            write_local(out, *exception)?;
+        },
+        Instruction::PopHandler => {
+            out.write_all(&[OP_POP_HANDLER])?;
+        },
+        Instruction::Throw{value} => {
+            out.write_all(&[OP_THROW])?;
+            write_local(out, *value)?;
+        },
+        Instruction::Return{result} => {
+            out.write_all(&[OP_RETURN])?;
+            write_local(out, *result)?;
+        },
+    }
+    Ok(())
+}
+
+fn read_instruction(input:  &mut impl Read,
+                    sigils: &mut Sigils,
+                    ) -> Result<Instruction, DeserializeError> {
+    let mut opcode = [0];
+    input.read_exact(&mut opcode)?;
+    let instruction = match opcode[0] {
+        OP_COPY => {
+            let from = read_local(input)?;
+            let to = read_local(input)?;
+            Instruction::Copy{from, to}
+        },
+        OP_COPY_RANGE => {
+            let from = read_local(input)?;
+            let to = read_local(input)?;
+            let count = read_usize(input)?;
+            Instruction::CopyRange{from, to, count}
+        },
+        OP_SWAP => {
+            let a = read_local(input)?;
+            let b = read_local(input)?;
+            Instruction::Swap{a, b}
+        },
+        OP_NOP => Instruction::Nop,
+        OP_INVOKE_STATIC => {
+            let result = read_local(input)?;
+            let spellbook = read_sigil(input, sigils)?;
+            let spell = read_sigil(input, sigils)?;
+            let arguments = read_locals(input)?;
+            Instruction::InvokeStatic{result, spellbook, spell, arguments}
+        },
+        OP_INVOKE_DYNAMIC => {
+            let result = read_local(input)?;
+            let spell = read_sigil(input, sigils)?;
+            let receiver = read_local(input)?;
+            let arguments = read_locals(input)?;
+            Instruction::InvokeDynamic{result, spell, receiver, arguments}
+        },
+        OP_MAKE_CLOSURE => {
+            let result = read_local(input)?;
+            let spellbook = read_sigil(input, sigils)?;
+            let spell = read_sigil(input, sigils)?;
+            let captures = read_locals(input)?;
+            Instruction::MakeClosure{result, spellbook, spell, captures}
+        },
+        OP_INVOKE_CLOSURE => {
+            let result = read_local(input)?;
+            let closure = read_local(input)?;
+            let arguments = read_locals(input)?;
+            Instruction::InvokeClosure{result, closure, arguments}
+        },
+        OP_JUMP => {
+            let target = read_usize(input)?;
+            Instruction::Jump{target}
+        },
+        OP_JUMP_RELATIVE => {
+            let offset = read_isize(input)?;
+            Instruction::JumpRelative{offset}
+        },
+        OP_BRANCH_IF_TRUTHY_RELATIVE => {
+            let condition = read_local(input)?;
+            let offset = read_isize(input)?;
+            Instruction::BranchIfTruthyRelative{condition, offset}
+        },
+        OP_BRANCH_IF_FALSY_RELATIVE => {
+            let condition = read_local(input)?;
+            let offset = read_isize(input)?;
+            Instruction::BranchIfFalsyRelative{condition, offset}
+        },
+        OP_SWITCH => {
+            let scrutinee = read_local(input)?;
+            let mut targets = Vec::new();
+            for _ in 0 .. read_usize(input)? {
+                targets.push(read_usize(input)?);
+            }
+            let targets = targets.into_boxed_slice();
+            let default = read_usize(input)?;
+            Instruction::Switch{scrutinee, targets, default}
+        },
+        OP_BRANCH_IF_TRUTHY => {
+            let condition = read_local(input)?;
+            let target = read_usize(input)?;
+            Instruction::BranchIfTruthy{condition, target}
+        },
+        OP_BRANCH_IF_FALSY => {
+            let condition = read_local(input)?;
+            let target = read_usize(input)?;
+            Instruction::BranchIfFalsy{condition, target}
+        },
+        opcode @ OP_ADD ..= OP_DIV |
+        opcode @ OP_EQUAL ..= OP_GREATER_THAN => {
+            let result = read_local(input)?;
+            let lhs = read_local(input)?;
+            let rhs = read_local(input)?;
+            match opcode {
+                OP_ADD       => Instruction::Add{result, lhs, rhs},
+                OP_SUB       => Instruction::Sub{result, lhs, rhs},
+                OP_MUL       => Instruction::Mul{result, lhs, rhs},
+                OP_DIV       => Instruction::Div{result, lhs, rhs},
+                OP_EQUAL     => Instruction::Equal{result, lhs, rhs},
+                OP_LESS_THAN => Instruction::LessThan{result, lhs, rhs},
+                _            => Instruction::GreaterThan{result, lhs, rhs},
+            }
+        },
+        OP_ALLOCATE => {
+            let result = read_local(input)?;
+            let enchantment = read_sigil(input, sigils)?;
+            let pointers = read_locals(input)?;
+            let auxiliary = read_bytes(input)?.into_boxed_slice();
+            Instruction::Allocate{result, enchantment, pointers, auxiliary}
+        },
+        OP_IS_ENCHANTED => {
+            let result = read_local(input)?;
+            let object = read_local(input)?;
+            let enchantment = read_sigil(input, sigils)?;
+            Instruction::IsEnchanted{result, object, enchantment}
+        },
+        OP_LOAD_CONSTANT => {
+            let result = read_local(input)?;
+            let index = read_usize(input)?;
+            Instruction::LoadConstant{result, index}
+        },
+        OP_GET_POINTER => {
+            let result = read_local(input)?;
+            let object = read_local(input)?;
+            let index = read_usize(input)?;
+            Instruction::GetPointer{result, object, index}
+        },
+        OP_ENCHANTMENT_OF => {
+            let result = read_local(input)?;
+            let object = read_local(input)?;
+            Instruction::EnchantmentOf{result, object}
+        },
+        OP_AUXILIARY_LEN => {
+            let result = read_local(input)?;
+            let object = read_local(input)?;
+            Instruction::AuxiliaryLen{result, object}
+        },
+        OP_PUSH_HANDLER => {
+            let target = read_usize(input)?;
+            let exception = read_local(input)?;
+            Instruction::PushHandler{target, exception}
+        },
+        OP_POP_HANDLER => Instruction::PopHandler,
+        OP_THROW => {
+            let value = read_local(input)?;
+            Instruction::Throw{value}
+        },
+        OP_RETURN => {
+            let result = read_local(input)?;
+            Instruction::Return{result}
+        },
+        opcode => return Err(DeserializeError::UnknownOpcode(opcode)),
+    };
+    Ok(instruction)
+}
+
+pub(crate) fn write_u32(out: &mut impl Write, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_usize(out: &mut impl Write, value: usize)
+    -> io::Result<()>
+{
+    out.write_all(&(value as u64).to_le_bytes())
+}
+
+pub(crate) fn write_isize(out: &mut impl Write, value: isize)
+    -> io::Result<()>
+{
+    out.write_all(&(value as i64).to_le_bytes())
+}
+
+fn write_local(out: &mut impl Write, local: Local) -> io::Result<()> {
+    write_u32(out, local.0)
+}
+
+fn write_locals(out: &mut impl Write, locals: &[Local]) -> io::Result<()> {
+    write_usize(out, locals.len())?;
+    for &local in locals {
+        write_local(out, local)?;
+    }
+    Ok(())
+}
+
+fn write_sigil(out:    &mut impl Write,
+               sigils: &Sigils,
+               sigil:  Sigil,
+               ) -> io::Result<()> {
+    let name = sigils.name(sigil).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Sigil not in database")
+    })?;
+    write_bytes(out, name)
+}
+
+pub(crate) fn write_bytes(out: &mut impl Write, bytes: &[u8])
+    -> io::Result<()>
+{
+    write_usize(out, bytes.len())?;
+    out.write_all(bytes)
+}
+
+pub(crate) fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_usize(input: &mut impl Read)
+    -> Result<usize, DeserializeError>
+{
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    let value = u64::from_le_bytes(bytes);
+    if value > usize::MAX as u64 {
+        return Err(DeserializeError::Overflow);
+    }
+    Ok(value as usize)
+}
+
+pub(crate) fn read_isize(input: &mut impl Read)
+    -> Result<isize, DeserializeError>
+{
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    let value = i64::from_le_bytes(bytes);
+    if value as isize as i64 != value {
+        return Err(DeserializeError::Overflow);
+    }
+    Ok(value as isize)
+}
+
+fn read_local(input: &mut impl Read) -> io::Result<Local> {
+    read_u32(input).map(Local)
+}
+
+fn read_locals(input: &mut impl Read)
+    -> Result<Box<[Local]>, DeserializeError>
+{
+    let mut locals = Vec::new();
+    for _ in 0 .. read_usize(input)? {
+        locals.push(read_local(input)?);
+    }
+    Ok(locals.into_boxed_slice())
+}
+
+fn read_sigil(input:  &mut impl Read,
+              sigils: &mut Sigils,
+              ) -> Result<Sigil, DeserializeError> {
+    let name = read_bytes(input)?;
+    Ok(sigils.intern_bytes(&name))
+}
+
+pub(crate) fn read_bytes(input: &mut impl Read)
+    -> Result<Vec<u8>, DeserializeError>
+{
+    let length = read_usize(input)?;
+
+    // Do not trust the length with an allocation of that size; the byte
+    // stream may be truncated or corrupt.
+    let mut bytes = Vec::new();
+    input.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        let error = io::Error::from(io::ErrorKind::UnexpectedEof);
+        return Err(DeserializeError::Io(error));
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::slice;
+
+    use datum::Datum;
+    use datum::Heap;
+    use interpret::Runtime;
+    use interpret::TypeSigils;
+    use interpret::run;
+
+    fn round_trip(spells: &Spells, from: &Sigils, into: &mut Sigils)
+        -> Spells
+    {
+        let mut bytes = Vec::new();
+        spells.serialize(from, &mut bytes).unwrap();
+        Spells::deserialize(&mut &bytes[..], into).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut sigils = Sigils::new();
+        let falsy = sigils.intern_str("false");
+        let truthy = sigils.intern_str("true");
+        let integer = sigils.intern_str("integer");
+        let book = sigils.intern_str("book");
+        let main = sigils.intern_str("main");
+        let double = sigils.intern_str("double");
+        let source = sigils.intern_str("main.mana");
+
+        let mut spells = Spells::new();
+        spells.insert(
+            SpellId{spellbook: book, spell: main, arity: 1},
+            Spell{instructions: Box::new([
+                Instruction::PushHandler{target: 6, exception: Local(0)},
+                Instruction::BranchIfFalsy{condition: Local(0), target: 6},
+                Instruction::InvokeStatic{result: Local(0), spellbook: book,
+                                          spell: double,
+                                          arguments: Box::new([Local(0)])},
+                Instruction::PopHandler,
+                Instruction::Switch{scrutinee: Local(0),
+                                    targets: Box::new([6, 0]), default: 5},
+                Instruction::Jump{target: 6},
+                Instruction::Return{result: Local(0)},
+            ]), local_variables: 1, constants: Box::new([]),
+            source_map: Some((1 ..= 7).map(|line| {
+                SourceSpan{file: source, line, column: 5}
+            }).collect())},
+        ).ok().unwrap();
+        spells.insert(
+            SpellId{spellbook: book, spell: double, arity: 1},
+            Spell{instructions: Box::new([
+                Instruction::LoadConstant{result: Local(1), index: 0},
+                Instruction::Nop,
+                Instruction::CopyRange{from: Local(0), to: Local(1),
+                                       count: 1},
+                Instruction::Add{result: Local(1), lhs: Local(0),
+                                 rhs: Local(0)},
+                Instruction::Swap{a: Local(0), b: Local(1)},
+                Instruction::Swap{a: Local(1), b: Local(0)},
+                Instruction::Equal{result: Local(0), lhs: Local(1),
+                                   rhs: Local(1)},
+                Instruction::LessThan{result: Local(0), lhs: Local(1),
+                                      rhs: Local(1)},
+                Instruction::GreaterThan{result: Local(0), lhs: Local(1),
+                                         rhs: Local(1)},
+                Instruction::Allocate{result: Local(0), enchantment: integer,
+                                      pointers: Box::new([Local(1)]),
+                                      auxiliary: Box::new(*b"pair")},
+                Instruction::GetPointer{result: Local(1), object: Local(0),
+                                        index: 0},
+                Instruction::EnchantmentOf{result: Local(0),
+                                           object: Local(1)},
+                Instruction::IsEnchanted{result: Local(0), object: Local(1),
+                                         enchantment: integer},
+                Instruction::AuxiliaryLen{result: Local(0),
+                                          object: Local(1)},
+                Instruction::BranchIfTruthyRelative{condition: Local(0),
+                                                    offset: 3},
+                Instruction::BranchIfFalsyRelative{condition: Local(1),
+                                                   offset: -1},
+                Instruction::JumpRelative{offset: 1},
+                Instruction::Throw{value: Local(1)},
+                Instruction::MakeClosure{result: Local(1), spellbook: book,
+                                         spell: double,
+                                         captures: Box::new([Local(0)])},
+                Instruction::InvokeClosure{result: Local(0),
+                                           closure: Local(1),
+                                           arguments: Box::new([])},
+            ]), local_variables: 2, constants: Box::new([
+                ConstantValue{enchantment: truthy,
+                              auxiliary: Box::new(*b"constant")},
+            ]), source_map: None},
+        ).ok().unwrap();
+
+        // Load into a database in which the sigils have different ids.
+        let mut other = Sigils::new();
+        other.intern_str("padding");
+        let loaded = round_trip(&spells, &sigils, &mut other);
+        assert_eq!(other.len(), 7);
+
+        let double_id = SpellId{spellbook: other.intern_str("book"),
+                                spell: other.intern_str("double"), arity: 1};
+        assert_eq!(&*loaded.get(double_id).unwrap().constants, &[
+            ConstantValue{enchantment: other.intern_str("true"),
+                          auxiliary: Box::new(*b"constant")},
+        ]);
+
+        let main_id = SpellId{spellbook: other.intern_str("book"),
+                              spell: other.intern_str("main"), arity: 1};
+        let main_spell = loaded.get(main_id).unwrap();
+        assert_eq!(main_spell.source_span(6), Some(SourceSpan{
+            file: other.intern_str("main.mana"), line: 7, column: 5,
+        }));
+        assert!(loaded.get(double_id).unwrap().source_map.is_none());
+        let heap = Heap::new();
+        let argument = Datum::from_i64(21).unwrap();
+        let runtime = Runtime{spells: &loaded, heap: &heap,
+                              types: TypeSigils::intern(&mut other)};
+        let result = run(&runtime, main_id, slice::from_ref(&argument));
+        let runtime = Runtime{spells: &spells, heap: &heap,
+                              types: TypeSigils::intern(&mut sigils)};
+        assert_eq!(runtime.types.falsy, falsy);
+        let expected = run(&runtime,
+                           SpellId{spellbook: book, spell: main, arity: 1},
+                           &[argument]);
+        assert_eq!(result.auxiliary(), expected.auxiliary());
+        assert_eq!(result.auxiliary(), &42i64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let mut sigils = Sigils::new();
+
+        let error = Spells::deserialize(&mut &b"MANB\x01\0\0\0"[..],
+                                        &mut sigils);
+        assert!(matches!(error, Err(DeserializeError::BadMagic)));
+
+        let error = Spells::deserialize(&mut &b"MANA\x01\0\0\0"[..],
+                                        &mut sigils);
+        assert!(matches!(error, Err(DeserializeError::UnsupportedVersion(1))));
+
+        let error = Spells::deserialize(&mut &b"MANA\x02\0"[..],
+                                        &mut sigils);
+        assert!(matches!(error, Err(DeserializeError::Io(_))));
+        assert!(error.unwrap_err().source().is_some());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut sigils = Sigils::new();
+        let book = sigils.intern_str("book");
+
+        let mut spells = Spells::new();
+        spells.insert(
+            SpellId{spellbook: book, spell: book, arity: 0},
+            Spell{instructions: Box::new([
+                Instruction::Return{result: Local(0)},
+            ]), local_variables: 1, constants: Box::new([]),
+            source_map: None},
+        ).ok().unwrap();
+
+        let mut bytes = Vec::new();
+        spells.serialize(&sigils, &mut bytes).unwrap();
+        for length in 0 .. bytes.len() {
+            let result = Spells::deserialize(&mut &bytes[.. length],
+                                             &mut sigils);
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn test_reproducible() {
+        let mut sigils = Sigils::new();
+        let book = sigils.intern_str("book");
+        let ids: Vec<SpellId> = (0 .. 10)
+            .map(|i| SpellId{spellbook: book,
+                             spell:     sigils.intern_str(&i.to_string()),
+                             arity:     i % 3})
+            .collect();
+        let spell = |local_variables| {
+            Spell{instructions: Box::new([]), local_variables,
+                  constants: Box::new([]), source_map: None}
+        };
+
+        let serialize = |ids: &mut dyn Iterator<Item = &SpellId>| {
+            let mut spells = Spells::new();
+            for &id in ids {
+                spells.insert(id, spell(id.arity)).ok().unwrap();
+            }
+            let mut bytes = Vec::new();
+            spells.serialize(&sigils, &mut bytes).unwrap();
+            bytes
+        };
+        assert_eq!(serialize(&mut ids.iter()),
+                   serialize(&mut ids.iter().rev()));
+    }
+
+    #[test]
+    fn test_unknown_sigil() {
+        let sigils = Sigils::new();
+
+        let mut spells = Spells::new();
+        spells.insert(
+            SpellId{spellbook: Sigil(0), spell: Sigil(0), arity: 0},
+            Spell{instructions: Box::new([]), local_variables: 0,
+                  constants: Box::new([]), source_map: None},
+        ).ok().unwrap();
+
+        let error = spells.serialize(&sigils, &mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
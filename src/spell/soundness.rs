@@ -0,0 +1,409 @@
+use super::*;
+
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use datum::CollectStatistics;
+use datum::HeapObserver;
+use interpret::InterpretError;
+use interpret::Runtime;
+use interpret::TypeSigils;
+use interpret::run;
+use interpret::run_with_fuel;
+use sigil::Sigils;
+
+const BOOK:  Sigil = Sigil(0);
+const MAIN:  Sigil = Sigil(1);
+const FALSE: Sigil = Sigil(2);
+const TRUE:  Sigil = Sigil(3);
+const INT:   Sigil = Sigil(4);
+const STR:   Sigil = Sigil(5);
+
+/// The native spell book::identity, at every arity from one to [MAX_ARITY],
+/// returns its first argument. Generated spells invoke it both directly and
+/// through closures, and every enchantment of data they create has it as
+/// well, for dynamic invocations.
+///
+/// [MAX_ARITY]: constant.MAX_ARITY.html
+const IDENTITY:  Sigil = Sigil(6);
+const MAX_ARITY: usize = 3;
+
+const TYPES: TypeSigils = TypeSigils{falsy: FALSE, truthy: TRUE,
+                                     integer: INT, string: STR};
+
+/// How much fuel a run may consume. Generated spells only jump forward, but
+/// catching an exception may jump back, so they are not guaranteed to finish.
+const FUEL: u64 = 10_000;
+
+/// An optimization pass, given the arity of the spell.
+type Pass = fn(&mut Spell, usize);
+
+/// Every optimization pass, in the order they are usually applied in.
+const PASSES: [(&str, Pass); 4] = [
+    ("fold_constants",      |spell, _| spell.fold_constants(TYPES)),
+    ("eliminate_dead_code", |spell, _| spell.eliminate_dead_code()),
+    ("peephole",            |spell, _| { spell.peephole(); }),
+    ("pack_locals",         |spell, arity| spell.pack_locals(arity)),
+];
+
+/// Run a spell book::main with the given integer arguments before and after
+/// each optimization pass, both on its own and after the passes before it,
+/// and panic unless the optimized spell is well-formed and does what the
+/// original does.
+///
+/// Each run gets a fresh heap. The runs must return structurally equal data,
+/// or fail in the same way, and must allocate the same data in the same
+/// order. Errors that name a local variable are compared by kind only, as
+/// packing renumbers the local variables. Optimizations never make a spell
+/// consume more fuel, so the optimized spell must finish if the original
+/// does; if the original does not finish within [FUEL], there is nothing to
+/// compare.
+///
+/// [FUEL]: constant.FUEL.html
+pub fn assert_optimization_sound(spell: &Spell, arguments: &[i64]) {
+    let arity = arguments.len();
+    let mut pipeline = spell.clone();
+    for &(name, pass) in &PASSES {
+        let mut optimized = spell.clone();
+        pass(&mut optimized, arity);
+        assert_same_effects(name, spell, &optimized, arguments);
+
+        pass(&mut pipeline, arity);
+        assert_same_effects(name, spell, &pipeline, arguments);
+    }
+}
+
+/// Assemble a spell database, and return it along with the identity of the
+/// spell book::main with the given arity and the type sigils, all interned
+/// into the same sigil database.
+pub fn assemble_main(source: &str, arity: usize)
+    -> (Spells, SpellId, TypeSigils)
+{
+    let mut sigils = Sigils::new();
+    let types = TypeSigils::intern(&mut sigils);
+    let spells = assemble(source, &mut sigils).unwrap();
+    let id = SpellId{spellbook: sigils.intern_str("book"),
+                     spell:     sigils.intern_str("main"),
+                     arity};
+    (spells, id, types)
+}
+
+/// Run a spell with each of the given lists of integer arguments before and
+/// after a pass, and return the integers it returns and the spell after the
+/// pass. Panics unless the spell is well-formed after the pass and returns
+/// the same integers as before.
+pub fn check_pass<F>(mut spells: Spells,
+                     id:         SpellId,
+                     types:      TypeSigils,
+                     runs:       &[&[i64]],
+                     pass:       F,
+                     ) -> (Vec<i64>, Spell)
+    where F: FnOnce(&mut Spell) {
+    let run_all = |spells: &Spells| -> Vec<i64> {
+        runs.iter().map(|arguments| {
+            let heap = Heap::new();
+            let arguments: Vec<Datum> = arguments.iter()
+                .map(|&argument| Datum::from_i64(argument).unwrap())
+                .collect();
+            let runtime = Runtime{spells, heap: &heap, types};
+            let result = run(&runtime, id, &arguments).as_i64();
+            result.unwrap()
+        }).collect()
+    };
+
+    let expected = run_all(&spells);
+    let mut spell = spells.remove(id).unwrap();
+    pass(&mut spell);
+    assert!(verify_as(id, &spell).is_ok());
+    spells.insert(id, spell).unwrap();
+    assert_eq!(run_all(&spells), expected);
+    (expected, spells.remove(id).unwrap())
+}
+
+/// Panic unless a spell and its optimized version are observably the same;
+/// see [assert_optimization_sound].
+///
+/// [assert_optimization_sound]: fn.assert_optimization_sound.html
+fn assert_same_effects(pass:      &str,
+                       original:  &Spell,
+                       optimized: &Spell,
+                       arguments: &[i64]) {
+    let id = SpellId{spellbook: BOOK, spell: MAIN, arity: arguments.len()};
+    assert!(verify_as(id, optimized).is_ok(),
+            "{} made {:?} ill-formed: {:?}", pass, original, optimized);
+
+    let (expected_spells, actual_spells) =
+        (only(id, original), only(id, optimized));
+    let (expected_log, actual_log) = (Recorder::new(), Recorder::new());
+    let expected_heap = Heap::new_with_observer(Box::new(expected_log.clone()));
+    let actual_heap = Heap::new_with_observer(Box::new(actual_log.clone()));
+    let expected = run_main(&expected_spells, &expected_heap, id, arguments);
+    let actual = run_main(&actual_spells, &actual_heap, id, arguments);
+
+    let same_outcome = match (&expected, &actual) {
+        (None, _)                    => return,
+        (Some(Ok(a)), Some(Ok(b)))   => a.structural_eq(b),
+        (Some(Err(a)), Some(Err(b))) => same_error(a, b),
+        (Some(_), _)                 => false,
+    };
+    assert!(same_outcome, "{} changed the outcome of {:?} from {:?} to {:?}: \
+                           {:?}", pass, original, expected, actual, optimized);
+    assert!(*expected_log.0.lock().unwrap() == *actual_log.0.lock().unwrap(),
+            "{} changed the allocations of {:?}: {:?}", pass, original,
+            optimized);
+}
+
+/// Run a spell on a heap, or return `None` if it runs out of fuel.
+fn run_main<'a>(spells:    &'a Spells,
+                heap:      &'a Heap,
+                id:        SpellId,
+                arguments: &[i64],
+                ) -> Option<Result<Datum<'a>, InterpretError>> {
+    let arguments: Vec<Datum> = arguments.iter()
+        .map(|&argument| Datum::from_i64(argument).unwrap())
+        .collect();
+    let runtime = Runtime{spells, heap, types: TYPES};
+    match run_with_fuel(&runtime, id, &arguments, FUEL) {
+        Ok(Ok(result)) => Some(Ok(result)),
+        Ok(Err(_))     => None,
+        Err(error)     => Some(Err(error)),
+    }
+}
+
+/// Whether two runs failed in the same way.
+fn same_error(a: &InterpretError, b: &InterpretError) -> bool {
+    use self::InterpretError::*;
+    match (a, b) {
+        (LocalOutOfBounds(_),   LocalOutOfBounds(_))   |
+        (LocalUninitialized(_), LocalUninitialized(_)) |
+        (NotAnInteger(_),       NotAnInteger(_))       |
+        (NotAClosure(_),        NotAClosure(_))        => true,
+        _ => a == b,
+    }
+}
+
+/// A spell database with a copy of one spell, and the native spells it may
+/// invoke; see [IDENTITY].
+///
+/// [IDENTITY]: constant.IDENTITY.html
+fn only(id: SpellId, spell: &Spell) -> Spells {
+    fn identity<'a>(_: &'a Heap, arguments: &[Datum<'a>]) -> Datum<'a> {
+        arguments[0].clone()
+    }
+
+    let mut spells = Spells::new();
+    spells.insert(id, spell.clone()).unwrap();
+    for &spellbook in &[BOOK, FALSE, TRUE, INT, STR] {
+        for arity in 1 ..= MAX_ARITY {
+            let id = SpellId{spellbook, spell: IDENTITY, arity};
+            spells.insert_native(id, identity).unwrap();
+        }
+    }
+    spells
+}
+
+/// The enchantment, number of pointers, and auxiliary part of an allocated
+/// datum.
+type Allocation = (Sigil, usize, Box<[u8]>);
+
+/// Records every datum allocated on a heap.
+#[derive(Clone)]
+struct Recorder(Arc<Mutex<Vec<Allocation>>>);
+
+impl Recorder {
+    fn new() -> Self {
+        Recorder(Arc::new(Mutex::new(Vec::new())))
+    }
+}
+
+impl HeapObserver for Recorder {
+    fn on_allocate(&self, datum: &Datum) {
+        let allocation = (datum.enchantment(), datum.pointers().len(),
+                          datum.auxiliary().into());
+        self.0.lock().unwrap().push(allocation);
+    }
+
+    fn on_collect(&self, _stats: &CollectStatistics) {
+    }
+}
+
+/// A xorshift pseudorandom number generator, so that every run of the tests
+/// generates the same spells.
+struct Random(u64);
+
+impl Random {
+    /// The seed must not be zero.
+    fn new(seed: u64) -> Self {
+        Random(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number from zero up to but not including `n`, which must not be
+    /// zero.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+
+    fn pick_many<T: Copy>(&mut self, items: &[T], max: usize) -> Vec<T> {
+        (0 .. self.below(max + 1)).map(|_| self.pick(items)).collect()
+    }
+
+    /// Like [pick_many], but at least one item, and `max` must not be zero.
+    ///
+    /// [pick_many]: #method.pick_many
+    fn pick_some<T: Copy>(&mut self, items: &[T], max: usize) -> Vec<T> {
+        (0 .. 1 + self.below(max)).map(|_| self.pick(items)).collect()
+    }
+}
+
+/// Generate a well-formed spell with the given arity, which assigns to every
+/// local variable before it reads any of them.
+///
+/// The spell is made of basic blocks that end in a return, a throw, or any
+/// kind of jump to a later basic block, or fall through into the next one.
+/// The only spells it invokes are the native ones of [only].
+///
+/// [only]: fn.only.html
+fn random_spell(random: &mut Random, arity: usize) -> Spell {
+    let mut builder = SpellBuilder::new();
+    let locals: Vec<Local> =
+        (0 .. arity + 1 + random.below(6)).map(|_| builder.local()).collect();
+    let constants: Vec<usize> = [
+        integer(0), integer(1), integer(-2), integer(3), integer(7),
+        ConstantValue{enchantment: TRUE,  auxiliary: Box::new([])},
+        ConstantValue{enchantment: FALSE, auxiliary: Box::new([])},
+        ConstantValue{enchantment: STR,   auxiliary: Box::new(*b"ab")},
+    ].iter().map(|constant| builder.constant(constant.clone())).collect();
+
+    for &local in &locals[arity ..] {
+        let constant = random.pick(&constants);
+        builder.load_constant(local, constant);
+    }
+
+    let labels: Vec<Label> =
+        (0 .. 1 + random.below(5)).map(|_| builder.label()).collect();
+    for (block, &label) in labels.iter().enumerate() {
+        let later = &labels[block + 1 ..];
+        builder.bind(label);
+        for _ in 0 .. random.below(10) {
+            random_instruction(random, &mut builder, &locals, &constants,
+                               later);
+        }
+        random_terminator(random, &mut builder, &locals, later);
+    }
+
+    builder.build()
+}
+
+/// Emit an instruction that does not end a basic block. Invocations are of
+/// [IDENTITY], at an arity it has unless the invocation is of a closure or a
+/// receiver of the wrong kind.
+///
+/// [IDENTITY]: constant.IDENTITY.html
+fn random_instruction(random:    &mut Random,
+                      builder:   &mut SpellBuilder,
+                      locals:    &[Local],
+                      constants: &[usize],
+                      later:     &[Label]) {
+    let sigils = [BOOK, FALSE, TRUE, INT];
+    let auxiliaries: [&[u8]; 3] = [b"", b"a", &3i64.to_le_bytes()];
+    let l = locals;
+    match random.below(26) {
+        0 | 1 => builder.copy(random.pick(l), random.pick(l)),
+        2 => {
+            let count = 1 + random.below(l.len());
+            let from = random.below(l.len() - count + 1);
+            let to = random.below(l.len() - count + 1);
+            builder.copy_range(l[from], l[to], count)
+        },
+        3 | 4 => builder.swap(random.pick(l), random.pick(l)),
+        5  => builder.nop(),
+        6  => builder.add(random.pick(l), random.pick(l), random.pick(l)),
+        7  => builder.sub(random.pick(l), random.pick(l), random.pick(l)),
+        8  => builder.mul(random.pick(l), random.pick(l), random.pick(l)),
+        9  => builder.div(random.pick(l), random.pick(l), random.pick(l)),
+        10 => builder.equal(random.pick(l), random.pick(l), random.pick(l)),
+        11 => builder.less_than(random.pick(l), random.pick(l),
+                                random.pick(l)),
+        12 => builder.greater_than(random.pick(l), random.pick(l),
+                                   random.pick(l)),
+        13 | 14 => builder.load_constant(random.pick(l),
+                                         random.pick(constants)),
+        15 => builder.allocate(random.pick(l), random.pick(&sigils),
+                               &random.pick_many(l, 2),
+                               random.pick(&auxiliaries)),
+        16 => builder.get_pointer(random.pick(l), random.pick(l),
+                                  random.below(2)),
+        17 => builder.enchantment_of(random.pick(l), random.pick(l)),
+        18 => builder.is_enchanted(random.pick(l), random.pick(l),
+                                   random.pick(&sigils)),
+        19 => builder.auxiliary_len(random.pick(l), random.pick(l)),
+        20 if !later.is_empty() =>
+            builder.push_handler(random.pick(later), random.pick(l)),
+        21 => builder.invoke_static(random.pick(l), BOOK, IDENTITY,
+                                    &random.pick_some(l, MAX_ARITY)),
+        22 => builder.invoke_dynamic(random.pick(l), IDENTITY, random.pick(l),
+                                     &random.pick_many(l, MAX_ARITY - 1)),
+        23 => builder.make_closure(random.pick(l), BOOK, IDENTITY,
+                                   &random.pick_some(l, MAX_ARITY - 1)),
+        24 => builder.invoke_closure(random.pick(l), random.pick(l),
+                                     &random.pick_many(l, 1)),
+        _  => builder.pop_handler(),
+    };
+}
+
+/// Emit the instruction that ends a basic block, if any. Only the last basic
+/// block has no later basic block, and it must not fall through.
+fn random_terminator(random:  &mut Random,
+                     builder: &mut SpellBuilder,
+                     locals:  &[Local],
+                     later:   &[Label]) {
+    let relative = random.below(2) == 0;
+    match random.below(if later.is_empty() { 2 } else { 7 }) {
+        0 => builder.ret(random.pick(locals)),
+        1 => builder.throw(random.pick(locals)),
+        2 if relative =>
+            builder.branch_if_truthy_relative(random.pick(locals),
+                                              random.pick(later)),
+        2 => builder.branch_if_truthy(random.pick(locals), random.pick(later)),
+        3 if relative =>
+            builder.branch_if_falsy_relative(random.pick(locals),
+                                             random.pick(later)),
+        3 => builder.branch_if_falsy(random.pick(locals), random.pick(later)),
+        4 if relative => builder.jump_relative(random.pick(later)),
+        4 => builder.jump(random.pick(later)),
+        5 => builder.switch(random.pick(locals), &random.pick_many(later, 3),
+                            random.pick(later)),
+        _ => builder,
+    };
+}
+
+fn integer(value: i64) -> ConstantValue {
+    ConstantValue{enchantment: INT, auxiliary: Box::new(value.to_le_bytes())}
+}
+
+#[test]
+fn test_optimizations_are_sound() {
+    for seed in 1 .. 2001u64 {
+        // Multiplying by an odd number keeps the seeds distinct and nonzero.
+        let mut random = Random::new(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let arity = random.below(3);
+        let spell = random_spell(&mut random, arity);
+        let arguments: Vec<i64> = (0 .. arity)
+            .map(|_| random.pick(&[0, 1, -1, 3, 1 << 40]))
+            .collect();
+        let id = SpellId{spellbook: BOOK, spell: MAIN, arity};
+        assert!(verify_as(id, &spell).is_ok(), "{:?}", spell);
+        assert_optimization_sound(&spell, &arguments);
+    }
+}
@@ -0,0 +1,325 @@
+use super::*;
+
+use std::error::Error;
+use std::fmt;
+
+/// Check that a spell is well-formed.
+///
+/// A well-formed spell only refers to local variables that it allocates, only
+/// jumps to instructions that exist, and never continues past its last
+/// instruction. Interpreting a well-formed spell can still fail, for example
+/// because it invokes a spell that does not exist or reads a local variable
+/// that was not yet assigned to.
+pub fn verify(spell: &Spell) -> Result<(), VerifyError> {
+    let length = spell.instructions.len();
+
+    if length == 0 {
+        return Err(VerifyError{instruction: 0,
+                               reason: VerifyErrorReason::FallsOffEnd});
+    }
+
+    for (index, instruction) in spell.instructions.iter().enumerate() {
+        let error = |reason| VerifyError{instruction: index, reason};
+
+        // Check ranges up front, so that their local variables need not be
+        // visited if they are too long.
+        if let Instruction::CopyRange{from, to, count} = instruction {
+            for start in &[from, to] {
+                let end = (start.0 as usize).checked_add(*count);
+                if end.is_none_or(|end| end > spell.local_variables) {
+                    let local = (start.0 as usize).max(spell.local_variables);
+                    return Err(error(VerifyErrorReason::LocalOutOfBounds(
+                        Local(local as u32))));
+                }
+            }
+        }
+
+        let mut bad_local = None;
+        instruction.for_each_local(|local| {
+            if local.0 as usize >= spell.local_variables {
+                bad_local = bad_local.or(Some(local));
+            }
+        });
+        if let Some(local) = bad_local {
+            return Err(error(VerifyErrorReason::LocalOutOfBounds(local)));
+        }
+
+        for target in instruction.jump_targets() {
+            if target >= length {
+                return Err(error(VerifyErrorReason::TargetOutOfBounds(target)));
+            }
+        }
+
+        if let Instruction::LoadConstant{index, ..} = instruction {
+            if *index >= spell.constants.len() {
+                return Err(error(VerifyErrorReason::ConstantOutOfBounds(
+                    *index)));
+            }
+        }
+
+        if let Some(offset) = instruction.jump_offset() {
+            let target = (index as isize).checked_add(offset);
+            if !target.is_some_and(|t| t >= 0 && (t as usize) < length) {
+                return Err(error(VerifyErrorReason::OffsetOutOfBounds(offset)));
+            }
+        }
+
+        if instruction.falls_through() && index + 1 == length {
+            return Err(error(VerifyErrorReason::FallsOffEnd));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that a spell is well-formed, as with [verify], and that it can be
+/// defined with the given id: that its arguments fit in the local variables
+/// it allocates.
+///
+/// [verify]: fn.verify.html
+pub fn verify_as(id: SpellId, spell: &Spell) -> Result<(), VerifyError> {
+    if spell.local_variables < id.arity {
+        let reason = VerifyErrorReason::TooFewLocalVariables{
+            arity:           id.arity,
+            local_variables: spell.local_variables,
+        };
+        return Err(VerifyError{instruction: 0, reason});
+    }
+    verify(spell)
+}
+
+/// This error is returned when verifying a spell that is not well-formed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerifyError {
+    /// The index of the offending instruction.
+    pub instruction: usize,
+
+    /// Why the instruction is not well-formed.
+    pub reason: VerifyErrorReason,
+}
+
+/// Why an instruction is not well-formed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VerifyErrorReason {
+    /// The instruction refers to a local variable that the spell does not
+    /// allocate.
+    LocalOutOfBounds(Local),
+
+    /// The instruction jumps to an instruction that does not exist.
+    TargetOutOfBounds(usize),
+
+    /// The instruction jumps relatively to an instruction that does not
+    /// exist.
+    OffsetOutOfBounds(isize),
+
+    /// The instruction loads a constant that the constant pool of the spell
+    /// does not have.
+    ConstantOutOfBounds(usize),
+
+    /// Interpretation may continue past the last instruction. An empty spell
+    /// reports this for instruction 0.
+    FallsOffEnd,
+
+    /// The spell allocates fewer local variables than the arity of its id,
+    /// so its arguments would not fit. This is reported for instruction 0.
+    /// See [verify_as].
+    ///
+    /// [verify_as]: fn.verify_as.html
+    TooFewLocalVariables{arity: usize, local_variables: usize},
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "instruction {}: {}", self.instruction, self.reason)
+    }
+}
+
+impl Error for VerifyError {}
+
+impl fmt::Display for VerifyErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyErrorReason::LocalOutOfBounds(local) =>
+                write!(f, "local variable v{} is out of bounds", local.0),
+            VerifyErrorReason::TargetOutOfBounds(target) =>
+                write!(f, "jump target {} is out of bounds", target),
+            VerifyErrorReason::OffsetOutOfBounds(offset) =>
+                write!(f, "jump offset {} is out of bounds", offset),
+            VerifyErrorReason::ConstantOutOfBounds(index) =>
+                write!(f, "constant {} is out of bounds", index),
+            VerifyErrorReason::FallsOffEnd =>
+                write!(f, "interpretation may continue past the last \
+                           instruction"),
+            VerifyErrorReason::TooFewLocalVariables{arity, local_variables} =>
+                write!(f, "spell takes {} arguments but allocates only {} \
+                           local variables", arity, local_variables),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sigil::Sigil;
+
+    fn spell(local_variables: usize, instructions: Vec<Instruction>) -> Spell {
+        Spell{instructions: instructions.into_boxed_slice(), local_variables,
+              constants: Box::new([]), source_map: None}
+    }
+
+    fn reason(spell: &Spell) -> (usize, VerifyErrorReason) {
+        let error = verify(spell).unwrap_err();
+        (error.instruction, error.reason)
+    }
+
+    #[test]
+    fn test_verify_well_formed() {
+        let spell = spell(2, vec![
+            Instruction::BranchIfTruthy{condition: Local(0), target: 3},
+            Instruction::InvokeStatic{
+                result:    Local(1),
+                spellbook: Sigil(0),
+                spell:     Sigil(1),
+                arguments: Box::new([Local(0)]),
+            },
+            Instruction::Jump{target: 0},
+            Instruction::Return{result: Local(1)},
+        ]);
+        assert_eq!(verify(&spell), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_local_out_of_bounds() {
+        let spell = spell(2, vec![
+            Instruction::Copy{from: Local(0), to: Local(1)},
+            Instruction::InvokeDynamic{
+                result:    Local(0),
+                spell:     Sigil(0),
+                receiver:  Local(1),
+                arguments: Box::new([Local(2)]),
+            },
+            Instruction::Return{result: Local(0)},
+        ]);
+        assert_eq!(reason(&spell),
+                   (1, VerifyErrorReason::LocalOutOfBounds(Local(2))));
+    }
+
+    #[test]
+    fn test_verify_copy_range() {
+        let copy_range = |from, to, count| spell(4, vec![
+            Instruction::CopyRange{from: Local(from), to: Local(to), count},
+            Instruction::Return{result: Local(0)},
+        ]);
+        assert_eq!(verify(&copy_range(0, 2, 2)), Ok(()));
+        assert_eq!(verify(&copy_range(1, 0, 3)), Ok(()));
+        assert_eq!(verify(&copy_range(4, 0, 0)), Ok(()));
+        assert_eq!(reason(&copy_range(0, 3, 2)),
+                   (0, VerifyErrorReason::LocalOutOfBounds(Local(4))));
+        assert_eq!(reason(&copy_range(5, 0, 1)),
+                   (0, VerifyErrorReason::LocalOutOfBounds(Local(5))));
+        assert_eq!(reason(&copy_range(1, 0, usize::MAX)),
+                   (0, VerifyErrorReason::LocalOutOfBounds(Local(4))));
+    }
+
+    #[test]
+    fn test_verify_as() {
+        let id = |arity| SpellId{spellbook: Sigil(0), spell: Sigil(1), arity};
+        let spell = spell(2, vec![
+            Instruction::Return{result: Local(1)},
+        ]);
+        assert_eq!(verify_as(id(2), &spell), Ok(()));
+
+        let error = verify_as(id(3), &spell).unwrap_err();
+        assert_eq!(error.reason, VerifyErrorReason::TooFewLocalVariables{
+            arity:           3,
+            local_variables: 2,
+        });
+        assert_eq!(error.to_string(), "instruction 0: spell takes 3 \
+                                       arguments but allocates only 2 local \
+                                       variables");
+
+        // The spell is verified too.
+        let empty = Spell{instructions: Box::new([]), ..spell};
+        assert_eq!(verify_as(id(0), &empty).unwrap_err().reason,
+                   VerifyErrorReason::FallsOffEnd);
+    }
+
+    #[test]
+    fn test_verify_target_out_of_bounds() {
+        let spell = spell(1, vec![
+            Instruction::BranchIfFalsy{condition: Local(0), target: 2},
+            Instruction::Return{result: Local(0)},
+        ]);
+        assert_eq!(reason(&spell),
+                   (0, VerifyErrorReason::TargetOutOfBounds(2)));
+    }
+
+    #[test]
+    fn test_verify_switch() {
+        let switch = |targets: &[usize], default| spell(1, vec![
+            Instruction::Switch{scrutinee: Local(0),
+                                targets: Box::from(targets), default},
+            Instruction::Return{result: Local(0)},
+        ]);
+        assert_eq!(verify(&switch(&[0, 1], 1)), Ok(()));
+        assert_eq!(reason(&switch(&[0, 2], 1)),
+                   (0, VerifyErrorReason::TargetOutOfBounds(2)));
+        assert_eq!(reason(&switch(&[], 3)),
+                   (0, VerifyErrorReason::TargetOutOfBounds(3)));
+    }
+
+    #[test]
+    fn test_verify_relative() {
+        let jump = |offset| spell(1, vec![
+            Instruction::BranchIfFalsyRelative{condition: Local(0), offset: 1},
+            Instruction::JumpRelative{offset},
+            Instruction::Return{result: Local(0)},
+        ]);
+        assert_eq!(verify(&jump(-1)), Ok(()));
+        assert_eq!(verify(&jump(1)), Ok(()));
+        assert_eq!(reason(&jump(-2)),
+                   (1, VerifyErrorReason::OffsetOutOfBounds(-2)));
+        assert_eq!(reason(&jump(2)),
+                   (1, VerifyErrorReason::OffsetOutOfBounds(2)));
+    }
+
+    #[test]
+    fn test_verify_load_constant() {
+        let mut spell = spell(1, vec![
+            Instruction::LoadConstant{result: Local(0), index: 0},
+            Instruction::Return{result: Local(0)},
+        ]);
+        assert_eq!(reason(&spell),
+                   (0, VerifyErrorReason::ConstantOutOfBounds(0)));
+
+        spell.constants = Box::new([
+            ConstantValue{enchantment: Sigil(0), auxiliary: Box::new([])},
+        ]);
+        assert_eq!(verify(&spell), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_falls_off_end() {
+        let spell = spell(2, vec![
+            Instruction::Copy{from: Local(0), to: Local(1)},
+        ]);
+        assert_eq!(reason(&spell), (0, VerifyErrorReason::FallsOffEnd));
+    }
+
+    #[test]
+    fn test_verify_empty() {
+        let spell = spell(0, vec![]);
+        assert_eq!(reason(&spell), (0, VerifyErrorReason::FallsOffEnd));
+    }
+
+    #[test]
+    fn test_verify_error_display() {
+        let spell = spell(1, vec![
+            Instruction::BranchIfFalsy{condition: Local(0), target: 2},
+            Instruction::Return{result: Local(0)},
+        ]);
+        assert_eq!(verify(&spell).unwrap_err().to_string(),
+                   "instruction 0: jump target 2 is out of bounds");
+    }
+}